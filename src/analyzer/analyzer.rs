@@ -1,7 +1,8 @@
 use crate::analyzer::char_filter::CharFilter;
 use crate::analyzer::error::Result;
 use crate::analyzer::token_filter::TokenFilter;
-use crate::analyzer::tokenizer::Tokenizer;
+use crate::analyzer::tokenizer::{Token, Tokenizer};
+use std::borrow::Cow;
 
 #[derive(Debug)]
 pub struct Analyzer<C: CharFilter, T: TokenFilter, I: Tokenizer> {
@@ -24,17 +25,93 @@ where
         }
     }
 
+    /// 只要词本身，建索引、解析查询语句用这个
     pub fn analyze(&self, text: &str) -> Result<Vec<String>> {
-        let text = self.char_filter.filter(text);
         let mut tokens = Vec::<String>::new();
+        self.analyze_each(text, |t| tokens.push(t.to_string()))?;
+
+        Ok(tokens)
+    }
+
+    /// 不收集成Vec<String>，直接把每个token交给f处理。
+    /// tokenizer直接切自输入（Cow::Borrowed）、token_filter也没有改写token的时候，整个过程不会为单个token分配内存
+    pub fn analyze_each(&self, text: &str, mut f: impl FnMut(&str)) -> Result<()> {
+        let text = self.char_filter.filter(text);
 
-        for token in self.tokenizer.tokenize(text.as_str()) {
-            match self.token_filter.filter(token) {
+        if self.token_filter.needs_neighbors() {
+            for token in self.joined_tokens(&text) {
+                f(&token.text);
+            }
+            return Ok(());
+        }
+
+        for token in self.tokenizer.tokenize(&text) {
+            match self.token_filter.filter(&token.text) {
                 None => (),
-                Some(t) => tokens.push(t.to_string()),
+                Some(t) => f(&t),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// 和analyze切出的词一样，另外带上每个词在text（char_filter之前的原文）里的字节偏移，
+    /// 以及在结果里的序号position。
+    ///
+    /// 偏移总是落在字符边界上，text[token.start..token.end]一定可以取。
+    /// 词和原文那一段完全一样时直接借用text，被char_filter或token_filter改过的词（转小写、
+    /// 同义词等）才会复制
+    pub fn analyze_tokens<'t>(&self, text: &'t str) -> Result<Vec<Token<'t>>> {
+        let (filtered, offsets) = self.char_filter.filter_with_offsets(text);
+        let mut tokens = Vec::<Token<'t>>::new();
+
+        let mut push = |t: Cow<str>, start: usize, end: usize| {
+            // 自定义的char filter记录的改动不在字符边界上时，偏移可能落在字符中间
+            let mut start = offsets.start(start).min(text.len());
+            while !text.is_char_boundary(start) {
+                start -= 1;
+            }
+            let mut end = offsets.end(end).clamp(start, text.len());
+            while !text.is_char_boundary(end) {
+                end += 1;
+            }
+
+            let original = &text[start..end];
+            let word = match t == original {
+                true => Cow::Borrowed(original),
+                false => Cow::Owned(t.into_owned()),
+            };
+            tokens.push(Token::new(word, start, end, tokens.len()));
+        };
+
+        if self.token_filter.needs_neighbors() {
+            for token in self.joined_tokens(&filtered) {
+                push(token.text, token.start, token.end);
+            }
+        } else {
+            for token in self.tokenizer.tokenize(&filtered) {
+                if let Some(t) = self.token_filter.filter(&token.text) {
+                    push(t, token.start, token.end);
+                }
             }
         }
 
         Ok(tokens)
     }
+
+    /// token_filter要看相邻的token时：先逐个filter，再整体交给join_neighbors。每个token都要复制一份
+    fn joined_tokens(&self, text: &str) -> Vec<Token<'static>> {
+        let mut tokens = self
+            .tokenizer
+            .tokenize(text)
+            .into_iter()
+            .filter_map(|token| {
+                let t = self.token_filter.filter(&token.text)?.into_owned();
+                Some(Token::new(t, token.start, token.end, token.position))
+            })
+            .collect();
+        self.token_filter.join_neighbors(&mut tokens);
+
+        tokens
+    }
 }
@@ -1,10 +1,63 @@
 use regex::Regex;
+use std::borrow::Cow;
+use std::ops::Range;
 
 pub trait CharFilter {
-    fn filter(&self, text: &str) -> String;
+    /// 不需要修改文本时返回Cow::Borrowed，避免复制整个文档
+    fn filter<'a>(&self, text: &'a str) -> Cow<'a, str>;
+
+    /// 和filter的结果一样，另外返回过滤后的位置怎么换回原文的位置。
+    /// 默认认为filter不改变文本长度，会删掉或替换字符的filter需要覆盖
+    fn filter_with_offsets<'a>(&self, text: &'a str) -> (Cow<'a, str>, OffsetMap) {
+        (self.filter(text), OffsetMap::new())
+    }
+}
+
+/// char filter前后的字节偏移对应关系。
+///
+/// 只记录改动过的地方：过滤后的一段文本替换了原文的一段（删掉时过滤后的那段为空），
+/// 两处改动之间的文本没有变，偏移按和前一处改动的距离推算。
+/// 被删掉的原文不属于前后任何一个词；替换出来的文本（解码的实体、转小写后的字符）
+/// 只要词包含了它的一部分，就对应整段原文
+#[derive(Debug, Clone, Default)]
+pub struct OffsetMap {
+    edits: Vec<(Range<usize>, Range<usize>)>,
+}
+
+impl OffsetMap {
+    pub fn new() -> Self {
+        OffsetMap { edits: Vec::new() }
+    }
+
+    /// 改动必须按位置顺序记录，长度没有变的替换不需要记录
+    pub fn push(&mut self, filtered: Range<usize>, original: Range<usize>) {
+        if filtered.len() != original.len() {
+            self.edits.push((filtered, original));
+        }
+    }
+
+    /// 一个词的开头，落在删掉的地方时换到删掉的部分之后
+    pub fn start(&self, filtered: usize) -> usize {
+        let i = self.edits.partition_point(|e| e.0.start <= filtered);
+        match i.checked_sub(1).map(|i| &self.edits[i]) {
+            None => filtered,
+            Some((f, o)) if filtered < f.end => o.start,
+            Some((f, o)) => o.end + (filtered - f.end),
+        }
+    }
+
+    /// 一个词的结尾，落在删掉的地方时停在删掉的部分之前
+    pub fn end(&self, filtered: usize) -> usize {
+        let i = self.edits.partition_point(|e| e.0.start < filtered);
+        match i.checked_sub(1).map(|i| &self.edits[i]) {
+            None => filtered,
+            Some((f, o)) if filtered <= f.end => o.end,
+            Some((f, o)) => o.end + (filtered - f.end),
+        }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct BasicCharFilter {}
 
 impl BasicCharFilter {
@@ -14,12 +67,12 @@ impl BasicCharFilter {
 }
 
 impl CharFilter for BasicCharFilter {
-    fn filter(&self, text: &str) -> String {
-        text.to_string()
+    fn filter<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        Cow::Borrowed(text)
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct CJKDocCharFilter {}
 
 impl CJKDocCharFilter {
@@ -29,16 +82,215 @@ impl CJKDocCharFilter {
 }
 
 impl CharFilter for CJKDocCharFilter {
-    fn filter(&self, text: &str) -> String {
+    fn filter<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        self.filter_with_offsets(text).0
+    }
+
+    fn filter_with_offsets<'a>(&self, text: &'a str) -> (Cow<'a, str>, OffsetMap) {
+        // 全角字母、半角片假名（\uFF00-\uFFEF）也保留，交给WidthFoldingTokenFilter统一写法
         lazy_static::lazy_static! {
-            static ref REGEX: Regex = Regex::new(r"[\s\p{N}\p{P}a-zA-Z\u2E80-\uFE4F]+").unwrap();
+            static ref REGEX: Regex =
+                Regex::new(r"[\s\p{N}\p{P}a-zA-Z\u2E80-\uFE4F\uFF00-\uFFEF]+").unwrap();
         }
 
         let mut result = String::new();
+        let mut offsets = OffsetMap::new();
+        let mut last_end = 0usize;
         for capture in REGEX.captures_iter(text) {
-            result.push_str(capture.get(0).unwrap().as_str());
+            let m = capture.get(0).unwrap();
+            offsets.push(result.len()..result.len(), last_end..m.start());
+            result.push_str(m.as_str());
+            last_end = m.end();
+        }
+
+        (Cow::Owned(result), offsets)
+    }
+}
+
+/// 把文本转成小写，让"Genshin"和"genshin"是同一个词。没有大写字母时不会复制
+#[derive(Debug, Default)]
+pub struct LowercaseCharFilter {}
+
+impl LowercaseCharFilter {
+    pub fn new() -> Self {
+        LowercaseCharFilter {}
+    }
+}
+
+impl CharFilter for LowercaseCharFilter {
+    fn filter<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        if text.chars().any(|c| c.is_uppercase()) {
+            Cow::Owned(text.to_lowercase())
+        } else {
+            Cow::Borrowed(text)
+        }
+    }
+
+    /// 有些字符转小写后字节数会变（比如'İ'变成"i̇"），每个这样的字符记一处替换
+    fn filter_with_offsets<'a>(&self, text: &'a str) -> (Cow<'a, str>, OffsetMap) {
+        let result = self.filter(text);
+        let mut offsets = OffsetMap::new();
+
+        if let Cow::Owned(_) = result {
+            let mut filtered = 0usize;
+            for (i, c) in text.char_indices() {
+                let len = c.to_lowercase().map(char::len_utf8).sum::<usize>();
+                offsets.push(filtered..filtered + len, i..i + c.len_utf8());
+                filtered += len;
+            }
+        }
+
+        (result, offsets)
+    }
+}
+
+/// 去掉html标签，只留下可见的文本，并解码常见的实体。
+/// script/style里的内容和注释整个丢掉；块级标签换成空格，避免前后两段文字粘在一起，
+/// 行内标签（b、a、span等）直接去掉，不会把一个词拆开
+#[derive(Debug, Default)]
+pub struct HtmlStripCharFilter {}
+
+impl HtmlStripCharFilter {
+    pub fn new() -> Self {
+        HtmlStripCharFilter {}
+    }
+}
+
+const HTML_INLINE_TAGS: &[&str] = &[
+    "a", "abbr", "b", "code", "em", "font", "i", "mark", "s", "small", "span", "strong", "sub",
+    "sup", "u",
+];
+
+impl CharFilter for HtmlStripCharFilter {
+    fn filter<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        self.filter_with_offsets(text).0
+    }
+
+    /// 每去掉一个标签、解码一个实体记一处替换，换成的空格也算在替换出来的文本里
+    fn filter_with_offsets<'a>(&self, text: &'a str) -> (Cow<'a, str>, OffsetMap) {
+        let mut offsets = OffsetMap::new();
+        if !text.contains(['<', '&']) {
+            return (Cow::Borrowed(text), offsets);
+        }
+
+        let mut result = String::with_capacity(text.len());
+        let mut rest = text;
+
+        while let Some(i) = rest.find(['<', '&']) {
+            result.push_str(&rest[..i]);
+            rest = &rest[i..];
+
+            // 这一处替换开始的位置
+            let (filtered, original) = (result.len(), text.len() - rest.len());
+
+            if rest.starts_with('&') {
+                let (decoded, len) = decode_html_entity(rest);
+                match decoded {
+                    Some(c) => result.push(c),
+                    None => result.push('&'),
+                }
+                rest = &rest[len..];
+                offsets.push(filtered..result.len(), original..text.len() - rest.len());
+                continue;
+            }
+
+            // <后面不是标签的开头时当作普通文本，比如"a < b"
+            let tag_start = rest[1..].chars().next();
+            if !matches!(tag_start, Some(c) if c.is_ascii_alphabetic() || c == '/' || c == '!' || c == '?')
+            {
+                result.push('<');
+                rest = &rest[1..];
+                continue;
+            }
+
+            if rest.starts_with("<!--") {
+                rest = rest.find("-->").map_or("", |end| &rest[end + 3..]);
+                push_space(&mut result);
+                offsets.push(filtered..result.len(), original..text.len() - rest.len());
+                continue;
+            }
+
+            // 没有闭合的标签一直到结尾，剩下的都丢掉
+            let end = match rest.find('>') {
+                Some(end) => end,
+                None => {
+                    rest = "";
+                    break;
+                }
+            };
+
+            let tag = &rest[1..end];
+            rest = &rest[end + 1..];
+
+            let is_close = tag.starts_with('/');
+            let name = tag
+                .trim_start_matches('/')
+                .split(|c: char| c.is_whitespace() || c == '/')
+                .next()
+                .unwrap_or("")
+                .to_ascii_lowercase();
+
+            if !is_close && (name == "script" || name == "style") {
+                let close = format!("</{}", name);
+                rest = match find_ignore_ascii_case(rest, &close) {
+                    Some(i) => rest[i..].find('>').map_or("", |end| &rest[i + end + 1..]),
+                    None => "",
+                };
+            }
+
+            if !HTML_INLINE_TAGS.contains(&name.as_str()) {
+                push_space(&mut result);
+            }
+            offsets.push(filtered..result.len(), original..text.len() - rest.len());
         }
 
-        result
+        result.push_str(rest);
+        (Cow::Owned(result), offsets)
+    }
+}
+
+/// 已经是空白结尾时不再重复加空格
+#[inline]
+fn push_space(result: &mut String) {
+    if !result.ends_with(char::is_whitespace) && !result.is_empty() {
+        result.push(' ');
+    }
+}
+
+fn find_ignore_ascii_case(haystack: &str, needle: &str) -> Option<usize> {
+    haystack
+        .as_bytes()
+        .windows(needle.len())
+        .position(|w| w.eq_ignore_ascii_case(needle.as_bytes()))
+}
+
+/// text以&开头，返回(解码出来的字符, 实体的字节长度)，不是合法的实体时返回(None, 1)
+fn decode_html_entity(text: &str) -> (Option<char>, usize) {
+    let end = match text[1..].find(';') {
+        // 实体都很短，太远的;不可能属于这个&
+        Some(end) if end <= 10 => end + 1,
+        _ => return (None, 1),
+    };
+
+    let c = match &text[1..end] {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some(' '),
+        entity => match entity.strip_prefix('#') {
+            Some(num) => match num.strip_prefix(['x', 'X']) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                None => num.parse::<u32>().ok(),
+            }
+            .and_then(char::from_u32),
+            None => None,
+        },
+    };
+
+    match c {
+        Some(c) => (Some(c), end + 1),
+        None => (None, 1),
     }
 }
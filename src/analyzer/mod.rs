@@ -2,6 +2,7 @@ pub mod tokenizer;
 pub mod char_filter;
 pub mod token_filter;
 pub mod analyzer;
+pub mod registry;
 mod error;
 
 pub use error::Error;
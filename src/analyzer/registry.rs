@@ -0,0 +1,299 @@
+use crate::analyzer::analyzer::Analyzer;
+use crate::analyzer::char_filter::{
+    BasicCharFilter, CJKDocCharFilter, CharFilter, HtmlStripCharFilter, LowercaseCharFilter,
+    OffsetMap,
+};
+use crate::analyzer::token_filter::{
+    BasicTokenFilter, CJKSingleCharPolicy, CJKSingleCharTokenFilter, StopWordTokenFilter,
+    SynonymTokenFilter, TokenFilter, WidthFoldingTokenFilter,
+};
+use crate::analyzer::tokenizer::{
+    CjkBigramTokenizer, JiebaTokenizer, NgramTokenizer, Token, Tokenizer, UnicodeWordTokenizer,
+};
+use crate::analyzer::Result;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// 按配置创建的analyzer，各个组件在运行时按名字选择
+pub type ConfiguredAnalyzer = Analyzer<AnyCharFilter, AnyTokenFilter, AnyTokenizer>;
+
+/// 配置文件里的一个analyzer，例如
+///
+/// ```toml
+/// char_filter = "cjk_doc"
+/// tokenizer = { type = "jieba" }
+/// token_filters = [{ type = "stop_words", path = "dict/stop_words.txt" }]
+/// ```
+///
+/// 没有写的部分和默认的一样：CJKDocCharFilter、JiebaTokenizer、BasicTokenFilter
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AnalyzerSettings {
+    pub char_filter: CharFilterName,
+    pub tokenizer: TokenizerSettings,
+    /// 按顺序组合：basic或stop_words只能放在第一个，synonym和cjk_single_char包装前面的结果。
+    /// width_fold只能放在最前面（basic、stop_words之前），最先统一全角半角。
+    /// 为空时相当于[basic]
+    pub token_filters: Vec<TokenFilterSettings>,
+}
+
+impl AnalyzerSettings {
+    /// 停用词、同义词文件在这里读取，相对路径相对于当前目录
+    pub fn build(&self) -> Result<ConfiguredAnalyzer> {
+        Ok(Analyzer::new(
+            self.char_filter.build(),
+            build_token_filter(&self.token_filters)?,
+            self.tokenizer.build()?,
+        ))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CharFilterName {
+    Basic,
+    #[default]
+    CjkDoc,
+    Lowercase,
+    HtmlStrip,
+}
+
+impl CharFilterName {
+    pub fn build(self) -> AnyCharFilter {
+        match self {
+            CharFilterName::Basic => AnyCharFilter::Basic(BasicCharFilter::new()),
+            CharFilterName::CjkDoc => AnyCharFilter::CJKDoc(CJKDocCharFilter::new()),
+            CharFilterName::Lowercase => AnyCharFilter::Lowercase(LowercaseCharFilter::new()),
+            CharFilterName::HtmlStrip => AnyCharFilter::HtmlStrip(HtmlStripCharFilter::new()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum AnyCharFilter {
+    Basic(BasicCharFilter),
+    CJKDoc(CJKDocCharFilter),
+    Lowercase(LowercaseCharFilter),
+    HtmlStrip(HtmlStripCharFilter),
+}
+
+impl CharFilter for AnyCharFilter {
+    fn filter<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        match self {
+            AnyCharFilter::Basic(f) => f.filter(text),
+            AnyCharFilter::CJKDoc(f) => f.filter(text),
+            AnyCharFilter::Lowercase(f) => f.filter(text),
+            AnyCharFilter::HtmlStrip(f) => f.filter(text),
+        }
+    }
+
+    fn filter_with_offsets<'a>(&self, text: &'a str) -> (Cow<'a, str>, OffsetMap) {
+        match self {
+            AnyCharFilter::Basic(f) => f.filter_with_offsets(text),
+            AnyCharFilter::CJKDoc(f) => f.filter_with_offsets(text),
+            AnyCharFilter::Lowercase(f) => f.filter_with_offsets(text),
+            AnyCharFilter::HtmlStrip(f) => f.filter_with_offsets(text),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TokenizerSettings {
+    #[default]
+    Jieba,
+    Ngram {
+        min_gram: usize,
+        max_gram: usize,
+    },
+    UnicodeWord,
+    /// 不写gram时是2
+    CjkBigram {
+        #[serde(default = "default_cjk_gram")]
+        gram: usize,
+    },
+}
+
+fn default_cjk_gram() -> usize {
+    2
+}
+
+impl TokenizerSettings {
+    /// n-gram的范围不对时返回错误，而不是像NgramTokenizer::new一样panic
+    pub fn build(&self) -> Result<AnyTokenizer> {
+        Ok(match *self {
+            TokenizerSettings::Jieba => AnyTokenizer::Jieba(JiebaTokenizer::new()),
+            TokenizerSettings::Ngram { min_gram, max_gram } => {
+                if min_gram == 0 || min_gram > max_gram {
+                    return Err(invalid(format!(
+                        "invalid n-gram range {}..={}",
+                        min_gram, max_gram
+                    )));
+                }
+                AnyTokenizer::Ngram(NgramTokenizer::new(min_gram, max_gram))
+            }
+            TokenizerSettings::UnicodeWord => {
+                AnyTokenizer::UnicodeWord(UnicodeWordTokenizer::new())
+            }
+            TokenizerSettings::CjkBigram { gram } => {
+                if gram == 0 {
+                    return Err(invalid("invalid CJK gram size 0".to_string()));
+                }
+                AnyTokenizer::CjkBigram(CjkBigramTokenizer::new(gram))
+            }
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum AnyTokenizer {
+    Jieba(JiebaTokenizer),
+    Ngram(NgramTokenizer),
+    UnicodeWord(UnicodeWordTokenizer),
+    CjkBigram(CjkBigramTokenizer),
+}
+
+impl Tokenizer for AnyTokenizer {
+    fn tokenize<'a>(&self, text: &'a str) -> Vec<Token<'a>> {
+        match self {
+            AnyTokenizer::Jieba(t) => t.tokenize(text),
+            AnyTokenizer::Ngram(t) => t.tokenize(text),
+            AnyTokenizer::UnicodeWord(t) => t.tokenize(text),
+            AnyTokenizer::CjkBigram(t) => t.tokenize(text),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TokenFilterSettings {
+    Basic,
+    StopWords { path: PathBuf },
+    Synonym { path: PathBuf },
+    CjkSingleChar { policy: CJKSingleCharPolicy },
+    WidthFold,
+}
+
+#[derive(Debug)]
+pub enum AnyTokenFilter {
+    Basic(BasicTokenFilter),
+    StopWords(StopWordTokenFilter),
+    Synonym(SynonymTokenFilter<Box<AnyTokenFilter>>),
+    CJKSingleChar(CJKSingleCharTokenFilter<Box<AnyTokenFilter>>),
+    WidthFold(WidthFoldingTokenFilter<Box<AnyTokenFilter>>),
+}
+
+impl TokenFilter for AnyTokenFilter {
+    fn filter<'a>(&self, token: &'a str) -> Option<Cow<'a, str>> {
+        match self {
+            AnyTokenFilter::Basic(f) => f.filter(token),
+            AnyTokenFilter::StopWords(f) => f.filter(token),
+            AnyTokenFilter::Synonym(f) => f.filter(token),
+            AnyTokenFilter::CJKSingleChar(f) => f.filter(token),
+            AnyTokenFilter::WidthFold(f) => f.filter(token),
+        }
+    }
+
+    fn needs_neighbors(&self) -> bool {
+        match self {
+            AnyTokenFilter::Basic(f) => f.needs_neighbors(),
+            AnyTokenFilter::StopWords(f) => f.needs_neighbors(),
+            AnyTokenFilter::Synonym(f) => f.needs_neighbors(),
+            AnyTokenFilter::CJKSingleChar(f) => f.needs_neighbors(),
+            AnyTokenFilter::WidthFold(f) => f.needs_neighbors(),
+        }
+    }
+
+    fn join_neighbors(&self, tokens: &mut Vec<Token<'static>>) {
+        match self {
+            AnyTokenFilter::Basic(f) => f.join_neighbors(tokens),
+            AnyTokenFilter::StopWords(f) => f.join_neighbors(tokens),
+            AnyTokenFilter::Synonym(f) => f.join_neighbors(tokens),
+            AnyTokenFilter::CJKSingleChar(f) => f.join_neighbors(tokens),
+            AnyTokenFilter::WidthFold(f) => f.join_neighbors(tokens),
+        }
+    }
+}
+
+fn build_token_filter(settings: &[TokenFilterSettings]) -> Result<AnyTokenFilter> {
+    // width_fold在inner之前转换，只能放在最前面，包装的是后面所有filter组成的链，
+    // 后面的filter（包括basic、stop_words）看到的都是转换后的token
+    if let Some((TokenFilterSettings::WidthFold, rest)) = settings.split_first() {
+        let inner = Box::new(build_token_filter(rest)?);
+        return Ok(AnyTokenFilter::WidthFold(WidthFoldingTokenFilter::new(
+            inner,
+        )));
+    }
+
+    let mut filter = None::<AnyTokenFilter>;
+
+    for (i, s) in settings.iter().enumerate() {
+        filter = Some(match s {
+            TokenFilterSettings::Basic | TokenFilterSettings::StopWords { .. } if i > 0 => {
+                return Err(invalid(
+                    "basic and stop_words can only be the first token filter after width_fold"
+                        .to_string(),
+                ))
+            }
+            TokenFilterSettings::Basic => basic_token_filter(),
+            TokenFilterSettings::StopWords { path } => {
+                AnyTokenFilter::StopWords(StopWordTokenFilter::with_stop_words(stop_words(path)?))
+            }
+            TokenFilterSettings::Synonym { path } => {
+                let file = open(path)?;
+                AnyTokenFilter::Synonym(SynonymTokenFilter::new(take_inner(&mut filter), file)?)
+            }
+            TokenFilterSettings::CjkSingleChar { policy } => AnyTokenFilter::CJKSingleChar(
+                CJKSingleCharTokenFilter::new(take_inner(&mut filter), *policy),
+            ),
+            TokenFilterSettings::WidthFold => {
+                return Err(invalid(
+                    "width_fold can only be placed before all other token filters".to_string(),
+                ))
+            }
+        });
+    }
+
+    Ok(filter.unwrap_or_else(basic_token_filter))
+}
+
+/// 前面还没有filter时包装BasicTokenFilter
+fn take_inner(filter: &mut Option<AnyTokenFilter>) -> Box<AnyTokenFilter> {
+    Box::new(filter.take().unwrap_or_else(basic_token_filter))
+}
+
+fn basic_token_filter() -> AnyTokenFilter {
+    AnyTokenFilter::Basic(BasicTokenFilter::new())
+}
+
+/// 同一个停用词文件只在第一次用到时读取，之后所有analyzer（title、content、查询，
+/// 以及查询服务打开的每个索引）共享这一份。修改文件后需要重启才会生效
+fn stop_words(path: &Path) -> Result<Arc<HashSet<String>>> {
+    lazy_static::lazy_static! {
+        static ref LOADED: Mutex<HashMap<PathBuf, Arc<HashSet<String>>>> = Mutex::new(HashMap::new());
+    }
+
+    let mut loaded = LOADED.lock().unwrap();
+    if let Some(words) = loaded.get(path) {
+        return Ok(words.clone());
+    }
+
+    let words = Arc::new(StopWordTokenFilter::read_stop_words(open(path)?)?);
+    loaded.insert(path.to_path_buf(), words.clone());
+    Ok(words)
+}
+
+/// 错误信息里带上文件路径，方便知道是配置里的哪个文件有问题
+fn open(path: &Path) -> Result<File> {
+    File::open(path)
+        .map_err(|e| std::io::Error::new(e.kind(), format!("{}: {}", path.display(), e)).into())
+}
+
+fn invalid(message: String) -> crate::analyzer::Error {
+    std::io::Error::new(ErrorKind::InvalidInput, message).into()
+}
@@ -1,14 +1,42 @@
+use crate::analyzer::tokenizer::Token;
 use crate::analyzer::Result;
 use regex::Regex;
-use std::collections::HashSet;
-use std::fs::File;
-use std::io::Read;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader, Read};
+use std::sync::Arc;
 
 pub trait TokenFilter {
-    fn filter<'a>(&self, token: &'a str) -> Option<&'a str>;
+    /// 返回None表示丢弃这个token。大多数filter原样返回Cow::Borrowed，需要改写token时返回Cow::Owned
+    fn filter<'a>(&self, token: &'a str) -> Option<Cow<'a, str>>;
+
+    /// 要看相邻token的filter返回true，Analyzer会先对所有token调用filter，再把留下来的交给join_neighbors
+    fn needs_neighbors(&self) -> bool {
+        false
+    }
+
+    /// 按相邻关系改写filter之后的整个token序列（合并、去掉），只在needs_neighbors为true时调用。
+    /// 偏移是tokenize时的，合并出的token覆盖被合并的token的范围；position由Analyzer重新编号
+    fn join_neighbors(&self, _tokens: &mut Vec<Token<'static>>) {}
 }
 
-#[derive(Debug)]
+/// 按配置组合filter时，包装类的filter里放的是Box
+impl<T: TokenFilter + ?Sized> TokenFilter for Box<T> {
+    fn filter<'a>(&self, token: &'a str) -> Option<Cow<'a, str>> {
+        (**self).filter(token)
+    }
+
+    fn needs_neighbors(&self) -> bool {
+        (**self).needs_neighbors()
+    }
+
+    fn join_neighbors(&self, tokens: &mut Vec<Token<'static>>) {
+        (**self).join_neighbors(tokens)
+    }
+}
+
+#[derive(Debug, Default)]
 pub struct BasicTokenFilter {}
 
 impl BasicTokenFilter {
@@ -18,7 +46,7 @@ impl BasicTokenFilter {
 }
 
 impl TokenFilter for BasicTokenFilter {
-    fn filter<'a>(&self, token: &'a str) -> Option<&'a str> {
+    fn filter<'a>(&self, token: &'a str) -> Option<Cow<'a, str>> {
         lazy_static::lazy_static! {
             static ref REGEX: Regex = Regex::new(r"\s+").unwrap();
         }
@@ -26,45 +54,519 @@ impl TokenFilter for BasicTokenFilter {
         if REGEX.is_match(token) {
             None
         } else {
-            Some(token)
+            Some(Cow::Borrowed(token))
         }
     }
 }
 
+/// 在BasicTokenFilter的基础上去掉停用词。没有停用词文件时直接用BasicTokenFilter。
+/// 停用词表可以在多个analyzer之间共享，title、content和查询用同一份表时不会各自读一遍
 #[derive(Debug)]
 pub struct StopWordTokenFilter {
-    stop_words: HashSet<String>,
+    basic: BasicTokenFilter,
+    stop_words: Arc<HashSet<String>>,
 }
 
 impl StopWordTokenFilter {
-    pub fn new(dict_file: &mut File) -> Result<Self> {
-        let mut buf = String::new();
-        dict_file.read_to_string(&mut buf)?;
+    /// 每行一个停用词，首尾空白和空行会被忽略
+    pub fn new(reader: impl Read) -> Result<Self> {
+        let stop_words = Self::read_stop_words(reader)?;
+        Ok(Self::with_stop_words(Arc::new(stop_words)))
+    }
 
+    /// 用已经读好的停用词表，空表不会去掉任何词
+    pub fn with_stop_words(stop_words: Arc<HashSet<String>>) -> Self {
+        StopWordTokenFilter {
+            basic: BasicTokenFilter::new(),
+            stop_words,
+        }
+    }
+
+    /// 格式和new一样
+    pub fn read_stop_words(reader: impl Read) -> Result<HashSet<String>> {
         let mut stop_words = HashSet::<String>::new();
 
-        for i in buf.split('\n') {
-            stop_words.insert(i.trim().to_string());
+        for line in BufReader::new(reader).lines() {
+            let line = line?;
+            let word = line.trim();
+            if !word.is_empty() {
+                stop_words.insert(word.to_string());
+            }
         }
 
-        Ok(StopWordTokenFilter { stop_words })
+        Ok(stop_words)
     }
 }
 
 impl TokenFilter for StopWordTokenFilter {
-    fn filter<'a>(&self, token: &'a str) -> Option<&'a str> {
-        lazy_static::lazy_static! {
-            static ref REGEX: Regex = Regex::new(r"\s+").unwrap();
-        }
+    fn filter<'a>(&self, token: &'a str) -> Option<Cow<'a, str>> {
+        let token = self.basic.filter(token)?;
 
-        if REGEX.is_match(token) {
+        if self.stop_words.contains(token.as_ref()) {
             return None;
         }
 
-        if self.stop_words.contains(token) {
+        Some(token)
+    }
+}
+
+/// 包装另一个TokenFilter，把同义词统一改写成标准形式。建索引和查询要用同样的同义词表，
+/// 这样搜"雷神"和搜"雷电将军"得到的是同一个term。目前只支持单个token到单个token的映射
+#[derive(Debug)]
+pub struct SynonymTokenFilter<T: TokenFilter> {
+    inner: T,
+    /// 同义词 => 标准形式
+    synonyms: HashMap<String, String>,
+}
+
+impl<T: TokenFilter> SynonymTokenFilter<T> {
+    /// 每行一组同义词，有两种写法：
+    /// - `雷神, 巴尔 => 雷电将军`：左边的词都改写成右边的词
+    /// - `雷电将军, 雷神, 巴尔`：后面的词都改写成第一个词
+    ///
+    /// 空行和#开头的行会被忽略
+    pub fn new(inner: T, reader: impl Read) -> Result<Self> {
+        let mut synonyms = HashMap::<String, String>::new();
+
+        for (i, line) in BufReader::new(reader).lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (words, canonical) = match line.split_once("=>") {
+                Some((words, canonical)) => (words, canonical.trim()),
+                None => line
+                    .split_once(',')
+                    .map_or((line, ""), |(c, w)| (w, c.trim())),
+            };
+
+            if canonical.is_empty() || canonical.contains(',') {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("invalid synonym rule at line {}: {}", i + 1, line),
+                )
+                .into());
+            }
+
+            for word in words.split(',').map(str::trim).filter(|w| !w.is_empty()) {
+                if word != canonical {
+                    synonyms.insert(word.to_string(), canonical.to_string());
+                }
+            }
+        }
+
+        Ok(SynonymTokenFilter { inner, synonyms })
+    }
+}
+
+impl<T: TokenFilter> TokenFilter for SynonymTokenFilter<T> {
+    fn filter<'a>(&self, token: &'a str) -> Option<Cow<'a, str>> {
+        let token = self.inner.filter(token)?;
+
+        match self.synonyms.get(token.as_ref()) {
+            Some(canonical) => Some(Cow::Owned(canonical.clone())),
+            None => Some(token),
+        }
+    }
+
+    fn needs_neighbors(&self) -> bool {
+        self.inner.needs_neighbors()
+    }
+
+    fn join_neighbors(&self, tokens: &mut Vec<Token<'static>>) {
+        self.inner.join_neighbors(tokens)
+    }
+}
+
+/// CJK单字token的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CJKSingleCharPolicy {
+    /// 原样保留
+    Keep,
+    /// 丢弃单字token，只保留包含它的词（例如jieba cut_for_search或者bigram切出来的多字token）
+    Drop,
+    /// 单字token和紧挨着的CJK字组成两个字的词代替它：优先和后一个token的第一个字组成，
+    /// 后面没有挨着的CJK字时和前一个token的最后一个字组成。连续的单字（"我 的 猫"）得到重叠的bigram
+    /// （"我的"、"的猫"），和CJKBigramTokenizer一样；前后都没有挨着的CJK字时保留单字。
+    /// 只在原文里紧挨着时才组合，中间有空白、标点或者被去掉的停用词时不算相邻
+    #[serde(rename = "bigram_only")]
+    BigramOnly,
+}
+
+/// 包装另一个TokenFilter，按policy处理单个CJK字符构成的token，拉丁字母等非CJK单字符不受影响
+#[derive(Debug)]
+pub struct CJKSingleCharTokenFilter<T: TokenFilter> {
+    inner: T,
+    policy: CJKSingleCharPolicy,
+}
+
+impl<T: TokenFilter> CJKSingleCharTokenFilter<T> {
+    pub fn new(inner: T, policy: CJKSingleCharPolicy) -> Self {
+        CJKSingleCharTokenFilter { inner, policy }
+    }
+}
+
+impl<T: TokenFilter> TokenFilter for CJKSingleCharTokenFilter<T> {
+    fn filter<'a>(&self, token: &'a str) -> Option<Cow<'a, str>> {
+        let token = self.inner.filter(token)?;
+
+        if self.policy == CJKSingleCharPolicy::Drop && is_cjk_single_char(&token) {
             return None;
         }
 
         Some(token)
     }
+
+    fn needs_neighbors(&self) -> bool {
+        self.policy == CJKSingleCharPolicy::BigramOnly || self.inner.needs_neighbors()
+    }
+
+    fn join_neighbors(&self, tokens: &mut Vec<Token<'static>>) {
+        self.inner.join_neighbors(tokens);
+        if self.policy != CJKSingleCharPolicy::BigramOnly {
+            return;
+        }
+
+        let mut joined = Vec::with_capacity(tokens.len());
+        for (i, token) in tokens.iter().enumerate() {
+            if !is_cjk_single_char(&token.text) {
+                joined.push(token.clone());
+                continue;
+            }
+
+            let next = tokens
+                .get(i + 1)
+                .filter(|next| next.start == token.end)
+                .and_then(|next| Some((next, next.text.chars().next()?)))
+                .filter(|(_, c)| is_cjk_char(*c));
+            let prev = i
+                .checked_sub(1)
+                .map(|i| &tokens[i])
+                .filter(|prev| prev.end == token.start)
+                .and_then(|prev| Some((prev, prev.text.chars().last()?)))
+                .filter(|(_, c)| is_cjk_char(*c));
+
+            match (prev, next) {
+                (_, Some((next, c))) => joined.push(Token::new(
+                    format!("{}{}", token.text, c),
+                    token.start,
+                    next.end,
+                    0,
+                )),
+                // 前一个单字已经和这个字组成了bigram
+                (Some((prev, _)), None) if is_cjk_single_char(&prev.text) => (),
+                (Some((prev, c)), None) => joined.push(Token::new(
+                    format!("{}{}", c, token.text),
+                    prev.start,
+                    token.end,
+                    0,
+                )),
+                (None, None) => joined.push(token.clone()),
+            }
+        }
+
+        *tokens = joined;
+    }
+}
+
+/// 包装另一个TokenFilter，先把全角和半角的写法统一，再交给inner，所以inner里的停用词、同义词只需要写半角的
+/// ASCII和全角的片假名。统一的范围：
+/// - 全角ASCII（ＡＢＣ１２３！）和全角空格换成半角
+/// - 全角的￠￡￢￣￤￥￦换成对应的半角符号
+/// - 半角片假名和标点（ｶﾀｶﾅ｡）换成全角，后面跟着的半角浊点、半浊点和前一个假名合成一个字（ｶﾞ => ガ）
+///
+/// 不转换大小写，也不做繁简转换。建索引和查询要用同样的设置，不然"ＡＢＣ"和"ABC"还是不同的term
+///
+/// ```
+/// use raiden_shogun_search::analyzer::token_filter::{
+///     BasicTokenFilter, StopWordTokenFilter, TokenFilter, WidthFoldingTokenFilter,
+/// };
+///
+/// let filter = WidthFoldingTokenFilter::new(BasicTokenFilter::new());
+/// let fold = |token| filter.filter(token).map(|t| t.into_owned());
+///
+/// assert_eq!(fold("ＡＢＣ１２３"), Some("ABC123".to_string()));
+/// assert_eq!(fold("Ｒａｉｄｅｎ将军２"), Some("Raiden将军2".to_string()));
+/// assert_eq!(fold("ＡBＣ"), fold("ABC"));
+/// assert_eq!(fold("ｶﾞｲﾄﾞﾌﾞｯｸ"), Some("ガイドブック".to_string()));
+/// assert_eq!(fold("ﾊﾟﾝ｡"), Some("パン。".to_string()));
+/// assert_eq!(fold("￥１００"), Some("¥100".to_string()));
+/// // 已经是统一的写法时不复制
+/// assert_eq!(filter.filter("ＡＢＣ　１２３"), None);
+/// assert!(matches!(filter.filter("雷电将军"), Some(std::borrow::Cow::Borrowed(_))));
+///
+/// // 在停用词之前统一，全角的停用词也会被去掉
+/// let stop_words = StopWordTokenFilter::new("the\nof".as_bytes()).unwrap();
+/// let filter = WidthFoldingTokenFilter::new(stop_words);
+/// assert_eq!(filter.filter("ｔｈｅ"), None);
+/// assert_eq!(filter.filter("ｏｆ"), None);
+/// assert_eq!(filter.filter("ｏｆｆ").unwrap(), "off");
+/// ```
+#[derive(Debug)]
+pub struct WidthFoldingTokenFilter<T: TokenFilter> {
+    inner: T,
+}
+
+impl<T: TokenFilter> WidthFoldingTokenFilter<T> {
+    pub fn new(inner: T) -> Self {
+        WidthFoldingTokenFilter { inner }
+    }
+}
+
+impl<T: TokenFilter> TokenFilter for WidthFoldingTokenFilter<T> {
+    fn filter<'a>(&self, token: &'a str) -> Option<Cow<'a, str>> {
+        match fold_width(token) {
+            Cow::Borrowed(token) => self.inner.filter(token),
+            // inner的结果借用的是临时的字符串，要复制一份
+            Cow::Owned(folded) => self
+                .inner
+                .filter(&folded)
+                .map(|t| Cow::Owned(t.into_owned())),
+        }
+    }
+
+    fn needs_neighbors(&self) -> bool {
+        self.inner.needs_neighbors()
+    }
+
+    fn join_neighbors(&self, tokens: &mut Vec<Token<'static>>) {
+        self.inner.join_neighbors(tokens)
+    }
+}
+
+/// 没有需要转换的字符时返回Cow::Borrowed
+fn fold_width(token: &str) -> Cow<'_, str> {
+    if !token
+        .chars()
+        .any(|c| fold_char(c).is_some() || is_halfwidth_kana(c))
+    {
+        return Cow::Borrowed(token);
+    }
+
+    let mut folded = String::with_capacity(token.len());
+    let mut chars = token.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if is_halfwidth_kana(c) {
+            let kana = HALFWIDTH_KANA[(c as u32 - 0xFF61) as usize];
+            let voiced = match chars.peek() {
+                Some('\u{FF9E}') => voice_kana(kana, false),
+                Some('\u{FF9F}') => voice_kana(kana, true),
+                _ => None,
+            };
+
+            match voiced {
+                Some(voiced) => {
+                    folded.push(voiced);
+                    chars.next();
+                }
+                None => folded.push(kana),
+            }
+        } else {
+            folded.push(fold_char(c).unwrap_or(c));
+        }
+    }
+
+    Cow::Owned(folded)
+}
+
+/// 半角片假名以外需要转换的字符
+#[inline]
+fn fold_char(c: char) -> Option<char> {
+    match c {
+        '\u{FF01}'..='\u{FF5E}' => char::from_u32(c as u32 - 0xFEE0),
+        '\u{3000}' => Some(' '),
+        '\u{FFE0}' => Some('\u{A2}'),
+        '\u{FFE1}' => Some('\u{A3}'),
+        '\u{FFE2}' => Some('\u{AC}'),
+        '\u{FFE3}' => Some('\u{AF}'),
+        '\u{FFE4}' => Some('\u{A6}'),
+        '\u{FFE5}' => Some('\u{A5}'),
+        '\u{FFE6}' => Some('\u{20A9}'),
+        _ => None,
+    }
+}
+
+#[inline]
+fn is_halfwidth_kana(c: char) -> bool {
+    ('\u{FF61}'..='\u{FF9F}').contains(&c)
+}
+
+/// U+FF61到U+FF9F对应的全角字符，最后两个是单独出现的浊点、半浊点
+const HALFWIDTH_KANA: [char; 63] = [
+    '。', '「', '」', '、', '・', 'ヲ', 'ァ', 'ィ', 'ゥ', 'ェ', 'ォ', 'ャ', 'ュ', 'ョ', 'ッ', 'ー',
+    'ア', 'イ', 'ウ', 'エ', 'オ', 'カ', 'キ', 'ク', 'ケ', 'コ', 'サ', 'シ', 'ス', 'セ', 'ソ', 'タ',
+    'チ', 'ツ', 'テ', 'ト', 'ナ', 'ニ', 'ヌ', 'ネ', 'ノ', 'ハ', 'ヒ', 'フ', 'ヘ', 'ホ', 'マ', 'ミ',
+    'ム', 'メ', 'モ', 'ヤ', 'ユ', 'ヨ', 'ラ', 'リ', 'ル', 'レ', 'ロ', 'ワ', 'ン', '゛', '゜',
+];
+
+/// 全角假名加上浊点（semi为true时是半浊点）后的字，不能加的返回None
+fn voice_kana(kana: char, semi: bool) -> Option<char> {
+    let code = kana as u32;
+    let voiced = match kana {
+        // ハ行：浊音+1，半浊音+2
+        'ハ' | 'ヒ' | 'フ' | 'ヘ' | 'ホ' if semi => code + 2,
+        'ハ' | 'ヒ' | 'フ' | 'ヘ' | 'ホ' => code + 1,
+        _ if semi => return None,
+        // カ行到タ行（不含促音ッ）：浊音+1
+        'カ' | 'キ' | 'ク' | 'ケ' | 'コ' | 'サ' | 'シ' | 'ス' | 'セ' | 'ソ' | 'タ' | 'チ'
+        | 'ツ' | 'テ' | 'ト' => code + 1,
+        'ウ' => 'ヴ' as u32,
+        'ワ' => 'ヷ' as u32,
+        'ヲ' => 'ヺ' as u32,
+        _ => return None,
+    };
+
+    char::from_u32(voiced)
+}
+
+/// 和CJKDocCharFilter保持一致的CJK范围，另外加上扩展B及之后的表意文字
+#[inline]
+pub fn is_cjk_char(c: char) -> bool {
+    ('\u{2E80}'..='\u{FE4F}').contains(&c) || ('\u{20000}'..='\u{2FA1F}').contains(&c)
+}
+
+/// 按unicode scalar value计数，而不是字节数
+#[inline]
+fn is_cjk_single_char(token: &str) -> bool {
+    let mut chars = token.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => is_cjk_char(c),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyzer::analyzer::Analyzer;
+    use crate::analyzer::char_filter::CJKDocCharFilter;
+    use crate::analyzer::tokenizer::Tokenizer;
+
+    /// "/"隔开的词首尾相接，空格隔开的不相邻
+    fn tokens(text: &str) -> Vec<Token<'static>> {
+        let mut tokens = Vec::new();
+        let mut pos = 0;
+        for (i, word) in text.split(' ').enumerate() {
+            pos += (i > 0) as usize;
+            for part in word.split('/') {
+                tokens.push(Token::new(
+                    part.to_string(),
+                    pos,
+                    pos + part.len(),
+                    tokens.len(),
+                ));
+                pos += part.len();
+            }
+        }
+        tokens
+    }
+
+    fn join(policy: CJKSingleCharPolicy, text: &str) -> Vec<String> {
+        let filter = CJKSingleCharTokenFilter::new(BasicTokenFilter::new(), policy);
+        let mut tokens = tokens(text)
+            .into_iter()
+            .filter(|t| filter.filter(&t.text).is_some())
+            .collect();
+        if filter.needs_neighbors() {
+            filter.join_neighbors(&mut tokens);
+        }
+        tokens.into_iter().map(|t| t.text.into_owned()).collect()
+    }
+
+    #[test]
+    fn cjk_single_char_policies() {
+        use CJKSingleCharPolicy::*;
+
+        for policy in [Keep, Drop, BigramOnly] {
+            assert_eq!(join(policy, "a"), ["a"]);
+            assert_eq!(join(policy, "ab"), ["ab"]);
+            assert_eq!(join(policy, "雷电/将军"), ["雷电", "将军"]);
+        }
+        assert_eq!(join(Keep, "的"), ["的"]);
+        assert!(join(Drop, "的").is_empty());
+        // 没有相邻的CJK字时保留单字
+        assert_eq!(join(BigramOnly, "的"), ["的"]);
+        assert_eq!(join(BigramOnly, "a/的/b"), ["a", "的", "b"]);
+        assert_eq!(join(BigramOnly, "我 的 猫"), ["我", "的", "猫"]);
+
+        // 连续的单字得到重叠的bigram
+        assert_eq!(join(BigramOnly, "我/的/猫"), ["我的", "的猫"]);
+        // 和相邻的多字词的第一个字或者最后一个字组成
+        assert_eq!(join(BigramOnly, "的/猫咪"), ["的猫", "猫咪"]);
+        assert_eq!(join(BigramOnly, "猫咪/的"), ["猫咪", "咪的"]);
+        assert_eq!(
+            join(BigramOnly, "雷电/的/将军 a"),
+            ["雷电", "的将", "将军", "a"]
+        );
+    }
+
+    /// 每个非空白字符一个token
+    struct CharTokenizer;
+
+    impl Tokenizer for CharTokenizer {
+        fn tokenize<'a>(&self, text: &'a str) -> Vec<Token<'a>> {
+            text.char_indices()
+                .filter(|(_, c)| !c.is_whitespace())
+                .enumerate()
+                .map(|(i, (start, c))| {
+                    Token::new(
+                        &text[start..start + c.len_utf8()],
+                        start,
+                        start + c.len_utf8(),
+                        i,
+                    )
+                })
+                .collect()
+        }
+    }
+
+    #[test]
+    fn analyzer_joins_neighbors() {
+        let filter = CJKSingleCharTokenFilter::new(
+            StopWordTokenFilter::new("了".as_bytes()).unwrap(),
+            CJKSingleCharPolicy::BigramOnly,
+        );
+        let analyzer = Analyzer::new(CJKDocCharFilter::new(), filter, CharTokenizer);
+
+        // 停用词去掉之后两边不算相邻
+        assert_eq!(
+            analyzer.analyze("雷电将军 来了人").unwrap(),
+            ["雷电", "电将", "将军", "来", "人"]
+        );
+        let tokens = analyzer.analyze_tokens("a雷电").unwrap();
+        let tokens = tokens
+            .iter()
+            .map(|t| (t.text.as_ref(), t.start, t.end, t.position))
+            .collect::<Vec<_>>();
+        assert_eq!(tokens, [("a", 0, 1, 0), ("雷电", 1, 7, 1)]);
+    }
+
+    #[test]
+    fn stop_word_lists() {
+        let filter = StopWordTokenFilter::new("  的\n\nthe\n因为\nof the\n".as_bytes()).unwrap();
+        let kept = |token| filter.filter(token).map(Cow::into_owned);
+
+        for word in ["的", "the", "因为"] {
+            assert_eq!(kept(word), None, "{}", word);
+        }
+        // 整行是一个停用词，不会拆开；token不含空白，所以这样的行不起作用
+        assert_eq!(kept("of").as_deref(), Some("of"));
+        assert_eq!(kept("因").as_deref(), Some("因"));
+        assert_eq!(kept("them").as_deref(), Some("them"));
+        assert_eq!(kept("The").as_deref(), Some("The"));
+
+        let empty = StopWordTokenFilter::with_stop_words(Arc::new(HashSet::new()));
+        for word in ["的", "the", "a", "因为"] {
+            assert_eq!(empty.filter(word).as_deref(), Some(word));
+        }
+        // 和BasicTokenFilter一样去掉空白
+        assert_eq!(empty.filter(" "), None);
+        assert!(StopWordTokenFilter::read_stop_words("\n  \n".as_bytes())
+            .unwrap()
+            .is_empty());
+    }
 }
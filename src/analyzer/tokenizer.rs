@@ -1,7 +1,47 @@
-use jieba_rs::Jieba;
+use crate::analyzer::token_filter::is_cjk_char;
+use jieba_rs::{Jieba, TokenizeMode};
+use std::borrow::Cow;
 
+/// 切出来的一个词和它在文本里的位置。
+///
+/// start、end是字节偏移，text[start..end]就是切出这个词的原文，token_filter改写过的词
+/// （同义词等）text和原文不一样，但偏移还是原文的位置，高亮时可以直接用。
+/// position是词的序号：Tokenizer输出时是在tokenize结果里的下标，经过Analyzer后是token_filter
+/// 保留下来的词里的序号，和索引里记录的词位置一致，短语查询按它判断是否相邻
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token<'a> {
+    pub text: Cow<'a, str>,
+    pub start: usize,
+    pub end: usize,
+    pub position: usize,
+}
+
+impl<'a> Token<'a> {
+    pub fn new(text: impl Into<Cow<'a, str>>, start: usize, end: usize, position: usize) -> Self {
+        Token {
+            text: text.into(),
+            start,
+            end,
+            position,
+        }
+    }
+
+    /// 复制text，不再借用原来的文本
+    pub fn into_owned(self) -> Token<'static> {
+        Token {
+            text: Cow::Owned(self.text.into_owned()),
+            start: self.start,
+            end: self.end,
+            position: self.position,
+        }
+    }
+}
+
+/// 切出来的词直接切自输入文本时用Cow::Borrowed，不需要分配内存；要生成新字符串的tokenizer用Cow::Owned，
+/// 每个token各自决定，Analyzer不需要知道tokenizer是哪一种
 pub trait Tokenizer {
-    fn tokenize<'a>(&self, text: &'a str) -> Vec<&'a str>;
+    /// start、end是在text里的字节偏移，position是在返回结果里的下标
+    fn tokenize<'a>(&self, text: &'a str) -> Vec<Token<'a>>;
 }
 
 #[derive(Debug)]
@@ -17,8 +57,257 @@ impl JiebaTokenizer {
     }
 }
 
+impl Default for JiebaTokenizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Tokenizer for JiebaTokenizer {
-    fn tokenize<'a>(&self, text: &'a str) -> Vec<&'a str> {
-        self.jieba.cut_for_search(text, true)
+    /// 和cut_for_search切出来的词、顺序都一样，jieba给的是字符位置，这里换成字节偏移
+    fn tokenize<'a>(&self, text: &'a str) -> Vec<Token<'a>> {
+        let mut char_starts = text.char_indices().map(|c| c.0).collect::<Vec<_>>();
+        char_starts.push(text.len());
+
+        self.jieba
+            .tokenize(text, TokenizeMode::Search, true)
+            .into_iter()
+            .enumerate()
+            .map(|(i, t)| Token::new(t.word, char_starts[t.start], char_starts[t.end], i))
+            .collect()
+    }
+}
+
+/// 把每个词（按空白分开）切成相互重叠的n-gram，长度从min_gram到max_gram个字符，
+/// 查询词的一部分也能直接匹配，不需要模糊搜索。适合产品编号之类的短字段。
+///
+/// 每个长度为n的词会生成大约n * (max_gram - min_gram + 1)个token，索引里的term数量和posting
+/// 都会成倍增加，长文本不要用
+#[derive(Debug)]
+pub struct NgramTokenizer {
+    min_gram: usize,
+    max_gram: usize,
+}
+
+impl NgramTokenizer {
+    pub fn new(min_gram: usize, max_gram: usize) -> Self {
+        assert!(
+            min_gram > 0 && min_gram <= max_gram,
+            "invalid n-gram range {}..={}",
+            min_gram,
+            max_gram
+        );
+
+        NgramTokenizer { min_gram, max_gram }
+    }
+}
+
+impl Tokenizer for NgramTokenizer {
+    fn tokenize<'a>(&self, text: &'a str) -> Vec<Token<'a>> {
+        let mut tokens = Vec::<Token<'a>>::new();
+        let mut char_starts = Vec::<usize>::new();
+
+        for word in text.split_whitespace() {
+            // word是text的一部分，用指针的差算出它在text里的偏移
+            let offset = word.as_ptr() as usize - text.as_ptr() as usize;
+
+            // 按字符而不是字节切，最后补上word.len()方便取结尾
+            char_starts.clear();
+            char_starts.extend(word.char_indices().map(|c| c.0 + offset));
+            let char_num = char_starts.len();
+            char_starts.push(offset + word.len());
+
+            for start in 0..char_num {
+                let max_end = (start + self.max_gram).min(char_num);
+                for end in start + self.min_gram..=max_end {
+                    let (s, e) = (char_starts[start], char_starts[end]);
+                    tokens.push(Token::new(&text[s..e], s, e, tokens.len()));
+                }
+            }
+        }
+
+        tokens
+    }
+}
+
+/// 按非字母数字字符切词，适合英文等用空格分词的文本，jieba会按词典把英文切得很奇怪。
+/// 夹在字母数字中间的撇号算作词的一部分（don't、O'Neil），连字符不算（state-of-the-art切成4个词）。
+/// 可以和JiebaTokenizer分别用在title和content两个analyzer上
+#[derive(Debug, Default)]
+pub struct UnicodeWordTokenizer {}
+
+impl UnicodeWordTokenizer {
+    pub fn new() -> Self {
+        UnicodeWordTokenizer {}
+    }
+}
+
+impl Tokenizer for UnicodeWordTokenizer {
+    fn tokenize<'a>(&self, text: &'a str) -> Vec<Token<'a>> {
+        let mut tokens = Vec::<Token<'a>>::new();
+        let mut start = None::<usize>;
+        let mut chars = text.char_indices().peekable();
+
+        while let Some((i, c)) = chars.next() {
+            let in_word = c.is_alphanumeric()
+                || (is_apostrophe(c)
+                    && start.is_some()
+                    && chars.peek().is_some_and(|n| n.1.is_alphanumeric()));
+
+            match (in_word, start) {
+                (true, None) => start = Some(i),
+                (false, Some(s)) => {
+                    tokens.push(Token::new(&text[s..i], s, i, tokens.len()));
+                    start = None;
+                }
+                _ => (),
+            }
+        }
+
+        if let Some(s) = start {
+            tokens.push(Token::new(&text[s..], s, text.len(), tokens.len()));
+        }
+
+        tokens
+    }
+}
+
+/// 不用词典的CJK切词：连续的CJK字符切成相互重叠的n-gram（默认2个字一组），
+/// 字母、数字组成的词整个作为一个token，其他字符（空白、标点）只用来分隔。
+///
+/// "神里绫华"切成"神里"、"里绫"、"绫华"，不管词典里有没有这个名字，查询"绫华"或者"神里绫华"都能找到。
+/// 代价是索引更大：n个字的CJK段生成n - gram + 1个token，几乎每个字都会在几个term里出现，
+/// posting比jieba多不少；也更不精确，"京都"会匹配到"北京都市"。
+/// 比gram短的CJK段整个作为一个token，单个字也能查到。
+/// 查询必须用同样的tokenizer，相邻的gram位置连续，短语查询可以正常使用
+///
+/// ```
+/// use raiden_shogun_search::analyzer::char_filter::CJKDocCharFilter;
+/// use raiden_shogun_search::analyzer::token_filter::BasicTokenFilter;
+/// use raiden_shogun_search::analyzer::tokenizer::CjkBigramTokenizer;
+/// use raiden_shogun_search::Analyzer;
+///
+/// let analyzer = Analyzer::new(
+///     CJKDocCharFilter::new(),
+///     BasicTokenFilter::new(),
+///     CjkBigramTokenizer::default(),
+/// );
+/// assert_eq!(
+///     analyzer.analyze("神里绫华 Ayaka 3.0").unwrap(),
+///     vec!["神里", "里绫", "绫华", "Ayaka", "3", "0"]
+/// );
+/// ```
+#[derive(Debug)]
+pub struct CjkBigramTokenizer {
+    gram: usize,
+}
+
+impl CjkBigramTokenizer {
+    pub fn new(gram: usize) -> Self {
+        assert!(gram > 0, "invalid CJK gram size {}", gram);
+
+        CjkBigramTokenizer { gram }
+    }
+
+    /// starts是这一段CJK每个字符的起始位置，end是这一段的结尾，处理完清空starts
+    fn push_grams<'a>(
+        &self,
+        text: &'a str,
+        starts: &mut Vec<usize>,
+        end: usize,
+        tokens: &mut Vec<Token<'a>>,
+    ) {
+        let char_num = starts.len();
+        starts.push(end);
+
+        for i in 0..=char_num.saturating_sub(self.gram) {
+            let (s, e) = (starts[i], starts[(i + self.gram).min(char_num)]);
+            tokens.push(Token::new(&text[s..e], s, e, tokens.len()));
+        }
+
+        starts.clear();
+    }
+}
+
+impl Default for CjkBigramTokenizer {
+    fn default() -> Self {
+        Self::new(2)
+    }
+}
+
+impl Tokenizer for CjkBigramTokenizer {
+    fn tokenize<'a>(&self, text: &'a str) -> Vec<Token<'a>> {
+        let mut tokens = Vec::<Token<'a>>::new();
+        let mut cjk_starts = Vec::<usize>::new();
+        let mut word_start = None::<usize>;
+
+        // 最后补一个空格，结尾的CJK段和词不需要单独处理
+        for (i, c) in text.char_indices().chain(std::iter::once((text.len(), ' '))) {
+            // CJK范围里也有标点（"。"、"「"等），只有文字才算
+            let is_cjk = c.is_alphanumeric() && is_cjk_char(c);
+            if !is_cjk && !cjk_starts.is_empty() {
+                self.push_grams(text, &mut cjk_starts, i, &mut tokens);
+            }
+
+            match (c.is_alphanumeric() && !is_cjk, word_start) {
+                (true, None) => word_start = Some(i),
+                (false, Some(s)) => {
+                    tokens.push(Token::new(&text[s..i], s, i, tokens.len()));
+                    word_start = None;
+                }
+                _ => (),
+            }
+
+            if is_cjk {
+                cjk_starts.push(i);
+            }
+        }
+
+        tokens
+    }
+}
+
+#[inline]
+fn is_apostrophe(c: char) -> bool {
+    c == '\'' || c == '\u{2019}'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(text: &str) -> Vec<(&str, usize, usize)> {
+        UnicodeWordTokenizer::new()
+            .tokenize(text)
+            .into_iter()
+            .enumerate()
+            .map(|(i, t)| {
+                assert_eq!(t.position, i);
+                assert_eq!(&text[t.start..t.end], t.text);
+                match t.text {
+                    Cow::Borrowed(word) => (word, t.start, t.end),
+                    Cow::Owned(_) => panic!("UnicodeWordTokenizer should borrow the input"),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn unicode_word_apostrophes_and_hyphens() {
+        assert_eq!(words("don't"), [("don't", 0, 5)]);
+        assert_eq!(words("Don’t stop"), [("Don’t", 0, 7), ("stop", 8, 12)]);
+        assert_eq!(
+            words("state-of-the-art"),
+            [
+                ("state", 0, 5),
+                ("of", 6, 8),
+                ("the", 9, 12),
+                ("art", 13, 16)
+            ]
+        );
+        // 不夹在字母数字中间的撇号只是分隔符
+        assert_eq!(words("'quoted' rock'"), [("quoted", 1, 7), ("rock", 9, 13)]);
+        assert_eq!(words("O'Neil's"), [("O'Neil's", 0, 8)]);
+        assert!(words("' - '").is_empty());
     }
 }
@@ -0,0 +1,80 @@
+//! 全文搜索引擎。不需要http服务时可以直接作为库使用：
+//!
+//! - 建索引：用两个[`Analyzer`]（title和content）和[`store::Config`]创建[`Builder`]，
+//!   [`Builder::add_document`]添加[`Document`]，[`Builder::finish`]写出一个segment
+//! - 查询：用[`query::Config`]打开同一个目录得到[`Query`]，调用[`Query::query`]、
+//!   [`Query::bool_query`]、[`Query::get_document`]等，导出所有命中的id用[`Query::scan`]
+//! - 换analyzer后重建索引：用新的analyzer创建Builder，调用[`Builder::reindex`]
+//! - 按配置文件创建analyzer、打开索引和http服务用到的部分在[`settings`]和[`service`]里，
+//!   可执行文件只是把它们组合起来
+//!
+//! 索引可以放在文件系统上（[`store::Config::new`]直接传`PathBuf`），也可以用
+//! [`Directory::memory`]只放在内存里。
+//!
+//! ```no_run
+//! use fst::automaton::Levenshtein;
+//! use raiden_shogun_search::analyzer::char_filter::CJKDocCharFilter;
+//! use raiden_shogun_search::analyzer::token_filter::BasicTokenFilter;
+//! use raiden_shogun_search::analyzer::tokenizer::JiebaTokenizer;
+//! use raiden_shogun_search::{query, store, Analyzer, Builder, Directory, Document, Occur, Query};
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let analyzer = || {
+//!     Analyzer::new(
+//!         CJKDocCharFilter::new(),
+//!         BasicTokenFilter::new(),
+//!         JiebaTokenizer::new(),
+//!     )
+//! };
+//! let directory = Directory::memory();
+//!
+//! let mut builder = Builder::new(
+//!     analyzer(),
+//!     analyzer(),
+//!     store::Config::new(directory.clone(), "wiki", 1),
+//! );
+//! builder.add_document(Document {
+//!     id: 1,
+//!     title: "雷电将军",
+//!     content: "稻妻的神，追求永恒。",
+//!     keywords: vec![("nation", "稻妻")],
+//!     numbers: vec![("rarity", 5.0)],
+//!     payload: None,
+//!     key: None,
+//! })?;
+//! builder.finish()?;
+//!
+//! let query = Query::new(
+//!     analyzer(),
+//!     query::Config::new(directory, "wiki", 3, 1, query::Similarity::default()),
+//! )?;
+//! let results = query.query(
+//!     "稻妻",
+//!     Occur::Should,
+//!     &|_| None::<Levenshtein>,
+//!     0..10,
+//!     &query::QueryOptions::default(),
+//! )?;
+//! assert_eq!(results.total, 1);
+//! assert_eq!(results.ids(), vec![1]);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! posting list和词典的格式（`store::posting`、`store::term`等）是内部实现，不对外公开，
+//! 只能通过Builder和Query读写。
+
+#[allow(clippy::module_inception)]
+pub mod analyzer;
+#[allow(clippy::module_inception)]
+pub mod query;
+pub mod service;
+pub mod settings;
+pub mod store;
+
+pub use analyzer::analyzer::Analyzer;
+pub use analyzer::char_filter::CharFilter;
+pub use analyzer::token_filter::TokenFilter;
+pub use analyzer::tokenizer::{Token, Tokenizer};
+pub use query::{Clause, Occur, Query, QueryOptions};
+pub use store::{Builder, Directory, Document, StoredDocument};
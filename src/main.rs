@@ -1,47 +1,27 @@
-use crate::analyzer::analyzer::Analyzer;
-use crate::analyzer::char_filter::{BasicCharFilter, CJKDocCharFilter};
-use crate::analyzer::token_filter::{BasicTokenFilter, StopWordTokenFilter, TokenFilter};
-use crate::analyzer::tokenizer::{JiebaTokenizer, Tokenizer};
-use crate::query::Query;
-use crate::service::build::{start_builder_thread, AddPostReq, BuildService, BuildServiceTask};
-use crate::store::builder::{Builder, Config};
-use crate::store::document::Document;
-use clap::{App, Arg, SubCommand};
+use clap::{value_t, App, Arg, ArgMatches, SubCommand};
 use core::future;
-use fst::automaton::Levenshtein;
-use futures::StreamExt;
-use hyper::service::{make_service_fn, service_fn, Service};
-use hyper::{Body, Method, Request, Response, Server, StatusCode};
-use serde::{Deserialize, Serialize};
-use std::fs::{read_dir, read_to_string, File};
+use hyper::service::Service;
+use hyper::Server;
+use raiden_shogun_search::analyzer::registry::TokenFilterSettings;
+use raiden_shogun_search::query::{self, Fuzziness, Occur, QueryOptions};
+use raiden_shogun_search::service::build::{
+    build_from_dir, compact, reindex, BuildOptions, BuildService, Builders, DocumentFormat,
+};
+use raiden_shogun_search::service::query::{open_query, QueryIndexes, QueryService};
+use raiden_shogun_search::settings::{QuerySettings, Settings};
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::mpsc::{Receiver, Sender};
-use std::sync::{mpsc, Arc, Mutex};
+use std::sync::Arc;
 use std::task::{Context, Poll};
-use std::thread;
-use std::time::SystemTime;
-
-mod analyzer;
-mod query;
-mod service;
-mod store;
-
-macro_rules! print_time_cost {
-    ($str: expr, $time: expr) => {
-        println!(
-            "{} costs: {}ms",
-            $str,
-            SystemTime::now().duration_since($time).unwrap().as_millis()
-        );
-    };
-}
+use tracing::level_filters::LevelFilter;
+use tracing::{error, info, trace_span, warn};
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::prelude::*;
 
 #[tokio::main]
 async fn main() {
-    //test_query_single();
-
     let matches = App::new("Raiden Shogun Search")
         .version("0.0.1")
         .author("Yuxiang Liu <windrunner414@outlook.com>")
@@ -51,22 +31,424 @@ async fn main() {
                 .short("a")
                 .value_name("ADDRESS")
                 .help("bind address")
-                .required(true)
                 .takes_value(true),
         )
-        .subcommand(SubCommand::with_name("build").about("build indexes"))
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .value_name("FILE")
+                .help("toml or json settings file, replaces --store-dir, --identifier, --cache-capacity and --stop-words; -a overrides the address in it")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("store-dir")
+                .long("store-dir")
+                .value_name("DIR")
+                .help("directory containing the index files")
+                .default_value("../../test_store/"),
+        )
+        .arg(
+            Arg::with_name("identifier")
+                .long("identifier")
+                .value_name("IDENTIFIER")
+                .help("name of the index used by requests without an /{identifier}/ path prefix")
+                .default_value("test"),
+        )
+        .arg(
+            Arg::with_name("cache-capacity")
+                .long("cache-capacity")
+                .value_name("N")
+                .help("number of query results to cache in the query server, 0 to disable")
+                .default_value("1024"),
+        )
+        .arg(
+            Arg::with_name("log-level")
+                .long("log-level")
+                .value_name("LEVEL")
+                .help("most verbose level of the json logs written to stdout, trace also logs time costs")
+                .possible_values(&["error", "warn", "info", "debug", "trace"])
+                .default_value("info"),
+        )
+        .subcommand(
+            SubCommand::with_name("build")
+                .about("build indexes")
+                .arg(
+                    Arg::with_name("stop-words")
+                        .long("stop-words")
+                        .value_name("FILE")
+                        .help("newline-delimited stop words removed from document content")
+                        .default_value("../../dict/stop_words.txt"),
+                )
+                .arg(
+                    Arg::with_name("merge")
+                        .long("merge")
+                        .help("merge all segments of the index into one after building"),
+                )
+                .arg(
+                    Arg::with_name("dir")
+                        .long("dir")
+                        .value_name("DIR")
+                        .help("build from files in this directory instead of serving /add")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .help("how a file is split into title and content")
+                        .possible_values(&["first-line", "separator", "json"])
+                        .default_value("first-line"),
+                )
+                .arg(
+                    Arg::with_name("separator")
+                        .long("separator")
+                        .value_name("SEPARATOR")
+                        .help("title/content separator for --format separator")
+                        .required_if("format", "separator")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("reindex")
+                .about("rebuild an existing index from its stored documents with the current analyzer settings")
+                .arg(
+                    Arg::with_name("stop-words")
+                        .long("stop-words")
+                        .value_name("FILE")
+                        .help("newline-delimited stop words removed from document content")
+                        .default_value("../../dict/stop_words.txt"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("compact")
+                .about("merge all segments of an index into one, dropping deleted documents"),
+        )
+        .subcommand(
+            SubCommand::with_name("query")
+                .about("search an index from the command line without starting a server")
+                .arg(
+                    Arg::with_name("sentence")
+                        .value_name("QUERY")
+                        .help("words to search for, any of them can match")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("fuzzy")
+                        .long("fuzzy")
+                        .value_name("DISTANCE")
+                        .help("max edit distance of each word: off, auto or a number")
+                        .default_value("off"),
+                )
+                .arg(
+                    Arg::with_name("limit")
+                        .long("limit")
+                        .value_name("N")
+                        .help("number of results to print")
+                        .default_value("10"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .value_name("FORMAT")
+                        .help("text prints one result per line, json prints the whole result")
+                        .possible_values(&["text", "json"])
+                        .default_value("text"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("inspect")
+                .about("print segments and statistics of an index, optionally its terms or a posting list")
+                .arg(
+                    Arg::with_name("terms")
+                        .long("terms")
+                        .help("also print every term and its document frequency"),
+                )
+                .arg(
+                    Arg::with_name("prefix")
+                        .long("prefix")
+                        .value_name("PREFIX")
+                        .help("only print terms starting with PREFIX, implies --terms")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("term")
+                        .long("term")
+                        .value_name("TERM")
+                        .help("also print the posting list of TERM as stored in the dictionary, not analyzed")
+                        .takes_value(true),
+                ),
+        )
         .get_matches();
 
-    let address = SocketAddr::from_str(matches.value_of("address").unwrap()).unwrap();
+    // 每行一条json日志，trace级别时在span结束时输出耗时。
+    // --log-level只作用于这个crate，hyper等依赖只输出warn以上的，不然debug级别全是连接的日志
+    let level = LevelFilter::from_str(matches.value_of("log-level").unwrap()).unwrap();
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::fmt::layer()
+                .json()
+                .with_span_events(FmtSpan::CLOSE)
+                .with_filter(
+                    Targets::new()
+                        .with_target("raiden_shogun_search", level)
+                        .with_default(LevelFilter::WARN),
+                ),
+        )
+        .init();
 
-    match matches.subcommand_matches("build") {
-        Some(_) => run_build_server(address).await,
-        None => run_query_server(address).await,
+    let settings = match matches.value_of("config") {
+        Some(path) => match Settings::from_file(Path::new(path)) {
+            Ok(settings) => settings,
+            Err(e) => {
+                error!(error = %e, "invalid config");
+                return;
+            }
+        },
+        None => settings_from_args(&matches),
     };
+
+    if matches.subcommand_matches("reindex").is_some() {
+        let options = BuildOptions {
+            store_dir: settings.store_dir,
+            identifier: settings.identifier,
+            settings: settings.build,
+            merge: false,
+        };
+        if let Err(e) = reindex(options).await {
+            error!(error = %e, "reindex failed");
+        }
+        return;
+    }
+
+    if matches.subcommand_matches("compact").is_some() {
+        let options = BuildOptions {
+            store_dir: settings.store_dir,
+            identifier: settings.identifier,
+            settings: settings.build,
+            merge: false,
+        };
+        match compact(options).await {
+            Ok(stats) => println!(
+                "segments: {} -> 1, bytes: {} -> {}, reclaimed {} bytes",
+                stats.segments,
+                stats.bytes_before,
+                stats.bytes_after,
+                stats.reclaimed(),
+            ),
+            Err(e) => error!(error = %e, "compact failed"),
+        }
+        return;
+    }
+
+    if let Some(args) = matches.subcommand_matches("query") {
+        let fuzziness = match args.value_of("fuzzy").unwrap() {
+            "off" => Fuzziness::Off,
+            "auto" => Fuzziness::Auto,
+            _ => Fuzziness::Edits(value_t!(args, "fuzzy", u8).unwrap_or_else(|e| e.exit())),
+        };
+        let limit = value_t!(args, "limit", usize).unwrap_or_else(|e| e.exit());
+
+        let sentence = args.value_of("sentence").unwrap();
+        let json = args.value_of("output") == Some("json");
+        if let Err(e) = query_command(&settings, sentence, fuzziness, limit, json) {
+            error!(error = %e, "query failed");
+        }
+        return;
+    }
+
+    if let Some(args) = matches.subcommand_matches("inspect") {
+        let terms = args.is_present("terms") || args.is_present("prefix");
+        let prefix = args.value_of("prefix").unwrap_or("");
+        if let Err(e) = inspect_command(&settings, terms, prefix, args.value_of("term")) {
+            error!(error = %e, "inspect failed");
+        }
+        return;
+    }
+
+    let build_options = matches
+        .subcommand_matches("build")
+        .map(|build| BuildOptions {
+            store_dir: settings.store_dir.clone(),
+            identifier: settings.identifier.clone(),
+            settings: settings.build.clone(),
+            merge: build.is_present("merge"),
+        });
+
+    if let Some(build) = matches.subcommand_matches("build") {
+        if let Some(dir) = build.value_of("dir") {
+            let format = match build.value_of("format").unwrap() {
+                "json" => DocumentFormat::Json,
+                "separator" => {
+                    DocumentFormat::Separator(build.value_of("separator").unwrap().to_string())
+                }
+                _ => DocumentFormat::FirstLine,
+            };
+
+            let options = build_options.unwrap();
+            if let Err(e) = build_from_dir(PathBuf::from(dir).as_path(), format, options).await {
+                error!(error = %e, "build failed");
+            }
+            return;
+        }
+    }
+
+    let configured = match build_options {
+        Some(_) => settings.build.address,
+        None => settings.query.address,
+    };
+    let address = match matches.value_of("address") {
+        Some(address) => SocketAddr::from_str(address).unwrap(),
+        None => configured.unwrap_or_else(|| {
+            clap::Error::with_description(
+                "the bind address (-a, or address in the config file) is required to start a server",
+                clap::ErrorKind::MissingRequiredArgument,
+            )
+            .exit()
+        }),
+    };
+
+    match build_options {
+        Some(options) => run_build_server(address, options).await,
+        None => {
+            run_query_server(
+                address,
+                settings.store_dir,
+                settings.identifier,
+                settings.query,
+            )
+            .await
+        }
+    };
+}
+
+/// query子命令：打开索引查一次，结果打印到stdout，不启动服务
+fn query_command(
+    settings: &Settings,
+    sentence: &str,
+    fuzziness: Fuzziness,
+    limit: usize,
+    json: bool,
+) -> query::Result<()> {
+    let query = open_query(
+        settings.store_dir.clone(),
+        &settings.identifier,
+        &settings.query,
+    )?;
+    let results = query.query(
+        sentence,
+        Occur::Should,
+        &|w| fuzziness.automaton(w),
+        0..limit,
+        &QueryOptions::default(),
+    )?;
+
+    if json {
+        println!("{}", serde_json::to_string(&results).unwrap());
+        return Ok(());
+    }
+
+    println!("{} results", results.total);
+    for hit in results.hits.iter() {
+        let title = query.get_document(hit.doc_id)?.title;
+        println!("{}\t{:.4}\t{}", hit.doc_id, hit.score, title);
+    }
+
+    Ok(())
+}
+
+/// inspect子命令：打印索引的segment和统计信息，按需打印词典或者一个词的posting list
+fn inspect_command(
+    settings: &Settings,
+    terms: bool,
+    prefix: &str,
+    term: Option<&str>,
+) -> query::Result<()> {
+    let query = open_query(
+        settings.store_dir.clone(),
+        &settings.identifier,
+        &settings.query,
+    )?;
+
+    let stats = query.stats();
+    println!(
+        "{}/{}: {} segments, {} documents, {} deleted, {} terms, index {} bytes, dict {} bytes",
+        settings.store_dir.display(),
+        settings.identifier,
+        stats.segments,
+        stats.doc_num,
+        stats.deleted_doc_num,
+        stats.terms,
+        stats.index_size,
+        stats.dict_size,
+    );
+    for segment in query.segments() {
+        println!(
+            "segment {}: {} documents, {} deleted, {} terms, avg len {:.2}/{:.2}, numeric index {}, key index {}",
+            segment.id,
+            segment.doc_num,
+            segment.deleted_doc_num,
+            segment.terms,
+            segment.avg_len.0,
+            segment.avg_len.1,
+            segment.numeric_index,
+            segment.key_index,
+        );
+    }
+
+    if terms {
+        println!();
+        for (term, df) in query.terms_with_prefix(prefix) {
+            println!("{}\t{}", term, df);
+        }
+    }
+
+    if let Some(term) = term {
+        println!();
+        println!("segment\tdoc\ttf\tlen\ttitle positions\tcontent positions");
+        for p in query.postings(term)? {
+            println!(
+                "{}\t{}{}\t{}/{}\t{}/{}\t{:?}\t{:?}",
+                p.segment,
+                p.doc_id,
+                if p.deleted { " (deleted)" } else { "" },
+                p.tf.0,
+                p.tf.1,
+                p.len.0,
+                p.len.1,
+                p.title_positions,
+                p.content_positions,
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// 没有--config时用命令行参数覆盖默认的设置
+fn settings_from_args(matches: &ArgMatches) -> Settings {
+    let mut settings = Settings {
+        store_dir: PathBuf::from(matches.value_of("store-dir").unwrap()),
+        identifier: matches.value_of("identifier").unwrap().to_string(),
+        ..Settings::default()
+    };
+
+    settings.query.cache_capacity =
+        value_t!(matches, "cache-capacity", usize).unwrap_or_else(|e| e.exit());
+
+    let build = matches
+        .subcommand_matches("build")
+        .or_else(|| matches.subcommand_matches("reindex"));
+    if let Some(build) = build {
+        settings.build.content_analyzer.token_filters = vec![TokenFilterSettings::StopWords {
+            path: PathBuf::from(build.value_of("stop-words").unwrap()),
+        }];
+    }
+
+    settings
 }
 
 struct MakeBuildService {
-    tx: Sender<BuildServiceTask>,
+    builders: Arc<Builders>,
 }
 
 impl<T> Service<T> for MakeBuildService {
@@ -80,58 +462,127 @@ impl<T> Service<T> for MakeBuildService {
 
     fn call(&mut self, _: T) -> Self::Future {
         future::ready(Ok(BuildService {
-            tx: self.tx.clone(),
+            builders: self.builders.clone(),
         }))
     }
 }
 
-async fn run_build_server(address: SocketAddr) {
-    let (task, tx) = start_builder_thread();
+async fn run_build_server(address: SocketAddr, options: BuildOptions) {
+    let (builders, task) = match Builders::start(options) {
+        Ok(builders) => builders,
+        Err(e) => {
+            error!(error = %e, "build failed");
+            return;
+        }
+    };
 
-    let make_svc = MakeBuildService { tx };
+    let builders = Arc::new(builders);
+    let make_svc = MakeBuildService {
+        builders: builders.clone(),
+    };
 
     let server = Server::bind(&address).serve(make_svc);
 
-    let graceful =
-        server.with_graceful_shutdown(async { task.await.expect("builder thread error") });
+    // 默认索引的builder线程退出（finish或者出错）后停止服务，错误已经在builder线程里打印过了
+    let graceful = server.with_graceful_shutdown(async {
+        if let Err(e) = task.await {
+            error!(error = %e, "builder thread failed");
+        }
+    });
 
     if let Err(e) = graceful.await {
-        eprintln!("server error: {}", e);
+        error!(error = %e, "server failed");
+    }
+
+    for task in builders.finish_others() {
+        if let Err(e) = task.await {
+            error!(error = %e, "builder thread failed");
+        }
+    }
+}
+
+struct MakeQueryService {
+    indexes: Arc<QueryIndexes>,
+}
+
+impl<T> Service<T> for MakeQueryService {
+    type Response = QueryService;
+    type Error = std::io::Error;
+    type Future = future::Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Ok(()).into()
+    }
+
+    fn call(&mut self, _: T) -> Self::Future {
+        future::ready(Ok(QueryService {
+            indexes: self.indexes.clone(),
+        }))
     }
 }
 
-async fn run_query_server(address: SocketAddr) {}
+async fn run_query_server(
+    address: SocketAddr,
+    store_dir: PathBuf,
+    identifier: String,
+    settings: QuerySettings,
+) {
+    if !store_dir.is_dir() {
+        error!(store_dir = %store_dir.display(), "store directory does not exist");
+        return;
+    }
 
-fn test_query_single() {
-    let time = SystemTime::now();
+    let indexes = Arc::new(QueryIndexes::new(store_dir, identifier, settings));
 
-    let analyzer = Analyzer::new(
-        CJKDocCharFilter::new(),
-        BasicTokenFilter::new(),
-        JiebaTokenizer::new(),
-    );
+    // 默认的索引在启动时就打开，有问题直接退出。只用/{identifier}/...访问其他索引时默认的索引可以不存在
+    let span = trace_span!("open_index").entered();
+    match indexes.get(None) {
+        Ok(_) => info!("index opened"),
+        Err(e @ query::Error::IndexNotFound(_)) => {
+            warn!(error = %e, "serving other indexes only")
+        }
+        Err(e) => {
+            error!(error = %e, "failed to open index");
+            return;
+        }
+    }
+    span.exit();
 
-    print_time_cost!("init analyzer", time);
+    let make_svc = MakeQueryService { indexes };
 
-    let mut query = Query::new(
-        analyzer,
-        query::Config::new(PathBuf::from("../../test_store/"), "test", 3, 1),
-    )
-    .unwrap();
+    let server = Server::bind(&address).serve(make_svc);
 
-    let time = SystemTime::now();
+    // 不再接受新的连接，正在处理的查询完成后才退出
+    let graceful = server.with_graceful_shutdown(shutdown_signal());
 
-    let results = query
-        .query(
-            "神里",
-            &|w| Levenshtein::new(w, if w.chars().count() > 4 { 1 } else { 0 }).ok(),
-            0..10,
-        )
-        .unwrap();
+    if let Err(e) = graceful.await {
+        error!(error = %e, "server failed");
+    }
+}
 
-    let costs = SystemTime::now().duration_since(time).unwrap().as_millis();
+/// Ctrl-C或者SIGTERM（容器停止时发送的）
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = match signal(SignalKind::terminate()) {
+            Ok(terminate) => terminate,
+            Err(e) => {
+                error!(error = %e, "failed to listen for SIGTERM");
+                let _ = tokio::signal::ctrl_c().await;
+                return;
+            }
+        };
+
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => (),
+            _ = terminate.recv() => (),
+        }
+    }
 
-    println!("{:?}", results);
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
 
-    println!("search costs: {}ms, total: {}", costs, results.len());
+    info!("shutting down");
 }
@@ -0,0 +1,428 @@
+use fst::Automaton;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+
+/// 查询时用来匹配词典的automaton。aut_builder只能返回一种类型，
+/// 需要按词选择前缀匹配或者模糊匹配时统一返回这个，不用和泛型参数A较劲
+#[derive(Debug)]
+pub enum QueryAutomaton {
+    /// 词典里以这些字节开头的词，见prefix_automaton
+    Prefix(Vec<u8>),
+    Fuzzy(LevenshteinAutomaton),
+}
+
+/// QueryAutomaton的状态，和QueryAutomaton的两种automaton一一对应
+#[derive(Debug, Clone)]
+pub enum QueryState {
+    /// 已经匹配的字节数，None表示已经不可能匹配
+    Prefix(Option<usize>),
+    Fuzzy(Option<LevenshteinState>),
+}
+
+/// 匹配词典里所有以word开头的词（包括word本身），比如"神里"匹配"神里绫华"。
+/// 用于输入时的自动补全，word是空字符串时返回None（按原样精确匹配）
+pub fn prefix_automaton(word: &str) -> Option<QueryAutomaton> {
+    if word.is_empty() {
+        return None;
+    }
+
+    Some(QueryAutomaton::Prefix(word.as_bytes().to_vec()))
+}
+
+/// 匹配和word编辑距离不超过max_dist的词。max_dist为0时返回None，这时按原样精确匹配
+pub fn fuzzy_automaton(word: &str, max_dist: u32) -> Option<QueryAutomaton> {
+    if max_dist == 0 {
+        return None;
+    }
+
+    Some(QueryAutomaton::Fuzzy(LevenshteinAutomaton::new(
+        word, max_dist,
+    )))
+}
+
+impl Automaton for QueryAutomaton {
+    type State = QueryState;
+
+    #[inline]
+    fn start(&self) -> Self::State {
+        match self {
+            QueryAutomaton::Prefix(_) => QueryState::Prefix(Some(0)),
+            QueryAutomaton::Fuzzy(aut) => QueryState::Fuzzy(aut.start()),
+        }
+    }
+
+    #[inline]
+    fn is_match(&self, state: &Self::State) -> bool {
+        match (self, state) {
+            (QueryAutomaton::Prefix(prefix), QueryState::Prefix(n)) => {
+                n.is_some_and(|n| n >= prefix.len())
+            }
+            (QueryAutomaton::Fuzzy(aut), QueryState::Fuzzy(state)) => aut.is_match(state),
+            _ => unreachable!("state from another automaton"),
+        }
+    }
+
+    #[inline]
+    fn can_match(&self, state: &Self::State) -> bool {
+        match (self, state) {
+            (QueryAutomaton::Prefix(_), QueryState::Prefix(n)) => n.is_some(),
+            (QueryAutomaton::Fuzzy(aut), QueryState::Fuzzy(state)) => aut.can_match(state),
+            _ => unreachable!("state from another automaton"),
+        }
+    }
+
+    #[inline]
+    fn will_always_match(&self, state: &Self::State) -> bool {
+        match (self, state) {
+            (QueryAutomaton::Prefix(prefix), QueryState::Prefix(n)) => {
+                n.is_some_and(|n| n >= prefix.len())
+            }
+            (QueryAutomaton::Fuzzy(aut), QueryState::Fuzzy(state)) => aut.will_always_match(state),
+            _ => unreachable!("state from another automaton"),
+        }
+    }
+
+    #[inline]
+    fn accept(&self, state: &Self::State, byte: u8) -> Self::State {
+        match (self, state) {
+            (QueryAutomaton::Prefix(prefix), QueryState::Prefix(n)) => {
+                QueryState::Prefix(match *n {
+                    Some(n) if n >= prefix.len() => Some(n),
+                    Some(n) if prefix[n] == byte => Some(n + 1),
+                    _ => None,
+                })
+            }
+            (QueryAutomaton::Fuzzy(aut), QueryState::Fuzzy(state)) => {
+                QueryState::Fuzzy(aut.accept(state, byte))
+            }
+            _ => unreachable!("state from another automaton"),
+        }
+    }
+}
+
+/// 按Unicode字符计算的编辑距离（插入、删除、替换各算1）。
+/// fst自带的Levenshtein对多字节字符有问题，比如"将军"在编辑距离1时连自己都匹配不上，
+/// 所以和WildcardAutomaton一样按字节驱动，凑齐一个完整的字符再更新距离
+#[derive(Debug, Clone)]
+pub struct LevenshteinAutomaton {
+    chars: Vec<char>,
+    max_dist: u32,
+}
+
+/// 状态为None时已经不可能匹配
+#[derive(Debug, Clone)]
+pub struct LevenshteinState {
+    /// 已经输入的字符和word每个前缀（0到chars.len()个字符）的编辑距离
+    row: Vec<u32>,
+    /// 还没输入完的UTF-8字符：已经输入的位，还差几个字节
+    pending: (u32, u8),
+}
+
+impl LevenshteinAutomaton {
+    pub fn new(word: &str, max_dist: u32) -> Self {
+        LevenshteinAutomaton {
+            chars: word.chars().collect(),
+            max_dist,
+        }
+    }
+
+    /// 输入一个完整的字符后的距离，所有前缀都超过max_dist时后面不管输入什么都不可能匹配
+    fn accept_char(&self, row: &[u32], c: u32) -> Option<LevenshteinState> {
+        let mut next = Vec::with_capacity(row.len());
+        next.push(row[0] + 1);
+        for (j, &w) in self.chars.iter().enumerate() {
+            let substitution = row[j] + (w as u32 != c) as u32;
+            next.push(substitution.min(row[j + 1] + 1).min(next[j] + 1));
+        }
+
+        if next.iter().all(|&d| d > self.max_dist) {
+            return None;
+        }
+
+        Some(LevenshteinState {
+            row: next,
+            pending: (0, 0),
+        })
+    }
+}
+
+impl Automaton for LevenshteinAutomaton {
+    type State = Option<LevenshteinState>;
+
+    fn start(&self) -> Self::State {
+        Some(LevenshteinState {
+            row: (0..=self.chars.len() as u32).collect(),
+            pending: (0, 0),
+        })
+    }
+
+    fn is_match(&self, state: &Self::State) -> bool {
+        state
+            .as_ref()
+            .is_some_and(|s| s.pending.1 == 0 && s.row[self.chars.len()] <= self.max_dist)
+    }
+
+    fn can_match(&self, state: &Self::State) -> bool {
+        state.is_some()
+    }
+
+    fn accept(&self, state: &Self::State, byte: u8) -> Self::State {
+        let state = state.as_ref()?;
+        let (bits, remaining) = state.pending;
+        let is_continuation = byte & 0xC0 == 0x80;
+
+        let (bits, remaining) = match (is_continuation, remaining) {
+            (true, 0) | (false, 1..) => return None,
+            (true, _) => ((bits << 6) | (byte & 0x3F) as u32, remaining - 1),
+            // 首字节：前面有几个1就一共有几个字节，剩下的位是码点的开头
+            (false, 0) => match byte.leading_ones() {
+                0 => (byte as u32, 0),
+                n => ((byte & (0x7F >> n)) as u32, (n - 1) as u8),
+            },
+        };
+
+        if remaining > 0 {
+            return Some(LevenshteinState {
+                row: state.row.clone(),
+                pending: (bits, remaining),
+            });
+        }
+
+        self.accept_char(&state.row, bits)
+    }
+}
+
+/// 通配符模式：*匹配任意个字符（包括0个），?匹配一个字符，其他字符按原样匹配。
+/// 按字节驱动，?会吃掉一个完整的UTF-8字符，所以"绫?华"能匹配"绫人华"
+#[derive(Debug, Clone)]
+pub struct WildcardAutomaton {
+    pattern: Vec<WildcardToken>,
+    /// 从这里开始后面全是*，走到这里之后不管再输入什么都匹配
+    star_suffix: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WildcardToken {
+    Byte(u8),
+    AnyChar,
+    AnyString,
+}
+
+impl WildcardAutomaton {
+    pub fn new(pattern: &str) -> Self {
+        let mut tokens = Vec::with_capacity(pattern.len());
+        for c in pattern.chars() {
+            match c {
+                '*' if tokens.last() == Some(&WildcardToken::AnyString) => (),
+                '*' => tokens.push(WildcardToken::AnyString),
+                '?' => tokens.push(WildcardToken::AnyChar),
+                _ => {
+                    let mut buf = [0u8; 4];
+                    tokens.extend(c.encode_utf8(&mut buf).bytes().map(WildcardToken::Byte));
+                }
+            }
+        }
+
+        let star_suffix = tokens.len()
+            - tokens
+                .iter()
+                .rev()
+                .take_while(|t| **t == WildcardToken::AnyString)
+                .count();
+
+        WildcardAutomaton {
+            pattern: tokens,
+            star_suffix,
+        }
+    }
+
+    /// 加入pos，以及从pos开始连续的*都可以不匹配任何字符，它们后面的位置也加入
+    fn push_closure(&self, state: &mut Vec<(usize, u8)>, mut pos: usize) {
+        loop {
+            if !state.contains(&(pos, 0)) {
+                state.push((pos, 0));
+            }
+
+            match self.pattern.get(pos) {
+                Some(WildcardToken::AnyString) => pos += 1,
+                _ => return,
+            }
+        }
+    }
+}
+
+/// 状态是所有可能的(模式中的位置, ?还要跳过的UTF-8后续字节数)，为空时已经不可能匹配
+impl Automaton for WildcardAutomaton {
+    type State = Vec<(usize, u8)>;
+
+    fn start(&self) -> Self::State {
+        let mut state = Vec::new();
+        self.push_closure(&mut state, 0);
+        state
+    }
+
+    fn is_match(&self, state: &Self::State) -> bool {
+        state.contains(&(self.pattern.len(), 0))
+    }
+
+    fn can_match(&self, state: &Self::State) -> bool {
+        !state.is_empty()
+    }
+
+    fn will_always_match(&self, state: &Self::State) -> bool {
+        state
+            .iter()
+            .any(|(pos, skip)| *skip == 0 && *pos >= self.star_suffix)
+    }
+
+    fn accept(&self, state: &Self::State, byte: u8) -> Self::State {
+        let mut next = Vec::with_capacity(state.len());
+        let is_continuation = byte & 0xC0 == 0x80;
+
+        for &(pos, skip) in state.iter() {
+            if skip > 0 {
+                match (is_continuation, skip) {
+                    (true, 1) => self.push_closure(&mut next, pos),
+                    (true, _) if !next.contains(&(pos, skip - 1)) => next.push((pos, skip - 1)),
+                    _ => (),
+                }
+                continue;
+            }
+
+            match self.pattern.get(pos) {
+                Some(WildcardToken::Byte(b)) if *b == byte => self.push_closure(&mut next, pos + 1),
+                // ?只能从一个字符的开头开始，按首字节判断后面还有几个字节
+                Some(WildcardToken::AnyChar) if !is_continuation => match byte.leading_ones() {
+                    0 => self.push_closure(&mut next, pos + 1),
+                    n if !next.contains(&(pos + 1, (n - 1) as u8)) => {
+                        next.push((pos + 1, (n - 1) as u8))
+                    }
+                    _ => (),
+                },
+                Some(WildcardToken::AnyString) => self.push_closure(&mut next, pos),
+                _ => (),
+            }
+        }
+
+        next
+    }
+}
+
+/// 模糊匹配的程度，automaton对每个词生成实际使用的automaton。
+/// http请求里可以写成true/false、"off"/"auto"或者编辑距离的数字
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(try_from = "FuzzinessRepr", into = "FuzzinessRepr")]
+pub enum Fuzziness {
+    /// 精确匹配
+    #[default]
+    Off,
+    /// 按词的长度选择：超过4个字符的词允许1个编辑距离，否则精确匹配
+    Auto,
+    /// 固定的编辑距离（按字符算），0和Off一样
+    Edits(u8),
+}
+
+impl Fuzziness {
+    pub fn automaton(self, word: &str) -> Option<QueryAutomaton> {
+        fuzzy_automaton(word, self.max_dist(word))
+    }
+
+    fn max_dist(self, word: &str) -> u32 {
+        match self {
+            Fuzziness::Off => 0,
+            Fuzziness::Auto if word.chars().count() > 4 => 1,
+            Fuzziness::Auto => 0,
+            Fuzziness::Edits(n) => n as u32,
+        }
+    }
+
+    /// 给Query::query_cached的aut_key，相同的key对同一个词生成一样的automaton
+    pub fn cache_key(self) -> String {
+        match self {
+            Fuzziness::Off | Fuzziness::Edits(0) => String::new(),
+            Fuzziness::Auto => String::from("fuzzy:auto"),
+            Fuzziness::Edits(n) => format!("fuzzy:{}", n),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum FuzzinessRepr {
+    Bool(bool),
+    Edits(u8),
+    Name(String),
+}
+
+impl TryFrom<FuzzinessRepr> for Fuzziness {
+    type Error = String;
+
+    fn try_from(repr: FuzzinessRepr) -> Result<Self, Self::Error> {
+        match repr {
+            FuzzinessRepr::Bool(false) => Ok(Fuzziness::Off),
+            FuzzinessRepr::Bool(true) => Ok(Fuzziness::Auto),
+            FuzzinessRepr::Edits(n) => Ok(Fuzziness::Edits(n)),
+            FuzzinessRepr::Name(name) => match name.as_str() {
+                "off" => Ok(Fuzziness::Off),
+                "auto" => Ok(Fuzziness::Auto),
+                _ => Err(format!("unknown fuzziness {}", name)),
+            },
+        }
+    }
+}
+
+impl From<Fuzziness> for FuzzinessRepr {
+    fn from(fuzziness: Fuzziness) -> Self {
+        match fuzziness {
+            Fuzziness::Off => FuzzinessRepr::Name(String::from("off")),
+            Fuzziness::Auto => FuzzinessRepr::Name(String::from("auto")),
+            Fuzziness::Edits(n) => FuzzinessRepr::Edits(n),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fst::{IntoStreamer, Set, Streamer};
+
+    fn search<A: Automaton>(aut: A) -> Vec<String> {
+        let set = Set::from_iter(vec![
+            "raiden",
+            "raidne",
+            "shogun",
+            "将军",
+            "稻妻",
+            "雷电",
+            "雷电将军",
+            "雷神将军",
+        ])
+        .unwrap();
+        let mut stream = set.search(aut).into_stream();
+        let mut keys = Vec::new();
+        while let Some(key) = stream.next() {
+            keys.push(String::from_utf8(key.to_vec()).unwrap());
+        }
+        keys
+    }
+
+    #[test]
+    fn fuzzy_counts_chars() {
+        let fuzzy = |word, n| search(Fuzziness::Edits(n).automaton(word).unwrap());
+
+        assert_eq!(fuzzy("雷电将军", 1), ["雷电将军", "雷神将军"]);
+        assert_eq!(
+            fuzzy("雷电将军", 2),
+            ["将军", "雷电", "雷电将军", "雷神将军"]
+        );
+        assert_eq!(fuzzy("将军", 1), ["将军"]);
+        assert_eq!(
+            fuzzy("将军", 2),
+            ["将军", "稻妻", "雷电", "雷电将军", "雷神将军"]
+        );
+        assert_eq!(fuzzy("raiden", 1), ["raiden"]);
+        assert_eq!(fuzzy("raiden", 2), ["raiden", "raidne"]);
+        // 删除一个字、插入一个字、插入加替换各一个，"将军"要删两个再插一个
+        assert_eq!(fuzzy("雷电将", 2), ["雷电", "雷电将军", "雷神将军"]);
+    }
+}
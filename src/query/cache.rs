@@ -0,0 +1,141 @@
+use crate::query::query::{MinShouldMatch, Occur, QueryOptions, SearchResults, SortBy};
+use crate::store::Field;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Range;
+use std::sync::Arc;
+
+/// 缓存的key：分析之后的词而不是原始的查询，空格、标点不同但分析结果一样的查询会命中同一个结果
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    /// 每个子句的occur、boost（f64::to_bits）和分析后的词，顺序和子句一样
+    clauses: Vec<(Occur, u64, Vec<String>)>,
+    /// 区分不同的模糊匹配设置，见Query::bool_query_cached
+    aut_key: String,
+    range: (usize, usize),
+    top_k: Option<usize>,
+    constant_score: bool,
+    field: Option<Field>,
+    min_should_match: Option<MinShouldMatch>,
+    /// 字段名和gt、gte、lt、lte（f64::to_bits）
+    numeric_range: Option<(String, [Option<u64>; 4])>,
+    /// 按字段排序时的字段名和是否升序
+    sort_by: Option<(String, bool)>,
+    normalize_scores: bool,
+}
+
+impl CacheKey {
+    pub fn new(
+        clauses: Vec<(Occur, f64, Vec<String>)>,
+        aut_key: &str,
+        range: &Range<usize>,
+        options: &QueryOptions,
+    ) -> Self {
+        CacheKey {
+            clauses: clauses
+                .into_iter()
+                .map(|(occur, boost, terms)| (occur, boost.to_bits(), terms))
+                .collect(),
+            aut_key: aut_key.to_string(),
+            range: (range.start, range.end),
+            top_k: options.top_k,
+            constant_score: options.constant_score,
+            field: options.field,
+            min_should_match: options.min_should_match,
+            numeric_range: options.numeric_range.as_ref().map(|r| {
+                (
+                    r.field.clone(),
+                    [r.gt, r.gte, r.lt, r.lte].map(|b| b.map(f64::to_bits)),
+                )
+            }),
+            sort_by: match &options.sort_by {
+                SortBy::Relevance => None,
+                SortBy::Field { name, ascending } => Some((name.clone(), *ascending)),
+            },
+            normalize_scores: options.normalize_scores,
+        }
+    }
+}
+
+/// 查询结果的LRU缓存。Query只读打开时的那些segment，之后写入的segment和删除对它不可见，
+/// 所以缓存在Query的整个生命周期内都有效；要看到新的数据得重新打开Query（查询服务在.segments
+/// 文件变了时会这样做），旧的缓存随着旧的Query一起丢掉
+#[derive(Debug)]
+pub struct QueryCache {
+    capacity: usize,
+    /// 每次访问加一，越大越新
+    tick: u64,
+    entries: HashMap<CacheKey, (Arc<SearchResults>, u64)>,
+    /// tick -> key，最小的是最久没有访问的
+    order: BTreeMap<u64, CacheKey>,
+    hits: u64,
+    misses: u64,
+}
+
+impl QueryCache {
+    pub fn new(capacity: usize) -> Self {
+        QueryCache {
+            capacity,
+            tick: 0,
+            entries: HashMap::with_capacity(capacity),
+            order: BTreeMap::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn get(&mut self, key: &CacheKey) -> Option<Arc<SearchResults>> {
+        self.tick += 1;
+
+        match self.entries.get_mut(key) {
+            Some((result, tick)) => {
+                let old = std::mem::replace(tick, self.tick);
+                if let Some(key) = self.order.remove(&old) {
+                    self.order.insert(self.tick, key);
+                }
+
+                self.hits += 1;
+                Some(result.clone())
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn insert(&mut self, key: CacheKey, result: Arc<SearchResults>) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        self.tick += 1;
+
+        if let Some((_, old)) = self.entries.insert(key.clone(), (result, self.tick)) {
+            self.order.remove(&old);
+        } else if self.entries.len() > self.capacity {
+            if let Some((_, oldest)) = self.order.pop_first() {
+                self.entries.remove(&oldest);
+            }
+        }
+
+        self.order.insert(self.tick, key);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            capacity: self.capacity,
+            len: self.entries.len(),
+            hits: self.hits,
+            misses: self.misses,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CacheStats {
+    pub capacity: usize,
+    pub len: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
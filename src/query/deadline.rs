@@ -0,0 +1,81 @@
+use crate::query::{Error, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// 查询的时间限制和取消标记，见QueryOptions::deadline。
+/// 只在合并两个posting list之间（包括模糊匹配展开的词合并成一个list时）、打分排序和逐篇读文档存储的循环里检查，
+/// 单个list的合并不会被打断，所以实际用时会稍微超过一点。
+/// 超时或者取消时整个查询返回错误，不会返回已经算出来的部分结果
+///
+/// ```
+/// use raiden_shogun_search::query::{Deadline, Error};
+/// use std::sync::atomic::{AtomicBool, Ordering};
+/// use std::sync::Arc;
+/// use std::time::Duration;
+///
+/// assert!(Deadline::default().check().is_ok());
+/// assert!(Deadline::after(Duration::from_secs(60)).check().is_ok());
+/// assert!(matches!(Deadline::after(Duration::ZERO).check(), Err(Error::Timeout)));
+///
+/// let cancelled = Arc::new(AtomicBool::new(false));
+/// let deadline = Deadline::default().with_cancel_flag(cancelled.clone());
+/// assert!(deadline.check().is_ok());
+/// cancelled.store(true, Ordering::Relaxed);
+/// assert!(matches!(deadline.check(), Err(Error::Cancelled)));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Deadline {
+    /// None表示不限制时间
+    instant: Option<Instant>,
+    /// 由其他线程设置成true时查询返回Error::Cancelled
+    cancelled: Option<Arc<AtomicBool>>,
+}
+
+impl Deadline {
+    /// 从现在开始计时，超过timeout返回Error::Timeout
+    pub fn after(timeout: Duration) -> Self {
+        Deadline::at(Instant::now() + timeout)
+    }
+
+    pub fn at(instant: Instant) -> Self {
+        Deadline {
+            instant: Some(instant),
+            cancelled: None,
+        }
+    }
+
+    /// 比如HTTP请求的future被drop（客户端断开）时设置flag，blocking线程里的查询就能提前结束
+    pub fn with_cancel_flag(mut self, cancelled: Arc<AtomicBool>) -> Self {
+        self.cancelled = Some(cancelled);
+        self
+    }
+
+    /// 已经取消时返回Error::Cancelled，超时返回Error::Timeout
+    pub fn check(&self) -> Result<()> {
+        if self
+            .cancelled
+            .as_ref()
+            .is_some_and(|c| c.load(Ordering::Relaxed))
+        {
+            return Err(Error::Cancelled);
+        }
+
+        match self.instant {
+            Some(instant) if Instant::now() >= instant => Err(Error::Timeout),
+            _ => Ok(()),
+        }
+    }
+
+    /// 在很长的循环里每CHECK_INTERVAL次才真正检查一次，i是循环的下标
+    #[inline]
+    pub(crate) fn check_every(&self, i: usize) -> Result<()> {
+        if i % CHECK_INTERVAL == 0 {
+            self.check()
+        } else {
+            Ok(())
+        }
+    }
+}
+
+const CHECK_INTERVAL: usize = 1024;
@@ -1,4 +1,5 @@
 use crate::analyzer;
+use crate::store::constants::{MIN_SUPPORTED_VERSION, VERSION};
 use crate::store;
 
 use std::fmt::{Formatter, Debug, Display};
@@ -11,7 +12,14 @@ pub enum Error {
     Io(std::io::Error),
     Fst(fst::Error),
     Incompatible,
-    Store(store::Error)
+    UnsupportedVersion(u8),
+    Store(store::Error),
+    OutOfRange,
+    OnlyExcluded,
+    DocumentNotFound(u32),
+    IndexNotFound(String),
+    Timeout,
+    Cancelled,
 }
 
 impl std::fmt::Display for Error {
@@ -21,7 +29,18 @@ impl std::fmt::Display for Error {
             Error::Io(ref e) => Display::fmt(&e, f),
             Error::Fst(ref e) => Display::fmt(&e, f),
             Error::Incompatible => write!(f, "incompatible data file"),
-            Error::Store(ref e) => Display::fmt(&e, f)
+            Error::UnsupportedVersion(v) => write!(
+                f,
+                "unsupported index version {} (supported {} to {}), the index needs to be rebuilt",
+                v, MIN_SUPPORTED_VERSION, VERSION
+            ),
+            Error::Store(ref e) => Display::fmt(&e, f),
+            Error::OutOfRange => write!(f, "range is out of top_k"),
+            Error::OnlyExcluded => write!(f, "query only contains excluded terms"),
+            Error::DocumentNotFound(id) => write!(f, "document {} not found", id),
+            Error::IndexNotFound(ref identifier) => write!(f, "index {} not found", identifier),
+            Error::Timeout => write!(f, "query timed out"),
+            Error::Cancelled => write!(f, "query cancelled"),
         }
     }
 }
@@ -33,7 +52,14 @@ impl std::error::Error for Error {
             Error::Io(ref e) => Some(e),
             Error::Fst(ref e) => Some(e),
             Error::Incompatible => None,
-            Error::Store(ref e) => Some(e)
+            Error::UnsupportedVersion(_) => None,
+            Error::Store(ref e) => Some(e),
+            Error::OutOfRange => None,
+            Error::OnlyExcluded => None,
+            Error::DocumentNotFound(_) => None,
+            Error::IndexNotFound(_) => None,
+            Error::Timeout => None,
+            Error::Cancelled => None,
         }
     }
 }
@@ -0,0 +1,133 @@
+use std::ops::Range;
+
+/// 高亮时包在匹配词两边的标签
+#[derive(Debug, Clone, Copy)]
+pub struct HighlightTags<'t> {
+    pub pre: &'t str,
+    pub post: &'t str,
+    /// tags是html时为true：标签以外的原文做html转义，内容里的<script>等不会被当作html。
+    /// 默认的<em></em>是true
+    pub escape_html: bool,
+}
+
+impl Default for HighlightTags<'_> {
+    fn default() -> Self {
+        HighlightTags {
+            pre: "<em>",
+            post: "</em>",
+            escape_html: true,
+        }
+    }
+}
+
+/// matches是text里匹配的词的字节区间（analyzer切出的token的start..end，落在字符边界上），
+/// 截取匹配最密集的max_len个字符，并用tags包住匹配的部分。
+/// 长度按字符而不是字节算，CJK一个字就是一个字符，转义不影响长度
+pub fn highlight(
+    text: &str,
+    matches: &[Range<usize>],
+    max_len: usize,
+    tags: HighlightTags,
+) -> String {
+    // 每个字符的起始字节位置，最后补一个text.len()方便算结尾
+    let mut char_starts = text.char_indices().map(|c| c.0).collect::<Vec<_>>();
+    let char_num = char_starts.len();
+    char_starts.push(text.len());
+
+    let to_char = |byte: usize| char_starts.binary_search(&byte).unwrap_or_else(|i| i);
+
+    // 匹配的字符区间，按开始位置排序并合并重叠的部分（search模式的jieba切出的词会重叠）
+    let mut matches = matches
+        .iter()
+        .filter(|m| m.start < m.end)
+        .map(|m| to_char(m.start)..to_char(m.end))
+        .collect::<Vec<_>>();
+    matches.sort_by_key(|m| m.start);
+
+    let mut merged = Vec::<Range<usize>>::with_capacity(matches.len());
+    for m in matches.into_iter() {
+        match merged.last_mut() {
+            Some(last) if m.start <= last.end => last.end = last.end.max(m.end),
+            _ => merged.push(m),
+        }
+    }
+
+    let window = best_window(&merged, char_num, max_len);
+
+    let mut result = String::with_capacity(char_starts[window.end] - char_starts[window.start]);
+    let mut pos = window.start;
+    for m in merged.iter() {
+        let (start, end) = (m.start.max(window.start), m.end.min(window.end));
+        if start >= end {
+            continue;
+        }
+
+        push_text(
+            &mut result,
+            &text[char_starts[pos]..char_starts[start]],
+            tags,
+        );
+        result.push_str(tags.pre);
+        push_text(
+            &mut result,
+            &text[char_starts[start]..char_starts[end]],
+            tags,
+        );
+        result.push_str(tags.post);
+        pos = end;
+    }
+    push_text(
+        &mut result,
+        &text[char_starts[pos]..char_starts[window.end]],
+        tags,
+    );
+
+    result
+}
+
+fn push_text(result: &mut String, text: &str, tags: HighlightTags) {
+    if !tags.escape_html {
+        result.push_str(text);
+        return;
+    }
+
+    for c in text.chars() {
+        match c {
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '&' => result.push_str("&amp;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&#39;"),
+            _ => result.push(c),
+        }
+    }
+}
+
+/// 找一个长度不超过max_len、完整包含最多匹配的字符区间，匹配放在区间中间。没有匹配时取开头
+fn best_window(matches: &[Range<usize>], char_num: usize, max_len: usize) -> Range<usize> {
+    let len = max_len.min(char_num);
+
+    let (mut best, mut best_count) = (0..0, 0usize);
+    let mut j = 0usize;
+    for i in 0..matches.len() {
+        j = j.max(i);
+        while j < matches.len() && matches[j].end - matches[i].start <= len {
+            j += 1;
+        }
+
+        if j - i > best_count {
+            best_count = j - i;
+            best = matches[i].start..matches[j - 1].end;
+        }
+    }
+
+    if best_count == 0 {
+        // 没有匹配，或者单个匹配就比max_len长
+        let start = matches.first().map_or(0, |m| m.start.min(char_num - len));
+        return start..start + len;
+    }
+
+    let padding = (len - best.len()) / 2;
+    let start = best.start.saturating_sub(padding).min(char_num - len);
+    start..start + len
+}
@@ -1,8 +1,52 @@
+mod automaton;
+mod cache;
+mod deadline;
 mod error;
+mod highlight;
+mod parser;
 mod query;
 pub(crate) mod score;
 
+pub use automaton::fuzzy_automaton;
+pub use automaton::Fuzziness;
+pub use automaton::prefix_automaton;
+pub use automaton::LevenshteinAutomaton;
+pub use automaton::LevenshteinState;
+pub use automaton::QueryAutomaton;
+pub use automaton::QueryState;
+pub use automaton::WildcardAutomaton;
+pub use cache::CacheStats;
+pub use deadline::Deadline;
 pub use error::Error;
 pub use error::Result;
+pub use highlight::HighlightTags;
+pub use parser::parse_query;
+pub use parser::QueryNode;
+pub use query::Clause;
 pub use query::Config;
+pub use query::DEFAULT_MAX_EXPANSIONS;
+pub use query::Explanation;
+pub use query::Group;
+pub use query::GroupBy;
+pub use query::GroupedResults;
+pub use query::IndexHealth;
+pub use query::MinShouldMatch;
+pub use query::MissingTerm;
+pub use query::NumericRange;
+pub use query::Occur;
 pub use query::Query;
+pub use query::QueryOptions;
+pub use query::Scan;
+pub use query::SearchHit;
+pub use query::SearchResults;
+pub use query::SegmentInfo;
+pub use query::SortBy;
+pub use query::Stats;
+pub use query::TermExplanation;
+pub use query::TermPosting;
+pub use query::Terms;
+pub use score::CustomSimilarity;
+pub use score::IndexStats;
+pub use score::Similarity;
+pub use score::TermPriorityCalculator;
+pub use crate::store::Field;
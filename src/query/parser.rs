@@ -0,0 +1,567 @@
+use crate::query::query::Occur;
+use crate::store::Field;
+
+/// parse_query的结果，用Query::parsed_query查询
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryNode {
+    /// 一个词，查询时经过analyzer，分出来的每个词都按它所在位置的occur处理。
+    /// boost和Clause::boost一样
+    Text { text: String, boost: f64 },
+    /// 分析后的词要按顺序紧挨着出现在同一个字段里，和Query::phrase_query的slop为0时一样，词都精确匹配。
+    /// 打分和occur为Must的Text一样
+    Phrase { text: String, boost: f64 },
+    /// 里面的条件只匹配这个字段，另一个字段的tf当作0，和QueryOptions::field一样
+    Field(Field, Box<QueryNode>),
+    /// 括号或者OR连起来的一组条件，和Query::bool_query的子句一样组合：
+    /// 有Must/Filter时结果要满足所有Must/Filter，Should只加分；没有时至少满足一个Should；
+    /// MustNot的去掉。只有MustNot的组在Must/Filter的位置上相当于把这些排除条件加到外面一层，
+    /// 在Should的位置上不限制结果，会被忽略
+    Group(Vec<(Occur, QueryNode)>),
+}
+
+impl QueryNode {
+    pub(crate) fn text(text: &str, boost: f64) -> Self {
+        QueryNode::Text {
+            text: text.to_string(),
+            boost,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Token<'s> {
+    LParen,
+    RParen,
+    Or,
+    /// 紧挨着词、引号或者左括号的-
+    Not,
+    /// 紧挨着词、引号或者左括号的+
+    Plus,
+    /// 词、引号或者左括号前面的title:、content:
+    Field(Field),
+    /// 引号里的内容和后面的boost，和Word一样还没有去掉转义的\
+    Phrase(&'s str, f64),
+    /// 还没有去掉转义的\
+    Word(&'s str),
+}
+
+/// 把"绫华 (雷神 OR 雷电将军) -旅行"这样的查询解析成一棵树：
+///
+/// - 用空格分开的词和括号，occur是传进来的occur，和Query::query一样，Must表示都要包含，Should表示包含任意一个即可
+/// - `A OR B`：A、B中至少要满足一个，OR比空格结合得更紧，`a b OR c d`是a、(b OR c)、d，
+///   OR只能是大写的，两边都是Should
+/// - `-词`、`-(...)`：不能包含，和Query::query一样精确匹配，不使用模糊匹配
+/// - `+词`、`+(...)`：必须包含，不管传进来的occur是什么
+/// - `"几个 词"`：短语，见QueryNode::Phrase
+/// - `title:词`、`content:(...)`、`title:"短语"`：只匹配这个字段，见QueryNode::Field，
+///   `title:-词`和`-title:词`一样
+/// - `词^n`、`"短语"^n`：权重乘上n，见Clause::boost
+/// - `\`：词和短语里\后面的字符按原样保留，没有上面的特殊含义，
+///   比如`title\:神里`是普通的词"title:神里"，`a\ b`是一个词"a b"，`"a \" b"`是短语`a " b`。
+///   最后单独的\后面没有字符，按原样保留
+///
+/// 输入总能被解析：缺少的右括号和引号补在最后，多出来的右括号、开头结尾和连续的OR都忽略，
+/// 空的括号和引号去掉，只有一个条件的括号换成这个条件本身，后面没有内容的title:当作普通的词
+///
+/// ```
+/// use raiden_shogun_search::query::{parse_query, Field, Occur, QueryNode};
+///
+/// let text = |t: &str| QueryNode::Text { text: t.to_string(), boost: 1.0 };
+///
+/// assert_eq!(
+///     parse_query("绫华 (雷神 OR 雷电将军) -旅行", Occur::Must),
+///     QueryNode::Group(vec![
+///         (Occur::Must, text("绫华")),
+///         (
+///             Occur::Must,
+///             QueryNode::Group(vec![(Occur::Should, text("雷神")), (Occur::Should, text("雷电将军"))]),
+///         ),
+///         (Occur::MustNot, text("旅行")),
+///     ])
+/// );
+///
+/// // 嵌套，OR比空格结合得更紧
+/// assert_eq!(
+///     parse_query("a (b OR (c d)) OR e", Occur::Must),
+///     QueryNode::Group(vec![
+///         (Occur::Must, text("a")),
+///         (
+///             Occur::Must,
+///             QueryNode::Group(vec![
+///                 (
+///                     Occur::Should,
+///                     QueryNode::Group(vec![
+///                         (Occur::Should, text("b")),
+///                         (
+///                             Occur::Should,
+///                             QueryNode::Group(vec![(Occur::Must, text("c")), (Occur::Must, text("d"))]),
+///                         ),
+///                     ]),
+///                 ),
+///                 (Occur::Should, text("e")),
+///             ]),
+///         ),
+///     ]),
+/// );
+///
+/// // 开头的-，排除的括号
+/// assert_eq!(
+///     parse_query("-旅行 绫华 -(雷神 OR 温迪^2)", Occur::Should),
+///     QueryNode::Group(vec![
+///         (Occur::MustNot, text("旅行")),
+///         (Occur::Should, text("绫华")),
+///         (
+///             Occur::MustNot,
+///             QueryNode::Group(vec![
+///                 (Occur::Should, text("雷神")),
+///                 (Occur::Should, QueryNode::Text { text: "温迪".to_string(), boost: 2.0 }),
+///             ]),
+///         ),
+///     ])
+/// );
+///
+/// // 空的括号和只有一个条件的括号
+/// assert_eq!(
+///     parse_query("a () (b) -() (())", Occur::Must),
+///     QueryNode::Group(vec![(Occur::Must, text("a")), (Occur::Must, text("b"))])
+/// );
+/// assert_eq!(parse_query("( OR )", Occur::Must), QueryNode::Group(vec![]));
+///
+/// // 不完整的输入
+/// assert_eq!(parse_query("OR a) OR (b", Occur::Must), parse_query("a OR b", Occur::Must));
+///
+/// // 必须包含、短语和字段
+/// let phrase = |t: &str, boost| QueryNode::Phrase { text: t.to_string(), boost };
+/// assert_eq!(
+///     parse_query(r#"title:神里 +旅行 -"关闭的 门" content:("稻妻 城"^2 OR 雷神) 12:30 title:"#, Occur::Should),
+///     QueryNode::Group(vec![
+///         (Occur::Should, QueryNode::Field(Field::Title, Box::new(text("神里")))),
+///         (Occur::Must, text("旅行")),
+///         (Occur::MustNot, phrase("关闭的 门", 1.0)),
+///         (
+///             Occur::Should,
+///             QueryNode::Field(
+///                 Field::Content,
+///                 Box::new(QueryNode::Group(vec![
+///                     (Occur::Should, phrase("稻妻 城", 2.0)),
+///                     (Occur::Should, text("雷神")),
+///                 ])),
+///             ),
+///         ),
+///         (Occur::Should, text("12:30")),
+///         (Occur::Should, text("title:")),
+///     ])
+/// );
+/// assert_eq!(
+///     parse_query(r#"a "" + "b c"#, Occur::Must),
+///     QueryNode::Group(vec![(Occur::Must, text("a")), (Occur::Must, text("+")), (Occur::Must, phrase("b c", 1.0))])
+/// );
+/// ```
+pub fn parse_query(text: &str, occur: Occur) -> QueryNode {
+    let tokens = tokenize(text);
+    let mut pos = 0;
+
+    QueryNode::Group(parse_sequence(&tokens, &mut pos, occur, false))
+}
+
+/// 解析到对应的右括号（nested）或者结尾，返回这一层的条件
+fn parse_sequence(
+    tokens: &[Token],
+    pos: &mut usize,
+    occur: Occur,
+    nested: bool,
+) -> Vec<(Occur, QueryNode)> {
+    let mut clauses = Vec::<(Occur, QueryNode)>::new();
+    // 上一个条件后面是OR，下一个条件要和它合成一组
+    let mut pending_or = false;
+    // 最后一个条件是OR合成的组，后面的OR继续往里加
+    let mut last_is_or = false;
+
+    while let Some(token) = tokens.get(*pos) {
+        *pos += 1;
+
+        let (prefix, node) = match token {
+            Token::RParen if nested => break,
+            Token::RParen => continue,
+            Token::Or => {
+                pending_or = !clauses.is_empty();
+                continue;
+            }
+            Token::Not | Token::Plus => match parse_operand(tokens, pos, occur) {
+                Some(node) => (Some(*token), node),
+                None => continue,
+            },
+            _ => {
+                *pos -= 1;
+                match parse_operand(tokens, pos, occur) {
+                    Some(node) => (None, node),
+                    None => continue,
+                }
+            }
+        };
+
+        let negated = prefix == Some(Token::Not);
+        let clause_occur = match prefix {
+            Some(Token::Not) => Occur::MustNot,
+            Some(Token::Plus) => Occur::Must,
+            _ => occur,
+        };
+        // OR的两边本来就是Should，+不起作用
+        let or_occur = if negated {
+            Occur::MustNot
+        } else {
+            Occur::Should
+        };
+
+        match clauses.last_mut() {
+            Some((_, QueryNode::Group(group))) if pending_or && last_is_or => {
+                group.push((or_occur, node));
+            }
+            Some(last) if pending_or => {
+                let (last_occur, last_node) =
+                    std::mem::replace(last, (occur, QueryNode::Group(Vec::new())));
+                let last_occur = if last_occur == Occur::MustNot {
+                    Occur::MustNot
+                } else {
+                    Occur::Should
+                };
+                last.1 = QueryNode::Group(vec![(last_occur, last_node), (or_occur, node)]);
+                last_is_or = true;
+            }
+            _ => {
+                clauses.push((clause_occur, node));
+                last_is_or = false;
+            }
+        }
+        pending_or = false;
+    }
+
+    clauses
+}
+
+/// 一个词、短语或者括号，括号是空的时候返回None
+fn parse_operand(tokens: &[Token], pos: &mut usize, occur: Occur) -> Option<QueryNode> {
+    let token = *tokens.get(*pos)?;
+    *pos += 1;
+
+    match token {
+        Token::Word(word) => Some(
+            match split_boost(word).filter(|(w, _)| !ends_with_escape(w)) {
+                Some((word, boost)) => QueryNode::text(&unescape(word), boost),
+                None => QueryNode::text(&unescape(word), 1f64),
+            },
+        ),
+        Token::Phrase(text, boost) => Some(QueryNode::Phrase {
+            text: unescape(text),
+            boost,
+        }),
+        Token::Field(field) => {
+            parse_operand(tokens, pos, occur).map(|node| QueryNode::Field(field, Box::new(node)))
+        }
+        Token::LParen => {
+            let mut clauses = parse_sequence(tokens, pos, occur, true);
+            match clauses.len() {
+                0 => None,
+                1 if clauses[0].0 != Occur::MustNot => clauses.pop().map(|c| c.1),
+                _ => Some(QueryNode::Group(clauses)),
+            }
+        }
+        // -、+后面不是词或者括号，当作没有这个-、+
+        _ => {
+            *pos -= 1;
+            None
+        }
+    }
+}
+
+/// 括号和引号里的短语单独作为token，其他的按空白分开。
+/// -、+、title:、content:只在开头并且后面还有内容时才有特殊含义
+fn tokenize(text: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut rest = text;
+
+    loop {
+        rest = rest.trim_start();
+        let c = match rest.chars().next() {
+            Some(c) => c,
+            None => break,
+        };
+
+        match c {
+            '(' => tokens.push(Token::LParen),
+            ')' => tokens.push(Token::RParen),
+            '-' | '+' if sign(rest).is_some() => tokens.push(sign(rest).unwrap()),
+            '"' => {
+                let end = 1 + find_unescaped(&rest[1..], |c| c == '"');
+                let (phrase, after) = (&rest[1..end], rest.get(end + 1..).unwrap_or(""));
+
+                // 紧跟在引号后面的^n是短语的boost
+                let boost_end = after
+                    .find(|c: char| c.is_whitespace() || c == '(' || c == ')')
+                    .unwrap_or(after.len());
+                let boost = after[..boost_end].strip_prefix('^').and_then(parse_boost);
+                rest = if boost.is_some() {
+                    &after[boost_end..]
+                } else {
+                    after
+                };
+
+                if !phrase.trim().is_empty() {
+                    tokens.push(Token::Phrase(phrase, boost.unwrap_or(1f64)));
+                }
+                continue;
+            }
+            _ if field_prefix(rest).is_some() => {
+                let (field, len) = field_prefix(rest).unwrap();
+                rest = &rest[len..];
+                // title:-词和-title:词一样，-、+放到字段前面，否则字段后面不是词或者括号，会被丢掉
+                while let Some(token) = sign(rest) {
+                    tokens.push(token);
+                    rest = &rest[1..];
+                }
+                tokens.push(Token::Field(field));
+                continue;
+            }
+            _ => {
+                let end = find_unescaped(rest, |c| c.is_whitespace() || c == '(' || c == ')');
+                tokens.push(match &rest[..end] {
+                    "OR" => Token::Or,
+                    word => Token::Word(word),
+                });
+                rest = &rest[end..];
+                continue;
+            }
+        }
+
+        rest = &rest[c.len_utf8()..];
+    }
+
+    tokens
+}
+
+/// 开头是有特殊含义的-、+（后面紧跟着内容）时返回对应的token
+fn sign(text: &str) -> Option<Token<'static>> {
+    let token = match text.chars().next()? {
+        '-' => Token::Not,
+        '+' => Token::Plus,
+        _ => return None,
+    };
+
+    match text[1..].starts_with(|c: char| !c.is_whitespace() && c != ')') {
+        true => Some(token),
+        false => None,
+    }
+}
+
+/// title:、content:开头并且后面紧跟着内容时返回(字段, 前缀的长度)
+fn field_prefix(text: &str) -> Option<(Field, usize)> {
+    [("title:", Field::Title), ("content:", Field::Content)]
+        .iter()
+        .find(|(prefix, _)| {
+            text.strip_prefix(prefix)
+                .is_some_and(|rest| rest.starts_with(|c: char| !c.is_whitespace() && c != ')'))
+        })
+        .map(|(prefix, field)| (*field, prefix.len()))
+}
+
+/// 第一个没有被\转义并且满足is_end的字符的位置，没有时返回text的长度
+fn find_unescaped(text: &str, is_end: impl Fn(char) -> bool) -> usize {
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            _ if is_end(c) => return i,
+            _ => (),
+        }
+    }
+
+    text.len()
+}
+
+/// 最后一个字符是用来转义的\，比如"a\^2"里的^是转义过的，不是boost
+fn ends_with_escape(text: &str) -> bool {
+    text.chars().rev().take_while(|c| *c == '\\').count() % 2 == 1
+}
+
+/// 去掉转义用的\，保留后面的字符。最后单独的\后面没有字符，按原样保留
+fn unescape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => result.push(chars.next().unwrap_or('\\')),
+            _ => result.push(c),
+        }
+    }
+
+    result
+}
+
+/// ^n结尾的是带boost的词，n要是非负的有限数字，前面的词不能是空的
+pub(crate) fn split_boost(word: &str) -> Option<(&str, f64)> {
+    word.rsplit_once('^')
+        .filter(|(w, _)| !w.is_empty())
+        .and_then(|(w, boost)| parse_boost(boost).map(|b| (w, b)))
+}
+
+fn parse_boost(boost: &str) -> Option<f64> {
+    boost
+        .parse::<f64>()
+        .ok()
+        .filter(|b| b.is_finite() && *b >= 0f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(t: &str) -> QueryNode {
+        QueryNode::text(t, 1f64)
+    }
+
+    fn parse(query: &str) -> Vec<(Occur, QueryNode)> {
+        match parse_query(query, Occur::Should) {
+            QueryNode::Group(clauses) => clauses,
+            node => panic!("not a group: {:?}", node),
+        }
+    }
+
+    #[test]
+    fn escaped_colon_is_literal() {
+        assert_eq!(parse(r"a\:b"), [(Occur::Should, text("a:b"))]);
+        // 转义过的冒号不是字段前缀
+        assert_eq!(parse(r"title\:神里"), [(Occur::Should, text("title:神里"))]);
+        assert_eq!(
+            parse(r"content:a\:b title\:"),
+            [
+                (
+                    Occur::Should,
+                    QueryNode::Field(Field::Content, Box::new(text("a:b")))
+                ),
+                (Occur::Should, text("title:")),
+            ]
+        );
+        // \\是一个\，后面的冒号没有转义，但它不在开头，本来就是普通的字符
+        assert_eq!(parse(r"a\\:b"), [(Occur::Should, text(r"a\:b"))]);
+        // 转义的空格和^
+        assert_eq!(
+            parse(r"12\ :30 a\^2"),
+            [
+                (Occur::Should, text("12 :30")),
+                (Occur::Should, text("a^2"))
+            ]
+        );
+        assert_eq!(
+            parse(r"a\:b^2"),
+            [(Occur::Should, QueryNode::text("a:b", 2f64))]
+        );
+    }
+
+    #[test]
+    fn escaped_operators_are_literal() {
+        assert_eq!(
+            parse(r"\(a\) \-b \+c \OR d"),
+            [
+                (Occur::Should, text("(a)")),
+                (Occur::Should, text("-b")),
+                (Occur::Should, text("+c")),
+                (Occur::Should, text("OR")),
+                (Occur::Should, text("d")),
+            ]
+        );
+        assert_eq!(
+            parse(r#"\"a b\""#),
+            [(Occur::Should, text("\"a")), (Occur::Should, text("b\""))]
+        );
+        // 短语里转义的引号不会结束短语
+        assert_eq!(
+            parse(r#""a \" b"^2 c"#),
+            [
+                (
+                    Occur::Should,
+                    QueryNode::Phrase {
+                        text: r#"a " b"#.to_string(),
+                        boost: 2f64
+                    }
+                ),
+                (Occur::Should, text("c")),
+            ]
+        );
+    }
+
+    #[test]
+    fn trailing_backslash_is_literal() {
+        assert_eq!(parse("a\\"), [(Occur::Should, text("a\\"))]);
+        assert_eq!(
+            parse("a \\"),
+            [(Occur::Should, text("a")), (Occur::Should, text("\\"))]
+        );
+        assert_eq!(parse("-a\\"), [(Occur::MustNot, text("a\\"))]);
+        assert_eq!(
+            parse("title:\\"),
+            [(
+                Occur::Should,
+                QueryNode::Field(Field::Title, Box::new(text("\\")))
+            )]
+        );
+        // 引号没有结束，最后的\也没有可以转义的字符
+        assert_eq!(
+            parse(r#""a b\"#),
+            [(
+                Occur::Should,
+                QueryNode::Phrase {
+                    text: r"a b\".to_string(),
+                    boost: 1f64
+                }
+            )]
+        );
+        // 转义的是引号，短语一直到结尾
+        assert_eq!(
+            parse(r#""a b\""#),
+            [(
+                Occur::Should,
+                QueryNode::Phrase {
+                    text: r#"a b""#.to_string(),
+                    boost: 1f64
+                }
+            )]
+        );
+    }
+
+    #[test]
+    fn sign_after_field_prefix_keeps_the_field() {
+        let title = |node| QueryNode::Field(Field::Title, Box::new(node));
+        assert_eq!(parse("title:-foo"), parse("-title:foo"));
+        assert_eq!(parse("title:-foo"), [(Occur::MustNot, title(text("foo")))]);
+        assert_eq!(
+            parse("a title:+foo"),
+            [
+                (Occur::Should, text("a")),
+                (Occur::Must, title(text("foo")))
+            ]
+        );
+        assert_eq!(
+            parse("title:-(a OR b)"),
+            [(
+                Occur::MustNot,
+                title(QueryNode::Group(vec![
+                    (Occur::Should, text("a")),
+                    (Occur::Should, text("b")),
+                ]))
+            )]
+        );
+        // 后面没有内容的-是普通的词
+        assert_eq!(
+            parse("title:- b"),
+            [
+                (Occur::Should, title(text("-"))),
+                (Occur::Should, text("b"))
+            ]
+        );
+        assert_eq!(
+            parse(r"title:\-foo"),
+            [(Occur::Should, title(text("-foo")))]
+        );
+    }
+}
@@ -2,256 +2,3329 @@ use crate::analyzer::analyzer::Analyzer;
 use crate::analyzer::char_filter::CharFilter;
 use crate::analyzer::token_filter::TokenFilter;
 use crate::analyzer::tokenizer::Tokenizer;
+use crate::query::automaton::{
+    prefix_automaton, LevenshteinAutomaton, QueryAutomaton, WildcardAutomaton,
+};
+use crate::query::cache::{CacheKey, CacheStats, QueryCache};
+use crate::query::deadline::Deadline;
+use crate::query::highlight::{self, HighlightTags};
+use crate::query::parser::{split_boost, QueryNode};
 use crate::query::score::{
-    calc_norm, calc_tf, Score, TermPriorityCalculator, TfIdfTermPriorityCalculator,
+    calc_tf, IndexStats, Score, Similarity, SimilarityCalculator, TermPriorityCalculator,
 };
 use crate::query::{Error, Result};
-use crate::store::constants::{
-    TERM_DICT_FILE_SUFFIX, TERM_DICT_MAGIC_NUMBER, TERM_INDEX_FILE_SUFFIX, TERM_INDEX_MAGIC_NUMBER,
-    VERSION,
+use crate::store::posting::{
+    Posting, PostingCursor, PostingListMerger, RawPostingList, TermPriorityInfo,
 };
-use crate::store::posting::{PostingListMerger, RawPostingList};
-use byteorder::{LittleEndian, ReadBytesExt};
-use fst::IntoStreamer;
-use memmap2::{Mmap, MmapOptions};
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::SeekFrom;
+use crate::store::segment::{open_segments, SegmentReader};
+use crate::store::tombstone::Tombstones;
+use crate::store::{Directory, Field, StoredDocument};
+use fst::automaton::{Automaton, Levenshtein, Str};
+use fst::{IntoStreamer, Streamer};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, BinaryHeap, HashMap, HashSet};
 use std::ops::Range;
-use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tracing::debug;
+
+/// wildcard_query一般使用的最多展开的词数
+pub const DEFAULT_MAX_EXPANSIONS: usize = 1024;
 
 #[derive(Debug)]
-pub struct Config<'a> {
-    store_dir: PathBuf,
-    identifier: &'a str,
+pub struct Config {
+    directory: Directory,
+    identifier: String,
     boost_title: u8,
     boost_content: u8,
+    similarity: Similarity,
+    /// 查询结果缓存的条数，0表示不缓存
+    cache_capacity: usize,
 }
 
-impl<'a> Config<'a> {
+impl Config {
+    /// directory可以直接传PathBuf，或者和Builder共用同一个Directory::memory()
     pub fn new(
-        store_dir: PathBuf,
-        identifier: &'a str,
+        directory: impl Into<Directory>,
+        identifier: impl Into<String>,
         boost_title: u8,
         boost_content: u8,
+        similarity: Similarity,
     ) -> Self {
         Config {
-            store_dir,
-            identifier,
+            directory: directory.into(),
+            identifier: identifier.into(),
             boost_title,
             boost_content,
+            similarity,
+            cache_capacity: 0,
+        }
+    }
+
+    /// 缓存最近capacity个不同查询的结果，见Query::bool_query_cached。默认不缓存
+    pub fn with_cache_capacity(mut self, capacity: usize) -> Self {
+        self.cache_capacity = capacity;
+        self
+    }
+}
+
+/// 子句中的词对结果和打分的影响
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Occur {
+    /// 必须包含，参与打分
+    Must,
+    /// 必须包含，不参与打分，适合只用来过滤的条件
+    Filter,
+    /// 可以不包含，包含时参与打分
+    #[default]
+    Should,
+    /// 不能包含，不参与打分
+    MustNot,
+}
+
+impl Occur {
+    #[inline]
+    fn is_required(self) -> bool {
+        matches!(self, Occur::Must | Occur::Filter)
+    }
+
+    #[inline]
+    fn is_scoring(self) -> bool {
+        matches!(self, Occur::Must | Occur::Should)
+    }
+
+    /// 同一个词出现在多个子句里时，取两者中更严格的限制，且只要有一处参与打分就参与打分。
+    /// MustNot的词单独处理，不会走到这里
+    fn merge(self, other: Occur) -> Occur {
+        match (
+            self.is_required() || other.is_required(),
+            self.is_scoring() || other.is_scoring(),
+        ) {
+            (true, true) => Occur::Must,
+            (true, false) => Occur::Filter,
+            _ => Occur::Should,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Clause<'s> {
+    pub occur: Occur,
+    /// 会经过analyzer分析，分出来的每个词都按occur处理
+    pub text: &'s str,
+    /// 这个子句里的词在query向量中的分量乘上boost，默认1.0。
+    /// 打分是余弦相似度，只有一个词时boost不影响分数，只改变多个词之间的相对权重
+    pub boost: f64,
+}
+
+impl<'s> Clause<'s> {
+    pub fn new(occur: Occur, text: &'s str) -> Self {
+        Clause {
+            occur,
+            text,
+            boost: 1f64,
+        }
+    }
+
+    /// 负数和NaN按0处理：词仍然参与匹配，但是不影响分数
+    pub fn with_boost(mut self, boost: f64) -> Self {
+        self.boost = if boost > 0f64 { boost } else { 0f64 };
+        self
+    }
+}
+
+/// 单次查询的选项
+#[derive(Debug, Clone, Default)]
+pub struct QueryOptions {
+    /// 检索的深度：只选出排在最前面的top_k个结果并排序，range在这top_k个里面分页，
+    /// range.end超过它时返回Error::OutOfRange。None时深度就是range.end
+    pub top_k: Option<usize>,
+    /// 只当作过滤条件用：不计算分数，命中的文档分数都是1，按doc id升序返回
+    pub constant_score: bool,
+    /// 只在这个字段里匹配和打分，只在其他字段包含查询词的文档不会出现在结果里，
+    /// MustNot的词也只检查这个字段。None表示所有字段
+    pub field: Option<Field>,
+    /// 至少要命中多少个Should的词，None表示不限制（没有Must/Filter时至少命中一个）
+    pub min_should_match: Option<MinShouldMatch>,
+    /// 只保留数值字段在范围内的文档，在打分之前过滤，total也是过滤之后的数量。
+    /// 每个候选文档都要从文档存储里读一次这个字段，命中的文档很多时会明显变慢
+    pub numeric_range: Option<NumericRange>,
+    /// 结果的顺序，默认按相关性
+    pub sort_by: SortBy,
+    /// 超过时间或者被取消时返回Error::Timeout/Error::Cancelled，没有部分结果。默认不限制。
+    /// 不是缓存key的一部分：命中缓存时直接返回，超时的查询不会被缓存
+    pub deadline: Deadline,
+    /// 把分数除以所有结果里最高的分数，最高的是1，其他的在[0, 1]里，方便在不同查询之间设置阈值。
+    /// 分数不会是负的，相当于下限固定为0的min-max归一化。只改变返回的分数，不影响顺序和total；
+    /// constant_score或者最高分是0时不变。默认关闭
+    pub normalize_scores: bool,
+}
+
+impl QueryOptions {
+    /// 要选出并排序的结果数：设置了top_k时是top_k，range不在前top_k个里面时返回Error::OutOfRange；
+    /// 没有设置时是range.end
+    fn depth(&self, range: &Range<usize>) -> Result<usize> {
+        match self.top_k {
+            Some(top_k) if range.end > top_k => Err(Error::OutOfRange),
+            Some(top_k) => Ok(top_k),
+            None => Ok(range.end),
+        }
+    }
+}
+
+/// 结果怎么排序。分页（range、top_k）按排序之后的顺序取
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum SortBy {
+    /// 分数从高到低，分数相同时doc id小的在前
+    #[default]
+    Relevance,
+    /// 按数值字段（建索引时的numbers）排序，值相同时再按相关性。
+    /// 没有这个字段（或者是NaN）的文档不管升序降序都排在最后。
+    /// 和numeric_range一样，每个候选文档都要从文档存储里读一次这个字段
+    Field {
+        name: String,
+        /// 默认降序，比如时间戳最新的在前
+        #[serde(default)]
+        ascending: bool,
+    },
+}
+
+/// 数值字段的范围，设置了的边界都要满足，都没有设置时只要求文档有这个字段。
+/// 没有这个字段的文档不在范围内
+///
+/// ```
+/// use raiden_shogun_search::query::NumericRange;
+///
+/// let range = NumericRange {
+///     gte: Some(20.0),
+///     lt: Some(50.0),
+///     ..NumericRange::new("price")
+/// };
+///
+/// assert!(range.contains(Some(20.0)));
+/// assert!(range.contains(Some(49.9)));
+/// assert!(!range.contains(Some(50.0)));
+/// assert!(!range.contains(Some(19.9)));
+/// assert!(!range.contains(None));
+///
+/// let range = NumericRange {
+///     gt: Some(20.0),
+///     lte: Some(50.0),
+///     ..NumericRange::new("price")
+/// };
+///
+/// assert!(!range.contains(Some(20.0)));
+/// assert!(range.contains(Some(50.0)));
+/// assert!(NumericRange::new("price").contains(Some(f64::MAX)));
+/// assert!(!NumericRange::new("price").contains(Some(f64::NAN)));
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NumericRange {
+    /// 建索引时numbers里的字段名
+    pub field: String,
+    #[serde(default)]
+    pub gt: Option<f64>,
+    #[serde(default)]
+    pub gte: Option<f64>,
+    #[serde(default)]
+    pub lt: Option<f64>,
+    #[serde(default)]
+    pub lte: Option<f64>,
+}
+
+impl NumericRange {
+    /// 不限制范围，用..NumericRange::new(field)设置需要的边界
+    pub fn new(field: impl Into<String>) -> Self {
+        NumericRange {
+            field: field.into(),
+            gt: None,
+            gte: None,
+            lt: None,
+            lte: None,
         }
     }
 
-    fn build_file_path(&self, suffix: &str) -> PathBuf {
-        let mut buf = self.store_dir.clone();
-        buf.push(String::from(self.identifier) + suffix);
-        buf
+    /// NaN不在任何范围内
+    pub fn contains(&self, value: Option<f64>) -> bool {
+        let value = match value {
+            Some(value) if !value.is_nan() => value,
+            _ => return false,
+        };
+
+        self.gt.map_or(true, |b| value > b)
+            && self.gte.map_or(true, |b| value >= b)
+            && self.lt.map_or(true, |b| value < b)
+            && self.lte.map_or(true, |b| value <= b)
+    }
+
+    /// 满足下界（没有设置下界时总是true），值越大越容易满足
+    fn above_lower(&self, value: f64) -> bool {
+        self.gt.map_or(true, |b| value > b) && self.gte.map_or(true, |b| value >= b)
+    }
+
+    /// 满足上界，值越小越容易满足
+    fn below_upper(&self, value: f64) -> bool {
+        self.lt.map_or(true, |b| value < b) && self.lte.map_or(true, |b| value <= b)
+    }
+}
+
+/// Should的词至少要命中的数量。总数是分析之后的词数：被停用词过滤掉的词不算在内，
+/// 不在词典里的词算在内（它们不可能命中），jieba切出的子词（"旅行者"切成旅行/行者/旅行者）各算一个。
+/// 算出来超过总数时按总数算
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MinShouldMatch {
+    /// 固定的数量
+    Count(usize),
+    /// 总数的百分比，向下取整
+    Percent(u8),
+}
+
+impl MinShouldMatch {
+    fn threshold(self, should_num: usize) -> usize {
+        match self {
+            MinShouldMatch::Count(n) => n.min(should_num),
+            MinShouldMatch::Percent(p) => should_num * (p.min(100) as usize) / 100,
+        }
     }
 }
 
 #[derive(Debug)]
-pub struct Query<'a, C, T, I>
+pub struct Query<C, T, I>
 where
     C: CharFilter,
     T: TokenFilter,
     I: Tokenizer,
 {
     analyzer: Analyzer<C, T, I>,
-    config: Config<'a>,
-    term_index: fst::Map<Mmap>,
-    term_dict: File,
+    /// 打开之后目前用不到
+    #[allow(dead_code)]
+    config: Config,
+    /// 从旧到新，posting list直接从各个segment的mmap里切出来，查询不需要可变状态
+    segments: Vec<SegmentReader>,
+    /// 所有segment的文档数之和，包括已经删除和被覆盖的文档
     doc_num: u32,
-    term_priority_calculator: TfIdfTermPriorityCalculator,
+    term_priority_calculator: SimilarityCalculator,
+    /// config里cache_capacity为0时是None
+    cache: Option<Mutex<QueryCache>>,
 }
 
-impl<'a, C, T, I> Query<'a, C, T, I>
+impl<C, T, I> Query<C, T, I>
 where
     C: CharFilter,
     T: TokenFilter,
     I: Tokenizer,
 {
-    pub fn new(analyzer: Analyzer<C, T, I>, config: Config<'a>) -> Result<Self> {
-        let index_file = File::open(
-            config
-                .build_file_path(TERM_INDEX_FILE_SUFFIX)
-                .to_str()
-                .unwrap(),
-        )?;
-        let index_offset = check_term_index(&index_file)?;
-
-        let mmap = unsafe {
-            MmapOptions::new()
-                .offset(index_offset as u64)
-                .map(&index_file)?
-        };
-        let fst = fst::Map::new(mmap)?;
+    pub fn new(analyzer: Analyzer<C, T, I>, config: Config) -> Result<Self> {
+        let segments = open_segments(&config.directory, &config.identifier)?;
 
-        let mut dict_file = File::open(
-            config
-                .build_file_path(TERM_DICT_FILE_SUFFIX)
-                .to_str()
-                .unwrap(),
-        )?;
-        check_term_dict(&dict_file)?;
-        let doc_num = dict_file.read_u32::<LittleEndian>()?;
+        // idf和平均长度用所有segment的统计，各个segment的平均长度按文档数加权
+        let doc_num = segments.iter().map(|s| s.doc_num).sum::<u32>();
+        let len_sum = segments.iter().fold((0f64, 0f64), |sum, s| {
+            (
+                sum.0 + s.avg_len.0 * s.doc_num as f64,
+                sum.1 + s.avg_len.1 * s.doc_num as f64,
+            )
+        });
+        let avg_len = (
+            len_sum.0 / doc_num.max(1) as f64,
+            len_sum.1 / doc_num.max(1) as f64,
+        );
 
-        let term_priority_calculator =
-            TfIdfTermPriorityCalculator::new(doc_num, config.boost_title, config.boost_content);
+        let stats = IndexStats {
+            doc_num,
+            avg_len,
+            boost_title: config.boost_title,
+            boost_content: config.boost_content,
+        };
+        let term_priority_calculator = SimilarityCalculator::new(&config.similarity, &stats);
 
         let query = Query {
             analyzer,
-            config,
-            term_index: fst,
-            term_dict: dict_file,
+            segments,
             doc_num,
             term_priority_calculator,
+            cache: Some(config.cache_capacity)
+                .filter(|c| *c > 0)
+                .map(|c| Mutex::new(QueryCache::new(c))),
+            config,
         };
 
         Ok(query)
     }
 
-    #[inline(always)]
-    fn find_posting_list(&mut self, offset: u64) -> Result<RawPostingList> {
-        Ok(RawPostingList::new(
-            &mut self.term_dict,
-            SeekFrom::Start(offset),
-        )?)
+    /// 所有segment的文档数之和，包括已经删除和被覆盖的文档，和计算idf用的一致
+    pub fn doc_num(&self) -> u32 {
+        self.doc_num
     }
 
-    #[inline(always)]
-    fn query_term_postings<A: fst::Automaton>(
-        &mut self,
-        word: &str,
-        aut_builder: &impl Fn(&str) -> Option<A>,
-    ) -> Result<Option<RawPostingList>> {
-        let dict_indexes = match aut_builder(word) {
-            None => self
-                .term_index
-                .get(word)
-                .map_or_else(Vec::new, |i| vec![(word.to_string(), i)]),
-            Some(aut) => self.term_index.search(aut).into_stream().into_str_vec()?,
+    /// term经过analyzer之后在词典里的df，和查询打分时用的一样：有多个segment时不包括已经删除的文档。
+    /// 分出多个词时（比如"旅行者"切成旅行/行者/旅行者）取最小的，也就是同时包含这些词的文档数的上限；
+    /// 被停用词过滤掉或者不在词典里时返回0。精确匹配，不使用模糊匹配
+    pub fn doc_freq(&self, term: &str) -> Result<u32> {
+        let exact = &|_: &str| None::<Levenshtein>;
+        let mut df = None::<u32>;
+
+        for word in self.analyzer.analyze(term)? {
+            let len = self
+                .query_term_postings(&word, exact, &Deadline::default())?
+                .map_or(0, |list| list.len());
+            df = Some(df.map_or(len, |df| df.min(len)));
+        }
+
+        Ok(df.unwrap_or(0))
+    }
+
+    /// 用doc_freq和doc_num按配置的相关性算法计算的idf，和explain里的一样（TfIdf时是score::calc_idf）
+    pub fn idf(&self, term: &str) -> Result<f64> {
+        Ok(self.term_priority_calculator.idf(self.doc_freq(term)?))
+    }
+
+    /// 确认每个segment的文件都能正常读取：词典能遍历到第一个词，它的posting list能解码。
+    /// 只读很少的数据，可以频繁调用
+    pub fn check_health(&self) -> Result<IndexHealth> {
+        let mut terms = 0;
+
+        for segment in self.segments.iter() {
+            terms += segment.term_index.len();
+
+            if let Some((_, offset)) = segment.term_index.stream().next() {
+                segment.posting_list(offset)?.cursor().next()?;
+            }
+        }
+
+        Ok(IndexHealth {
+            segments: self.segments.len(),
+            doc_num: self.doc_num,
+            terms,
+        })
+    }
+
+    /// 索引的统计信息。需要遍历每个segment的doc id，比check_health慢，不适合频繁调用
+    pub fn stats(&self) -> Stats {
+        let mut stats = Stats {
+            segments: self.segments.len(),
+            doc_num: 0,
+            deleted_doc_num: 0,
+            terms: 0,
+            index_size: 0,
+            dict_size: 0,
+            cache: None,
         };
 
-        let mut other: Option<(String, u64)> = None;
-        for index in dict_indexes.into_iter() {
-            if index.0.as_str() == word {
-                return Ok(Some(self.find_posting_list(index.1)?));
-            } else {
-                other = Some(index);
+        stats.cache = self.cache.as_ref().map(|c| lock_cache(c).stats());
+
+        for segment in self.segments.iter() {
+            stats.terms += segment.term_index.len();
+            stats.index_size += segment.term_index.as_fst().as_bytes().len() as u64;
+            stats.dict_size += segment.dict_size() as u64;
+
+            for id in segment.doc_store.ids() {
+                if segment.tombstones.contains(id) {
+                    stats.deleted_doc_num += 1;
+                } else {
+                    stats.doc_num += 1;
+                }
             }
         }
 
-        other.map_or_else(
-            || Ok(None),
-            |index| Ok(Some(self.find_posting_list(index.1)?)),
-        )
+        stats
     }
 
-    pub fn query<A: fst::Automaton>(
-        &mut self,
-        sentence: &str,
-        aut_builder: &impl Fn(&str) -> Option<A>,
-        range: Range<usize>,
-    ) -> Result<Vec<u32>> {
-        let sentence_ar = self.analyzer.analyze(sentence)?;
+    /// 按字典序遍历词典里的所有词和它的df。直接读各个segment的fst，不会把词典拷贝出来
+    pub fn terms(&self) -> Terms<'_> {
+        let mut op = fst::map::OpBuilder::new();
+        for segment in self.segments.iter() {
+            op = op.add(segment.term_index.stream());
+        }
 
-        let mut postings = Vec::<(&str, RawPostingList)>::new();
+        Terms {
+            stream: op.union(),
+            segments: &self.segments,
+        }
+    }
 
-        let mut query_terms = HashMap::<&str, u16>::new();
+    /// 和terms一样，只遍历以prefix开头的词，可以用来做自动补全
+    pub fn terms_with_prefix<'q>(&'q self, prefix: &'q str) -> Terms<'q> {
+        let mut op = fst::map::OpBuilder::new();
+        for segment in self.segments.iter() {
+            op = op.add(segment.term_index.search(Str::new(prefix).starts_with()));
+        }
 
-        for word in sentence_ar.iter() {
-            match query_terms.get_mut(word.as_str()) {
-                None => {
-                    query_terms.insert(word.as_str(), 1);
+        Terms {
+            stream: op.union(),
+            segments: &self.segments,
+        }
+    }
+
+    /// 每个segment的header和文件里的信息，从旧到新，用来排查索引的问题
+    pub fn segments(&self) -> Vec<SegmentInfo> {
+        self.segments
+            .iter()
+            .map(|segment| {
+                let deleted_doc_num = segment
+                    .doc_store
+                    .ids()
+                    .filter(|id| segment.tombstones.contains(*id))
+                    .count() as u32;
+
+                SegmentInfo {
+                    id: segment.id,
+                    doc_num: segment.doc_num,
+                    deleted_doc_num,
+                    avg_len: segment.avg_len,
+                    terms: segment.term_index.len(),
+                    numeric_index: segment.numeric_index.is_some(),
+                    key_index: segment.key_index.is_some(),
                 }
-                Some(i) => {
-                    if *i < u16::MAX {
-                        *i += 1;
-                    }
+            })
+            .collect()
+    }
+
+    /// term在各个segment里的posting list，原样解码，包括已经删除和被覆盖的文档。
+    /// term不经过analyzer，要和词典里的词完全一样，可以先用terms或者terms_with_prefix查看
+    pub fn postings(&self, term: &str) -> Result<Vec<TermPosting>> {
+        let mut postings = Vec::new();
+
+        for segment in self.segments.iter() {
+            let offset = match segment.term_index.get(term) {
+                Some(offset) => offset,
+                None => continue,
+            };
+
+            let list = segment.posting_list(offset)?;
+            let mut cursor = list.cursor();
+            while let Some(p) = cursor.next()? {
+                let (title_positions, content_positions) = list.positions(&p)?;
+                postings.push(TermPosting {
+                    segment: segment.id,
+                    doc_id: p.doc_id,
+                    tf: p.tf,
+                    len: p.len,
+                    title_positions,
+                    content_positions,
+                    deleted: segment.tombstones.contains(p.doc_id),
+                });
+            }
+        }
+
+        Ok(postings)
+    }
+
+    /// 按doc id取回建索引时的原文。不要求id来自查询结果，任何建过索引且没有删除的id都可以
+    pub fn get_document(&self, doc_id: u32) -> Result<StoredDocument> {
+        // 同一个id最多只有一个segment里是有效的
+        for segment in self.segments.iter().rev() {
+            if segment.tombstones.contains(doc_id) {
+                continue;
+            }
+
+            if let Some(doc) = segment.doc_store.get(doc_id)? {
+                return Ok(doc);
+            }
+        }
+
+        Err(Error::DocumentNotFound(doc_id))
+    }
+
+    /// 外部id对应的有效文档的doc id，key没有用过，或者文档已经删除、被同一个id的新文档覆盖时返回None
+    pub fn find_key(&self, key: &str) -> Result<Option<u32>> {
+        let id = self
+            .segments
+            .iter()
+            .rev()
+            .filter_map(|segment| segment.key_index.as_ref())
+            .find_map(|index| index.get(key));
+
+        let id = match id {
+            Some(id) => id as u32,
+            None => return Ok(None),
+        };
+
+        match self.get_document(id) {
+            Ok(doc) if doc.key.as_deref() == Some(key) => Ok(Some(id)),
+            Ok(_) | Err(Error::DocumentNotFound(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// 一次取回多篇文档，顺序和ids一样，ids可以重复。和get_document一样，有任何一个id不存在时返回DocumentNotFound。
+    /// 先按doc id排序，每个segment按offset表的顺序读，只读用到的文档，不用逐个从最新的segment开始找
+    pub fn get_documents(&self, ids: &[u32]) -> Result<Vec<StoredDocument>> {
+        let mut pending = ids.to_vec();
+        pending.sort_unstable();
+        pending.dedup();
+
+        let mut found = HashMap::with_capacity(pending.len());
+        for segment in self.segments.iter().rev() {
+            if pending.is_empty() {
+                break;
+            }
+
+            let mut rest = Vec::new();
+            for id in pending.into_iter() {
+                if segment.tombstones.contains(id) {
+                    rest.push(id);
                     continue;
                 }
+
+                match segment.doc_store.get(id)? {
+                    Some(doc) => {
+                        found.insert(id, doc);
+                    }
+                    None => rest.push(id),
+                }
             }
+            pending = rest;
+        }
 
-            match self.query_term_postings(word.as_str(), aut_builder)? {
-                None => (),
-                Some(v) => {
-                    postings.push((word.as_str(), v));
+        if let Some(id) = pending.first() {
+            return Err(Error::DocumentNotFound(*id));
+        }
+
+        // 重复的id要拷贝，最后一次出现时才能拿走
+        let mut remaining = HashMap::<u32, usize>::with_capacity(found.len());
+        for id in ids.iter() {
+            *remaining.entry(*id).or_default() += 1;
+        }
+
+        let mut docs = Vec::with_capacity(ids.len());
+        for id in ids.iter() {
+            let count = remaining.get_mut(id).unwrap();
+            *count -= 1;
+            docs.push(match *count {
+                0 => found.remove(id).unwrap(),
+                _ => found[id].clone(),
+            });
+        }
+
+        Ok(docs)
+    }
+
+    /// 文档的数值字段，没有这个字段时返回None。和get_document一样查找有效的segment，但是只读这一个字段
+    fn get_number(&self, doc_id: u32, name: &str) -> Result<Option<f64>> {
+        for segment in self.segments.iter().rev() {
+            if segment.tombstones.contains(doc_id) {
+                continue;
+            }
+
+            if let Some(value) = segment.doc_store.get_number(doc_id, name)? {
+                return Ok(value);
+            }
+        }
+
+        Err(Error::DocumentNotFound(doc_id))
+    }
+
+    /// 文档的keyword字段，和get_number一样只读这一个字段
+    fn get_keyword(&self, doc_id: u32, name: &str) -> Result<Option<String>> {
+        for segment in self.segments.iter().rev() {
+            if segment.tombstones.contains(doc_id) {
+                continue;
+            }
+
+            if let Some(value) = segment.doc_store.get_keyword(doc_id, name)? {
+                return Ok(value);
+            }
+        }
+
+        Err(Error::DocumentNotFound(doc_id))
+    }
+
+    /// 所有有效的文档里数值字段在range内的doc id，升序。
+    /// 有segment没有数值字段的索引（版本9之前建的）时，scan_doc_store为true就逐篇读这个segment的文档存储，
+    /// 否则返回None
+    fn numeric_range_ids(
+        &self,
+        range: &NumericRange,
+        scan_doc_store: bool,
+        deadline: &Deadline,
+    ) -> Result<Option<Vec<u32>>> {
+        let mut ids = Vec::new();
+
+        for segment in self.segments.iter() {
+            let index = match &segment.numeric_index {
+                Some(index) => index,
+                None if scan_doc_store => {
+                    for (i, doc_id) in segment.doc_store.ids().enumerate() {
+                        deadline.check_every(i)?;
+                        if !segment.tombstones.contains(doc_id)
+                            && range.contains(
+                                segment
+                                    .doc_store
+                                    .get_number(doc_id, &range.field)?
+                                    .flatten(),
+                            )
+                        {
+                            ids.push(doc_id);
+                        }
+                    }
+                    continue;
+                }
+                None => return Ok(None),
+            };
+
+            let column = match index.field(&range.field) {
+                Some(column) => column,
+                None => continue,
+            };
+
+            // 值是升序的，先二分跳过下界以下的部分，到上界为止
+            let start = column.partition_point(|value| !range.above_lower(value));
+            for i in start..column.len() {
+                deadline.check_every(i - start)?;
+                let (value, doc_id) = column.get(i);
+                if !range.below_upper(value) {
+                    break;
+                }
+                if range.contains(Some(value)) && !segment.tombstones.contains(doc_id) {
+                    ids.push(doc_id);
                 }
             }
         }
 
-        postings.sort_by(|a, b| a.1.len().cmp(&b.1.len()));
+        ids.sort_unstable();
+        ids.dedup();
+        Ok(Some(ids))
+    }
 
-        let mut df = Vec::<u32>::with_capacity(postings.len());
-        let mut query_score = Vec::<f64>::with_capacity(postings.len());
-        let mut merger = PostingListMerger::new();
+    /// 在文档内容里用<em></em>标出sentence中的词，返回匹配最密集的max_len个字符，标签以外的原文做了html转义。
+    /// sentence和query的一样，-开头的排除词不会被高亮。
+    /// 内容用查询的analyzer重新分析，分析后和查询词相同的token才会被标出
+    pub fn highlight(&self, doc_id: u32, sentence: &str, max_len: usize) -> Result<String> {
+        self.highlight_with_tags(doc_id, sentence, max_len, HighlightTags::default())
+    }
 
-        for p in postings.iter() {
-            let list = &p.1;
-            let query_term = query_terms.get(p.0).unwrap();
-            let tf = calc_tf(*query_term);
-            let norm = calc_norm(sentence.chars().count());
-            query_score.push(
-                self.term_priority_calculator
-                    .calc(list.len(), tf, tf, norm, norm),
-            );
-            df.push(list.len());
-            merger.union(&p.1)?;
+    pub fn highlight_with_tags(
+        &self,
+        doc_id: u32,
+        sentence: &str,
+        max_len: usize,
+        tags: HighlightTags,
+    ) -> Result<String> {
+        let terms = self.highlight_terms(sentence)?;
+        self.snippet(doc_id, &terms, max_len, tags)
+    }
+
+    /// 给这一页的每个结果填上SearchHit::snippet，和highlight一样用<em></em>，原文做了html转义
+    pub fn highlight_hits(
+        &self,
+        results: &mut SearchResults,
+        sentence: &str,
+        max_len: usize,
+    ) -> Result<()> {
+        let terms = self.highlight_terms(sentence)?;
+        for hit in results.hits.iter_mut() {
+            let snippet = self.snippet(hit.doc_id, &terms, max_len, HighlightTags::default())?;
+            hit.snippet = Some(snippet);
         }
 
-        let mut result = Vec::new();
+        Ok(())
+    }
 
-        // TODO: 可以用Reverse反向sort
-        merger.mut_get_postings().sort_by_cached_key(|p| {
-            let mut score = Vec::<f64>::with_capacity(postings.len());
-            let terms = p.get_term_priority_info();
-            for i in 0..terms.len() {
-                let term = unsafe { terms.get_unchecked(i) };
-                score.push(self.term_priority_calculator.calc(
-                    *unsafe { df.get_unchecked(i) },
-                    term.tf.0,
-                    term.tf.1,
-                    term.norm.0,
-                    term.norm.1,
-                ))
-            }
-            Score::new(&query_score, &score)
-        });
+    /// 要高亮的词：sentence里不排除的词分析之后的结果
+    fn highlight_terms(&self, sentence: &str) -> Result<HashSet<String>> {
+        Ok(self
+            .analyzer
+            .analyze(&split_sentence(sentence).included_text())?
+            .into_iter()
+            .collect())
+    }
 
-        let pl = merger.get_postings();
+    fn snippet(
+        &self,
+        doc_id: u32,
+        terms: &HashSet<String>,
+        max_len: usize,
+        tags: HighlightTags,
+    ) -> Result<String> {
+        let doc = self.get_document(doc_id)?;
 
-        if range.start < pl.len() {
-            let start = pl.len() - range.start;
-            let end = if range.end <= pl.len() {
-                pl.len() - range.end
-            } else {
-                0
+        // 内容也用同一个analyzer切开，和查询词比较的是分析之后的词，大小写、全角半角、同义词
+        // 都和查询时一致，也不会匹配到词的一部分
+        let matches = self
+            .analyzer
+            .analyze_tokens(&doc.content)?
+            .into_iter()
+            .filter(|token| terms.contains(token.text.as_ref()))
+            .map(|token| token.start..token.end)
+            .collect::<Vec<_>>();
+
+        Ok(highlight::highlight(&doc.content, &matches, max_len, tags))
+    }
+
+    /// 合并query_term_postings返回的list用的merger，只有一个segment时由它跳过已经删除的doc
+    fn posting_list_merger(&self, field: Option<Field>) -> PostingListMerger<'_> {
+        let tombstones = match self.segments.as_slice() {
+            [segment] => Some(&segment.tombstones),
+            _ => None,
+        };
+        PostingListMerger::new(field, tombstones)
+    }
+
+    /// 只有一个segment时list不去掉已经删除的doc，由posting_list_merger合并时跳过，这样不需要拷贝；
+    /// 有多个segment时每个segment的list先去掉自己失效的doc再合并，合并后同一个doc只会出现一次
+    fn query_term_postings<A: fst::Automaton>(
+        &self,
+        word: &str,
+        aut_builder: &impl Fn(&str) -> Option<A>,
+        deadline: &Deadline,
+    ) -> Result<Option<RawPostingList<'_>>> {
+        let aut = aut_builder(word);
+        let multi_segment = self.segments.len() > 1;
+        let mut lists = Vec::new();
+
+        for segment in self.segments.iter() {
+            let dict_indexes = match aut {
+                None => segment.term_index.get(word).into_iter().collect(),
+                Some(ref aut) => segment.term_index.search(aut).into_stream().into_values(),
             };
 
-            for i in (end..start).rev() {
-                result.push(unsafe { pl.get_unchecked(i) }.get_doc_id());
+            let tombstones = Some(&segment.tombstones).filter(|_| multi_segment);
+            for index in dict_indexes.into_iter() {
+                lists.push((segment.posting_list(index)?, tombstones));
             }
         }
 
-        Ok(result)
+        union_lists(lists, deadline)
     }
-}
 
-fn check_term_index(mut reader: impl std::io::Read) -> Result<usize> {
-    if reader.read_u64::<LittleEndian>()? != TERM_INDEX_MAGIC_NUMBER || reader.read_u8()? != VERSION
-    {
-        return Err(Error::Incompatible);
+    /// 和query_term_postings一样，但是最多只取词典顺序的前max_terms个匹配的词。
+    /// 每个segment的词典都是有序的，所有segment的前max_terms个里一定包含了合起来的前max_terms个
+    fn expand_term_postings<A: fst::Automaton>(
+        &self,
+        aut: &A,
+        max_terms: usize,
+        deadline: &Deadline,
+    ) -> Result<Option<RawPostingList<'_>>> {
+        let multi_segment = self.segments.len() > 1;
+        let mut matched = Vec::<(Vec<u8>, u64, &SegmentReader)>::new();
+
+        for segment in self.segments.iter() {
+            let mut stream = segment.term_index.search(aut).into_stream();
+            let mut n = 0;
+            while let Some((key, index)) = stream.next() {
+                if n >= max_terms {
+                    break;
+                }
+                deadline.check_every(n)?;
+                matched.push((key.to_vec(), index, segment));
+                n += 1;
+            }
+        }
+
+        let keys = matched
+            .iter()
+            .map(|m| m.0.as_slice())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .take(max_terms)
+            .collect::<HashSet<_>>();
+
+        let mut lists = Vec::new();
+        for (key, index, segment) in matched.iter() {
+            if keys.contains(key.as_slice()) {
+                let tombstones = Some(&segment.tombstones).filter(|_| multi_segment);
+                lists.push((segment.posting_list(*index)?, tombstones));
+            }
+        }
+
+        union_lists(lists, deadline)
     }
 
-    Ok((64 + 8) / 8)
-}
+    /// 查询没有结果时用来排查：找出分析后不在词典里的词（通常是建索引和查询用的analyzer不一致），
+    /// 并给出词典里相近的词。不会影响query本身，需要时再调用
+    pub fn diagnose(&self, sentence: &str, max_suggestions: usize) -> Result<Vec<MissingTerm>> {
+        let mut missing = Vec::<MissingTerm>::new();
+
+        for term in self.analyzer.analyze(sentence)? {
+            if self
+                .segments
+                .iter()
+                .any(|s| s.term_index.contains_key(&term))
+                || missing.iter().any(|m| m.term == term)
+            {
+                continue;
+            }
+
+            let mut suggestions = Vec::<String>::new();
+            for distance in 1..=2 {
+                let aut = LevenshteinAutomaton::new(&term, distance);
+                for segment in self.segments.iter() {
+                    let mut stream = segment.term_index.search(&aut).into_stream();
+                    while let Some((key, _)) = stream.next() {
+                        if suggestions.len() >= max_suggestions {
+                            break;
+                        }
+
+                        let key = String::from_utf8_lossy(key);
+                        if !suggestions.iter().any(|s| *s == key) {
+                            suggestions.push(key.into_owned());
+                        }
+                    }
+                }
+
+                if !suggestions.is_empty() {
+                    break;
+                }
+            }
+
+            missing.push(MissingTerm { term, suggestions });
+        }
+
+        Ok(missing)
+    }
+
+    /// occur为Must时返回包含所有词的文档（AND），为Should时返回包含任意一个词的文档（OR）。
+    /// 以-开头的词（如"绫华 -神里"）会被排除，不能只有排除的词。
+    /// 以^n结尾的词（如"绫华^2 旅行"）的权重乘上n，见Clause::boost
+    pub fn query<A: fst::Automaton>(
+        &self,
+        sentence: &str,
+        occur: Occur,
+        aut_builder: &impl Fn(&str) -> Option<A>,
+        range: Range<usize>,
+        options: &QueryOptions,
+    ) -> Result<SearchResults> {
+        let split = split_sentence(sentence);
+
+        self.bool_query(&split.clauses(occur), aut_builder, range, options)
+    }
+
+    /// 和query一样，不过每个子句可以单独指定Occur。
+    /// 有Must/Filter子句时，结果只包含满足所有Must/Filter的文档，Should子句不再限制结果，
+    /// 只给同时包含这些词的文档加分；没有Must/Filter时至少要命中一个Should的词。
+    /// MustNot的词精确匹配（不使用aut_builder），包含它们的文档最后会被去掉，
+    /// 只有MustNot的词时返回Error::OnlyExcluded
+    pub fn bool_query<A: fst::Automaton>(
+        &self,
+        clauses: &[Clause],
+        aut_builder: &impl Fn(&str) -> Option<A>,
+        range: Range<usize>,
+        options: &QueryOptions,
+    ) -> Result<SearchResults> {
+        options.depth(&range)?;
+
+        match self.merge_clauses(
+            clauses,
+            aut_builder,
+            options.field,
+            options.min_should_match,
+            &options.deadline,
+        )? {
+            Some(merged) => self.rank(merged, range, options),
+            None => Ok(SearchResults::default()),
+        }
+    }
+
+    /// 边输入边搜索，用于自动补全：和query一样，但是输入的最后一个词可能还没有打完，
+    /// 分析后在输入的末尾结束的词（"神里 绫"里的"绫"、"神里绫"切出的"绫"）用前缀匹配，
+    /// 词典里所有以它开头的词合并起来当作同一个词打分；其他的词用aut_builder精确或者模糊匹配。
+    /// 输入以空白结尾时最后一个词已经完整了，和query一样匹配；最后一个词是排除的词时也不做前缀匹配。
+    /// 没有空格隔开时取决于分词，jieba把没打完的字和前面的字切成一个词时（比如"绫华雷"）就匹配不到。
+    /// 前缀很短时会展开成很多词，需要限制时间时设置options.deadline
+    ///
+    /// ```
+    /// use raiden_shogun_search::analyzer::char_filter::CJKDocCharFilter;
+    /// use raiden_shogun_search::analyzer::token_filter::BasicTokenFilter;
+    /// use raiden_shogun_search::analyzer::tokenizer::UnicodeWordTokenizer;
+    /// use raiden_shogun_search::query::{self, Fuzziness};
+    /// use raiden_shogun_search::{store, Analyzer, Builder, Directory, Document, Occur, Query};
+    ///
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let analyzer = || {
+    ///     Analyzer::new(
+    ///         CJKDocCharFilter::new(),
+    ///         BasicTokenFilter::new(),
+    ///         UnicodeWordTokenizer::new(),
+    ///     )
+    /// };
+    /// let directory = Directory::memory();
+    ///
+    /// let mut builder = Builder::new(analyzer(), analyzer(), store::Config::new(directory.clone(), "t", 1));
+    /// for (id, content) in [(1, "kamisato ayaka"), (2, "kamisato ayato"), (3, "raiden shogun")] {
+    ///     builder.add_document(Document {
+    ///         id,
+    ///         title: "",
+    ///         content,
+    ///         keywords: vec![],
+    ///         numbers: vec![],
+    ///         payload: None,
+    ///         key: None,
+    ///     })?;
+    /// }
+    /// builder.finish()?;
+    ///
+    /// let query = Query::new(analyzer(), query::Config::new(directory, "t", 3, 1, query::Similarity::default()))?;
+    /// let search = |sentence: &str, fuzziness: Fuzziness| -> query::Result<Vec<u32>> {
+    ///     let results = query.search_as_you_type(
+    ///         sentence,
+    ///         Occur::Must,
+    ///         &|w| fuzziness.automaton(w),
+    ///         0..10,
+    ///         &query::QueryOptions::default(),
+    ///     )?;
+    ///     let mut ids = results.ids();
+    ///     ids.sort();
+    ///     Ok(ids)
+    /// };
+    ///
+    /// // 只有一个词
+    /// assert_eq!(search("kami", Fuzziness::Off)?, vec![1, 2]);
+    /// assert_eq!(search("ra", Fuzziness::Off)?, vec![3]);
+    /// assert_eq!(search("kamisato", Fuzziness::Off)?, vec![1, 2]);
+    ///
+    /// // 前面的词按原样或者模糊匹配，只有最后一个词是前缀
+    /// assert_eq!(search("kamisato aya", Fuzziness::Off)?, vec![1, 2]);
+    /// assert_eq!(search("kamisato ayak", Fuzziness::Off)?, vec![1]);
+    /// assert_eq!(search("kami ayak", Fuzziness::Off)?, Vec::<u32>::new());
+    /// assert_eq!(search("kamisatp ayak", Fuzziness::Edits(1))?, vec![1]);
+    ///
+    /// // 以空格结尾时最后一个词已经打完了
+    /// assert_eq!(search("kamisato ", Fuzziness::Off)?, vec![1, 2]);
+    /// assert_eq!(search("kamisato aya ", Fuzziness::Off)?, Vec::<u32>::new());
+    /// assert_eq!(search("kamisato -ayak", Fuzziness::Off)?, vec![1, 2]);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn search_as_you_type(
+        &self,
+        sentence: &str,
+        occur: Occur,
+        aut_builder: &impl Fn(&str) -> Option<QueryAutomaton>,
+        range: Range<usize>,
+        options: &QueryOptions,
+    ) -> Result<SearchResults> {
+        let prefixes = self.prefix_words(sentence)?;
+        let aut_builder = |word: &str| match prefixes.iter().any(|p| p == word) {
+            true => prefix_automaton(word),
+            false => aut_builder(word),
+        };
+
+        self.query(sentence, occur, &aut_builder, range, options)
+    }
+
+    /// search_as_you_type里要前缀匹配的词：最后一个词（去掉boost）分析后在它末尾结束的词
+    fn prefix_words(&self, sentence: &str) -> Result<Vec<String>> {
+        if sentence.ends_with(char::is_whitespace) {
+            return Ok(Vec::new());
+        }
+
+        let last = match sentence.split_whitespace().next_back() {
+            Some(word) if word.len() > 1 && word.starts_with('-') => return Ok(Vec::new()),
+            Some(word) => split_boost(word).map_or(word, |(word, _)| word),
+            None => return Ok(Vec::new()),
+        };
+
+        Ok(self
+            .analyzer
+            .analyze_tokens(last)?
+            .into_iter()
+            .filter(|token| token.end == last.len())
+            .map(|token| token.text.into_owned())
+            .collect())
+    }
+
+    /// 和query一样，结果会被缓存，见bool_query_cached
+    pub fn query_cached<A: fst::Automaton>(
+        &self,
+        sentence: &str,
+        occur: Occur,
+        aut_builder: &impl Fn(&str) -> Option<A>,
+        aut_key: &str,
+        range: Range<usize>,
+        options: &QueryOptions,
+    ) -> Result<Arc<SearchResults>> {
+        let split = split_sentence(sentence);
+
+        self.bool_query_cached(&split.clauses(occur), aut_builder, aut_key, range, options)
+    }
+
+    /// 和bool_query一样，但是先按分析后的词、range和options查缓存，没有开启缓存时直接查询。
+    /// aut_builder是闭包没法比较，调用方用aut_key区分不同的模糊匹配设置：
+    /// aut_key相同的aut_builder必须对同一个词生成一样的automaton，否则会拿到别的设置的结果
+    pub fn bool_query_cached<A: fst::Automaton>(
+        &self,
+        clauses: &[Clause],
+        aut_builder: &impl Fn(&str) -> Option<A>,
+        aut_key: &str,
+        range: Range<usize>,
+        options: &QueryOptions,
+    ) -> Result<Arc<SearchResults>> {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => {
+                return Ok(Arc::new(self.bool_query(
+                    clauses,
+                    aut_builder,
+                    range,
+                    options,
+                )?))
+            }
+        };
+
+        let mut analyzed = Vec::with_capacity(clauses.len());
+        for clause in clauses.iter() {
+            analyzed.push((
+                clause.occur,
+                clause.boost,
+                self.analyzer.analyze(clause.text)?,
+            ));
+        }
+        let key = CacheKey::new(analyzed, aut_key, &range, options);
+
+        // 查询期间不持有锁，同一个查询同时到达时可能会各查一次
+        if let Some(result) = lock_cache(cache).get(&key) {
+            return Ok(result);
+        }
+
+        let result = Arc::new(self.bool_query(clauses, aut_builder, range, options)?);
+        lock_cache(cache).insert(key, result.clone());
+
+        Ok(result)
+    }
+
+    /// 短语查询：文档中要按顺序出现sentence分析后的所有词，打分和occur为Must的query一样。
+    /// 位置按token_filter之后留下的token计数，所以被过滤掉的空白、停用词不占位置。
+    /// slop是允许词之间多出来的token总数，0表示必须紧挨着；词要出现在同一个字段里。
+    /// 不支持模糊匹配和排除的词，min_should_match不起作用
+    pub fn phrase_query(
+        &self,
+        sentence: &str,
+        slop: u32,
+        range: Range<usize>,
+        options: &QueryOptions,
+    ) -> Result<SearchResults> {
+        options.depth(&range)?;
+
+        let exact = &|_: &str| None::<Levenshtein>;
+        let mut merged = match self.merge_clauses(
+            &[Clause::new(Occur::Must, sentence)],
+            exact,
+            options.field,
+            None,
+            &options.deadline,
+        )? {
+            Some(merged) => merged,
+            None => return Ok(SearchResults::default()),
+        };
+
+        let phrase = self.analyzer.analyze(sentence)?;
+        self.retain_phrase(
+            &mut merged.merger,
+            &phrase,
+            slop,
+            options.field,
+            &options.deadline,
+        )?;
 
-fn check_term_dict(mut reader: impl std::io::Read) -> Result<usize> {
-    if reader.read_u64::<LittleEndian>()? != TERM_DICT_MAGIC_NUMBER || reader.read_u8()? != VERSION
-    {
-        return Err(Error::Incompatible);
+        self.rank(merged, range, options)
     }
 
-    Ok((64 + 8) / 8)
+    /// 只留下merger里按顺序包含phrase的所有词的文档，多出来的token总数不超过slop。
+    /// merger里的文档要已经包含了所有的词，只有一个词时不需要检查位置
+    fn retain_phrase(
+        &self,
+        merger: &mut PostingListMerger,
+        phrase: &[String],
+        slop: u32,
+        field: Option<Field>,
+        deadline: &Deadline,
+    ) -> Result<()> {
+        if phrase.len() < 2 {
+            return Ok(());
+        }
+
+        let exact = &|_: &str| None::<Levenshtein>;
+        let mut lists = Vec::with_capacity(phrase.len());
+        for term in phrase.iter() {
+            match self.query_term_postings(term, exact, deadline)? {
+                Some(list) => lists.push(list),
+                None => {
+                    merger.mut_get_postings().clear();
+                    return Ok(());
+                }
+            }
+        }
+
+        let mut cursors = lists.iter().map(|l| l.cursor()).collect::<Vec<_>>();
+        let mut keep = Vec::with_capacity(merger.len() as usize);
+
+        // 候选的doc是按id升序的，cursor只需要往前走
+        for (i, posting) in merger.get_postings().iter().enumerate() {
+            deadline.check_every(i)?;
+            let mut positions = Vec::with_capacity(lists.len());
+            for (list, cursor) in lists.iter().zip(cursors.iter_mut()) {
+                match cursor.advance_to(posting.get_doc_id())? {
+                    Some(p) if p.doc_id == posting.get_doc_id() => {
+                        positions.push(list.positions(&p)?)
+                    }
+                    _ => break,
+                }
+            }
+
+            keep.push(
+                positions.len() == lists.len()
+                    && match field {
+                        None => {
+                            match_phrase(&positions, |p| &p.0, slop)
+                                || match_phrase(&positions, |p| &p.1, slop)
+                        }
+                        Some(Field::Title) => match_phrase(&positions, |p| &p.0, slop),
+                        Some(Field::Content) => match_phrase(&positions, |p| &p.1, slop),
+                    },
+            );
+        }
+
+        let mut keep = keep.into_iter();
+        merger.mut_get_postings().retain(|_| keep.next().unwrap());
+
+        Ok(())
+    }
+
+    /// 通配符查询，见WildcardAutomaton。pattern不经过analyzer，直接和词典里的词匹配，
+    /// 所以要写成建索引后的形式（比如英文是小写的）。匹配到的所有词合并起来当作同一个词打分。
+    /// "*"这样的模式会匹配整个词典，所以最多只用词典顺序的前max_expansions个词，
+    /// 一般用DEFAULT_MAX_EXPANSIONS。min_should_match不起作用
+    pub fn wildcard_query(
+        &self,
+        pattern: &str,
+        max_expansions: usize,
+        range: Range<usize>,
+        options: &QueryOptions,
+    ) -> Result<SearchResults> {
+        options.depth(&range)?;
+
+        let aut = WildcardAutomaton::new(pattern);
+        let list = match self.expand_term_postings(&aut, max_expansions, &options.deadline)? {
+            Some(list) => list,
+            None => return Ok(SearchResults::default()),
+        };
+
+        let mut merger = self.posting_list_merger(options.field);
+        merger.union(&list)?;
+
+        let query_len = pattern.chars().count().min(u16::MAX as usize) as u16;
+        let tf = calc_tf(1);
+        let tf = match options.field {
+            None => (tf, tf),
+            Some(Field::Title) => (tf, 0),
+            Some(Field::Content) => (0, tf),
+        };
+
+        let merged = MergedPostings {
+            merger,
+            terms: vec![(pattern.to_string(), Occur::Must)],
+            df: vec![list.len()],
+            scoring: vec![true],
+            query_score: vec![self.term_priority_calculator.calc(
+                list.len(),
+                tf.0,
+                tf.1,
+                query_len,
+                query_len,
+            )],
+        };
+
+        self.rank(merged, range, options)
+    }
+
+    /// 只按数值字段查询，返回numeric_range内所有的文档，比如某段时间内发布的（时间戳存成numbers）。
+    /// 结果按doc id升序，分数都是1，和constant_score一样；options里只有top_k和deadline起作用。
+    /// 要和查询词一起用时设置QueryOptions::numeric_range，两者用的是同一个索引
+    ///
+    /// ```
+    /// use raiden_shogun_search::analyzer::char_filter::CJKDocCharFilter;
+    /// use raiden_shogun_search::analyzer::token_filter::BasicTokenFilter;
+    /// use raiden_shogun_search::analyzer::tokenizer::UnicodeWordTokenizer;
+    /// use raiden_shogun_search::query::{self, NumericRange, QueryOptions};
+    /// use raiden_shogun_search::{store, Analyzer, Builder, Directory, Document, Query};
+    ///
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let analyzer = || {
+    ///     Analyzer::new(
+    ///         CJKDocCharFilter::new(),
+    ///         BasicTokenFilter::new(),
+    ///         UnicodeWordTokenizer::new(),
+    ///     )
+    /// };
+    /// let directory = Directory::memory();
+    ///
+    /// let mut builder = Builder::new(analyzer(), analyzer(), store::Config::new(directory.clone(), "t", 1));
+    /// for (id, price) in [(1, 30.0), (2, 10.0), (3, 50.0), (4, 20.0)] {
+    ///     builder.add_document(Document { id, title: "", content: "raiden", keywords: vec![], numbers: vec![("price", price)], payload: None, key: None })?;
+    /// }
+    /// builder.add_document(Document { id: 5, title: "", content: "raiden", keywords: vec![], numbers: vec![], payload: None, key: None })?;
+    /// builder.finish()?;
+    /// builder.delete_document(4);
+    /// builder.finish()?;
+    ///
+    /// let query = Query::new(analyzer(), query::Config::new(directory, "t", 3, 1, query::Similarity::default()))?;
+    /// let range = NumericRange {
+    ///     gte: Some(20.0),
+    ///     lt: Some(50.0),
+    ///     ..NumericRange::new("price")
+    /// };
+    ///
+    /// let results = query.range_query(&range, 0..10, &QueryOptions::default())?;
+    /// assert_eq!(results.ids(), vec![1]);
+    /// assert_eq!(results.total, 1);
+    ///
+    /// let results = query.range_query(&NumericRange::new("price"), 0..2, &QueryOptions::default())?;
+    /// assert_eq!(results.ids(), vec![1, 2]);
+    /// assert_eq!(results.total, 3);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn range_query(
+        &self,
+        numeric_range: &NumericRange,
+        range: Range<usize>,
+        options: &QueryOptions,
+    ) -> Result<SearchResults> {
+        options.depth(&range)?;
+
+        let ids = self
+            .numeric_range_ids(numeric_range, true, &options.deadline)?
+            .unwrap_or_default();
+
+        Ok(SearchResults {
+            total: ids.len() as u32,
+            hits: ids
+                .iter()
+                .skip(range.start)
+                .take(range.end.saturating_sub(range.start))
+                .map(|doc_id| SearchHit {
+                    doc_id: *doc_id,
+                    score: 1f64,
+                    snippet: None,
+                })
+                .collect(),
+        })
+    }
+
+    /// 按parse_query解析出来的树查询，每一组怎么组合见QueryNode::Group。
+    /// 每个词都经过analyzer，aut_builder用于模糊匹配，排除的词精确匹配，和query一样。
+    /// 同一个词出现在树的多个地方时每处各算一次分。min_should_match不起作用，
+    /// 只有排除的词时返回Error::OnlyExcluded
+    pub fn parsed_query<A: fst::Automaton>(
+        &self,
+        node: &QueryNode,
+        aut_builder: &impl Fn(&str) -> Option<A>,
+        range: Range<usize>,
+        options: &QueryOptions,
+    ) -> Result<SearchResults> {
+        options.depth(&range)?;
+
+        let single;
+        let clauses = match node {
+            QueryNode::Group(clauses) => clauses.as_slice(),
+            _ => {
+                single = [(Occur::Must, node.clone())];
+                &single[..]
+            }
+        };
+
+        let query_len = included_len(clauses).min(u16::MAX as usize) as u16;
+        match self.merge_group(
+            clauses,
+            aut_builder,
+            false,
+            options.field,
+            query_len,
+            &options.deadline,
+        )? {
+            Some(merged) => self.rank(merged, range, options),
+            None if has_excluded(clauses) => Err(Error::OnlyExcluded),
+            None => Ok(SearchResults::default()),
+        }
+    }
+
+    /// 按options打分排序，返回range范围内的结果和命中的总数
+    fn rank(
+        &self,
+        merged: MergedPostings,
+        range: Range<usize>,
+        options: &QueryOptions,
+    ) -> Result<SearchResults> {
+        let depth = options.depth(&range)?;
+        let MergedPostings {
+            mut merger,
+            df,
+            scoring,
+            query_score,
+            ..
+        } = merged;
+
+        // 在数值字段的索引里二分找到范围内的doc id，再和postings求交集（都是按doc id升序的）。
+        // 有旧segment没有这个索引时对每个候选文档读文档存储
+        if let Some(range) = &options.numeric_range {
+            match self.numeric_range_ids(range, false, &options.deadline)? {
+                Some(ids) => {
+                    let mut ids = ids.iter().peekable();
+                    merger.mut_get_postings().retain(|p| {
+                        let doc_id = p.get_doc_id();
+                        while ids.next_if(|id| **id < doc_id).is_some() {}
+                        ids.peek() == Some(&&doc_id)
+                    });
+                }
+                None => {
+                    let mut keep = Vec::with_capacity(merger.len() as usize);
+                    for (i, p) in merger.get_postings().iter().enumerate() {
+                        options.deadline.check_every(i)?;
+                        keep.push(range.contains(self.get_number(p.get_doc_id(), &range.field)?));
+                    }
+
+                    let mut keep = keep.into_iter();
+                    merger.mut_get_postings().retain(|_| keep.next().unwrap());
+                }
+            }
+        }
+
+        let postings = merger.get_postings();
+        let total = merger.len();
+
+        // 按字段排序时每个posting的排序键：(没有值, 值)，降序时值取反，这样都按升序比较
+        let field_key = |p: &Posting| -> Result<Option<(bool, Score)>> {
+            let (name, ascending) = match &options.sort_by {
+                SortBy::Relevance => return Ok(None),
+                SortBy::Field { name, ascending } => (name, *ascending),
+            };
+
+            Ok(Some(match self.get_number(p.get_doc_id(), name)? {
+                Some(value) if !value.is_nan() => {
+                    (false, Score::new(if ascending { value } else { -value }))
+                }
+                _ => (true, Score::new(0f64)),
+            }))
+        };
+
+        if options.constant_score && options.sort_by == SortBy::Relevance {
+            // top_k已经保证了range.end不会超出，这里直接按doc id顺序取range
+            return Ok(SearchResults {
+                total,
+                hits: postings
+                    .iter()
+                    .skip(range.start)
+                    .take(range.len())
+                    .map(|p| SearchHit {
+                        doc_id: p.get_doc_id(),
+                        score: 1f64,
+                        snippet: None,
+                    })
+                    .collect(),
+            });
+        }
+
+        let end = depth.min(postings.len());
+        if range.start >= range.end.min(end) {
+            return Ok(SearchResults {
+                total,
+                hits: Vec::new(),
+            });
+        }
+
+        let calc_score = |p: &Posting| {
+            let mut score = Vec::<f64>::with_capacity(df.len());
+            let terms = p.get_term_priority_info();
+            for i in 0..terms.len() {
+                let term = unsafe { terms.get_unchecked(i) };
+                score.push(if *unsafe { scoring.get_unchecked(i) } {
+                    self.term_priority_calculator.calc(
+                        *unsafe { df.get_unchecked(i) },
+                        term.tf.0,
+                        term.tf.1,
+                        term.len.0,
+                        term.len.1,
+                    )
+                } else {
+                    0f64
+                })
+            }
+            Score::new(self.term_priority_calculator.combine(&query_score, &score))
+        };
+
+        // 先按字段（如果有），再按分数从高到低，分数相同时doc id小的在前（postings是按doc id升序的），
+        // 越小越靠前。constant_score时分数都是1。只需要前end个：用大小为end的最大堆，
+        // 堆顶是目前留下的最靠后的一个，新的比它靠前时替换掉它，不用保存和排序全部的结果
+        let mut heap = BinaryHeap::with_capacity(end);
+        // 所有结果的最高分，按字段排序时最高分不一定在留下的结果里
+        let mut top = 0f64;
+        for (i, p) in postings.iter().enumerate() {
+            options.deadline.check_every(i)?;
+            let score = match options.constant_score {
+                true => Score::new(1f64),
+                false => calc_score(p),
+            };
+            top = top.max(score.value());
+
+            let key = (field_key(p)?, Reverse(score), i);
+            if heap.len() < end {
+                heap.push(key);
+            } else if let Some(mut last) = heap.peek_mut() {
+                if key < *last {
+                    *last = key;
+                }
+            }
+        }
+        options.deadline.check()?;
+        let scores = heap.into_sorted_vec();
+        if !options.normalize_scores {
+            top = 0f64;
+        }
+
+        Ok(SearchResults {
+            total,
+            hits: scores[range.start..range.end.min(end)]
+                .iter()
+                .map(|(_, score, i)| SearchHit {
+                    doc_id: postings[*i].get_doc_id(),
+                    score: match top > 0f64 {
+                        true => score.0.value() / top,
+                        false => score.0.value(),
+                    },
+                    snippet: None,
+                })
+                .collect(),
+        })
+    }
+
+    /// 只返回命中的文档数量，不打分、不排序，词的处理和query一样。词都不在词典里时返回0
+    pub fn count<A: fst::Automaton>(
+        &self,
+        sentence: &str,
+        aut_builder: &impl Fn(&str) -> Option<A>,
+        occur: Occur,
+    ) -> Result<u32> {
+        let split = split_sentence(sentence);
+        let clauses = split.clauses(occur);
+
+        Ok(self
+            .merge_clauses(&clauses, aut_builder, None, None, &Deadline::default())?
+            .map_or(0, |merged| merged.merger.len()))
+    }
+
+    /// 按doc id从小到大逐个返回命中的文档，词的处理和count一样。用于导出所有命中的id这种批量提取，
+    /// 不打分也不排序，需要排名时用query。
+    ///
+    /// query要把所有命中的文档合并到一起、打分排序之后才能取出一页，用它一页一页地翻完整个结果，
+    /// 每一页都要重新合并一遍。scan不收集结果，每次next只在各个词的posting list上往前走到下一个命中的doc，
+    /// 内存只和词的数量有关，不管命中多少文档。有多个segment时每个词在各个segment里的list会先合并成一个，
+    /// 这部分和这个词的df成正比。
+    ///
+    /// 解码posting list出错时返回Err，之后不再返回任何结果
+    ///
+    /// ```
+    /// use raiden_shogun_search::analyzer::char_filter::CJKDocCharFilter;
+    /// use raiden_shogun_search::analyzer::token_filter::BasicTokenFilter;
+    /// use raiden_shogun_search::analyzer::tokenizer::UnicodeWordTokenizer;
+    /// use raiden_shogun_search::query::{self, Fuzziness};
+    /// use raiden_shogun_search::{store, Analyzer, Builder, Directory, Document, Occur, Query};
+    ///
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let analyzer = || {
+    ///     Analyzer::new(
+    ///         CJKDocCharFilter::new(),
+    ///         BasicTokenFilter::new(),
+    ///         UnicodeWordTokenizer::new(),
+    ///     )
+    /// };
+    /// let directory = Directory::memory();
+    ///
+    /// let mut builder = Builder::new(analyzer(), analyzer(), store::Config::new(directory.clone(), "t", 1));
+    /// for id in 0..3000u32 {
+    ///     let content = match id % 3 {
+    ///         0 => "raiden shogun",
+    ///         1 => "kamisato ayaka",
+    ///         _ => "raiden ayaka",
+    ///     };
+    ///     builder.add_document(Document { id, title: "", content, keywords: vec![], numbers: vec![], payload: None, key: None })?;
+    ///     // 分成几个segment，后面的segment删除和覆盖前面的文档
+    ///     if id % 1000 == 999 {
+    ///         builder.finish()?;
+    ///     }
+    /// }
+    /// builder.delete_document(0);
+    /// builder.update_document(Document { id: 3, title: "", content: "kamisato ayato", keywords: vec![], numbers: vec![], payload: None, key: None })?;
+    /// builder.finish()?;
+    ///
+    /// let query = Query::new(analyzer(), query::Config::new(directory, "t", 3, 1, query::Similarity::default()))?;
+    /// let scan = |sentence: &str, occur: Occur| -> query::Result<Vec<u32>> {
+    ///     query.scan(sentence, &|w| Fuzziness::Off.automaton(w), occur)?.collect()
+    /// };
+    ///
+    /// let raiden = scan("raiden", Occur::Must)?;
+    /// assert_eq!(raiden.len(), 1998);
+    /// assert_eq!(&raiden[..4], &[2, 5, 6, 8]);
+    /// assert!(raiden.windows(2).all(|w| w[0] < w[1]));
+    ///
+    /// // 和count、query的结果是同一批文档
+    /// for (sentence, occur) in [
+    ///     ("raiden ayaka", Occur::Must),
+    ///     ("raiden ayaka", Occur::Should),
+    ///     ("shogun ayaka -kamisato", Occur::Should),
+    ///     ("ayato", Occur::Must),
+    ///     ("nahida", Occur::Should),
+    /// ] {
+    ///     let ids = scan(sentence, occur)?;
+    ///     let mut expected = query
+    ///         .query(sentence, occur, &|w| Fuzziness::Off.automaton(w), 0..3000, &Default::default())?
+    ///         .ids();
+    ///     expected.sort();
+    ///     assert_eq!(ids, expected);
+    ///     assert_eq!(ids.len() as u32, query.count(sentence, &|w| Fuzziness::Off.automaton(w), occur)?);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn scan<A: fst::Automaton>(
+        &self,
+        sentence: &str,
+        aut_builder: &impl Fn(&str) -> Option<A>,
+        occur: Occur,
+    ) -> Result<Scan<'_>> {
+        let split = split_sentence(sentence);
+        let (query_terms, excluded_terms, _) = self.clause_terms(&split.clauses(occur))?;
+        let deadline = Deadline::default();
+
+        let mut required = Vec::<RawPostingList>::new();
+        let mut optional = Vec::<RawPostingList>::new();
+        for term in query_terms.iter() {
+            match self.query_term_postings(term.0.as_str(), aut_builder, &deadline)? {
+                None if term.1.is_required() => return Ok(Scan::empty()),
+                None => (),
+                Some(list) if term.1.is_required() => required.push(list),
+                Some(list) => optional.push(list),
+            }
+        }
+
+        // 有必须的词时可选的词不影响结果。小的list放前面，由它驱动intersection
+        if !required.is_empty() {
+            optional.clear();
+        }
+        required.sort_by_key(|list| list.len());
+
+        let mut excluded = Vec::new();
+        for term in excluded_terms.iter() {
+            if let Some(list) =
+                self.query_term_postings(term.as_str(), &|_| None::<A>, &deadline)?
+            {
+                excluded.push(list.into_cursor());
+            }
+        }
+
+        // 和posting_list_merger一样，只有一个segment时list里还有删除的doc
+        let tombstones = match self.segments.as_slice() {
+            [segment] => Some(&segment.tombstones).filter(|t| !t.is_empty()),
+            _ => None,
+        };
+
+        Ok(Scan {
+            required: required
+                .into_iter()
+                .map(RawPostingList::into_cursor)
+                .collect(),
+            optional: optional
+                .into_iter()
+                .map(RawPostingList::into_cursor)
+                .collect(),
+            excluded,
+            tombstones,
+            next: Some(0),
+        })
+    }
+
+    /// 统计命中的文档里keyword字段field每个值出现的次数，按次数从多到少排序，次数相同的按值排序。
+    /// 词的处理和count一样；没有这个字段的文档不计入。每篇命中的文档都要从文档存储里读一次
+    pub fn facet<A: fst::Automaton>(
+        &self,
+        sentence: &str,
+        aut_builder: &impl Fn(&str) -> Option<A>,
+        occur: Occur,
+        field: &str,
+    ) -> Result<Vec<(String, u32)>> {
+        let split = split_sentence(sentence);
+        let clauses = split.clauses(occur);
+
+        let merged =
+            match self.merge_clauses(&clauses, aut_builder, None, None, &Deadline::default())? {
+                Some(merged) => merged,
+                None => return Ok(Vec::new()),
+            };
+
+        let mut counts = HashMap::<String, u32>::new();
+        for p in merged.merger.get_postings().iter() {
+            let mut doc = self.get_document(p.get_doc_id())?;
+            if let Some(value) = doc.keywords.remove(field) {
+                *counts.entry(value).or_insert(0) += 1;
+            }
+        }
+
+        let mut counts = counts.into_iter().collect::<Vec<_>>();
+        counts.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+        Ok(counts)
+    }
+
+    /// 按keyword字段把命中的文档分组，类似Lucene的grouping/collapse，比如按来源去重。
+    /// 参数和query一样，range是组的范围而不是文档的，见GroupBy。组按各自排在最前面的文档排序。
+    /// 没有这个字段的文档不属于任何组，只计入total。
+    /// 要给所有命中的文档排序并且各读一次这个字段，top_k不起作用
+    pub fn group_by<A: fst::Automaton>(
+        &self,
+        sentence: &str,
+        occur: Occur,
+        aut_builder: &impl Fn(&str) -> Option<A>,
+        group: &GroupBy,
+        range: Range<usize>,
+        options: &QueryOptions,
+    ) -> Result<GroupedResults> {
+        let split = split_sentence(sentence);
+
+        let merged = match self.merge_clauses(
+            &split.clauses(occur),
+            aut_builder,
+            options.field,
+            options.min_should_match,
+            &options.deadline,
+        )? {
+            Some(merged) => merged,
+            None => return Ok(GroupedResults::default()),
+        };
+        let options = QueryOptions {
+            top_k: None,
+            ..options.clone()
+        };
+        let results = self.rank(merged, 0..usize::MAX, &options)?;
+
+        let mut groups = Vec::<Group>::new();
+        let mut index = HashMap::<String, usize>::new();
+        for (i, hit) in results.hits.into_iter().enumerate() {
+            options.deadline.check_every(i)?;
+            let value = match self.get_keyword(hit.doc_id, &group.field)? {
+                Some(value) => value,
+                None => continue,
+            };
+
+            let entry = match index.get(&value) {
+                Some(i) => &mut groups[*i],
+                None => {
+                    index.insert(value.clone(), groups.len());
+                    groups.push(Group {
+                        value,
+                        count: 0,
+                        hits: Vec::new(),
+                    });
+                    groups.last_mut().unwrap()
+                }
+            };
+            entry.count += 1;
+            if entry.hits.len() < group.size {
+                entry.hits.push(hit);
+            }
+        }
+
+        Ok(GroupedResults {
+            total: results.total,
+            total_groups: groups.len(),
+            groups: groups
+                .into_iter()
+                .skip(range.start)
+                .take(range.len())
+                .collect(),
+        })
+    }
+
+    /// 解释doc_id在这次查询中的分数：每个词的df、idf、这篇文档存的tf和字段长度、各字段的贡献，
+    /// 以及最后计算余弦相似度的各个部分。参数和query一样，文档没有命中时matched为false
+    pub fn explain<A: fst::Automaton>(
+        &self,
+        sentence: &str,
+        occur: Occur,
+        aut_builder: &impl Fn(&str) -> Option<A>,
+        doc_id: u32,
+    ) -> Result<Explanation> {
+        let split = split_sentence(sentence);
+        let clauses = split.clauses(occur);
+
+        let merged =
+            match self.merge_clauses(&clauses, aut_builder, None, None, &Deadline::default())? {
+                Some(merged) => merged,
+                None => return Ok(Explanation::not_matched(doc_id)),
+            };
+
+        let postings = merged.merger.get_postings();
+        let posting = postings
+            .binary_search_by_key(&doc_id, |p| p.get_doc_id())
+            .ok()
+            .map(|i| &postings[i]);
+
+        let calculator = &self.term_priority_calculator;
+        let mut terms = Vec::with_capacity(merged.terms.len());
+        let mut doc_score = Vec::with_capacity(merged.terms.len());
+
+        for (i, (term, occur)) in merged.terms.into_iter().enumerate() {
+            let info = posting.map_or_else(TermPriorityInfo::not_exist, |p| {
+                p.get_term_priority_info()[i].clone()
+            });
+            let df = merged.df[i];
+
+            let (field_scores, doc_weight) = if merged.scoring[i] {
+                (
+                    (
+                        calculator.calc(df, info.tf.0, 0, info.len.0, 0),
+                        calculator.calc(df, 0, info.tf.1, 0, info.len.1),
+                    ),
+                    calculator.calc(df, info.tf.0, info.tf.1, info.len.0, info.len.1),
+                )
+            } else {
+                ((0f64, 0f64), 0f64)
+            };
+            doc_score.push(doc_weight);
+
+            terms.push(TermExplanation {
+                term,
+                occur,
+                df,
+                idf: calculator.idf(df),
+                tf: info.tf,
+                len: info.len,
+                field_scores,
+                doc_weight,
+                query_weight: merged.query_score[i],
+            });
+        }
+
+        let dot_product = merged
+            .query_score
+            .iter()
+            .zip(doc_score.iter())
+            .map(|(a, b)| a * b)
+            .sum::<f64>();
+        let query_norm = merged.query_score.iter().map(|a| a * a).sum::<f64>().sqrt();
+        let doc_norm = doc_score.iter().map(|b| b * b).sum::<f64>().sqrt();
+
+        Ok(Explanation {
+            doc_id,
+            matched: posting.is_some(),
+            score: if posting.is_some() {
+                calculator.combine(&merged.query_score, &doc_score)
+            } else {
+                0f64
+            },
+            dot_product,
+            query_norm,
+            doc_norm,
+            terms,
+        })
+    }
+
+    /// 分析所有子句并合并posting list，同时算好打分需要的信息。
+    /// 有必须包含的词不在词典里时返回None，这时不会有任何结果
+    fn merge_clauses<A: fst::Automaton>(
+        &self,
+        clauses: &[Clause],
+        aut_builder: &impl Fn(&str) -> Option<A>,
+        field: Option<Field>,
+        min_should_match: Option<MinShouldMatch>,
+        deadline: &Deadline,
+    ) -> Result<Option<MergedPostings<'_>>> {
+        let (query_terms, excluded_terms, query_len) = self.clause_terms(clauses)?;
+
+        // 空的或者全是停用词的查询没有可以匹配的词，不需要再查posting list
+        if query_terms.is_empty() {
+            return Ok(None);
+        }
+
+        let mut required = Vec::<(usize, RawPostingList)>::new();
+        let mut optional = Vec::<(usize, RawPostingList)>::new();
+
+        for (i, term) in query_terms.iter().enumerate() {
+            deadline.check()?;
+            match self.query_term_postings(term.0.as_str(), aut_builder, deadline)? {
+                None if term.1.is_required() => return Ok(None),
+                None => (),
+                Some(v) if term.1.is_required() => required.push((i, v)),
+                Some(v) => optional.push((i, v)),
+            }
+        }
+
+        // 小的posting list放前面，intersection时由小的驱动
+        required.sort_by_key(|p| p.1.len());
+        optional.sort_by_key(|p| p.1.len());
+
+        let mut df = Vec::<u32>::with_capacity(query_terms.len());
+        let mut scoring = Vec::<bool>::with_capacity(query_terms.len());
+        let mut query_score = Vec::<f64>::with_capacity(query_terms.len());
+        let mut terms = Vec::<(String, Occur)>::with_capacity(query_terms.len());
+        let mut merger = self.posting_list_merger(field);
+
+        let query_len = query_len.min(u16::MAX as usize) as u16;
+
+        for (n, p) in required.iter().chain(optional.iter()).enumerate() {
+            let (term, list) = (&query_terms[p.0], &p.1);
+            deadline.check()?;
+
+            if n == 0 || required.is_empty() {
+                merger.union(list)?;
+            } else if n < required.len() {
+                merger.intersection(list)?;
+            } else {
+                merger.attach(list)?;
+            }
+
+            query_score.push(if term.1.is_scoring() {
+                self.query_term_score(list.len(), term.2, field, query_len) * term.3
+            } else {
+                0f64
+            });
+            scoring.push(term.1.is_scoring());
+            df.push(list.len());
+            terms.push((term.0.clone(), term.1));
+        }
+
+        // 在打分之前按命中的Should词数过滤，posting里没命中的词是not_exist
+        if let Some(min_should_match) = min_should_match {
+            let should_num = query_terms.iter().filter(|t| t.1 == Occur::Should).count();
+            let threshold = min_should_match.threshold(should_num);
+
+            if threshold > 0 {
+                merger.mut_get_postings().retain(|p| {
+                    let infos = p.get_term_priority_info();
+                    let matched = terms
+                        .iter()
+                        .zip(infos.iter())
+                        .filter(|(t, info)| t.1 == Occur::Should && info.tf != (0, 0))
+                        .count();
+                    matched >= threshold
+                });
+            }
+        }
+
+        for term in excluded_terms.iter() {
+            deadline.check()?;
+            if let Some(list) = self.query_term_postings(term.as_str(), &|_| None::<A>, deadline)? {
+                merger.difference(&list)?;
+            }
+        }
+
+        Ok(Some(MergedPostings {
+            merger,
+            terms,
+            df,
+            scoring,
+            query_score,
+        }))
+    }
+
+    /// 分析每个子句，返回(query_terms, 排除的词, 不排除的子句的总字符数)。
+    /// query_terms是(term, occur, 在查询中出现的次数, boost)，同一个词出现在多个子句里时合并occur，boost取最大的。
+    /// 只有排除的词时返回Error::OnlyExcluded
+    fn clause_terms(&self, clauses: &[Clause]) -> Result<(QueryTerms, Vec<String>, usize)> {
+        let mut query_terms = QueryTerms::new();
+        let mut term_index = HashMap::<String, usize>::new();
+        let mut excluded_terms = Vec::<String>::new();
+        let mut query_len = 0usize;
+
+        for clause in clauses {
+            if clause.occur == Occur::MustNot {
+                excluded_terms.extend(self.analyzer.analyze(clause.text)?);
+                continue;
+            }
+
+            query_len += clause.text.chars().count();
+
+            for word in self.analyzer.analyze(clause.text)? {
+                match term_index.get(&word) {
+                    None => {
+                        term_index.insert(word.clone(), query_terms.len());
+                        query_terms.push((word, clause.occur, 1, clause.boost));
+                    }
+                    Some(i) => {
+                        let term = &mut query_terms[*i];
+                        term.1 = term.1.merge(clause.occur);
+                        term.2 = term.2.saturating_add(1);
+                        term.3 = term.3.max(clause.boost);
+                    }
+                }
+            }
+        }
+
+        debug!(terms = ?query_terms, excluded = ?excluded_terms, "query terms");
+
+        if query_terms.is_empty() && !excluded_terms.is_empty() {
+            return Err(Error::OnlyExcluded);
+        }
+
+        Ok((query_terms, excluded_terms, query_len))
+    }
+
+    /// 合并parsed_query的一个组，和merge_clauses的组合方式一样，嵌套的组先递归合并成一个merger。
+    /// exact时所有词都精确匹配（在排除的组里）。组里没有不排除的条件时返回None，上一层当作没有这个组；
+    /// 必须包含的条件没有命中任何文档时返回空的结果
+    fn merge_group<A: fst::Automaton>(
+        &self,
+        clauses: &[(Occur, QueryNode)],
+        aut_builder: &impl Fn(&str) -> Option<A>,
+        exact: bool,
+        field: Option<Field>,
+        query_len: u16,
+        deadline: &Deadline,
+    ) -> Result<Option<MergedPostings<'_>>> {
+        let no_match = || Ok(Some(MergedPostings::new(self.posting_list_merger(field))));
+        let aut = |w: &str| if exact { None } else { aut_builder(w) };
+
+        // 在必须满足的位置上只有排除条件的组，相当于把这些排除条件放在这一层
+        let mut flat = Vec::with_capacity(clauses.len());
+        for clause in clauses.iter() {
+            match clause {
+                (occur, QueryNode::Group(group))
+                    if occur.is_required()
+                        && !group.is_empty()
+                        && group.iter().all(|c| c.0 == Occur::MustNot) =>
+                {
+                    flat.extend(group.iter())
+                }
+                _ => flat.push(clause),
+            }
+        }
+
+        let mut required = Vec::<GroupPart>::new();
+        let mut optional = Vec::<GroupPart>::new();
+        let mut excluded = Vec::<GroupPart>::new();
+
+        for (occur, node) in flat {
+            deadline.check()?;
+
+            let occur = *occur;
+            let part = match node {
+                QueryNode::Text { text, boost } => {
+                    let mut words = Vec::<(String, u16)>::new();
+                    for word in self.analyzer.analyze(text)? {
+                        match words.iter_mut().find(|w| w.0 == word) {
+                            Some(w) => w.1 = w.1.saturating_add(1),
+                            None => words.push((word, 1)),
+                        }
+                    }
+
+                    for (word, count) in words {
+                        let list = match occur {
+                            Occur::MustNot => {
+                                self.query_term_postings(&word, &|_| None::<A>, deadline)?
+                            }
+                            _ => self.query_term_postings(&word, &aut, deadline)?,
+                        };
+
+                        match list {
+                            None if occur.is_required() => return no_match(),
+                            None => (),
+                            Some(list) => {
+                                let part = GroupPart::Term(list, (word, occur, count, *boost));
+                                match occur {
+                                    Occur::MustNot => excluded.push(part),
+                                    _ if occur.is_required() => required.push(part),
+                                    _ => optional.push(part),
+                                }
+                            }
+                        }
+                    }
+                    continue;
+                }
+                _ => {
+                    let exact = exact || occur == Occur::MustNot;
+                    let merged = match node {
+                        QueryNode::Phrase { text, boost } => {
+                            let clauses = [(Occur::Must, QueryNode::text(text, *boost))];
+                            let mut merged = self.merge_group(
+                                &clauses,
+                                aut_builder,
+                                true,
+                                field,
+                                query_len,
+                                deadline,
+                            )?;
+                            if let Some(merged) = merged.as_mut() {
+                                let phrase = self.analyzer.analyze(text)?;
+                                self.retain_phrase(
+                                    &mut merged.merger,
+                                    &phrase,
+                                    0,
+                                    field,
+                                    deadline,
+                                )?;
+                            }
+                            merged
+                        }
+                        // 和Group一样合并成一个merger，只是里面的条件都只看这个字段
+                        QueryNode::Field(field, node) => {
+                            let clauses = [(Occur::Must, (**node).clone())];
+                            let field = Some(*field);
+                            self.merge_group(
+                                &clauses,
+                                aut_builder,
+                                exact,
+                                field,
+                                query_len,
+                                deadline,
+                            )?
+                        }
+                        QueryNode::Group(group) => {
+                            self.merge_group(group, aut_builder, exact, field, query_len, deadline)?
+                        }
+                        QueryNode::Text { .. } => unreachable!(),
+                    };
+
+                    match merged {
+                        None => continue,
+                        Some(merged) if merged.merger.len() == 0 && occur.is_required() => {
+                            return no_match()
+                        }
+                        Some(mut merged) => {
+                            if occur == Occur::Filter {
+                                merged.scoring.iter_mut().for_each(|s| *s = false);
+                                merged.query_score.iter_mut().for_each(|s| *s = 0f64);
+                            }
+                            GroupPart::Group(merged)
+                        }
+                    }
+                }
+            };
+
+            match occur {
+                Occur::MustNot => excluded.push(part),
+                _ if occur.is_required() => required.push(part),
+                _ => optional.push(part),
+            }
+        }
+
+        if required.is_empty() && optional.is_empty() {
+            return Ok(None);
+        }
+
+        // 小的放前面，intersection时由小的驱动
+        required.sort_by_key(GroupPart::len);
+        optional.sort_by_key(GroupPart::len);
+
+        let required_num = required.len();
+        let mut merged = MergedPostings::new(self.posting_list_merger(field));
+
+        for (n, part) in required.into_iter().chain(optional).enumerate() {
+            deadline.check()?;
+
+            let (union, intersection) = (n == 0 || required_num == 0, n < required_num);
+            match part {
+                GroupPart::Term(list, (word, occur, count, boost)) => {
+                    if union {
+                        merged.merger.union(&list)?;
+                    } else if intersection {
+                        merged.merger.intersection(&list)?;
+                    } else {
+                        merged.merger.attach(&list)?;
+                    }
+
+                    merged.query_score.push(if occur.is_scoring() {
+                        self.query_term_score(list.len(), count, field, query_len) * boost
+                    } else {
+                        0f64
+                    });
+                    merged.scoring.push(occur.is_scoring());
+                    merged.df.push(list.len());
+                    merged.terms.push((word, occur));
+                }
+                GroupPart::Group(group) => {
+                    if union {
+                        merged.merger.union_merger(group.merger);
+                    } else if intersection {
+                        merged.merger.intersection_merger(group.merger);
+                    } else {
+                        merged.merger.attach_merger(group.merger);
+                    }
+
+                    merged.query_score.extend(group.query_score);
+                    merged.scoring.extend(group.scoring);
+                    merged.df.extend(group.df);
+                    merged.terms.extend(group.terms);
+                }
+            }
+        }
+
+        for part in excluded.iter() {
+            deadline.check()?;
+            match part {
+                GroupPart::Term(list, _) => merged.merger.difference(list)?,
+                GroupPart::Group(group) => merged.merger.difference_merger(&group.merger),
+            }
+        }
+
+        Ok(Some(merged))
+    }
+
+    /// 查询中出现count次的词在query向量中的分量（还没有乘boost），field不是None时另一个字段的tf当作0
+    fn query_term_score(&self, df: u32, count: u16, field: Option<Field>, query_len: u16) -> f64 {
+        let tf = calc_tf(count);
+        let tf = match field {
+            None => (tf, tf),
+            Some(Field::Title) => (tf, 0),
+            Some(Field::Content) => (0, tf),
+        };
+
+        self.term_priority_calculator
+            .calc(df, tf.0, tf.1, query_len, query_len)
+    }
+}
+
+/// (term, occur, 在查询中出现的次数, boost)
+type QueryTerms = Vec<(String, Occur, u16, f64)>;
+
+/// parsed_query里一个组的一个条件：一个词的posting list和(词, occur, 在这个词里出现的次数, boost)，
+/// 或者一个嵌套的组合并出来的结果
+enum GroupPart<'t> {
+    Term(RawPostingList<'t>, (String, Occur, u16, f64)),
+    Group(MergedPostings<'t>),
+}
+
+impl GroupPart<'_> {
+    fn len(&self) -> u32 {
+        match self {
+            GroupPart::Term(list, _) => list.len(),
+            GroupPart::Group(merged) => merged.merger.len(),
+        }
+    }
+}
+
+/// 合并后的posting和打分需要的信息，和每个posting里的词一一对应
+struct MergedPostings<'t> {
+    merger: PostingListMerger<'t>,
+    /// 合并的顺序和query_terms不同，explain时用来知道每个位置是哪个词
+    terms: Vec<(String, Occur)>,
+    df: Vec<u32>,
+    /// 这个词是否参与打分，Filter的词不参与
+    scoring: Vec<bool>,
+    query_score: Vec<f64>,
+}
+
+impl<'t> MergedPostings<'t> {
+    fn new(merger: PostingListMerger<'t>) -> Self {
+        MergedPostings {
+            merger,
+            terms: Vec::new(),
+            df: Vec::new(),
+            scoring: Vec::new(),
+            query_score: Vec::new(),
+        }
+    }
+}
+
+/// parsed_query里不排除的词的总字符数，和merge_clauses里的query_len一样用来算query向量
+fn included_len(clauses: &[(Occur, QueryNode)]) -> usize {
+    fn node_len(node: &QueryNode) -> usize {
+        match node {
+            QueryNode::Text { text, .. } | QueryNode::Phrase { text, .. } => text.chars().count(),
+            QueryNode::Group(group) => included_len(group),
+            QueryNode::Field(_, node) => node_len(node),
+        }
+    }
+
+    clauses
+        .iter()
+        .filter(|c| c.0 != Occur::MustNot)
+        .map(|c| node_len(&c.1))
+        .sum()
+}
+
+/// 树里任何地方有排除的条件
+fn has_excluded(clauses: &[(Occur, QueryNode)]) -> bool {
+    fn node_has_excluded(node: &QueryNode) -> bool {
+        match node {
+            QueryNode::Text { .. } | QueryNode::Phrase { .. } => false,
+            QueryNode::Group(group) => has_excluded(group),
+            QueryNode::Field(_, node) => node_has_excluded(node),
+        }
+    }
+
+    clauses
+        .iter()
+        .any(|c| c.0 == Occur::MustNot || node_has_excluded(&c.1))
+}
+
+/// positions里按短语的顺序是每个词在这篇文档里的位置，field取出其中一个字段的。
+/// 短语的第一个词从每个可能的位置开始，后面的词依次往后找，多出来的token总数不超过slop
+fn match_phrase(
+    positions: &[(Vec<u32>, Vec<u32>)],
+    field: impl Fn(&(Vec<u32>, Vec<u32>)) -> &Vec<u32>,
+    slop: u32,
+) -> bool {
+    fn match_from(lists: &[&Vec<u32>], prev: u32, slop: u32) -> bool {
+        let (list, rest) = match lists.split_first() {
+            Some(v) => v,
+            None => return true,
+        };
+
+        let start = list.partition_point(|p| *p <= prev);
+        list[start..]
+            .iter()
+            .take_while(|p| **p - prev - 1 <= slop)
+            .any(|p| match_from(rest, *p, slop - (*p - prev - 1)))
+    }
+
+    let lists = positions.iter().map(field).collect::<Vec<_>>();
+    let (first, rest) = match lists.split_first() {
+        Some(v) => v,
+        None => return false,
+    };
+
+    first.iter().any(|p| match_from(rest, *p, slop))
+}
+
+/// 匹配到多个词（或者多个segment里的同一个词）时合并成一个list，当作同一个词打分。
+/// 展开的词很多时合并很慢，每合并一个list检查一次deadline
+fn union_lists<'a>(
+    mut lists: Vec<(RawPostingList<'a>, Option<&Tombstones>)>,
+    deadline: &Deadline,
+) -> Result<Option<RawPostingList<'a>>> {
+    match lists.len() {
+        0 => Ok(None),
+        1 if lists[0].1.is_none() => Ok(lists.pop().map(|l| l.0)),
+        _ => RawPostingList::union_all_with(&lists, || deadline.check()),
+    }
+}
+
+// 持有锁的时候只是读写HashMap，不会panic，锁被污染时数据也是完整的
+fn lock_cache(cache: &Mutex<QueryCache>) -> std::sync::MutexGuard<'_, QueryCache> {
+    cache.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// query的输入按空格拆开后的结果：普通的词合成一个子句，带boost的词各自一个子句
+struct SplitSentence<'s> {
+    included: String,
+    boosted: Vec<(&'s str, f64)>,
+    excluded: String,
+}
+
+impl<'s> SplitSentence<'s> {
+    fn clauses(&self, occur: Occur) -> Vec<Clause<'_>> {
+        let mut clauses = Vec::with_capacity(self.boosted.len() + 2);
+        clauses.push(Clause::new(occur, self.included.as_str()));
+        for (word, boost) in self.boosted.iter() {
+            clauses.push(Clause::new(occur, word).with_boost(*boost));
+        }
+        clauses.push(Clause::new(Occur::MustNot, self.excluded.as_str()));
+        clauses
+    }
+
+    /// 所有不排除的词，去掉了boost
+    fn included_text(&self) -> String {
+        let mut text = self.included.clone();
+        for (word, _) in self.boosted.iter() {
+            text.push(' ');
+            text.push_str(word);
+        }
+        text
+    }
+}
+
+/// -开头的是排除的词，^n结尾的是带boost的词。n不是非负的有限数字时整个当作普通的词
+fn split_sentence(sentence: &str) -> SplitSentence<'_> {
+    let (mut included, mut boosted, mut excluded) = (Vec::new(), Vec::new(), Vec::new());
+
+    for word in sentence.split_whitespace() {
+        if let Some(w) = word.strip_prefix('-').filter(|w| !w.is_empty()) {
+            excluded.push(w);
+            continue;
+        }
+
+        match split_boost(word) {
+            Some(boosted_word) => boosted.push(boosted_word),
+            None => included.push(word),
+        }
+    }
+
+    SplitSentence {
+        included: included.join(" "),
+        boosted,
+        excluded: excluded.join(" "),
+    }
+}
+
+/// Query::scan的结果，按doc id从小到大返回命中的文档
+#[derive(Debug)]
+pub struct Scan<'q> {
+    /// 都要包含，按list长度从小到大
+    required: Vec<PostingCursor<'q>>,
+    /// 没有必须的词时包含任意一个就行
+    optional: Vec<PostingCursor<'q>>,
+    excluded: Vec<PostingCursor<'q>>,
+    tombstones: Option<&'q Tombstones>,
+    /// 下一个要找的doc id至少是多少，None表示已经结束了
+    next: Option<u32>,
+}
+
+impl Scan<'_> {
+    fn empty() -> Self {
+        Scan {
+            required: Vec::new(),
+            optional: Vec::new(),
+            excluded: Vec::new(),
+            tombstones: None,
+            next: None,
+        }
+    }
+
+    /// 不考虑删除和排除的词，下一个>=target的命中的doc
+    fn next_candidate(&mut self, mut target: u32) -> Result<Option<u32>> {
+        if let Some((first, rest)) = self.required.split_first_mut() {
+            // 轮流在每个list里跳到target，有list跳过了target就从它的位置重新开始，直到所有list停在同一个doc
+            'candidate: loop {
+                target = match first.advance_to(target)? {
+                    Some(p) => p.doc_id,
+                    None => return Ok(None),
+                };
+
+                for cursor in rest.iter_mut() {
+                    match cursor.advance_to(target)? {
+                        Some(p) if p.doc_id > target => {
+                            target = p.doc_id;
+                            continue 'candidate;
+                        }
+                        Some(_) => (),
+                        None => return Ok(None),
+                    }
+                }
+
+                return Ok(Some(target));
+            }
+        }
+
+        let mut min = None::<u32>;
+        for cursor in self.optional.iter_mut() {
+            if let Some(p) = cursor.advance_to(target)? {
+                min = Some(min.map_or(p.doc_id, |m| m.min(p.doc_id)));
+            }
+        }
+
+        Ok(min)
+    }
+
+    fn is_excluded(&mut self, doc_id: u32) -> Result<bool> {
+        if self.tombstones.is_some_and(|t| t.contains(doc_id)) {
+            return Ok(true);
+        }
+
+        for cursor in self.excluded.iter_mut() {
+            if matches!(cursor.advance_to(doc_id)?, Some(p) if p.doc_id == doc_id) {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    fn advance(&mut self, target: u32) -> Result<Option<u32>> {
+        let mut target = target;
+
+        while let Some(doc_id) = self.next_candidate(target)? {
+            if !self.is_excluded(doc_id)? {
+                return Ok(Some(doc_id));
+            }
+
+            target = match doc_id.checked_add(1) {
+                Some(target) => target,
+                None => return Ok(None),
+            };
+        }
+
+        Ok(None)
+    }
+}
+
+impl Iterator for Scan<'_> {
+    type Item = Result<u32>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let target = self.next?;
+
+        match self.advance(target) {
+            Ok(Some(doc_id)) => {
+                self.next = doc_id.checked_add(1);
+                Some(Ok(doc_id))
+            }
+            Ok(None) => {
+                self.next = None;
+                None
+            }
+            Err(e) => {
+                self.next = None;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// 一次查询的结果
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct SearchResults {
+    /// 满足查询条件（包括排除的词、min_should_match和短语）的文档总数，不受range和top_k影响
+    pub total: u32,
+    /// range范围内的结果，按分数从高到低
+    pub hits: Vec<SearchHit>,
+}
+
+impl SearchResults {
+    pub fn ids(&self) -> Vec<u32> {
+        self.hits.iter().map(|h| h.doc_id).collect()
+    }
+}
+
+/// Query::group_by怎么分组
+#[derive(Debug, Clone, PartialEq)]
+pub struct GroupBy {
+    /// 按这个keyword字段的值分组
+    pub field: String,
+    /// 每组最多返回几篇文档，0表示只统计数量
+    pub size: usize,
+}
+
+impl GroupBy {
+    /// 每组只返回排在最前面的一篇
+    pub fn new(field: &str) -> Self {
+        GroupBy {
+            field: field.to_string(),
+            size: 1,
+        }
+    }
+}
+
+/// Query::group_by的结果
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct GroupedResults {
+    /// 命中的文档总数，包括没有分组字段的
+    pub total: u32,
+    /// 组的总数，不受range影响
+    pub total_groups: usize,
+    /// range范围内的组
+    pub groups: Vec<Group>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Group {
+    /// 分组字段的值
+    pub value: String,
+    /// 这组命中的文档数，不受group_size影响
+    pub count: u32,
+    /// 这组排在最前面的最多GroupBy::size篇文档
+    pub hits: Vec<SearchHit>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SearchHit {
+    pub doc_id: u32,
+    /// 相关性算法combine的结果，constant_score时为1，normalize_scores时除以了最高分
+    pub score: f64,
+    /// 内容的摘要，查询时不会生成，调用Query::highlight_hits之后才有
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snippet: Option<String>,
+}
+
+/// Query::explain的结果。分数是query向量和doc向量的余弦相似度：
+/// score = dot_product / (query_norm * doc_norm)
+#[derive(Debug, Serialize)]
+pub struct Explanation {
+    pub doc_id: u32,
+    /// 文档是否在结果中：满足所有必须的条件、不包含排除的词并且没有被删除
+    pub matched: bool,
+    /// 由相关性算法的combine计算，下面三项是余弦相似度的分量，自定义combine时仅供参考
+    pub score: f64,
+    pub dot_product: f64,
+    pub query_norm: f64,
+    pub doc_norm: f64,
+    /// 按合并posting list的顺序，不在词典里的词不会出现
+    pub terms: Vec<TermExplanation>,
+}
+
+impl Explanation {
+    fn not_matched(doc_id: u32) -> Self {
+        Explanation {
+            doc_id,
+            matched: false,
+            score: 0f64,
+            dot_product: 0f64,
+            query_norm: 0f64,
+            doc_norm: 0f64,
+            terms: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct TermExplanation {
+    pub term: String,
+    pub occur: Occur,
+    pub df: u32,
+    pub idf: f64,
+    /// (tf_title, tf_content)，索引里存的量化后的值
+    pub tf: (u8, u8),
+    /// (len_title, len_content)，索引里存的字段长度
+    pub len: (u16, u16),
+    /// (title, content)各自对doc_weight的贡献，已经乘过idf和boost
+    pub field_scores: (f64, f64),
+    /// doc向量中这个词的分量
+    pub doc_weight: f64,
+    /// query向量中这个词的分量，已经乘过Clause::boost，Filter的词为0
+    pub query_weight: f64,
+}
+
+/// Query::terms返回的迭代器，每次返回(词, df)。
+/// df是各个segment里posting list的长度之和，包括已经删除和被覆盖但还没有合并掉的文档
+pub struct Terms<'q> {
+    stream: fst::map::Union<'q>,
+    segments: &'q [SegmentReader],
+}
+
+impl Iterator for Terms<'_> {
+    type Item = (String, u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let segments = self.segments;
+        let (term, values) = self.stream.next()?;
+
+        // 只读posting list的header，文件损坏读不出来时当作0
+        let df = values
+            .iter()
+            .map(|v| {
+                segments[v.index]
+                    .posting_list(v.value)
+                    .map_or(0, |list| list.len() as u64)
+            })
+            .sum();
+
+        Some((String::from_utf8_lossy(term).into_owned(), df))
+    }
+}
+
+/// Query::check_health的结果
+#[derive(Debug, Serialize)]
+pub struct IndexHealth {
+    pub segments: usize,
+    /// 和Query::doc_num一样
+    pub doc_num: u32,
+    /// 各个segment词典里的词数之和，同一个词在多个segment里会重复计算
+    pub terms: usize,
+}
+
+/// Query::segments的结果
+#[derive(Debug, Serialize)]
+pub struct SegmentInfo {
+    pub id: u32,
+    /// 写这个segment时的文档数，包括之后删除和被覆盖的
+    pub doc_num: u32,
+    /// 已经删除或者被新segment覆盖的文档数
+    pub deleted_doc_num: u32,
+    /// (title平均长度, content平均长度)
+    pub avg_len: (f64, f64),
+    pub terms: usize,
+    /// 有没有数值字段和外部id的索引，旧版本建的segment没有
+    pub numeric_index: bool,
+    pub key_index: bool,
+}
+
+/// Query::postings的结果，一个segment里的一个posting
+#[derive(Debug, Serialize)]
+pub struct TermPosting {
+    pub segment: u32,
+    pub doc_id: u32,
+    /// (tf_title, tf_content)
+    pub tf: (u8, u8),
+    /// (len_title, len_content)，计算norm用的长度
+    pub len: (u16, u16),
+    pub title_positions: Vec<u32>,
+    pub content_positions: Vec<u32>,
+    /// 在这个segment的tombstone里，查询时不会返回
+    pub deleted: bool,
+}
+
+/// Query::stats的结果，文件大小都是所有segment之和
+#[derive(Debug, Serialize)]
+pub struct Stats {
+    pub segments: usize,
+    /// 能查到的文档数
+    pub doc_num: u32,
+    /// 已经删除或者被新segment覆盖、但还没有合并掉的文档数
+    pub deleted_doc_num: u32,
+    /// 同一个词在多个segment里会重复计算
+    pub terms: usize,
+    /// 词典（fst）的字节数
+    pub index_size: u64,
+    /// posting list文件的字节数
+    pub dict_size: u64,
+    /// 没有开启缓存时是None
+    pub cache: Option<CacheStats>,
+}
+
+/// 分析后的查询词在词典里不存在时的诊断信息
+#[derive(Debug, Serialize)]
+pub struct MissingTerm {
+    pub term: String,
+    /// 词典中编辑距离最近的词，先找距离1的，没有再找距离2的
+    pub suggestions: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyzer::char_filter::CJKDocCharFilter;
+    use crate::analyzer::token_filter::BasicTokenFilter;
+    use crate::analyzer::tokenizer::UnicodeWordTokenizer;
+    use crate::store::{self, Builder, Document};
+
+    type TestAnalyzer = Analyzer<CJKDocCharFilter, BasicTokenFilter, UnicodeWordTokenizer>;
+
+    fn analyzer() -> TestAnalyzer {
+        Analyzer::new(
+            CJKDocCharFilter::new(),
+            BasicTokenFilter::new(),
+            UnicodeWordTokenizer::new(),
+        )
+    }
+
+    fn builder(
+        directory: &Directory,
+    ) -> Builder<
+        CJKDocCharFilter,
+        BasicTokenFilter,
+        UnicodeWordTokenizer,
+        CJKDocCharFilter,
+        BasicTokenFilter,
+        UnicodeWordTokenizer,
+    > {
+        Builder::new(
+            analyzer(),
+            analyzer(),
+            store::Config::new(directory.clone(), "t", 1),
+        )
+    }
+
+    fn doc<'a>(id: u32, title: &'a str, content: &'a str) -> Document<'a> {
+        Document {
+            id,
+            title,
+            content,
+            keywords: vec![],
+            numbers: vec![],
+            payload: None,
+            key: None,
+        }
+    }
+
+    fn open(
+        directory: &Directory,
+    ) -> Query<CJKDocCharFilter, BasicTokenFilter, UnicodeWordTokenizer> {
+        let config = Config::new(directory.clone(), "t", 3, 1, Similarity::default());
+        Query::new(analyzer(), config).unwrap()
+    }
+
+    fn search_with(
+        query: &Query<CJKDocCharFilter, BasicTokenFilter, UnicodeWordTokenizer>,
+        sentence: &str,
+        range: Range<usize>,
+        options: &QueryOptions,
+    ) -> SearchResults {
+        let exact = &|_: &str| None::<Levenshtein>;
+        query
+            .query(sentence, Occur::Should, exact, range, options)
+            .unwrap()
+    }
+
+    fn search(
+        query: &Query<CJKDocCharFilter, BasicTokenFilter, UnicodeWordTokenizer>,
+        sentence: &str,
+        range: Range<usize>,
+    ) -> SearchResults {
+        search_with(query, sentence, range, &QueryOptions::default())
+    }
+
+    #[test]
+    fn get_documents_keeps_input_order_across_segments() {
+        let directory = Directory::memory();
+        let mut builder = builder(&directory);
+        for id in 0..4 {
+            builder.add_document(doc(id, "old", "raiden")).unwrap();
+        }
+        builder.finish().unwrap();
+        for id in 4..8 {
+            builder.add_document(doc(id, "new", "raiden")).unwrap();
+        }
+        // 覆盖第一个segment里的2，删除3
+        builder.add_document(doc(2, "updated", "raiden")).unwrap();
+        builder.delete_document(3);
+        builder.finish().unwrap();
+        builder.add_document(doc(8, "last", "raiden")).unwrap();
+        builder.finish().unwrap();
+
+        let query = open(&directory);
+        let docs = query.get_documents(&[8, 0, 2, 5, 0, 7]).unwrap();
+        let docs = docs
+            .iter()
+            .map(|d| (d.id, d.title.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            docs,
+            [
+                (8, "last"),
+                (0, "old"),
+                (2, "updated"),
+                (5, "new"),
+                (0, "old"),
+                (7, "new")
+            ]
+        );
+
+        assert!(query.get_documents(&[]).unwrap().is_empty());
+        assert!(matches!(
+            query.get_documents(&[1, 3]),
+            Err(Error::DocumentNotFound(3))
+        ));
+        assert!(matches!(
+            query.get_documents(&[100]),
+            Err(Error::DocumentNotFound(100))
+        ));
+    }
+
+    #[test]
+    fn compact_drops_deleted_documents() {
+        let directory = Directory::memory();
+        let mut builder = builder(&directory);
+        for id in 0..20 {
+            builder
+                .add_document(doc(id, "old", "raiden shogun"))
+                .unwrap();
+        }
+        builder.finish().unwrap();
+        for id in 0..15 {
+            builder.delete_document(id);
+        }
+        builder
+            .add_document(doc(19, "new", "raiden shogun"))
+            .unwrap();
+        builder.finish().unwrap();
+
+        let stats = builder.compact().unwrap();
+        assert_eq!(stats.segments, 2);
+        assert!(stats.bytes_after < stats.bytes_before);
+        assert_eq!(stats.reclaimed(), stats.bytes_before - stats.bytes_after);
+
+        let query = open(&directory);
+        let segments = query.segments();
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].doc_num, 5);
+        assert_eq!(segments[0].deleted_doc_num, 0);
+        let docs = query.get_documents(&[19, 15]).unwrap();
+        assert_eq!(docs[0].title, "new");
+        assert_eq!(docs[1].title, "old");
+        assert!(query.get_documents(&[0]).is_err());
+
+        // 已经是一个segment、没有删除时什么都省不下
+        assert_eq!(builder.compact().unwrap().reclaimed(), 0);
+    }
+
+    #[test]
+    fn normalize_scores_divides_by_top_score() {
+        let directory = Directory::memory();
+        let mut builder = builder(&directory);
+        builder
+            .add_document(doc(0, "raiden", "raiden shogun inazuma"))
+            .unwrap();
+        builder
+            .add_document(doc(1, "", "raiden raiden raiden shogun ei"))
+            .unwrap();
+        builder
+            .add_document(doc(2, "", "shogun of inazuma and other words"))
+            .unwrap();
+        builder.add_document(doc(3, "", "unrelated")).unwrap();
+        builder.finish().unwrap();
+        let query = open(&directory);
+
+        let raw = search(&query, "raiden shogun", 0..10);
+        assert_eq!(raw.hits.len(), 3);
+        // 默认不归一化，分数就是combine的结果
+        assert_eq!(search(&query, "raiden shogun", 0..10), raw);
+        assert!(raw.hits[0].score < 1f64);
+
+        let options = QueryOptions {
+            normalize_scores: true,
+            ..QueryOptions::default()
+        };
+        let normalized = search_with(&query, "raiden shogun", 0..10, &options);
+        assert_eq!(normalized.total, raw.total);
+        assert_eq!(normalized.ids(), raw.ids());
+        assert_eq!(normalized.hits[0].score, 1f64);
+        let top = raw.hits[0].score;
+        for (n, r) in normalized.hits.iter().zip(&raw.hits) {
+            assert!((n.score - r.score / top).abs() < 1e-12);
+            assert!(n.score > 0f64 && n.score <= 1f64);
+        }
+
+        // 第二页也是除以所有结果里的最高分，不是这一页的
+        let page = search_with(&query, "raiden shogun", 1..3, &options);
+        assert_eq!(page.hits, normalized.hits[1..]);
+    }
+
+    #[test]
+    fn highlight_compares_analyzed_tokens() {
+        use crate::analyzer::char_filter::LowercaseCharFilter;
+        use crate::analyzer::token_filter::WidthFoldingTokenFilter;
+
+        let directory = Directory::memory();
+        let mut builder = builder(&directory);
+        let content = "Genshin Impact: start the ＡＲＴ of art";
+        builder.add_document(doc(0, "", content)).unwrap();
+        builder.finish().unwrap();
+
+        let analyzer = Analyzer::new(
+            LowercaseCharFilter::new(),
+            WidthFoldingTokenFilter::new(BasicTokenFilter::new()),
+            UnicodeWordTokenizer::new(),
+        );
+        let config = Config::new(directory.clone(), "t", 3, 1, Similarity::default());
+        let query = Query::new(analyzer, config).unwrap();
+
+        // 大小写和全角半角按分析之后的词比较，"start"里的"art"不是一个词
+        assert_eq!(
+            query.highlight(0, "GENSHIN art", 100).unwrap(),
+            "<em>Genshin</em> Impact: start the <em>ＡＲＴ</em> of <em>art</em>"
+        );
+        assert_eq!(query.highlight(0, "sta", 100).unwrap(), content);
+    }
+
+    #[test]
+    fn snippets_escape_html_and_fill_hits() {
+        let directory = Directory::memory();
+        let mut builder = builder(&directory);
+        let content = "<script>alert('raiden')</script> raiden & shogun";
+        builder.add_document(doc(0, "", content)).unwrap();
+        builder.add_document(doc(1, "", "raiden \"ei\"")).unwrap();
+        builder.finish().unwrap();
+        let query = open(&directory);
+
+        assert_eq!(
+            query.highlight(0, "raiden", 100).unwrap(),
+            "&lt;script&gt;alert(&#39;<em>raiden</em>&#39;)&lt;/script&gt; <em>raiden</em> &amp; shogun"
+        );
+        // 不是html的标签可以不转义
+        let tags = HighlightTags {
+            pre: "[",
+            post: "]",
+            escape_html: false,
+        };
+        assert_eq!(
+            query.highlight_with_tags(0, "shogun", 100, tags).unwrap(),
+            "<script>alert('raiden')</script> raiden & [shogun]"
+        );
+
+        let mut results = search(&query, "raiden", 0..10);
+        assert!(results.hits.iter().all(|hit| hit.snippet.is_none()));
+        query.highlight_hits(&mut results, "raiden", 100).unwrap();
+        let snippets = results
+            .hits
+            .iter()
+            .map(|hit| (hit.doc_id, hit.snippet.clone().unwrap()))
+            .collect::<HashMap<_, _>>();
+        assert_eq!(snippets[&0], query.highlight(0, "raiden", 100).unwrap());
+        assert_eq!(snippets[&1], "<em>raiden</em> &quot;ei&quot;");
+    }
+
+    #[test]
+    fn constant_score_hits_score_one() {
+        let directory = Directory::memory();
+        let mut builder = builder(&directory);
+        for (id, title, content) in [(0, "", "raiden shogun"), (1, "raiden", "raiden raiden")] {
+            let numbers = vec![("n", id as f64)];
+            builder
+                .add_document(Document {
+                    numbers,
+                    ..doc(id, title, content)
+                })
+                .unwrap();
+        }
+        builder.add_document(doc(2, "", "unrelated")).unwrap();
+        builder.finish().unwrap();
+        let query = open(&directory);
+
+        let options = QueryOptions {
+            constant_score: true,
+            ..QueryOptions::default()
+        };
+        let results = search_with(&query, "raiden", 0..10, &options);
+        assert_eq!(results.ids(), vec![0, 1]);
+        assert!(results.hits.iter().all(|hit| hit.score == 1f64));
+
+        let range = NumericRange::new("n");
+        let results = query.range_query(&range, 0..10, &options).unwrap();
+        assert_eq!(results.ids(), vec![0, 1]);
+        assert!(results.hits.iter().all(|hit| hit.score == 1f64));
+    }
+
+    #[test]
+    fn group_by_returns_top_hits_and_counts_per_group() {
+        let directory = Directory::memory();
+        let mut builder = builder(&directory);
+        let with_source = |id, content, source| Document {
+            keywords: vec![("source", source)],
+            ..doc(id, "", content)
+        };
+        builder
+            .add_document(with_source(0, "raiden shogun raiden", "wiki"))
+            .unwrap();
+        builder
+            .add_document(with_source(1, "raiden of inazuma", "wiki"))
+            .unwrap();
+        builder
+            .add_document(with_source(2, "raiden raiden", "forum"))
+            .unwrap();
+        builder
+            .add_document(with_source(3, "raiden and many other words", "wiki"))
+            .unwrap();
+        builder.add_document(doc(4, "", "raiden")).unwrap();
+        builder.finish().unwrap();
+        // 新segment里1换到了news，3被删除
+        builder
+            .add_document(with_source(1, "raiden of inazuma", "news"))
+            .unwrap();
+        builder.delete_document(3);
+        builder.finish().unwrap();
+
+        let query = open(&directory);
+        let exact = &|_: &str| None::<Levenshtein>;
+        let group_by = |query: &Query<_, _, _>, size, range| {
+            let group = GroupBy {
+                size,
+                ..GroupBy::new("source")
+            };
+            query
+                .group_by(
+                    "raiden",
+                    Occur::Should,
+                    exact,
+                    &group,
+                    range,
+                    &QueryOptions::default(),
+                )
+                .unwrap()
+        };
+        let groups = |results: &GroupedResults| {
+            results
+                .groups
+                .iter()
+                .map(|g| {
+                    (
+                        g.value.clone(),
+                        g.count,
+                        g.hits.iter().map(|h| h.doc_id).collect(),
+                    )
+                })
+                .collect::<Vec<(String, u32, Vec<u32>)>>()
+        };
+        // 组按各自最前面的文档排序，这里每组只有一篇，4没有source
+        let expected = search(&query, "raiden", 0..10)
+            .ids()
+            .into_iter()
+            .filter_map(|id| match id {
+                0 => Some(("wiki".to_string(), 1, vec![0])),
+                1 => Some(("news".to_string(), 1, vec![1])),
+                2 => Some(("forum".to_string(), 1, vec![2])),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        let results = group_by(&query, 2, 0..10);
+        assert_eq!(results.total, 4);
+        assert_eq!(results.total_groups, 3);
+        assert_eq!(groups(&results), expected);
+        assert_eq!(groups(&group_by(&query, 2, 1..2)), expected[1..2]);
+
+        // 每组只保留前size篇，count还是全部的
+        builder
+            .add_document(with_source(5, "raiden shogun", "forum"))
+            .unwrap();
+        builder
+            .add_document(with_source(6, "raiden shogun", "forum"))
+            .unwrap();
+        builder.finish().unwrap();
+        let query = open(&directory);
+        let results = group_by(&query, 1, 0..10);
+        let forum = results.groups.iter().find(|g| g.value == "forum").unwrap();
+        assert_eq!(forum.count, 3);
+        assert_eq!(forum.hits.len(), 1);
+        let top = search(&query, "raiden", 0..10)
+            .ids()
+            .into_iter()
+            .find(|id| [2, 5, 6].contains(id))
+            .unwrap();
+        assert_eq!(forum.hits[0].doc_id, top);
+        assert!(results.groups.iter().all(|g| g.hits.len() == 1));
+        let counts = group_by(&query, 0, 0..10);
+        assert!(counts.groups.iter().all(|g| g.hits.is_empty()));
+        assert_eq!(counts.groups.iter().map(|g| g.count).sum::<u32>(), 5);
+    }
+
+    #[test]
+    fn must_with_zero_posting_term_matches_nothing() {
+        let directory = Directory::memory();
+        let mut builder = builder(&directory);
+        builder.add_document(doc(0, "", "raiden ayaka")).unwrap();
+        builder.add_document(doc(1, "", "raiden shogun")).unwrap();
+        builder.finish().unwrap();
+        // ayaka还在词典里，但是唯一的文档被删除了
+        builder.delete_document(0);
+        builder.finish().unwrap();
+        let query = open(&directory);
+
+        let exact = &|_: &str| None::<Levenshtein>;
+        let options = QueryOptions::default();
+        for sentence in ["raiden ayaka", "raiden missing", "ayaka"] {
+            let results = query
+                .query(sentence, Occur::Must, exact, 0..10, &options)
+                .unwrap();
+            assert_eq!(results, SearchResults::default(), "{}", sentence);
+        }
+        assert_eq!(query.count("raiden ayaka", exact, Occur::Must).unwrap(), 0);
+        assert_eq!(search(&query, "raiden ayaka", 0..10).ids(), [1]);
+    }
+
+    #[test]
+    fn pagination_windows() {
+        let directory = Directory::memory();
+        let mut builder = builder(&directory);
+        let contents = (0..7)
+            .map(|i| format!("raiden {}", "filler ".repeat(i)))
+            .collect::<Vec<_>>();
+        for (id, content) in contents.iter().enumerate() {
+            builder.add_document(doc(id as u32, "", content)).unwrap();
+        }
+        builder.finish().unwrap();
+        let query = open(&directory);
+
+        let all = search(&query, "raiden", 0..10);
+        assert_eq!(all.total, 7);
+        assert_eq!(all.hits.len(), 7);
+        assert!(all.hits.windows(2).all(|w| w[0].score >= w[1].score));
+
+        // end超过total时返回剩下的，最后一页不满
+        let page = search(&query, "raiden", 5..10);
+        assert_eq!(page.total, 7);
+        assert_eq!(page.hits, all.hits[5..]);
+        assert_eq!(search(&query, "raiden", 3..6).hits, all.hits[3..6]);
+
+        // start >= total和空的range都没有结果，total不变
+        for range in [7..10, 8..9, 100..200, 0..0, 3..3] {
+            let page = search(&query, "raiden", range.clone());
+            assert_eq!(page.total, 7, "{:?}", range);
+            assert!(page.hits.is_empty(), "{:?}", range);
+        }
+    }
+
+    #[test]
+    fn top_k_pages_inside_top_results() {
+        let directory = Directory::memory();
+        let mut builder = builder(&directory);
+        for id in 0..7 {
+            let content = format!("raiden {}", "filler ".repeat(id));
+            builder.add_document(doc(id as u32, "", &content)).unwrap();
+        }
+        builder.finish().unwrap();
+        let query = open(&directory);
+
+        let all = search(&query, "raiden", 0..10);
+        let options = QueryOptions {
+            top_k: Some(4),
+            ..QueryOptions::default()
+        };
+        for range in [0..4, 1..3, 3..4, 4..4] {
+            let page = search_with(&query, "raiden", range.clone(), &options);
+            assert_eq!(page.total, 7);
+            assert_eq!(page.hits, all.hits[range]);
+        }
+
+        let exact = &|_: &str| None::<Levenshtein>;
+        let result = query.query("raiden", Occur::Should, exact, 3..5, &options);
+        assert!(matches!(result, Err(Error::OutOfRange)));
+    }
+
+    /// 堆里只留前range.end个，每一页都要和排序全部结果之后取的一样
+    #[test]
+    fn bounded_ranking_matches_full_sort() {
+        let directory = Directory::memory();
+        let mut builder = builder(&directory);
+        for id in 0..200u32 {
+            // 分数有很多相同的，按字段排序时有的文档没有这个字段
+            let content = format!("raiden {}", "filler ".repeat(id as usize % 13));
+            let numbers = match id % 5 {
+                0 => vec![],
+                _ => vec![("n", (id * 37 % 101) as f64)],
+            };
+            builder
+                .add_document(Document {
+                    numbers,
+                    ..doc(id, "", &content)
+                })
+                .unwrap();
+        }
+        builder.finish().unwrap();
+        let query = open(&directory);
+
+        for sort_by in [
+            SortBy::Relevance,
+            SortBy::Field {
+                name: "n".to_string(),
+                ascending: true,
+            },
+            SortBy::Field {
+                name: "n".to_string(),
+                ascending: false,
+            },
+        ] {
+            let options = QueryOptions {
+                sort_by,
+                ..QueryOptions::default()
+            };
+            let all = search_with(&query, "raiden", 0..200, &options);
+            let ids = all.ids();
+            assert_eq!(ids.iter().collect::<BTreeSet<_>>().len(), 200);
+
+            for range in [0..1, 0..10, 37..53, 190..200] {
+                let page = search_with(&query, "raiden", range.clone(), &options);
+                assert_eq!(page.total, 200);
+                assert_eq!(page.hits, all.hits[range]);
+            }
+        }
+    }
+
+    #[test]
+    fn title_only_match_with_empty_content_scores() {
+        let directory = Directory::memory();
+        let mut builder = builder(&directory);
+        builder.add_document(doc(0, "raiden shogun", "")).unwrap();
+        builder.add_document(doc(1, "", "kamisato ayaka")).unwrap();
+        builder.finish().unwrap();
+        let query = open(&directory);
+
+        for field in [None, Some(Field::Title)] {
+            let options = QueryOptions {
+                field,
+                ..QueryOptions::default()
+            };
+            let results = search_with(&query, "raiden", 0..10, &options);
+            assert_eq!(results.ids(), [0], "{:?}", field);
+            let score = results.hits[0].score;
+            assert!(score.is_finite() && score > 0f64, "{:?}: {}", field, score);
+        }
+        let options = QueryOptions {
+            field: Some(Field::Content),
+            ..QueryOptions::default()
+        };
+        assert_eq!(search_with(&query, "raiden", 0..10, &options).total, 0);
+    }
+
+    #[test]
+    fn term_boost_flips_order() {
+        let directory = Directory::memory();
+        let mut builder = builder(&directory);
+        builder
+            .add_document(doc(0, "", "ayaka ayaka ayaka travel"))
+            .unwrap();
+        builder
+            .add_document(doc(1, "", "ayaka travel travel travel"))
+            .unwrap();
+        builder.finish().unwrap();
+        let query = open(&directory);
+
+        assert_eq!(search(&query, "ayaka^4 travel", 0..10).ids(), [0, 1]);
+        assert_eq!(search(&query, "ayaka travel^4", 0..10).ids(), [1, 0]);
+
+        let exact = &|_: &str| None::<Levenshtein>;
+        let clauses = |ayaka, travel| {
+            [
+                Clause::new(Occur::Should, "ayaka").with_boost(ayaka),
+                Clause::new(Occur::Should, "travel").with_boost(travel),
+            ]
+        };
+        let options = QueryOptions::default();
+        for (boosts, expected) in [((4f64, 1f64), [0, 1]), ((1f64, 4f64), [1, 0])] {
+            let results = query
+                .bool_query(&clauses(boosts.0, boosts.1), exact, 0..10, &options)
+                .unwrap();
+            assert_eq!(results.ids(), expected, "{:?}", boosts);
+        }
+    }
+
+    #[test]
+    fn deleting_top_hit_updates_results_and_total() {
+        let directory = Directory::memory();
+        let mut builder = builder(&directory);
+        builder
+            .add_document(doc(0, "", "raiden raiden raiden"))
+            .unwrap();
+        for id in 1..12 {
+            let content = format!("raiden {}", "filler ".repeat(id as usize));
+            builder.add_document(doc(id, "", &content)).unwrap();
+        }
+        builder.finish().unwrap();
+
+        let before = search(&open(&directory), "raiden", 0..10);
+        assert_eq!(before.total, 12);
+        assert_eq!(before.hits[0].doc_id, 0);
+
+        builder.delete_document(0);
+        builder.finish().unwrap();
+        let query = open(&directory);
+
+        // 删除的文档不占分页的位置：第一页还是10个，total少了1
+        let after = search(&query, "raiden", 0..10);
+        assert_eq!(after.total, 11);
+        assert_eq!(after.hits.len(), 10);
+        assert!(!after.ids().contains(&0));
+        assert_eq!(after.ids()[..9], before.ids()[1..]);
+        assert_eq!(search(&query, "raiden", 10..20).hits.len(), 1);
+        let exact = &|_: &str| None::<Levenshtein>;
+        assert_eq!(query.count("raiden", exact, Occur::Should).unwrap(), 11);
+    }
+
+    #[test]
+    fn phrases_do_not_span_values() {
+        let phrase = |query: &Query<_, _, _>, slop| {
+            let results = query
+                .phrase_query("raiden shogun", slop, 0..10, &QueryOptions::default())
+                .unwrap();
+            let mut ids = results.ids();
+            ids.sort_unstable();
+            ids
+        };
+        let multi = format!("raiden{}shogun", store::VALUE_SEPARATOR);
+
+        let directory = Directory::memory();
+        let mut builder = builder(&directory);
+        builder.add_document(doc(1, "", "raiden shogun")).unwrap();
+        builder.add_document(doc(2, "", &multi)).unwrap();
+        builder.finish().unwrap();
+        let query = open(&directory);
+
+        assert_eq!(search(&query, "shogun", 0..10).total, 2);
+        assert_eq!(phrase(&query, 0), [1]);
+        let gap = store::builder::DEFAULT_POSITION_GAP;
+        assert_eq!(phrase(&query, gap - 1), [1]);
+        assert_eq!(phrase(&query, gap), [1, 2]);
+
+        // gap为0时各个值直接连起来
+        let directory = Directory::memory();
+        let mut builder = Builder::new(
+            analyzer(),
+            analyzer(),
+            store::Config::new(directory.clone(), "t", 1).with_position_gap(0),
+        );
+        builder.add_document(doc(2, "", &multi)).unwrap();
+        builder.finish().unwrap();
+        assert_eq!(phrase(&open(&directory), 0), [2]);
+    }
 }
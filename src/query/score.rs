@@ -1,23 +1,39 @@
-use crate::query::Result;
 use std::cmp::Ordering;
+use std::fmt::Debug;
+use std::sync::Arc;
 
 #[inline(always)]
 pub fn calc_idf(df: u32, total_doc_num: u32) -> f64 {
     1f64 + f64::ln(total_doc_num as f64 / (df + 1) as f64)
 }
 
+/// BM25的idf，不会出现负数
+#[inline(always)]
+pub fn calc_bm25_idf(df: u32, total_doc_num: u32) -> f64 {
+    f64::ln(1f64 + (total_doc_num as f64 - df as f64 + 0.5) / (df as f64 + 0.5))
+}
+
 #[inline(always)]
 pub fn calc_tf(freq: u16) -> u8 {
     ((freq as f64).sqrt() * 8f64) as u8
 }
 
+/// 1/sqrt(len)，乘255和以前量化成u8时的范围一致。长度为0（字段为空）时是0
 #[inline(always)]
-pub fn calc_norm(len: usize) -> u8 {
-    (1f64 / (len as f64).sqrt() * 255f64) as u8
+pub fn calc_norm(len: u16) -> f64 {
+    if len == 0 {
+        return 0f64;
+    }
+
+    255f64 / (len as f64).sqrt()
 }
 
-pub trait TermPriorityCalculator {
-    fn calc(&self, df: u32, tf_title: u8, tf_content: u8, norm_title: u8, norm_content: u8) -> f64;
+/// len是字段的原始长度（字符数，已经按min_norm_len取过下限），查询向量用的是查询的长度
+pub trait TermPriorityCalculator: Debug {
+    fn calc(&self, df: u32, tf_title: u8, tf_content: u8, len_title: u16, len_content: u16) -> f64;
+
+    /// calc中用到的idf，explain时单独展示
+    fn idf(&self, df: u32) -> f64;
 }
 
 #[derive(Debug)]
@@ -39,51 +55,252 @@ impl TfIdfTermPriorityCalculator {
 
 impl TermPriorityCalculator for TfIdfTermPriorityCalculator {
     #[inline(always)]
-    fn calc(&self, df: u32, tf_title: u8, tf_content: u8, norm_title: u8, norm_content: u8) -> f64 {
+    fn calc(&self, df: u32, tf_title: u8, tf_content: u8, len_title: u16, len_content: u16) -> f64 {
         calc_idf(df, self.total_doc_num)
-            * (tf_title as f64 * norm_title as f64 * self.boost_title as f64
-                + tf_content as f64 * norm_content as f64 * self.boost_content as f64)
+            * (tf_title as f64 * calc_norm(len_title) * self.boost_title as f64
+                + tf_content as f64 * calc_norm(len_content) * self.boost_content as f64)
+    }
+
+    #[inline(always)]
+    fn idf(&self, df: u32) -> f64 {
+        calc_idf(df, self.total_doc_num)
+    }
+}
+
+/// 标准的BM25，title和content分别计算后按boost加权。
+///
+/// 索引里存的是量化后的tf，这里用calc_tf的反函数近似还原词频：freq ≈ (tf / 8)²。
+/// 字段长度是原始值，长度惩罚是精确的
+#[derive(Debug)]
+pub struct Bm25TermPriorityCalculator {
+    total_doc_num: u32,
+    boost_title: u8,
+    boost_content: u8,
+    k1: f64,
+    b: f64,
+    /// (avg_len_title, avg_len_content)
+    avg_len: (f64, f64),
+}
+
+impl Bm25TermPriorityCalculator {
+    pub fn new(
+        total_doc_num: u32,
+        boost_title: u8,
+        boost_content: u8,
+        k1: f64,
+        b: f64,
+        avg_len: (f64, f64),
+    ) -> Self {
+        Bm25TermPriorityCalculator {
+            total_doc_num,
+            boost_title,
+            boost_content,
+            k1,
+            b,
+            avg_len: (avg_len.0.max(1f64), avg_len.1.max(1f64)),
+        }
+    }
+
+    #[inline(always)]
+    fn calc_field(&self, tf: u8, len: u16, avg_len: f64) -> f64 {
+        if tf == 0 || len == 0 {
+            return 0f64;
+        }
+
+        let freq = (tf as f64 / 8f64).powi(2);
+        let len = len as f64;
+
+        freq * (self.k1 + 1f64) / (freq + self.k1 * (1f64 - self.b + self.b * len / avg_len))
+    }
+}
+
+impl TermPriorityCalculator for Bm25TermPriorityCalculator {
+    #[inline(always)]
+    fn calc(&self, df: u32, tf_title: u8, tf_content: u8, len_title: u16, len_content: u16) -> f64 {
+        calc_bm25_idf(df, self.total_doc_num)
+            * (self.calc_field(tf_title, len_title, self.avg_len.0) * self.boost_title as f64
+                + self.calc_field(tf_content, len_content, self.avg_len.1)
+                    * self.boost_content as f64)
+    }
+
+    #[inline(always)]
+    fn idf(&self, df: u32) -> f64 {
+        calc_bm25_idf(df, self.total_doc_num)
+    }
+}
+
+/// 查询时使用的相关性算法
+#[derive(Debug, Clone, Default)]
+pub enum Similarity {
+    #[default]
+    TfIdf,
+    /// k1控制词频饱和的速度，b控制字段长度的影响，常用k1=1.2、b=0.75
+    Bm25 {
+        k1: f64,
+        b: f64,
+    },
+    Custom(Arc<dyn CustomSimilarity>),
+}
+
+/// Query::new打开索引后得到的统计，自定义的相关性算法用它构造calculator
+#[derive(Debug, Clone)]
+pub struct IndexStats {
+    /// 所有segment的文档数之和
+    pub doc_num: u32,
+    /// (title平均长度, content平均长度)
+    pub avg_len: (f64, f64),
+    pub boost_title: u8,
+    pub boost_content: u8,
+}
+
+/// 自定义的相关性算法。
+///
+/// 每个词在查询和文档中的权重由calculator计算，得到查询和文档两个权重向量，
+/// 再由combine合成最终的分数，默认和内置的算法一样使用余弦相似度
+pub trait CustomSimilarity: Debug + Send + Sync {
+    fn calculator(&self, stats: &IndexStats) -> Box<dyn TermPriorityCalculator + Send + Sync>;
+
+    /// 两个向量的长度相同，不参与打分的词两边都是0
+    fn combine(&self, query: &[f64], doc: &[f64]) -> f64 {
+        unsafe { calc_cosine_unchecked(query, doc) }
     }
 }
 
-// TODO: 计算中会不会溢出
+/// Query::new根据Similarity构造出来的calculator
+#[derive(Debug)]
+pub enum SimilarityCalculator {
+    TfIdf(TfIdfTermPriorityCalculator),
+    Bm25(Bm25TermPriorityCalculator),
+    Custom(
+        Box<dyn TermPriorityCalculator + Send + Sync>,
+        Arc<dyn CustomSimilarity>,
+    ),
+}
+
+impl SimilarityCalculator {
+    pub fn new(similarity: &Similarity, stats: &IndexStats) -> Self {
+        match similarity {
+            Similarity::TfIdf => SimilarityCalculator::TfIdf(TfIdfTermPriorityCalculator::new(
+                stats.doc_num,
+                stats.boost_title,
+                stats.boost_content,
+            )),
+            Similarity::Bm25 { k1, b } => {
+                SimilarityCalculator::Bm25(Bm25TermPriorityCalculator::new(
+                    stats.doc_num,
+                    stats.boost_title,
+                    stats.boost_content,
+                    *k1,
+                    *b,
+                    stats.avg_len,
+                ))
+            }
+            Similarity::Custom(custom) => {
+                SimilarityCalculator::Custom(custom.calculator(stats), custom.clone())
+            }
+        }
+    }
+
+    /// 查询和文档的权重向量合成最终的分数
+    #[inline(always)]
+    pub fn combine(&self, query: &[f64], doc: &[f64]) -> f64 {
+        match self {
+            SimilarityCalculator::Custom(_, custom) => custom.combine(query, doc),
+            _ => unsafe { calc_cosine_unchecked(query, doc) },
+        }
+    }
+}
+
+impl TermPriorityCalculator for SimilarityCalculator {
+    #[inline(always)]
+    fn calc(&self, df: u32, tf_title: u8, tf_content: u8, len_title: u16, len_content: u16) -> f64 {
+        match self {
+            SimilarityCalculator::TfIdf(c) => {
+                c.calc(df, tf_title, tf_content, len_title, len_content)
+            }
+            SimilarityCalculator::Bm25(c) => {
+                c.calc(df, tf_title, tf_content, len_title, len_content)
+            }
+            SimilarityCalculator::Custom(c, _) => {
+                c.calc(df, tf_title, tf_content, len_title, len_content)
+            }
+        }
+    }
+
+    #[inline(always)]
+    fn idf(&self, df: u32) -> f64 {
+        match self {
+            SimilarityCalculator::TfIdf(c) => c.idf(df),
+            SimilarityCalculator::Bm25(c) => c.idf(df),
+            SimilarityCalculator::Custom(c, _) => c.idf(df),
+        }
+    }
+}
+
+/// 任意一个向量全是0时（例如只有Filter的词，或者文档没有命中任何参与打分的词）返回0而不是NaN。
+///
+/// tf、norm、boost相乘后每一项可能很大，词多的时候平方和会损失精度，
+/// 所以先把两个向量分别除以各自绝对值最大的一项，余弦不受缩放影响。
+/// boost很大时权重可能溢出成无穷大，这时只有无穷大的分量起作用（取极限）。
+/// 结果限制在[-1, 1]，内置的算法权重都不是负数，分数在[0, 1]
 #[inline(always)]
 pub unsafe fn calc_cosine_unchecked(a: &[f64], b: &[f64]) -> f64 {
+    let (mut max_a, mut max_b) = (0f64, 0f64);
+    for i in 0..a.len() {
+        max_a = max_a.max(a.get_unchecked(i).abs());
+        max_b = max_b.max(b.get_unchecked(i).abs());
+    }
+
+    // 同时排除了NaN
+    if !(max_a > 0f64 && max_b > 0f64) {
+        return 0f64;
+    }
+
+    let scale = |x: f64, max: f64| match max.is_finite() {
+        true => x / max,
+        false if x.is_infinite() => x.signum(),
+        false => 0f64,
+    };
     let (mut product, mut q_sum_a, mut q_sum_b) = (0f64, 0f64, 0f64);
 
     for i in 0..a.len() {
-        let an = a.get_unchecked(i);
-        let bn = b.get_unchecked(i);
+        let an = scale(*a.get_unchecked(i), max_a);
+        let bn = scale(*b.get_unchecked(i), max_b);
         product += an * bn;
         q_sum_a += an * an;
         q_sum_b += bn * bn;
     }
 
-    product / (q_sum_a.sqrt() * q_sum_b.sqrt())
+    (product / (q_sum_a.sqrt() * q_sum_b.sqrt())).clamp(-1f64, 1f64)
 }
 
 #[derive(Debug)]
 pub struct Score {
-    cosine: f64,
+    value: f64,
 }
 
 impl Score {
-    pub fn new(a: &[f64], b: &[f64]) -> Self {
+    /// NaN当作0，保证Ord是全序的，排序结果不会乱
+    pub fn new(value: f64) -> Self {
         Score {
-            cosine: unsafe { calc_cosine_unchecked(a, b) },
+            value: if value.is_nan() { 0f64 } else { value },
         }
     }
+
+    pub fn value(&self) -> f64 {
+        self.value
+    }
 }
 
 impl PartialEq for Score {
     fn eq(&self, other: &Self) -> bool {
-        self.cosine.eq(&other.cosine)
+        self.value.eq(&other.value)
     }
 }
 
 impl PartialOrd for Score {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        self.cosine.partial_cmp(&other.cosine)
+        Some(self.cmp(other))
     }
 }
 
@@ -91,12 +308,56 @@ impl Eq for Score {}
 
 impl Ord for Score {
     fn cmp(&self, other: &Self) -> Ordering {
-        if self.cosine > other.cosine {
+        if self.value > other.value {
             Ordering::Greater
-        } else if self.cosine < other.cosine {
+        } else if self.value < other.value {
             Ordering::Less
         } else {
             Ordering::Equal
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cosine(a: &[f64], b: &[f64]) -> f64 {
+        assert_eq!(a.len(), b.len());
+        unsafe { calc_cosine_unchecked(a, b) }
+    }
+
+    #[test]
+    fn cosine_of_empty_or_disjoint_vectors_is_zero() {
+        assert_eq!(cosine(&[], &[]), 0f64);
+        assert_eq!(cosine(&[0f64, 0f64], &[0f64, 0f64]), 0f64);
+        assert_eq!(cosine(&[1f64, 2f64], &[0f64, 0f64]), 0f64);
+        // 没有共同的词
+        assert_eq!(cosine(&[3f64, 0f64, 0f64], &[0f64, 5f64, 7f64]), 0f64);
+        assert_eq!(cosine(&[f64::NAN], &[1f64]), 0f64);
+        assert!((cosine(&[1f64, 1f64], &[2f64, 2f64]) - 1f64).abs() < 1e-12);
+
+        assert_eq!(Score::new(f64::NAN).value(), 0f64);
+        assert_eq!(Score::new(f64::NAN), Score::new(0f64));
+    }
+
+    #[test]
+    fn many_high_boost_terms_stay_in_range() {
+        let calculator = TfIdfTermPriorityCalculator::new(1000, u8::MAX, u8::MAX);
+        // 同一个高权重的词重复50次，每个都乘上很大的boost
+        for boost in [1f64, 255f64, 1e12, 1e300, f64::MAX, f64::INFINITY] {
+            let query = (0..50)
+                .map(|_| calculator.calc(1, u8::MAX, u8::MAX, 1, 1) * boost)
+                .collect::<Vec<_>>();
+            let doc = (0..50)
+                .map(|i| calculator.calc(1, (i * 5) as u8, u8::MAX, 1, (i + 1) as u16))
+                .collect::<Vec<_>>();
+
+            for (a, b) in [(&query, &doc), (&query, &query), (&doc, &query)] {
+                let score = cosine(a, b);
+                assert!((0f64..=1f64).contains(&score), "{}: {}", boost, score);
+            }
+            assert!(cosine(&query, &doc) > 0f64, "{}", boost);
+        }
+    }
+}
@@ -1,90 +1,508 @@
-use crate::analyzer::analyzer::Analyzer;
-use crate::analyzer::char_filter::{CJKDocCharFilter, CharFilter};
-use crate::analyzer::token_filter::{BasicTokenFilter, StopWordTokenFilter, TokenFilter};
-use crate::analyzer::tokenizer::{JiebaTokenizer, Tokenizer};
+use crate::analyzer;
+use crate::analyzer::char_filter::CharFilter;
+use crate::analyzer::registry::{AnalyzerSettings, ConfiguredAnalyzer};
+use crate::analyzer::token_filter::TokenFilter;
+use crate::analyzer::tokenizer::Tokenizer;
+use crate::service::{is_valid_identifier, split_identifier};
+use crate::settings::BuildSettings;
 use crate::store;
 use crate::store::Document;
-use core::future;
 use futures::{Future, StreamExt};
 use hyper::service::Service;
 use hyper::{Body, Method, Request, Response, StatusCode};
+use rayon::prelude::*;
+use serde::de::{DeserializeOwned, Deserializer};
 use serde::{Deserialize, Serialize};
-use std::fs::File;
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{create_dir_all, read_dir, read_to_string};
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
-use std::sync::mpsc;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
 use std::task::{Context, Poll};
-use std::thread;
-use std::thread::JoinHandle;
-use std::time::SystemTime;
-
-macro_rules! print_time_cost {
-    ($str: expr, $time: expr) => {
-        println!(
-            "{} costs: {}ms",
-            $str,
-            SystemTime::now().duration_since($time).unwrap().as_millis()
-        );
-    };
+use std::{fmt, io};
+use tracing::{debug, error, info, trace_span, warn};
+
+/// 建索引用到的路径和analyzer，来自配置文件或命令行参数
+#[derive(Debug, Clone)]
+pub struct BuildOptions {
+    pub store_dir: PathBuf,
+    pub identifier: String,
+    pub settings: BuildSettings,
+    /// 写完这次的segment后把所有segment合并成一个
+    pub merge: bool,
 }
 
-pub fn start_builder_thread() -> (tokio::task::JoinHandle<()>, mpsc::Sender<BuildServiceTask>) {
-    let (tx, rx): (
-        mpsc::Sender<BuildServiceTask>,
-        mpsc::Receiver<BuildServiceTask>,
-    ) = mpsc::channel();
+/// 创建索引目录失败，或者按配置创建analyzer失败（比如停用词文件不存在）
+#[derive(Debug)]
+pub enum BuildError {
+    StoreDir { path: PathBuf, error: io::Error },
+    Analyzer(analyzer::Error),
+}
 
-    let builder_thread = tokio::task::spawn_blocking(move || {
-        let time = SystemTime::now();
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::StoreDir { path, error } => write!(f, "{}: {}", path.display(), error),
+            BuildError::Analyzer(e) => write!(f, "{}", e),
+        }
+    }
+}
 
-        let mut stop_words_file = File::open("../../dict/stop_words.txt").unwrap();
-        let title_analyzer = Analyzer::new(
-            CJKDocCharFilter::new(),
-            BasicTokenFilter::new(),
-            JiebaTokenizer::new(),
-        );
-        let content_analyzer = Analyzer::new(
-            CJKDocCharFilter::new(),
-            StopWordTokenFilter::new(&mut stop_words_file).unwrap(),
-            JiebaTokenizer::new(),
+impl std::error::Error for BuildError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BuildError::StoreDir { error, .. } => Some(error),
+            BuildError::Analyzer(e) => Some(e),
+        }
+    }
+}
+
+/// /stats返回的建索引进度
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BuildStats {
+    /// 启动以来处理过的添加和更新的文档数，包括因为已经删除而跳过的
+    pub added: u64,
+    /// 启动以来处理过的删除请求数
+    pub deleted: u64,
+    /// 还没有写到segment里的部分
+    pub pending: store::BuilderStats,
+}
+
+/// builder线程遇到的致命错误。线程退出后channel会关闭，service据此区分是正常finish还是出错了。
+/// 同时记录建索引的进度，每处理完一个任务更新一次
+#[derive(Debug, Default)]
+pub struct BuildStatus {
+    error: Mutex<Option<String>>,
+    stats: Mutex<BuildStats>,
+    /// 线程已经退出，包括finish完、出错和panic
+    exited: AtomicBool,
+}
+
+impl BuildStatus {
+    pub fn error(&self) -> Option<String> {
+        self.error.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn stats(&self) -> BuildStats {
+        self.stats.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    fn update_stats(&self, added: u64, deleted: u64, pending: store::BuilderStats) {
+        let mut stats = self.stats.lock().unwrap_or_else(|e| e.into_inner());
+        stats.added += added;
+        stats.deleted += deleted;
+        stats.pending = pending;
+    }
+
+    fn fail(&self, error: String) {
+        error!(%error, "builder failed");
+        *self.error.lock().unwrap_or_else(|e| e.into_inner()) = Some(error);
+    }
+
+    fn exited(&self) -> bool {
+        self.exited.load(Ordering::Acquire)
+    }
+}
+
+/// builder线程退出时标记状态，panic时还要标记为出错，否则service只会看到channel关闭。
+/// 在builder之后drop，标记为退出时索引文件已经写完
+struct ExitGuard(Arc<BuildStatus>);
+
+impl Drop for ExitGuard {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            self.0.fail("builder thread panicked".to_string());
+        }
+        self.0.exited.store(true, Ordering::Release);
+    }
+}
+
+pub type BuilderThread = (
+    tokio::task::JoinHandle<()>,
+    mpsc::Sender<BuildServiceTask>,
+    Arc<BuildStatus>,
+);
+
+/// 建索引服务里每个identifier各有一个builder线程。默认的索引在服务启动时就启动，
+/// 其他的在第一次往/{identifier}/add等写入时才启动，用的是同一份store_dir和settings
+#[derive(Debug)]
+pub struct Builders {
+    options: BuildOptions,
+    /// identifier -> (channel, 状态, 线程)。默认索引的线程由Builders::start的调用方等待，这里是None
+    threads: Mutex<HashMap<String, RunningBuilder>>,
+}
+
+type RunningBuilder = (
+    mpsc::Sender<BuildServiceTask>,
+    Arc<BuildStatus>,
+    Option<tokio::task::JoinHandle<()>>,
+);
+
+impl Builders {
+    /// 启动默认索引的builder线程，返回的JoinHandle在它finish或者出错时结束
+    pub fn start(options: BuildOptions) -> Result<(Self, tokio::task::JoinHandle<()>), BuildError> {
+        let (task, tx, status) = start_builder_thread(options.clone())?;
+
+        let mut threads = HashMap::new();
+        threads.insert(options.identifier.clone(), (tx, status, None));
+
+        let builders = Builders {
+            options,
+            threads: Mutex::new(threads),
+        };
+
+        Ok((builders, task))
+    }
+
+    /// identifier为None时是默认的索引。还没有启动时返回None
+    pub fn get(
+        &self,
+        identifier: Option<&str>,
+    ) -> Option<(mpsc::Sender<BuildServiceTask>, Arc<BuildStatus>)> {
+        let identifier = identifier.unwrap_or(&self.options.identifier);
+
+        Self::running(&mut self.lock(), identifier)
+    }
+
+    /// 和get一样，但是还没有启动时启动一个。启动要创建analyzer，比较慢，应该在blocking线程里调用。
+    /// 创建analyzer时不持有锁，不会挡住其他索引的请求
+    pub fn get_or_start(
+        &self,
+        identifier: &str,
+    ) -> Result<(mpsc::Sender<BuildServiceTask>, Arc<BuildStatus>), BuildError> {
+        if let Some(builder) = self.get(Some(identifier)) {
+            return Ok(builder);
+        }
+
+        let prepared = PreparedBuilder::new(BuildOptions {
+            identifier: identifier.to_string(),
+            ..self.options.clone()
+        })?;
+
+        // 创建analyzer期间可能有别的请求已经启动了同一个identifier，用它的，这边的analyzer丢掉。
+        // 线程在持有锁时启动，同一个identifier不会同时有两个线程
+        let mut threads = self.lock();
+        if let Some(builder) = Self::running(&mut threads, identifier) {
+            return Ok(builder);
+        }
+
+        let (task, tx, status) = prepared.spawn();
+        threads.insert(
+            identifier.to_string(),
+            (tx.clone(), status.clone(), Some(task)),
         );
 
-        print_time_cost!("init analyzer", time);
-        let time = SystemTime::now();
+        Ok((tx, status))
+    }
+
+    /// 线程已经退出（finish完或者出错）的builder从表里去掉并返回None，下一次写入时会重新启动。
+    /// 默认索引的线程退出时服务也会停止，不去掉，之后的请求仍然得到409或者500
+    fn running(
+        threads: &mut HashMap<String, RunningBuilder>,
+        identifier: &str,
+    ) -> Option<(mpsc::Sender<BuildServiceTask>, Arc<BuildStatus>)> {
+        let (tx, status, task) = threads.get(identifier)?;
 
-        let mut builder = store::Builder::new(
+        if task.is_some() && status.exited() {
+            threads.remove(identifier);
+            return None;
+        }
+
+        Some((tx.clone(), status.clone()))
+    }
+
+    /// 让所有还没有finish的builder线程都finish，返回默认索引以外的线程的JoinHandle。
+    /// 停止服务前调用，避免其他索引还没有写到segment里的文档丢失
+    pub fn finish_others(&self) -> Vec<tokio::task::JoinHandle<()>> {
+        self.lock()
+            .drain()
+            .filter_map(|(_, (tx, _, task))| {
+                let _ = tx.send(BuildServiceTask::Finish);
+                task
+            })
+            .collect()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, RunningBuilder>> {
+        self.threads.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// 索引目录不存在时会自动创建。analyzer在这里就创建好，停用词等文件有问题直接返回错误而不是在builder线程里panic
+pub fn start_builder_thread(options: BuildOptions) -> Result<BuilderThread, BuildError> {
+    Ok(PreparedBuilder::new(options)?.spawn())
+}
+
+/// 创建好了索引目录和analyzer、还没有启动线程的builder。
+/// 创建analyzer比较慢，Builders在不持有锁的时候做这一步
+struct PreparedBuilder {
+    options: BuildOptions,
+    title_analyzer: ConfiguredAnalyzer,
+    content_analyzer: ConfiguredAnalyzer,
+}
+
+impl PreparedBuilder {
+    fn new(options: BuildOptions) -> Result<Self, BuildError> {
+        create_dir_all(&options.store_dir).map_err(|error| BuildError::StoreDir {
+            path: options.store_dir.clone(),
+            error,
+        })?;
+
+        let span = trace_span!("init_analyzer").entered();
+        let title_analyzer = options
+            .settings
+            .title_analyzer
+            .build()
+            .map_err(BuildError::Analyzer)?;
+        let content_analyzer = options
+            .settings
+            .content_analyzer
+            .build()
+            .map_err(BuildError::Analyzer)?;
+        span.exit();
+
+        Ok(PreparedBuilder {
+            options,
             title_analyzer,
             content_analyzer,
-            store::Config::new(PathBuf::from("../../test_store/"), "test"),
-        );
+        })
+    }
+
+    fn spawn(self) -> BuilderThread {
+        let PreparedBuilder {
+            options:
+                BuildOptions {
+                    store_dir,
+                    identifier,
+                    settings,
+                    merge,
+                },
+            title_analyzer,
+            content_analyzer,
+        } = self;
+
+        let (tx, rx): (
+            mpsc::Sender<BuildServiceTask>,
+            mpsc::Receiver<BuildServiceTask>,
+        ) = mpsc::channel();
+
+        let status = Arc::new(BuildStatus::default());
+        let thread_status = status.clone();
+
+        let builder_thread = tokio::task::spawn_blocking(move || {
+            let _guard = ExitGuard(thread_status.clone());
+            let span = trace_span!("build_indexes", %identifier).entered();
 
-        for task in rx {
-            match task.data {
-                Some(data) => {
-                    builder
-                        .add_document(Document {
-                            id: data.id,
-                            title: data.title.as_str(),
-                            content: data.content.as_str(),
-                        })
-                        .unwrap();
-                    println!("add document({}) {}", data.id, data.title);
+            let mut config =
+                store::Config::new(store_dir, identifier.as_str(), settings.min_norm_len)
+                    .with_position_gap(settings.position_gap);
+            if let Some(policy) = settings.merge_policy {
+                config = config.with_merge_policy(policy);
+            }
+            let mut builder = store::Builder::new(title_analyzer, content_analyzer, config);
+
+            for task in rx {
+                let (added, deleted) = match &task {
+                    BuildServiceTask::Add(_) | BuildServiceTask::Update(_) => (1, 0),
+                    BuildServiceTask::AddBatch(batch) => (batch.len() as u64, 0),
+                    BuildServiceTask::Delete(_) => (0, 1),
+                    BuildServiceTask::Finish => (0, 0),
+                };
+
+                let result = match task {
+                    BuildServiceTask::Add(data) => add_document(&mut builder, data),
+                    BuildServiceTask::AddBatch(batch) => add_batch(&mut builder, batch),
+                    BuildServiceTask::Update(data) => update_document(&mut builder, data),
+                    BuildServiceTask::Delete(data) => delete_document(&mut builder, data),
+                    BuildServiceTask::Finish => break,
+                };
+
+                // 出错时builder里可能已经写了一半，不能再继续，直接退出线程关闭channel
+                if let Err(e) = result {
+                    thread_status.fail(e.to_string());
+                    return;
                 }
-                None => break,
+
+                thread_status.update_stats(added, deleted, builder.stats());
             }
+
+            if let Err(e) = builder.finish() {
+                thread_status.fail(e.to_string());
+                return;
+            }
+            span.exit();
+            info!(%identifier, "indexes built");
+
+            if merge {
+                let _span = trace_span!("merge_segments", %identifier).entered();
+                match builder.merge_segments() {
+                    Ok(()) => info!(%identifier, "segments merged"),
+                    Err(e) => thread_status.fail(e.to_string()),
+                }
+            }
+        });
+
+        (builder_thread, tx, status)
+    }
+}
+
+fn add_document<C, T, I, C2, T2, I2>(
+    builder: &mut store::Builder<C, T, I, C2, T2, I2>,
+    data: AddPostReq,
+) -> store::Result<()>
+where
+    C: CharFilter,
+    T: TokenFilter,
+    I: Tokenizer,
+    C2: CharFilter,
+    T2: TokenFilter,
+    I2: Tokenizer,
+{
+    let id = doc_id(builder, &data)?;
+
+    match builder.add_document(data.document(id)) {
+        Ok(()) => debug!(id, title = %data.title, "document added"),
+        // 只影响这一篇文档，跳过就行
+        Err(e @ (store::Error::Deleted(_) | store::Error::KeyConflict(..))) => {
+            warn!(id, error = %e, "document skipped")
+        }
+        Err(e) => return Err(e),
+    }
+
+    Ok(())
+}
+
+/// 请求里有id时直接用，只有key时用Builder::id_for_key分配，validate保证两者至少有一个
+fn doc_id<C, T, I, C2, T2, I2>(
+    builder: &mut store::Builder<C, T, I, C2, T2, I2>,
+    data: &AddPostReq,
+) -> store::Result<u32>
+where
+    C: CharFilter,
+    T: TokenFilter,
+    I: Tokenizer,
+    C2: CharFilter,
+    T2: TokenFilter,
+    I2: Tokenizer,
+{
+    match (&data.key, data.id) {
+        (_, Some(id)) => Ok(id),
+        (Some(key), None) => builder.id_for_key(key),
+        (None, None) => unreachable!("AddPostReq::validate requires id or key"),
+    }
+}
+
+/// 整批文档先用rayon并行分词，再在当前线程按原来的顺序插入，建出来的索引和逐个添加一样
+fn add_batch<C, T, I, C2, T2, I2>(
+    builder: &mut store::Builder<C, T, I, C2, T2, I2>,
+    batch: Vec<AddPostReq>,
+) -> store::Result<()>
+where
+    C: CharFilter + Sync,
+    T: TokenFilter + Sync,
+    I: Tokenizer + Sync,
+    C2: CharFilter + Sync,
+    T2: TokenFilter + Sync,
+    I2: Tokenizer + Sync,
+{
+    // 分配id要修改builder，先按顺序做完，分词时就不需要访问builder的可变状态
+    let ids = batch
+        .iter()
+        .map(|data| doc_id(builder, data))
+        .collect::<store::Result<Vec<_>>>()?;
+
+    let analyzed = {
+        let builder = &*builder;
+        batch
+            .par_iter()
+            .zip(ids)
+            .map(|(data, id)| builder.analyze_document(data.document(id)))
+            .collect::<Vec<_>>()
+    };
+
+    for doc in analyzed {
+        let doc = doc?;
+        let (id, title) = (doc.doc.id, doc.doc.title);
+
+        match builder.add_analyzed(doc) {
+            Ok(()) => debug!(id, title, "document added"),
+            Err(e @ (store::Error::Deleted(_) | store::Error::KeyConflict(..))) => {
+                warn!(id, error = %e, "document skipped")
+            }
+            Err(e) => return Err(e),
         }
+    }
+
+    Ok(())
+}
+
+fn update_document<C, T, I, C2, T2, I2>(
+    builder: &mut store::Builder<C, T, I, C2, T2, I2>,
+    data: AddPostReq,
+) -> store::Result<()>
+where
+    C: CharFilter,
+    T: TokenFilter,
+    I: Tokenizer,
+    C2: CharFilter,
+    T2: TokenFilter,
+    I2: Tokenizer,
+{
+    let id = doc_id(builder, &data)?;
 
-        builder.finish().unwrap();
+    match builder.update_document(data.document(id)) {
+        Ok(()) => debug!(id, title = %data.title, "document updated"),
+        Err(e @ store::Error::KeyConflict(..)) => warn!(id, error = %e, "document skipped"),
+        Err(e) => return Err(e),
+    }
+
+    Ok(())
+}
 
-        print_time_cost!("build indexes", time);
-    });
+/// 按key删除时key还没有用过就什么都不做
+fn delete_document<C, T, I, C2, T2, I2>(
+    builder: &mut store::Builder<C, T, I, C2, T2, I2>,
+    data: DeletePostReq,
+) -> store::Result<()>
+where
+    C: CharFilter,
+    T: TokenFilter,
+    I: Tokenizer,
+    C2: CharFilter,
+    T2: TokenFilter,
+    I2: Tokenizer,
+{
+    let id = match (data.id, &data.key) {
+        (Some(id), _) => id,
+        (None, Some(key)) => match builder.find_key(key)? {
+            Some(id) => id,
+            None => {
+                debug!(%key, "unknown key, nothing to delete");
+                return Ok(());
+            }
+        },
+        (None, None) => unreachable!("/delete requires id or key"),
+    };
 
-    (builder_thread, tx)
+    builder.delete_document(id);
+    debug!(id, "document deleted");
+    Ok(())
 }
 
+/// 请求路径里可以在这些名字前面加上/{identifier}选择索引
+const ROUTES: &[&str] = &[
+    "add",
+    "add_batch",
+    "bulk",
+    "update",
+    "delete",
+    "stats",
+    "finish",
+];
+
 pub struct BuildService {
-    pub tx: mpsc::Sender<BuildServiceTask>,
+    pub builders: Arc<Builders>,
 }
 
 type SvcResponse = Response<Body>;
@@ -101,60 +519,840 @@ impl Service<Request<Body>> for BuildService {
     }
 
     fn call(&mut self, req: Request<Body>) -> Self::Future {
-        let (parts, mut body) = req.into_parts();
-        match (parts.method, parts.uri.path()) {
-            (Method::POST, "/add") => {
-                let tx = self.tx.clone();
-                Box::pin(async move {
-                    let body: serde_json::Result<AddPostReq> =
-                        serde_json::from_slice(&body.next().await.unwrap().unwrap());
-
-                    match body {
-                        Ok(data) => {
-                            tx.send(BuildServiceTask { data: Some(data) }).unwrap();
-                            Ok(Response::builder()
-                                .status(StatusCode::OK)
-                                .body(Body::empty())
-                                .unwrap())
-                        }
+        let (parts, body) = req.into_parts();
+        let (identifier, path) = split_identifier(parts.uri.path(), ROUTES);
+        let identifier = identifier.map(str::to_string);
+        let builders = self.builders.clone();
+
+        match (parts.method, path) {
+            (Method::POST, "/add") => Box::pin(async move {
+                // 大一点的文档会分成好几个chunk，parse_body会收完整个body再解析
+                let data: AddPostReq = match parse_body(body).await {
+                    Ok(data) => data,
+                    Err(resp) => return Ok(resp),
+                };
+                if let Some(resp) = check_docs([&data]) {
+                    return Ok(resp);
+                }
+
+                let (tx, status) = match find_builder(builders, identifier, true).await {
+                    Ok(builder) => builder,
+                    Err(resp) => return Ok(resp),
+                };
+
+                Ok(send_task(
+                    &tx,
+                    &status,
+                    BuildServiceTask::Add(data),
+                    Body::empty(),
+                ))
+            }),
+
+            (Method::POST, "/add_batch") => Box::pin(async move {
+                // 任何一篇解析失败整批都不接受
+                let batch: Vec<AddPostReq> = match parse_body(body).await {
+                    Ok(batch) => batch,
+                    Err(resp) => return Ok(resp),
+                };
+                if let Some(resp) = check_docs(&batch) {
+                    return Ok(resp);
+                }
+
+                let (tx, status) = match find_builder(builders, identifier, true).await {
+                    Ok(builder) => builder,
+                    Err(resp) => return Ok(resp),
+                };
+
+                let resp = serde_json::to_vec(&AddBatchResp {
+                    accepted: batch.len(),
+                })
+                .unwrap();
+                let task = BuildServiceTask::AddBatch(batch);
+                Ok(send_task(&tx, &status, task, Body::from(resp)))
+            }),
+
+            // 每行一个和/add一样的json，边收边每BUILD_BATCH_SIZE行发一次AddBatch，不用把整个body放在内存里。
+            // 解析失败或者不满足validate的行跳过，在返回的rejected和errors里，其他行照常添加
+            (Method::POST, "/bulk") => Box::pin(async move {
+                let (tx, status) = match find_builder(builders, identifier, true).await {
+                    Ok(builder) => builder,
+                    Err(resp) => return Ok(resp),
+                };
+
+                let mut bulk = BulkReader::new();
+                let mut body = body;
+                while let Some(chunk) = body.next().await {
+                    // 已经发出去的行不能撤回，客户端可以按id或者key重试整个请求
+                    let chunk = match chunk {
+                        Ok(chunk) => chunk,
                         Err(e) => {
-                            eprintln!("bad request: {}", e);
-                            Ok(Response::builder()
-                                .status(StatusCode::BAD_REQUEST)
-                                .body(Body::empty())
-                                .unwrap())
+                            warn!(error = %e, accepted = bulk.resp.accepted, "bulk request interrupted");
+                            return Ok(response(StatusCode::BAD_REQUEST, Body::empty()));
+                        }
+                    };
+
+                    for batch in bulk.feed(&chunk) {
+                        if tx.send(BuildServiceTask::AddBatch(batch)).is_err() {
+                            return Ok(closed_response(&status));
                         }
                     }
-                })
-            }
+                }
 
-            (Method::GET, "/finish") => {
-                self.tx.send(BuildServiceTask { data: None }).unwrap();
-                Box::pin(async {
-                    Ok(Response::builder()
-                        .status(StatusCode::OK)
-                        .body(Body::empty())
-                        .unwrap())
-                })
-            }
+                let (batch, resp) = bulk.finish();
+                let resp = serde_json::to_vec(&resp).unwrap();
+                let task = BuildServiceTask::AddBatch(batch);
+                Ok(send_task(&tx, &status, task, Body::from(resp)))
+            }),
 
-            _ => Box::pin(async {
-                Ok(Response::builder()
-                    .status(StatusCode::NOT_FOUND)
-                    .body(Body::empty())
-                    .unwrap())
+            // 和/add的body一样，id不存在时相当于/add，所以重试是安全的
+            (Method::POST, "/update") => Box::pin(async move {
+                let data: AddPostReq = match parse_body(body).await {
+                    Ok(data) => data,
+                    Err(resp) => return Ok(resp),
+                };
+                if let Some(resp) = check_docs([&data]) {
+                    return Ok(resp);
+                }
+
+                let (tx, status) = match find_builder(builders, identifier, true).await {
+                    Ok(builder) => builder,
+                    Err(resp) => return Ok(resp),
+                };
+
+                let task = BuildServiceTask::Update(data);
+                Ok(send_task(&tx, &status, task, Body::empty()))
             }),
+
+            (Method::POST, "/delete") => Box::pin(async move {
+                let data: DeletePostReq = match parse_body(body).await {
+                    Ok(data) => data,
+                    Err(resp) => return Ok(resp),
+                };
+                if data.id.is_none() && data.key.is_none() {
+                    warn!("bad request: /delete requires id or key");
+                    return Ok(response(StatusCode::BAD_REQUEST, Body::empty()));
+                }
+
+                let (tx, status) = match find_builder(builders, identifier, true).await {
+                    Ok(builder) => builder,
+                    Err(resp) => return Ok(resp),
+                };
+
+                let task = BuildServiceTask::Delete(data);
+                Ok(send_task(&tx, &status, task, Body::empty()))
+            }),
+
+            (Method::GET, "/stats") => Box::pin(async move {
+                let (_, status) = match find_builder(builders, identifier, false).await {
+                    Ok(builder) => builder,
+                    Err(resp) => return Ok(resp),
+                };
+
+                let resp = serde_json::to_vec(&status.stats()).unwrap();
+                Ok(response(StatusCode::OK, Body::from(resp)))
+            }),
+
+            (Method::GET, "/finish") => Box::pin(async move {
+                let (tx, status) = match find_builder(builders, identifier, false).await {
+                    Ok(builder) => builder,
+                    Err(resp) => return Ok(resp),
+                };
+
+                Ok(send_task(&tx, &status, BuildServiceTask::Finish, Body::empty()))
+            }),
+
+            _ => Box::pin(async { Ok(response(StatusCode::NOT_FOUND, Body::empty())) }),
         }
     }
 }
 
-pub struct BuildServiceTask {
-    data: Option<AddPostReq>,
+/// 找到identifier对应的builder线程，start为true时没有就启动一个，否则返回404。
+/// identifier不合法时返回400，启动失败时返回500
+async fn find_builder(
+    builders: Arc<Builders>,
+    identifier: Option<String>,
+    start: bool,
+) -> Result<(mpsc::Sender<BuildServiceTask>, Arc<BuildStatus>), SvcResponse> {
+    if let Some(builder) = builders.get(identifier.as_deref()) {
+        return Ok(builder);
+    }
+
+    let identifier = match identifier {
+        Some(identifier) if start && is_valid_identifier(&identifier) => identifier,
+        Some(identifier) if start => {
+            warn!(?identifier, "bad request: invalid identifier");
+            return Err(response(StatusCode::BAD_REQUEST, Body::empty()));
+        }
+        _ => return Err(response(StatusCode::NOT_FOUND, Body::empty())),
+    };
+
+    match tokio::task::spawn_blocking(move || builders.get_or_start(&identifier)).await {
+        Ok(Ok(builder)) => Ok(builder),
+        Ok(Err(e)) => {
+            error!(error = %e, "failed to start builder");
+            Err(response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Body::from(format!("index build failed: {}", e)),
+            ))
+        }
+        Err(e) => {
+            error!(error = %e, "builder task failed");
+            Err(response(StatusCode::INTERNAL_SERVER_ERROR, Body::empty()))
+        }
+    }
+}
+
+/// 任何一篇不满足AddPostReq::validate就返回要回给客户端的400，整批都不接受
+fn check_docs<'a>(docs: impl IntoIterator<Item = &'a AddPostReq>) -> Option<SvcResponse> {
+    for doc in docs {
+        if let Err(e) = doc.validate() {
+            warn!(error = %e, "bad request");
+            return Some(response(StatusCode::BAD_REQUEST, Body::empty()));
+        }
+    }
+
+    None
+}
+
+/// 读取整个body并解析成json。客户端中途断开或者格式不对都返回400
+async fn parse_body<T: DeserializeOwned>(body: Body) -> Result<T, SvcResponse> {
+    let body = hyper::body::to_bytes(body).await.map_err(|e| {
+        warn!(error = %e, "bad request");
+        response(StatusCode::BAD_REQUEST, Body::empty())
+    })?;
+
+    serde_json::from_slice(&body).map_err(|e| {
+        warn!(error = %e, "bad request");
+        response(StatusCode::BAD_REQUEST, Body::empty())
+    })
+}
+
+/// 把任务交给builder线程，成功时返回200和body。builder线程已经退出时channel是关闭的：
+/// 出过错返回500，否则是已经finish了，返回409
+fn send_task(
+    tx: &mpsc::Sender<BuildServiceTask>,
+    status: &BuildStatus,
+    task: BuildServiceTask,
+    body: Body,
+) -> SvcResponse {
+    if tx.send(task).is_ok() {
+        return response(StatusCode::OK, body);
+    }
+
+    closed_response(status)
+}
+
+/// builder线程已经退出时的响应，见send_task
+fn closed_response(status: &BuildStatus) -> SvcResponse {
+    match status.error() {
+        Some(e) => response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Body::from(format!("index build failed: {}", e)),
+        ),
+        None => response(StatusCode::CONFLICT, Body::from("index is closed")),
+    }
+}
+
+#[inline]
+fn response(status: StatusCode, body: Body) -> SvcResponse {
+    Response::builder().status(status).body(body).unwrap()
+}
+
+pub enum BuildServiceTask {
+    Add(AddPostReq),
+    /// 一次请求里的多篇文档，只占用一次channel发送
+    AddBatch(Vec<AddPostReq>),
+    /// 替换同一个id的旧文档，见Builder::update_document
+    Update(AddPostReq),
+    /// 只是标记删除，见Builder::delete_document
+    Delete(DeletePostReq),
+    Finish,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct AddPostReq {
-    id: u32,
+    /// 和key至少要有一个，只有key时由builder分配，同一个key总是分配到同一个id
+    #[serde(default)]
+    id: Option<u32>,
+    /// 外部id，比如url或者数据库的主键，同一个key不能用在两个id上
+    #[serde(default)]
+    key: Option<String>,
+    /// title和content可以是字符串数组，作为多值字段，每个值单独分词。
+    /// 存下来的是用store::VALUE_SEPARATOR连起来的字符串，/document等返回的也是它
+    #[serde(deserialize_with = "deserialize_values")]
     title: String,
+    #[serde(deserialize_with = "deserialize_values")]
     content: String,
+    /// {"category": "武器"}这样的keyword字段，不分词，用于/facet统计
+    #[serde(default)]
+    keywords: BTreeMap<String, String>,
+    /// {"price": 39.9}这样的数值字段，用于/search的range过滤
+    #[serde(default)]
+    numbers: BTreeMap<String, f64>,
+    /// 任意json，原样存下来，/document和/search（"payload": true）时返回，不参与搜索
+    #[serde(default)]
+    payload: Option<serde_json::Value>,
+}
+
+fn deserialize_values<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Values {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match Values::deserialize(deserializer)? {
+        Values::One(value) => value,
+        Values::Many(values) => values.join(&store::VALUE_SEPARATOR.to_string()),
+    })
+}
+
+/// payload序列化之后的最大字节数，超过时/add等接口返回400
+pub const MAX_PAYLOAD_SIZE: usize = 64 * 1024;
+
+impl AddPostReq {
+    /// 检查serde检查不了的限制
+    fn validate(&self) -> Result<(), String> {
+        let name = match (self.id, self.key.as_deref()) {
+            (Some(id), _) => id.to_string(),
+            (None, Some(key)) if !key.is_empty() => format!("{:?}", key),
+            _ => return Err("document requires id or non-empty key".to_string()),
+        };
+
+        if let Some(payload) = &self.payload {
+            let size = serde_json::to_vec(payload).map_or(usize::MAX, |p| p.len());
+            if size > MAX_PAYLOAD_SIZE {
+                return Err(format!(
+                    "payload of document {} is {} bytes, larger than {}",
+                    name, size, MAX_PAYLOAD_SIZE
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// id是请求里的id，或者按key分配的id，见doc_id
+    fn document(&self, id: u32) -> Document<'_> {
+        Document {
+            id,
+            title: self.title.as_str(),
+            content: self.content.as_str(),
+            keywords: self
+                .keywords
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str()))
+                .collect(),
+            numbers: self
+                .numbers
+                .iter()
+                .map(|(name, value)| (name.as_str(), *value))
+                .collect(),
+            payload: self.payload.as_ref(),
+            key: self.key.as_deref(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct AddBatchResp {
+    accepted: usize,
+}
+
+#[derive(Serialize, Debug, Default)]
+struct BulkResp {
+    accepted: usize,
+    rejected: usize,
+    /// 最多MAX_BULK_ERRORS个，rejected比它多时后面的只计数
+    errors: Vec<BulkLineError>,
+}
+
+#[derive(Serialize, Debug)]
+struct BulkLineError {
+    /// 从1开始的行号
+    line: usize,
+    error: String,
+}
+
+/// /bulk的响应里最多列出这么多行的错误
+const MAX_BULK_ERRORS: usize = 100;
+
+/// 把/bulk的body按行切开解析，chunk的边界可以在一行中间。空行忽略
+#[derive(Debug)]
+struct BulkReader {
+    /// 还没有遇到换行符的部分
+    pending: Vec<u8>,
+    line: usize,
+    batch: Vec<AddPostReq>,
+    resp: BulkResp,
+}
+
+impl BulkReader {
+    fn new() -> Self {
+        BulkReader {
+            pending: Vec::new(),
+            line: 0,
+            batch: Vec::with_capacity(BUILD_BATCH_SIZE),
+            resp: BulkResp::default(),
+        }
+    }
+
+    /// 返回这次攒满BUILD_BATCH_SIZE篇的批次
+    fn feed(&mut self, chunk: &[u8]) -> Vec<Vec<AddPostReq>> {
+        let mut full = Vec::new();
+        let mut rest = chunk;
+
+        while let Some(end) = rest.iter().position(|b| *b == b'\n') {
+            let line = if self.pending.is_empty() {
+                self.parse_line(&rest[..end])
+            } else {
+                let mut line = std::mem::take(&mut self.pending);
+                line.extend_from_slice(&rest[..end]);
+                self.parse_line(&line)
+            };
+            rest = &rest[end + 1..];
+
+            if let Some(doc) = line {
+                self.batch.push(doc);
+                if self.batch.len() >= BUILD_BATCH_SIZE {
+                    let batch = Vec::with_capacity(BUILD_BATCH_SIZE);
+                    full.push(std::mem::replace(&mut self.batch, batch));
+                }
+            }
+        }
+
+        self.pending.extend_from_slice(rest);
+        full
+    }
+
+    /// 最后一行可以没有换行符，返回剩下不满一批的文档和响应
+    fn finish(mut self) -> (Vec<AddPostReq>, BulkResp) {
+        let pending = std::mem::take(&mut self.pending);
+        if let Some(doc) = self.parse_line(&pending) {
+            self.batch.push(doc);
+        }
+
+        (self.batch, self.resp)
+    }
+
+    fn parse_line(&mut self, line: &[u8]) -> Option<AddPostReq> {
+        self.line += 1;
+        if line.iter().all(u8::is_ascii_whitespace) {
+            return None;
+        }
+
+        let doc = serde_json::from_slice::<AddPostReq>(line)
+            .map_err(|e| e.to_string())
+            .and_then(|doc| doc.validate().map(|()| doc));
+
+        match doc {
+            Ok(doc) => {
+                self.resp.accepted += 1;
+                Some(doc)
+            }
+            Err(error) => {
+                debug!(line = self.line, %error, "bulk line rejected");
+                self.resp.rejected += 1;
+                if self.resp.errors.len() < MAX_BULK_ERRORS {
+                    self.resp.errors.push(BulkLineError {
+                        line: self.line,
+                        error,
+                    });
+                }
+                None
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeletePostReq {
+    /// 和key至少要有一个，都有时按id删除
+    #[serde(default)]
+    id: Option<u32>,
+    #[serde(default)]
+    key: Option<String>,
+}
+
+/// 离线从目录导入时，单个文件里标题和正文的分隔方式
+#[derive(Debug, Clone)]
+pub enum DocumentFormat {
+    /// 第一行是标题，剩下的是正文，文件名（不含扩展名）是doc id
+    FirstLine,
+    /// 用分隔符分开标题和正文，文件名（不含扩展名）是doc id
+    Separator(String),
+    /// 整个文件是一个和/add接口一样的json
+    Json,
+}
+
+impl DocumentFormat {
+    fn parse(&self, path: &Path, text: &str) -> Result<AddPostReq, String> {
+        let id = || -> Result<u32, String> {
+            path.file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse().ok())
+                .ok_or_else(|| "file name is not a valid doc id".to_string())
+        };
+
+        let (title, content) = match self {
+            DocumentFormat::Json => {
+                let doc: AddPostReq = serde_json::from_str(text).map_err(|e| e.to_string())?;
+                doc.validate()?;
+                return Ok(doc);
+            }
+            DocumentFormat::FirstLine => text.split_once('\n').unwrap_or((text, "")),
+            DocumentFormat::Separator(sep) => text
+                .split_once(sep.as_str())
+                .ok_or_else(|| format!("separator {:?} not found", sep))?,
+        };
+
+        Ok(AddPostReq {
+            id: Some(id()?),
+            key: None,
+            title: title.trim().to_string(),
+            content: content.to_string(),
+            keywords: BTreeMap::new(),
+            numbers: BTreeMap::new(),
+            payload: None,
+        })
+    }
+}
+
+/// build_from_dir每攒够这么多篇文档发一次AddBatch，一批里的文档会并行分词
+const BUILD_BATCH_SIZE: usize = 256;
+
+/// 不启动http服务，直接把目录下的每个文件当作一篇文档建索引
+pub async fn build_from_dir(
+    dir: &Path,
+    format: DocumentFormat,
+    options: BuildOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (task, tx, status) = start_builder_thread(options)?;
+    let mut batch = Vec::with_capacity(BUILD_BATCH_SIZE);
+
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+
+        let doc = read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|text| format.parse(&path, &text));
+
+        match doc {
+            Ok(data) => batch.push(data),
+            Err(e) => warn!(path = %path.display(), error = %e, "file skipped"),
+        }
+
+        if batch.len() >= BUILD_BATCH_SIZE {
+            let full = std::mem::replace(&mut batch, Vec::with_capacity(BUILD_BATCH_SIZE));
+            // builder线程出错退出了，错误在下面从status里取
+            if tx.send(BuildServiceTask::AddBatch(full)).is_err() {
+                break;
+            }
+        }
+    }
+
+    if !batch.is_empty() {
+        let _ = tx.send(BuildServiceTask::AddBatch(batch));
+    }
+    let _ = tx.send(BuildServiceTask::Finish);
+    task.await?;
+
+    if let Some(e) = status.error() {
+        return Err(e.into());
+    }
+
+    Ok(())
+}
+
+/// 不启动http服务，把索引的所有segment合并成一个，去掉已经删除的文档，见store::Builder::compact。
+/// 合并不需要分词，用默认的analyzer，不读配置里的停用词等文件
+pub async fn compact(
+    options: BuildOptions,
+) -> Result<store::CompactStats, Box<dyn std::error::Error>> {
+    let BuildOptions {
+        store_dir,
+        identifier,
+        settings,
+        ..
+    } = options;
+
+    let analyzer = || {
+        AnalyzerSettings::default()
+            .build()
+            .map_err(BuildError::Analyzer)
+    };
+    let (title_analyzer, content_analyzer) = (analyzer()?, analyzer()?);
+
+    let stats = tokio::task::spawn_blocking(move || {
+        let _span = trace_span!("compact", %identifier).entered();
+
+        let mut builder = store::Builder::new(
+            title_analyzer,
+            content_analyzer,
+            store::Config::new(store_dir, identifier.as_str(), settings.min_norm_len),
+        );
+        builder.compact()
+    })
+    .await??;
+
+    Ok(stats)
+}
+
+/// reindex每处理这么多篇文档打一条进度日志
+const REINDEX_PROGRESS_INTERVAL: u32 = 10000;
+
+/// 不启动http服务，用options里的analyzer把已有索引doc store里的文档重新建一遍索引，返回重新添加的文档数。
+/// 见store::Builder::reindex
+pub async fn reindex(options: BuildOptions) -> Result<u32, Box<dyn std::error::Error>> {
+    let BuildOptions {
+        store_dir,
+        identifier,
+        settings,
+        ..
+    } = options;
+
+    let span = trace_span!("init_analyzer").entered();
+    let title_analyzer = settings
+        .title_analyzer
+        .build()
+        .map_err(BuildError::Analyzer)?;
+    let content_analyzer = settings
+        .content_analyzer
+        .build()
+        .map_err(BuildError::Analyzer)?;
+    span.exit();
+
+    let count = tokio::task::spawn_blocking(move || {
+        let _span = trace_span!("reindex", %identifier).entered();
+
+        let mut builder = store::Builder::new(
+            title_analyzer,
+            content_analyzer,
+            store::Config::new(store_dir, identifier.as_str(), settings.min_norm_len)
+                .with_position_gap(settings.position_gap),
+        );
+
+        let count = builder.reindex(|count| {
+            if count % REINDEX_PROGRESS_INTERVAL == 0 {
+                info!(%identifier, count, "reindexing");
+            }
+        })?;
+        info!(%identifier, count, "reindexed");
+
+        Ok::<_, store::Error>(count)
+    })
+    .await??;
+
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::query::open_query;
+    use crate::settings::QuerySettings;
+
+    async fn call(service: &mut BuildService, method: Method, uri: &str, body: Body) -> StatusCode {
+        let req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(body)
+            .unwrap();
+        service.call(req).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn add_body_spanning_many_chunks() {
+        let store_dir =
+            std::env::temp_dir().join(format!("shogun-build-test-{}", std::process::id()));
+        let options = BuildOptions {
+            store_dir: store_dir.clone(),
+            identifier: "chunks".to_string(),
+            settings: BuildSettings {
+                content_analyzer: AnalyzerSettings::default(),
+                ..BuildSettings::default()
+            },
+            merge: false,
+        };
+        let (builders, task) = Builders::start(options).unwrap();
+        let mut service = BuildService {
+            builders: Arc::new(builders),
+        };
+
+        // 远大于一次读到的数据，按固定长度切开，切点会落在多字节字符中间
+        let content = "雷电将军 raiden shogun 稻妻\n".repeat(4000);
+        let body = serde_json::to_vec(&serde_json::json!({
+            "id": 7,
+            "title": "一心净土",
+            "content": content,
+        }))
+        .unwrap();
+        let (mut sender, req_body) = Body::channel();
+        let send = tokio::spawn(async move {
+            for chunk in body.chunks(4093) {
+                sender.send_data(chunk.to_vec().into()).await.unwrap();
+            }
+        });
+        assert_eq!(
+            call(&mut service, Method::POST, "/add", req_body).await,
+            StatusCode::OK
+        );
+        send.await.unwrap();
+
+        assert_eq!(
+            call(&mut service, Method::GET, "/finish", Body::empty()).await,
+            StatusCode::OK
+        );
+        task.await.unwrap();
+
+        let query = open_query(store_dir.clone(), "chunks", &QuerySettings::default()).unwrap();
+        let doc = query.get_document(7).unwrap();
+        std::fs::remove_dir_all(&store_dir).unwrap();
+        assert_eq!(doc.title, "一心净土");
+        assert_eq!(doc.content, content);
+    }
+
+    #[test]
+    fn title_and_content_accept_several_values() {
+        let data: AddPostReq = serde_json::from_value(serde_json::json!({
+            "id": 1,
+            "title": "稻妻",
+            "content": ["雷电将军", "raiden shogun"],
+        }))
+        .unwrap();
+
+        assert_eq!(data.title, "稻妻");
+        assert_eq!(
+            data.content,
+            format!("雷电将军{}raiden shogun", store::VALUE_SEPARATOR)
+        );
+    }
+
+    #[tokio::test]
+    async fn finished_builder_restarts_on_next_write() {
+        let store_dir =
+            std::env::temp_dir().join(format!("shogun-restart-test-{}", std::process::id()));
+        let options = BuildOptions {
+            store_dir: store_dir.clone(),
+            identifier: "main".to_string(),
+            settings: BuildSettings {
+                content_analyzer: AnalyzerSettings::default(),
+                ..BuildSettings::default()
+            },
+            merge: false,
+        };
+        let (builders, task) = Builders::start(options).unwrap();
+        let builders = Arc::new(builders);
+        let mut service = BuildService {
+            builders: builders.clone(),
+        };
+        let add = |id: u32| {
+            let body = serde_json::json!({ "id": id, "title": "稻妻", "content": "雷电将军" });
+            Body::from(body.to_string())
+        };
+
+        for id in 1..=2 {
+            assert_eq!(
+                call(&mut service, Method::POST, "/other/add", add(id)).await,
+                StatusCode::OK
+            );
+            assert_eq!(
+                call(&mut service, Method::GET, "/other/finish", Body::empty()).await,
+                StatusCode::OK
+            );
+            // 线程写完segment退出之前仍然是409，退出后从表里去掉
+            while builders.get(Some("other")).is_some() {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        }
+
+        assert_eq!(
+            call(&mut service, Method::GET, "/finish", Body::empty()).await,
+            StatusCode::OK
+        );
+        task.await.unwrap();
+
+        let query = open_query(store_dir.clone(), "other", &QuerySettings::default()).unwrap();
+        let found = [query.get_document(1).is_ok(), query.get_document(2).is_ok()];
+        std::fs::remove_dir_all(&store_dir).unwrap();
+        assert_eq!(found, [true, true]);
+    }
+
+    type TestBuilder = store::Builder<
+        analyzer::registry::AnyCharFilter,
+        analyzer::registry::AnyTokenFilter,
+        analyzer::registry::AnyTokenizer,
+        analyzer::registry::AnyCharFilter,
+        analyzer::registry::AnyTokenFilter,
+        analyzer::registry::AnyTokenizer,
+    >;
+
+    fn memory_builder(directory: &store::Directory) -> TestBuilder {
+        let settings = AnalyzerSettings::default();
+        store::Builder::new(
+            settings.build().unwrap(),
+            settings.build().unwrap(),
+            store::Config::new(directory.clone(), "t", 1),
+        )
+    }
+
+    /// Memory目录里的所有文件
+    fn files(directory: &store::Directory) -> BTreeMap<String, Vec<u8>> {
+        match directory {
+            store::Directory::Memory(files) => files
+                .read()
+                .unwrap()
+                .iter()
+                .map(|(name, data)| (name.clone(), data.to_vec()))
+                .collect(),
+            store::Directory::Fs(_) => unreachable!(),
+        }
+    }
+
+    fn requests(ids: std::ops::Range<usize>) -> Vec<AddPostReq> {
+        ids.map(|i| {
+            serde_json::from_value(serde_json::json!({
+                "id": i,
+                "title": format!("雷电将军 第{}章", i),
+                "content": format!(
+                    "稻妻城的天守阁 raiden shogun {} 眼狩令 {}",
+                    "一心净土 ".repeat(i % 7),
+                    i * 31 % 101,
+                ),
+                "keywords": {"chapter": (i % 5).to_string()},
+                "numbers": {"page": i},
+            }))
+            .unwrap()
+        })
+        .collect()
+    }
+
+    /// 并行分词不能影响结果：同样的输入建两次，和逐个添加建出来的文件逐字节相同
+    #[test]
+    fn batch_builds_are_byte_identical() {
+        let build = |batch: bool| {
+            let directory = store::Directory::memory();
+            let mut builder = memory_builder(&directory);
+            if batch {
+                for start in (0..300).step_by(64) {
+                    add_batch(&mut builder, requests(start..300.min(start + 64))).unwrap();
+                }
+            } else {
+                for data in requests(0..300) {
+                    add_document(&mut builder, data).unwrap();
+                }
+            }
+            builder.finish().unwrap();
+            files(&directory)
+        };
+
+        let first = build(true);
+        assert!(first.keys().any(|name| name.ends_with(".musou")));
+        assert_eq!(first, build(true));
+        assert_eq!(first, build(false));
+    }
 }
@@ -1 +1,23 @@
 pub mod build;
+pub mod query;
+
+/// 一个服务可以处理多个索引，请求路径是/{identifier}/search这样的。
+/// 第一段是routes里的名字时是不带identifier的路径，返回None，由调用方使用默认的索引
+fn split_identifier<'p>(path: &'p str, routes: &[&str]) -> (Option<&'p str>, &'p str) {
+    let first = path[1..].split('/').next().unwrap_or("");
+    let rest = &path[(1 + first.len()).min(path.len())..];
+
+    if routes.contains(&first) || rest.is_empty() {
+        (None, path)
+    } else {
+        (Some(first), rest)
+    }
+}
+
+/// identifier会用作索引目录里的文件名前缀，只允许字母、数字、_和-
+pub fn is_valid_identifier(identifier: &str) -> bool {
+    !identifier.is_empty()
+        && identifier
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+}
@@ -0,0 +1,650 @@
+use crate::analyzer::registry::{AnyCharFilter, AnyTokenFilter, AnyTokenizer};
+use crate::query;
+use crate::query::{
+    parse_query, Deadline, Explanation, Field, Fuzziness, MinShouldMatch, NumericRange, Occur,
+    Query, QueryOptions, SortBy,
+};
+use crate::service::{is_valid_identifier, split_identifier};
+use crate::settings::QuerySettings;
+use crate::store::segment::segments_file_name;
+use futures::Future;
+use hyper::service::Service;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tracing::{error, trace_span, warn};
+
+pub type SearchQuery = Query<AnyCharFilter, AnyTokenFilter, AnyTokenizer>;
+
+/// analyzer、权重、相关性算法和缓存大小都来自settings
+pub fn open_query(
+    store_dir: PathBuf,
+    identifier: &str,
+    settings: &QuerySettings,
+) -> query::Result<SearchQuery> {
+    Query::new(
+        settings.analyzer.build()?,
+        query::Config::new(
+            store_dir,
+            identifier,
+            settings.boost_title,
+            settings.boost_content,
+            settings.similarity.into(),
+        )
+        .with_cache_capacity(settings.cache_capacity),
+    )
+}
+
+/// 一个查询服务可以处理store_dir下的多个索引。索引在第一次被查询时才打开，
+/// 打开的数量超过settings.max_open_indexes时关掉最久没有查询过的。
+/// 建索引服务写完新的segment后索引会被重新打开，见QueryIndexes::get
+pub struct QueryIndexes {
+    store_dir: PathBuf,
+    /// 请求路径里没有identifier时用的索引
+    default_identifier: String,
+    settings: QuerySettings,
+    open: Mutex<OpenIndexes>,
+}
+
+#[derive(Default)]
+struct OpenIndexes {
+    /// 每次访问加一，越大越新
+    tick: u64,
+    /// identifier -> (Query, 打开时的.segments文件内容, 访问时间)
+    entries: HashMap<String, (Arc<SearchQuery>, Vec<u8>, u64)>,
+    /// tick -> identifier，最小的是最久没有访问的
+    order: BTreeMap<u64, String>,
+}
+
+impl QueryIndexes {
+    pub fn new(store_dir: PathBuf, default_identifier: String, settings: QuerySettings) -> Self {
+        QueryIndexes {
+            store_dir,
+            default_identifier,
+            settings,
+            open: Mutex::new(OpenIndexes::default()),
+        }
+    }
+
+    /// identifier为None时是默认的索引。identifier不合法或者索引还没有建过时返回Error::IndexNotFound。
+    /// 打开索引（主要是创建analyzer）比较慢，应该在blocking线程里调用。打开期间不持有锁，
+    /// 同一个索引同时被第一次查询时可能会各打开一次，只有先放进来的那个会被保留。
+    ///
+    /// 每次都读一遍.segments文件（只有segment编号，很小），和打开时的不一样说明建索引服务
+    /// 写了新的segment或者合并过，这时重新打开，旧的Query和它的查询缓存一起丢掉
+    pub fn get(&self, identifier: Option<&str>) -> query::Result<Arc<SearchQuery>> {
+        let identifier = identifier.unwrap_or(&self.default_identifier);
+
+        if !is_valid_identifier(identifier) {
+            return Err(query::Error::IndexNotFound(identifier.to_string()));
+        }
+
+        // 在打开Query之前读，中间索引又变了的话下一次查询会再打开一次
+        let manifest = match std::fs::read(self.store_dir.join(segments_file_name(identifier))) {
+            Ok(manifest) => manifest,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(query::Error::IndexNotFound(identifier.to_string()))
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        if let Some(query) = self.lock().touch(identifier, &manifest) {
+            return Ok(query);
+        }
+
+        let query = Arc::new(open_query(
+            self.store_dir.clone(),
+            identifier,
+            &self.settings,
+        )?);
+
+        let mut open = self.lock();
+        if let Some(query) = open.touch(identifier, &manifest) {
+            return Ok(query);
+        }
+
+        open.tick += 1;
+        let tick = open.tick;
+        open.entries
+            .insert(identifier.to_string(), (query.clone(), manifest, tick));
+        open.order.insert(tick, identifier.to_string());
+
+        // 正在进行的查询还持有Arc，关掉的索引等它们结束后才真正释放
+        if open.entries.len() > self.settings.max_open_indexes.max(1) {
+            if let Some((_, oldest)) = open.order.pop_first() {
+                open.entries.remove(&oldest);
+            }
+        }
+
+        Ok(query)
+    }
+
+    /// 从现在开始计时的deadline，取settings.timeout_ms和请求里的timeout_ms中更短的那个，都没有设置时不限制
+    fn deadline(&self, timeout_ms: Option<u64>) -> Deadline {
+        let timeout_ms = match (self.settings.timeout_ms, timeout_ms) {
+            (0, timeout_ms) => timeout_ms,
+            (limit, timeout_ms) => Some(timeout_ms.map_or(limit, |t| t.min(limit))),
+        };
+
+        timeout_ms.map_or_else(Deadline::default, |t| {
+            Deadline::after(Duration::from_millis(t))
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, OpenIndexes> {
+        // 持有锁时只操作map，不会panic在一半
+        self.open.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl OpenIndexes {
+    /// 已经打开时更新访问时间并返回。打开时的.segments文件和manifest不一样时关掉它，返回None
+    fn touch(&mut self, identifier: &str, manifest: &[u8]) -> Option<Arc<SearchQuery>> {
+        self.tick += 1;
+        let tick = self.tick;
+
+        let (query, opened, last) = self.entries.get_mut(identifier)?;
+        if opened.as_slice() != manifest {
+            self.order.remove(last);
+            self.entries.remove(identifier);
+            return None;
+        }
+
+        self.order.remove(last);
+        self.order.insert(tick, identifier.to_string());
+        *last = tick;
+
+        Some(query.clone())
+    }
+}
+
+/// 请求路径里可以在这些名字前面加上/{identifier}选择索引
+const ROUTES: &[&str] = &["search", "explain", "facet", "health", "stats", "document"];
+
+/// Query的查询方法都只需要&self，所有请求共享同一组打开的Query并发查询
+pub struct QueryService {
+    pub indexes: Arc<QueryIndexes>,
+}
+
+type SvcResponse = Response<Body>;
+type SvcError = hyper::Error;
+type SvcFuture = dyn Future<Output = Result<SvcResponse, SvcError>> + Send;
+
+impl Service<Request<Body>> for QueryService {
+    type Response = Response<Body>;
+    type Error = hyper::Error;
+    type Future = Pin<Box<SvcFuture>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Ok(()).into()
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let (parts, body) = req.into_parts();
+        let (identifier, path) = split_identifier(parts.uri.path(), ROUTES);
+        let identifier = identifier.map(str::to_string);
+        let indexes = self.indexes.clone();
+
+        match (parts.method, path) {
+            (Method::POST, "/search") => Box::pin(async move {
+                let req: SearchReq = match parse_body(body).await? {
+                    Ok(req) => req,
+                    Err(resp) => return Ok(resp),
+                };
+                let range = match req.range() {
+                    Ok(range) => range,
+                    Err(e) => {
+                        warn!(error = %e, "bad request");
+                        return Ok(response(StatusCode::BAD_REQUEST, Body::empty()));
+                    }
+                };
+
+                // 客户端断开时hyper会drop这个future，cancel跟着drop，blocking线程里的查询就会提前结束
+                let cancel = CancelOnDrop::default();
+                let deadline = indexes
+                    .deadline(req.timeout_ms)
+                    .with_cancel_flag(cancel.0.clone());
+                let index = move || indexes.get(identifier.as_deref());
+
+                Ok(run_query(move || search(&*index()?, &req, range, deadline)).await)
+            }),
+
+            (Method::POST, "/explain") => Box::pin(async move {
+                let req: ExplainReq = match parse_body(body).await? {
+                    Ok(req) => req,
+                    Err(resp) => return Ok(resp),
+                };
+
+                Ok(run_query(move || explain(&*indexes.get(identifier.as_deref())?, &req)).await)
+            }),
+
+            (Method::POST, "/facet") => Box::pin(async move {
+                let req: FacetReq = match parse_body(body).await? {
+                    Ok(req) => req,
+                    Err(resp) => return Ok(resp),
+                };
+
+                Ok(run_query(move || facet(&*indexes.get(identifier.as_deref())?, &req)).await)
+            }),
+
+            // 索引文件读取失败时返回500
+            (Method::GET, "/health") => Box::pin(async move {
+                Ok(run_query(move || indexes.get(identifier.as_deref())?.check_health()).await)
+            }),
+
+            (Method::GET, "/stats") => Box::pin(async move {
+                Ok(run_query(move || Ok(indexes.get(identifier.as_deref())?.stats())).await)
+            }),
+
+            (Method::GET, path) if path.starts_with("/document/") => {
+                let doc_id = path["/document/".len()..].parse::<u32>();
+                Box::pin(async move {
+                    let doc_id = match doc_id {
+                        Ok(doc_id) => doc_id,
+                        Err(_) => return Ok(response(StatusCode::BAD_REQUEST, Body::empty())),
+                    };
+
+                    Ok(
+                        run_query(move || indexes.get(identifier.as_deref())?.get_document(doc_id))
+                            .await,
+                    )
+                })
+            }
+
+            _ => Box::pin(async { Ok(response(StatusCode::NOT_FOUND, Body::empty())) }),
+        }
+    }
+}
+
+fn search(
+    query: &SearchQuery,
+    req: &SearchReq,
+    range: Range<usize>,
+    deadline: Deadline,
+) -> query::Result<SearchResp> {
+    let _span = trace_span!("search", q = %req.q).entered();
+
+    let options = QueryOptions {
+        field: req.field,
+        min_should_match: req.min_should_match,
+        numeric_range: req.range.clone(),
+        sort_by: req.sort.clone(),
+        deadline,
+        ..QueryOptions::default()
+    };
+
+    let mut resp = if let (true, Some(numeric_range)) = (req.q.trim().is_empty(), &req.range) {
+        SearchResp::new(&query.range_query(numeric_range, range, &options)?)
+    } else if req.wildcard {
+        SearchResp::new(&query.wildcard_query(
+            req.q.as_str(),
+            query::DEFAULT_MAX_EXPANSIONS,
+            range,
+            &options,
+        )?)
+    } else if let Some(slop) = req.slop {
+        SearchResp::new(&query.phrase_query(req.q.as_str(), slop, range, &options)?)
+    } else if req.prefix {
+        let fuzzy = req.fuzzy;
+        SearchResp::new(&query.search_as_you_type(
+            req.q.as_str(),
+            req.occur,
+            &|w| fuzzy.automaton(w),
+            range,
+            &options,
+        )?)
+    } else if req.syntax {
+        let fuzzy = req.fuzzy;
+        SearchResp::new(&query.parsed_query(
+            &parse_query(req.q.as_str(), req.occur),
+            &|w| fuzzy.automaton(w),
+            range,
+            &options,
+        )?)
+    } else {
+        let fuzzy = req.fuzzy;
+        let results = query.query_cached(
+            req.q.as_str(),
+            req.occur,
+            &|w| fuzzy.automaton(w),
+            &fuzzy.cache_key(),
+            range,
+            &options,
+        )?;
+        SearchResp::new(&results)
+    };
+
+    if req.payload || req.keys {
+        options.deadline.check()?;
+        resp.load_documents(query, req.payload, req.keys)?;
+    }
+
+    if let Some(max_len) = req.highlight {
+        options.deadline.check()?;
+        resp.load_highlights(query, req.q.as_str(), max_len)?;
+    }
+
+    Ok(resp)
+}
+
+fn explain(query: &SearchQuery, req: &ExplainReq) -> query::Result<Explanation> {
+    let fuzzy = req.fuzzy;
+    query.explain(
+        req.q.as_str(),
+        req.occur,
+        &|w| fuzzy.automaton(w),
+        req.doc_id,
+    )
+}
+
+/// 返回[[值, 文档数], ...]，按文档数从多到少排序
+fn facet(query: &SearchQuery, req: &FacetReq) -> query::Result<Vec<(String, u32)>> {
+    let fuzzy = req.fuzzy;
+    query.facet(
+        req.q.as_str(),
+        &|w| fuzzy.automaton(w),
+        req.occur,
+        req.field.as_str(),
+    )
+}
+
+/// 读取整个body并解析成json，格式不对时返回400的response
+async fn parse_body<T: DeserializeOwned>(body: Body) -> Result<Result<T, SvcResponse>, SvcError> {
+    let body = hyper::body::to_bytes(body).await?;
+
+    Ok(serde_json::from_slice(&body).map_err(|e| {
+        warn!(error = %e, "bad request");
+        response(StatusCode::BAD_REQUEST, Body::empty())
+    }))
+}
+
+/// 查询是CPU密集的同步操作，放到blocking线程里执行，不要阻塞异步worker。
+/// 结果序列化成json返回，错误按类型转成对应的状态码
+async fn run_query<T, F>(f: F) -> SvcResponse
+where
+    T: Serialize + Send + 'static,
+    F: FnOnce() -> query::Result<T> + Send + 'static,
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(Ok(resp)) => response(
+            StatusCode::OK,
+            Body::from(serde_json::to_vec(&resp).unwrap()),
+        ),
+        Ok(Err(e @ query::Error::OnlyExcluded)) => {
+            warn!(error = %e, "bad request");
+            response(StatusCode::BAD_REQUEST, Body::empty())
+        }
+        Ok(Err(query::Error::DocumentNotFound(_))) | Ok(Err(query::Error::IndexNotFound(_))) => {
+            response(StatusCode::NOT_FOUND, Body::empty())
+        }
+        Ok(Err(e @ query::Error::Timeout)) => {
+            warn!(error = %e, "query failed");
+            response(StatusCode::SERVICE_UNAVAILABLE, Body::empty())
+        }
+        // 客户端已经断开了，这个response不会被发出去
+        Ok(Err(query::Error::Cancelled)) => {
+            response(StatusCode::SERVICE_UNAVAILABLE, Body::empty())
+        }
+        Ok(Err(e)) => {
+            error!(error = %e, "query failed");
+            response(StatusCode::INTERNAL_SERVER_ERROR, Body::empty())
+        }
+        Err(e) => {
+            error!(error = %e, "query task failed");
+            response(StatusCode::INTERNAL_SERVER_ERROR, Body::empty())
+        }
+    }
+}
+
+/// drop时设置取消标记。正常返回时也会设置，但那时查询已经结束了
+#[derive(Default)]
+struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+#[inline]
+fn response(status: StatusCode, body: Body) -> SvcResponse {
+    Response::builder().status(status).body(body).unwrap()
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SearchReq {
+    q: String,
+    #[serde(default)]
+    from: usize,
+    /// 默认10，不能超过MAX_SEARCH_SIZE
+    #[serde(default = "default_size")]
+    size: usize,
+    /// true或"auto"按词的长度决定编辑距离，数字是固定的编辑距离，默认精确匹配
+    #[serde(default)]
+    fuzzy: Fuzziness,
+    /// "must"要求包含所有词，默认"should"包含任意一个词即可
+    #[serde(default)]
+    occur: Occur,
+    /// "title"或"content"，只在这个字段里搜索，默认所有字段
+    #[serde(default)]
+    field: Option<Field>,
+    /// {"count": 2}或{"percent": 60}，至少要包含多少个词，只对"should"有意义
+    #[serde(default)]
+    min_should_match: Option<MinShouldMatch>,
+    /// 设置了就按短语查询，0表示词必须紧挨着，这时fuzzy、occur和min_should_match不起作用
+    #[serde(default)]
+    slop: Option<u32>,
+    /// 把q当作"神里*"、"绫?华"这样的通配符模式，不经过分词，这时其他查询选项只有field起作用
+    #[serde(default)]
+    wildcard: bool,
+    /// 把q按"绫华 (雷神 OR 雷电将军) -旅行"这样的语法解析，见parse_query，occur是不带OR的词的默认值。
+    /// min_should_match不起作用，结果不缓存
+    #[serde(default)]
+    syntax: bool,
+    /// 边输入边搜索：q的最后一个词按前缀匹配（q以空格结尾时除外），其他的词按fuzzy匹配，结果不缓存
+    #[serde(default)]
+    prefix: bool,
+    /// {"field": "price", "gte": 20, "lt": 50}，只返回数值字段在范围内的文档。
+    /// q为空时返回范围内的所有文档，按doc id升序，见Query::range_query
+    #[serde(default)]
+    range: Option<NumericRange>,
+    /// {"type": "field", "name": "time"}按数值字段降序，加上"ascending": true升序，默认按相关性
+    #[serde(default)]
+    sort: SortBy,
+    /// true时同时返回每个结果的payload，顺序和ids一样，没有payload的是null
+    #[serde(default)]
+    payload: bool,
+    /// true时同时返回每个结果建索引时的key，顺序和ids一样，没有key的是null
+    #[serde(default)]
+    keys: bool,
+    /// 设置了就同时返回每个结果content的摘要，最多这么多个字符，q里的词用<em></em>标出，
+    /// 其他的原文做了html转义，可以直接插入页面，见Query::highlight
+    #[serde(default)]
+    highlight: Option<usize>,
+    /// 这次查询最多用多少毫秒，超过时返回503，不返回部分结果。不能超过配置里的timeout_ms
+    #[serde(default)]
+    timeout_ms: Option<u64>,
+}
+
+fn default_size() -> usize {
+    10
+}
+
+/// size的上限，超过时返回400，更多的结果要用from翻页
+pub const MAX_SEARCH_SIZE: usize = 1000;
+
+impl SearchReq {
+    /// 检查serde检查不了的限制，返回要查询的结果范围。from和size都来自客户端，相加可能溢出
+    fn range(&self) -> Result<Range<usize>, String> {
+        if self.size > MAX_SEARCH_SIZE {
+            return Err(format!(
+                "size {} is larger than {}",
+                self.size, MAX_SEARCH_SIZE
+            ));
+        }
+
+        match self.from.checked_add(self.size) {
+            Some(end) => Ok(self.from..end),
+            None => Err(format!("from {} is too large", self.from)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SearchResp {
+    /// 命中的文档总数，用来分页
+    total: u32,
+    ids: Vec<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    payloads: Option<Vec<Option<serde_json::Value>>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    keys: Option<Vec<Option<String>>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    highlights: Option<Vec<String>>,
+}
+
+impl SearchResp {
+    fn new(results: &query::SearchResults) -> Self {
+        SearchResp {
+            total: results.total,
+            ids: results.ids(),
+            payloads: None,
+            keys: None,
+            highlights: None,
+        }
+    }
+
+    /// payload和key都在文档存储里，用get_documents一次读出当前这一页
+    fn load_documents(
+        &mut self,
+        query: &SearchQuery,
+        payload: bool,
+        key: bool,
+    ) -> query::Result<()> {
+        let mut payloads = Vec::with_capacity(self.ids.len());
+        let mut keys = Vec::with_capacity(self.ids.len());
+        for doc in query.get_documents(&self.ids)? {
+            payloads.push(doc.payload);
+            keys.push(doc.key);
+        }
+
+        self.payloads = Some(payloads).filter(|_| payload);
+        self.keys = Some(keys).filter(|_| key);
+        Ok(())
+    }
+
+    /// 和load_documents一样只处理当前这一页，顺序和ids一样
+    fn load_highlights(
+        &mut self,
+        query: &SearchQuery,
+        q: &str,
+        max_len: usize,
+    ) -> query::Result<()> {
+        let mut highlights = Vec::with_capacity(self.ids.len());
+        for id in self.ids.iter() {
+            highlights.push(query.highlight(*id, q, max_len)?);
+        }
+
+        self.highlights = Some(highlights);
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExplainReq {
+    q: String,
+    doc_id: u32,
+    #[serde(default)]
+    fuzzy: Fuzziness,
+    #[serde(default)]
+    occur: Occur,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FacetReq {
+    q: String,
+    /// 建索引时keywords里的字段名
+    field: String,
+    #[serde(default)]
+    fuzzy: Fuzziness,
+    #[serde(default)]
+    occur: Occur,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyzer::registry::AnalyzerSettings;
+    use crate::store;
+
+    fn search_req(from: usize, size: usize) -> SearchReq {
+        serde_json::from_value(serde_json::json!({"q": "雷电", "from": from, "size": size}))
+            .unwrap()
+    }
+
+    #[test]
+    fn search_range_is_checked() {
+        assert_eq!(search_req(20, 10).range(), Ok(20..30));
+        assert_eq!(
+            search_req(usize::MAX - 10, 10).range(),
+            Ok(usize::MAX - 10..usize::MAX)
+        );
+        assert!(search_req(usize::MAX, 10).range().is_err());
+        assert!(search_req(0, MAX_SEARCH_SIZE).range().is_ok());
+        assert!(search_req(0, MAX_SEARCH_SIZE + 1).range().is_err());
+    }
+
+    /// 每次用一个新的Builder写一个segment，和建索引服务每次finish一样
+    fn add_segment(store_dir: &std::path::Path, id: u32) {
+        let settings = AnalyzerSettings::default();
+        let mut builder = store::Builder::new(
+            settings.build().unwrap(),
+            settings.build().unwrap(),
+            store::Config::new(store_dir.to_path_buf(), "t", 1),
+        );
+        builder
+            .add_document(store::Document {
+                id,
+                title: "稻妻",
+                content: "雷电将军",
+                keywords: vec![],
+                numbers: vec![],
+                payload: None,
+                key: None,
+            })
+            .unwrap();
+        builder.finish().unwrap();
+    }
+
+    #[test]
+    fn reopens_index_when_segments_change() {
+        let store_dir =
+            std::env::temp_dir().join(format!("shogun-reopen-test-{}", std::process::id()));
+        std::fs::create_dir_all(&store_dir).unwrap();
+        let indexes =
+            QueryIndexes::new(store_dir.clone(), "t".to_string(), QuerySettings::default());
+
+        add_segment(&store_dir, 1);
+        let first = indexes.get(None).unwrap();
+        let unchanged = indexes.get(None).unwrap();
+
+        add_segment(&store_dir, 2);
+        let reopened = indexes.get(None).unwrap();
+        let found = reopened.get_document(2).is_ok();
+        std::fs::remove_dir_all(&store_dir).unwrap();
+
+        assert!(Arc::ptr_eq(&first, &unchanged));
+        assert!(first.get_document(2).is_err());
+        assert!(!Arc::ptr_eq(&first, &reopened));
+        assert!(found);
+    }
+}
@@ -0,0 +1,154 @@
+use crate::analyzer::registry::{AnalyzerSettings, TokenFilterSettings};
+use crate::query::Similarity;
+use crate::store::builder::DEFAULT_POSITION_GAP;
+use crate::store::MergePolicy;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+/// 建索引和查询的全部配置，可以从--config指定的toml或json文件读取，
+/// 没有配置文件时由命令行参数填充。文件里没有写的项使用默认值，写错的项名会报错
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// 相对路径相对于当前目录，和命令行参数一样
+    pub store_dir: PathBuf,
+    pub identifier: String,
+    pub build: BuildSettings,
+    pub query: QuerySettings,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            store_dir: PathBuf::from("../../test_store/"),
+            identifier: String::from("test"),
+            build: BuildSettings::default(),
+            query: QuerySettings::default(),
+        }
+    }
+}
+
+impl Settings {
+    /// .json结尾的按json解析，其他的按toml解析
+    pub fn from_file(path: &Path) -> Result<Self, SettingsError> {
+        let text = std::fs::read_to_string(path).map_err(|e| SettingsError {
+            path: path.to_path_buf(),
+            message: e.to_string(),
+        })?;
+
+        let result = match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => serde_json::from_str(&text).map_err(|e| e.to_string()),
+            _ => toml::from_str(&text).map_err(|e| e.to_string()),
+        };
+
+        result.map_err(|message| SettingsError {
+            path: path.to_path_buf(),
+            message,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BuildSettings {
+    /// 建索引服务监听的地址，比如"127.0.0.1:8081"，命令行的-a优先
+    pub address: Option<SocketAddr>,
+    /// 见store::Config::new
+    pub min_norm_len: usize,
+    pub title_analyzer: AnalyzerSettings,
+    pub content_analyzer: AnalyzerSettings,
+    /// 不写时不自动合并，segment只在build --merge时合并成一个
+    pub merge_policy: Option<MergePolicy>,
+    /// 见store::Config::with_position_gap
+    pub position_gap: u32,
+}
+
+impl Default for BuildSettings {
+    /// 和以前写死的一样：content去掉../../dict/stop_words.txt里的停用词，title不去
+    fn default() -> Self {
+        BuildSettings {
+            address: None,
+            min_norm_len: 1,
+            title_analyzer: AnalyzerSettings::default(),
+            content_analyzer: AnalyzerSettings {
+                token_filters: vec![TokenFilterSettings::StopWords {
+                    path: PathBuf::from("../../dict/stop_words.txt"),
+                }],
+                ..AnalyzerSettings::default()
+            },
+            merge_policy: None,
+            position_gap: DEFAULT_POSITION_GAP,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuerySettings {
+    /// 查询服务监听的地址，和build.address分开，同一个配置文件可以同时启动两个服务。命令行的-a优先
+    pub address: Option<SocketAddr>,
+    pub boost_title: u8,
+    pub boost_content: u8,
+    pub similarity: SimilaritySettings,
+    /// 0表示不缓存，每个索引单独缓存这么多条
+    pub cache_capacity: usize,
+    /// 查询服务最多同时打开多少个索引，超过时关掉最久没有查询过的，至少是1
+    pub max_open_indexes: usize,
+    /// /search最多用多少毫秒，超过时返回503，0表示不限制。请求里的timeout_ms只能把它改得更短
+    pub timeout_ms: u64,
+    /// 分析查询语句用的analyzer，要和建索引时的analyzer切出一样的词
+    pub analyzer: AnalyzerSettings,
+}
+
+impl Default for QuerySettings {
+    fn default() -> Self {
+        QuerySettings {
+            address: None,
+            boost_title: 3,
+            boost_content: 1,
+            similarity: SimilaritySettings::default(),
+            cache_capacity: 1024,
+            max_open_indexes: 16,
+            timeout_ms: 0,
+            analyzer: AnalyzerSettings::default(),
+        }
+    }
+}
+
+/// 配置文件里能写的相关性算法，自定义的算法只能在代码里用Similarity::Custom
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SimilaritySettings {
+    #[default]
+    TfIdf,
+    Bm25 {
+        k1: f64,
+        b: f64,
+    },
+}
+
+impl From<SimilaritySettings> for Similarity {
+    fn from(settings: SimilaritySettings) -> Self {
+        match settings {
+            SimilaritySettings::TfIdf => Similarity::TfIdf,
+            SimilaritySettings::Bm25 { k1, b } => Similarity::Bm25 { k1, b },
+        }
+    }
+}
+
+/// 配置文件读取或解析失败
+#[derive(Debug)]
+pub struct SettingsError {
+    path: PathBuf,
+    message: String,
+}
+
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.message)
+    }
+}
+
+impl std::error::Error for SettingsError {}
@@ -3,42 +3,117 @@ use crate::analyzer::char_filter::CharFilter;
 use crate::analyzer::token_filter::TokenFilter;
 use crate::analyzer::tokenizer::Tokenizer;
 use crate::store::constants::{
-    TERM_DICT_FILE_SUFFIX, TERM_DICT_MAGIC_NUMBER, TERM_INDEX_FILE_SUFFIX, TERM_INDEX_MAGIC_NUMBER,
-    VERSION,
+    DOC_STORE_FILE_SUFFIX, KEY_INDEX_FILE_SUFFIX, KEY_INDEX_MAGIC_NUMBER,
+    NUMERIC_INDEX_FILE_SUFFIX, TERM_DICT_FILE_SUFFIX, TERM_DICT_MAGIC_NUMBER,
+    TERM_INDEX_FILE_SUFFIX, TERM_INDEX_MAGIC_NUMBER, TOMBSTONE_FILE_SUFFIX, VERSION,
 };
-use crate::store::document::Document;
+use crate::store::directory::{Directory, DirectoryWriter, FileBytes};
+use crate::store::doc_store::{DocStoreReader, DocStoreWriter};
+use crate::store::document::{AnalyzedDocument, Document, VALUE_SEPARATOR};
 use crate::store::error::{Error, Result};
-use crate::store::posting::PostingListBuilder;
+use crate::store::merge::MergePolicy;
+use crate::store::numeric::NumericIndexBuilder;
+use crate::store::posting::{PostingListBuilder, RawPostingList};
+use crate::store::segment::{
+    open_key_index, open_segments, segment_file_name, SegmentReader, Segments,
+};
 use crate::store::term::{BuildingTermData, BuildingTermDictionary};
+use crate::store::tombstone::Tombstones;
 use byteorder::{LittleEndian, WriteBytesExt};
-use std::fs::File;
-use std::path::PathBuf;
+use fst::Streamer;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::convert::TryFrom;
 
 #[derive(Debug)]
-pub struct Config<'a> {
-    store_dir: PathBuf,
-    identifier: &'a str,
+pub struct Config {
+    directory: Directory,
+    identifier: String,
+    /// 计算norm时字段长度的下限。norm是1/sqrt(len)，很短的文档（比如只有一个词）norm会非常大，
+    /// 很容易排到长文档前面。比它短的字段都按这个长度算norm，1相当于不限制
+    min_norm_len: usize,
+    /// 设置了时每次finish之后按它合并segment
+    merge_policy: Option<MergePolicy>,
+    position_gap: u32,
 }
 
-impl<'a> Config<'a> {
-    pub fn new(store_dir: PathBuf, identifier: &'a str) -> Self {
+/// Config::with_position_gap的默认值，比一般的短语长得多
+pub const DEFAULT_POSITION_GAP: u32 = 100;
+
+impl Config {
+    /// directory可以直接传PathBuf，或者Directory::memory()只在内存里建索引
+    pub fn new(
+        directory: impl Into<Directory>,
+        identifier: impl Into<String>,
+        min_norm_len: usize,
+    ) -> Self {
         Config {
-            store_dir,
-            identifier,
+            directory: directory.into(),
+            identifier: identifier.into(),
+            min_norm_len,
+            merge_policy: None,
+            position_gap: DEFAULT_POSITION_GAP,
         }
     }
 
-    fn build_file_path(&self, suffix: &str) -> PathBuf {
-        let mut buf = self.store_dir.clone();
-        buf.push(String::from(self.identifier) + suffix);
-        buf
+    /// 每次finish写完新的segment后调用Builder::merge_with_policy，长期运行的builder不会越攒越多小segment
+    pub fn with_merge_policy(mut self, policy: MergePolicy) -> Self {
+        self.merge_policy = Some(policy);
+        self
+    }
+
+    /// 多值字段（见VALUE_SEPARATOR）相邻两个值之间空出的位置数。短语和近似短语查询的slop
+    /// 小于它时不会跨值匹配，0相当于把各个值直接连起来
+    pub fn with_position_gap(mut self, gap: u32) -> Self {
+        self.position_gap = gap;
+        self
+    }
+
+    fn create_file(&self, segment: u32, suffix: &str) -> Result<DirectoryWriter> {
+        let name = segment_file_name(&self.identifier, segment, suffix);
+        Ok(self.directory.create(&name)?)
     }
 }
 
 // TODO: 这泛型太迷了，能简化吗？
 
+/// 一个segment的全部文件
+const SEGMENT_FILE_SUFFIXES: [&str; 6] = [
+    TERM_INDEX_FILE_SUFFIX,
+    TERM_DICT_FILE_SUFFIX,
+    DOC_STORE_FILE_SUFFIX,
+    TOMBSTONE_FILE_SUFFIX,
+    NUMERIC_INDEX_FILE_SUFFIX,
+    KEY_INDEX_FILE_SUFFIX,
+];
+
+/// Builder::compact的结果，大小是identifier.segments里所有segment文件的字节数之和
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CompactStats {
+    /// 合并前的segment数，合并后只有1个
+    pub segments: usize,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+impl CompactStats {
+    /// 省下的字节数，合并后变大时是0
+    pub fn reclaimed(&self) -> u64 {
+        self.bytes_before.saturating_sub(self.bytes_after)
+    }
+}
+
+/// Builder::stats的结果
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BuilderStats {
+    pub doc_num: u32,
+    pub terms: usize,
+    /// 标记删除的doc id数
+    pub deleted: usize,
+}
+
 #[derive(Debug)]
-pub struct Builder<'a, C, T, I, C2, T2, I2>
+pub struct Builder<C, T, I, C2, T2, I2>
 where
     C: CharFilter,
     T: TokenFilter,
@@ -49,13 +124,40 @@ where
 {
     title_analyzer: Analyzer<C, T, I>,
     content_analyzer: Analyzer<C2, T2, I2>,
-    config: Config<'a>,
+    config: Config,
 
     dict: BuildingTermDictionary,
     doc_num: u32,
+    /// (title总长度, content总长度)，用来算BM25需要的平均长度
+    len_sum: (u64, u64),
+    /// 第一次add_document时才创建文件，Builder::new不需要返回Result
+    doc_store: Option<DocStoreWriter>,
+    numbers: NumericIndexBuilder,
+    deleted: BTreeSet<u32>,
+    /// 这次（还没有finish的segment里）添加过的doc id，update_document用来判断要不要先finish
+    added: BTreeSet<u32>,
+    /// 正在写的segment编号，创建第一个文件时才从identifier.segments里分配
+    segment: Option<u32>,
+    /// 这次添加的文档的外部id -> doc id，finish时写成这个segment的外部id索引
+    keys: BTreeMap<String, u32>,
+    /// id_for_key分配了、但还没有添加文档的外部id
+    allocated: HashMap<String, u32>,
+    /// id_for_key下一个可以分配的doc id的下限，用u64避免u32::MAX + 1溢出
+    next_key_id: u64,
+    /// 已经finish的segment的外部id索引，第一次用到时才打开，segment变了之后重新打开
+    key_lookup: Option<KeyLookup>,
 }
 
-impl<'a, C, T, I, C2, T2, I2> Builder<'a, C, T, I, C2, T2, I2>
+/// 已经finish的segment里的外部id
+#[derive(Debug)]
+struct KeyLookup {
+    /// 从新到旧
+    indexes: Vec<fst::Map<FileBytes>>,
+    /// 所有segment里最大的doc id + 1
+    next_id: u64,
+}
+
+impl<C, T, I, C2, T2, I2> Builder<C, T, I, C2, T2, I2>
 where
     C: CharFilter,
     T: TokenFilter,
@@ -67,7 +169,7 @@ where
     pub fn new(
         title_analyzer: Analyzer<C, T, I>,
         content_analyzer: Analyzer<C2, T2, I2>,
-        config: Config<'a>,
+        config: Config,
     ) -> Self {
         Builder {
             title_analyzer,
@@ -75,98 +177,645 @@ where
             config,
             dict: BuildingTermDictionary::new(),
             doc_num: 0,
+            len_sum: (0, 0),
+            doc_store: None,
+            numbers: NumericIndexBuilder::new(),
+            deleted: BTreeSet::new(),
+            added: BTreeSet::new(),
+            segment: None,
+            keys: BTreeMap::new(),
+            allocated: HashMap::new(),
+            next_key_id: 0,
+            key_lookup: None,
         }
     }
 
+    /// 已经删除的id不能再添加：删除只是打了标记，旧文档的posting还在，
+    /// 再用同一个id添加会和旧的posting混在一起。要更新文档可以在finish之后再添加，
+    /// 新segment里的文档会覆盖旧segment里同一个id的文档
     pub fn add_document(&mut self, doc: Document) -> Result<()> {
-        self.doc_num += 1;
+        if self.deleted.contains(&doc.id) {
+            return Err(Error::Deleted(doc.id));
+        }
 
-        for term in self.title_analyzer.analyze(doc.title)? {
-            self.add_term(term.as_str(), &doc, true)?;
+        let doc = self.analyze_document(doc)?;
+        self.add_analyzed(doc)
+    }
+
+    /// 只运行两个analyzer，不修改Builder。分词是建索引最耗CPU的部分，
+    /// 一批文档可以先在多个线程里分别调用这个，再按原来的顺序add_analyzed，结果和逐个add_document一样
+    pub fn analyze_document<'d>(&self, doc: Document<'d>) -> Result<AnalyzedDocument<'d>> {
+        let gap = self.config.position_gap;
+
+        Ok(AnalyzedDocument {
+            title_terms: analyze_values(&self.title_analyzer, doc.title, gap)?,
+            content_terms: analyze_values(&self.content_analyzer, doc.content, gap)?,
+            doc,
+        })
+    }
+
+    /// 插入analyze_document的结果，和add_document一样已经删除的id不能再添加
+    pub fn add_analyzed(&mut self, analyzed: AnalyzedDocument) -> Result<()> {
+        let doc = analyzed.doc;
+        if self.deleted.contains(&doc.id) {
+            return Err(Error::Deleted(doc.id));
         }
 
-        for term in self.content_analyzer.analyze(doc.content)? {
-            self.add_term(term.as_str(), &doc, false)?;
+        let key = doc.key.filter(|key| !key.is_empty());
+        if let Some(key) = key {
+            match self.find_key(key)? {
+                Some(id) if id != doc.id => return Err(Error::KeyConflict(key.to_string(), id)),
+                _ => {
+                    self.keys.insert(key.to_string(), doc.id);
+                    self.allocated.remove(key);
+                }
+            }
+        }
+
+        if self.doc_store.is_none() {
+            self.doc_store = Some(self.create_doc_store()?);
+        }
+        self.doc_store.as_mut().unwrap().add(&doc)?;
+        self.numbers.add(doc.id, &doc.numbers);
+        self.added.insert(doc.id);
+
+        self.doc_num += 1;
+
+        let dict = &mut self.dict;
+        let len = (
+            doc.title.chars().count().max(self.config.min_norm_len),
+            doc.content.chars().count().max(self.config.min_norm_len),
+        );
+        self.len_sum.0 += len.0 as u64;
+        self.len_sum.1 += len.1 as u64;
+
+        // 超过u16的长度按u16::MAX存，这么长的字段norm已经很小了，区别不大
+        let len = (
+            len.0.min(u16::MAX as usize) as u16,
+            len.1.min(u16::MAX as usize) as u16,
+        );
+
+        // 位置按token_filter之后留下的token计数，查询短语时用同样的方式计数
+        for (position, term) in analyzed.title_terms.iter() {
+            Self::add_term(dict, term, doc.id, len, *position, true);
+        }
+        for (position, term) in analyzed.content_terms.iter() {
+            Self::add_term(dict, term, doc.id, len, *position, false);
         }
 
         Ok(())
     }
 
+    /// 把doc id标记为已删除，finish时写到.del文件里，查询时会过滤掉这些文档，
+    /// 对这次添加的和之前的segment里的文档都有效。
+    /// doc_num和平均长度仍然包含被删除的文档，只影响打分，不影响结果
+    pub fn delete_document(&mut self, doc_id: u32) {
+        self.deleted.insert(doc_id);
+    }
+
+    /// 外部id对应的doc id，从来没有添加过时返回None。
+    /// 删除文档不会释放它的外部id，再添加同一个外部id还是原来的doc id，直到合并segment时被删除的文档被去掉
+    pub fn find_key(&mut self, key: &str) -> Result<Option<u32>> {
+        if let Some(id) = self.keys.get(key).or_else(|| self.allocated.get(key)) {
+            return Ok(Some(*id));
+        }
+
+        let lookup = self.key_lookup()?;
+        Ok(lookup
+            .indexes
+            .iter()
+            .find_map(|index| index.get(key))
+            .map(|id| id as u32))
+    }
+
+    /// 外部id已经有doc id时返回原来的，否则分配一个比所有已经用过的doc id都大的新id。
+    /// 添加文档时把返回的id和key一起放进Document，同一个外部id用到不同的doc id上时返回Error::KeyConflict。
+    /// 分配了但没有添加文档的id在finish之后就作废了
+    ///
+    /// ```
+    /// use raiden_shogun_search::analyzer::char_filter::CJKDocCharFilter;
+    /// use raiden_shogun_search::analyzer::token_filter::BasicTokenFilter;
+    /// use raiden_shogun_search::analyzer::tokenizer::UnicodeWordTokenizer;
+    /// use raiden_shogun_search::{store, Analyzer, Builder, Directory, Document};
+    ///
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let analyzer = || {
+    ///     Analyzer::new(
+    ///         CJKDocCharFilter::new(),
+    ///         BasicTokenFilter::new(),
+    ///         UnicodeWordTokenizer::new(),
+    ///     )
+    /// };
+    /// let mut builder = Builder::new(analyzer(), analyzer(), store::Config::new(Directory::memory(), "t", 1));
+    ///
+    /// let doc = |id, key| Document { id, title: "", content: "raiden", keywords: vec![], numbers: vec![], payload: None, key: Some(key) };
+    /// builder.add_document(doc(7, "https://example.com/a"))?;
+    /// builder.finish()?;
+    ///
+    /// let b = builder.id_for_key("https://example.com/b")?;
+    /// assert_eq!(b, 8);
+    /// assert_eq!(builder.id_for_key("https://example.com/a")?, 7);
+    /// builder.update_document(doc(b, "https://example.com/b"))?;
+    /// assert!(builder.add_document(doc(9, "https://example.com/a")).is_err());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn id_for_key(&mut self, key: &str) -> Result<u32> {
+        if let Some(id) = self.find_key(key)? {
+            return Ok(id);
+        }
+
+        let after = |id: Option<&u32>| id.map_or(0, |id| *id as u64 + 1);
+        let id = self
+            .key_lookup()?
+            .next_id
+            .max(self.next_key_id)
+            .max(after(self.added.iter().next_back()))
+            .max(after(self.deleted.iter().next_back()));
+        let id = u32::try_from(id).map_err(|_| Error::OutOfRange)?;
+
+        self.allocated.insert(key.to_string(), id);
+        self.next_key_id = id as u64 + 1;
+        Ok(id)
+    }
+
+    fn key_lookup(&mut self) -> Result<&KeyLookup> {
+        if self.key_lookup.is_none() {
+            let (directory, identifier) = (&self.config.directory, &self.config.identifier);
+            let mut lookup = KeyLookup {
+                indexes: Vec::new(),
+                next_id: 0,
+            };
+
+            for id in Segments::read(directory, identifier)?.ids().iter().rev() {
+                if let Some(index) = open_key_index(directory, identifier, *id)? {
+                    lookup.indexes.push(index);
+                }
+
+                let name = segment_file_name(identifier, *id, DOC_STORE_FILE_SUFFIX);
+                if let Some(last) = DocStoreReader::new(directory.open(&name)?)?.last_id() {
+                    lookup.next_id = lookup.next_id.max(last as u64 + 1);
+                }
+            }
+
+            self.key_lookup = Some(lookup);
+        }
+
+        Ok(self.key_lookup.as_ref().unwrap())
+    }
+
+    /// 还没有finish的部分的统计，finish之后清零
+    pub fn stats(&self) -> BuilderStats {
+        BuilderStats {
+            doc_num: self.doc_num,
+            terms: self.dict.len(),
+            deleted: self.deleted.len(),
+        }
+    }
+
+    /// 用doc替换同一个id的旧版本。旧版本在之前的segment里时，新segment里的文档会覆盖它，不需要删除；
+    /// 这次已经添加或删除过这个id时，两个版本的posting会混在一起，所以先finish，新版本写到下一个segment里。
+    /// id从来没有添加过时和add_document一样，重复update同一篇文档结果不变
+    pub fn update_document(&mut self, doc: Document) -> Result<()> {
+        if self.added.contains(&doc.id) || self.deleted.contains(&doc.id) {
+            self.finish()?;
+        }
+
+        self.add_document(doc)
+    }
+
     #[inline]
-    fn add_term(&mut self, term: &str, doc: &Document, is_title: bool) -> Result<()> {
-        match self.dict.get_mut(term) {
+    fn add_term(
+        dict: &mut BuildingTermDictionary,
+        term: &str,
+        doc_id: u32,
+        len: (u16, u16),
+        position: u32,
+        is_title: bool,
+    ) {
+        match dict.get_mut(term) {
             None => {
                 let mut d = BuildingTermData::new();
-                d.add_posting(doc, is_title);
-                self.dict.insert(term.to_string(), d);
+                d.add_posting(doc_id, len, position, is_title);
+                dict.insert(term.to_string(), d);
             }
-            Some(d) => d.add_posting(doc, is_title),
+            Some(d) => d.add_posting(doc_id, len, position, is_title),
+        }
+    }
+
+    /// 把这次添加和删除的文档写成一个新的segment，之前的segment不变。
+    /// 之后可以继续添加文档，下次finish会再写一个segment。
+    /// Config里设置了合并策略时接着按策略合并，合并出错时新的segment已经生效了
+    pub fn finish(&mut self) -> Result<()> {
+        let segment = self.write_segment()?;
+
+        let mut segments = Segments::read(&self.config.directory, &self.config.identifier)?;
+        segments.push(segment);
+        segments.write(&self.config.directory, &self.config.identifier)?;
+
+        if let Some(policy) = self.config.merge_policy.clone() {
+            self.merge_with_policy(&policy)?;
         }
 
         Ok(())
     }
 
-    pub fn finish(&mut self) -> Result<()> {
-        let index_file = File::create(
-            self.config
-                .build_file_path(TERM_INDEX_FILE_SUFFIX)
-                .to_str()
-                .unwrap(),
-        )?;
-        let mut index_writer = std::io::BufWriter::new(index_file);
-
-        let dict_file = File::create(
-            self.config
-                .build_file_path(TERM_DICT_FILE_SUFFIX)
-                .to_str()
-                .unwrap(),
-        )?;
-        let mut dict_writer = std::io::BufWriter::new(dict_file);
-        let mut dict_offset = 0u64;
-
-        self.write_index_header(&mut index_writer)?;
-        dict_offset += self.write_dict_header(&mut dict_writer)?;
-
-        let mut fst_builder = fst::raw::Builder::new(index_writer)?;
+    /// 把这次添加和删除的文档写成segment文件并清空，返回segment编号，还没有加到identifier.segments里
+    fn write_segment(&mut self) -> Result<u32> {
+        let segment = self.segment_id()?;
+
+        let doc_num = self.doc_num.max(1) as f64;
+        let avg_len = (
+            self.len_sum.0 as f64 / doc_num,
+            self.len_sum.1 as f64 / doc_num,
+        );
+        let doc_store = match self.doc_store.take() {
+            Some(doc_store) => doc_store,
+            None => self.create_doc_store()?,
+        };
+        let mut writer = SegmentWriter::new(&self.config, segment, self.doc_num, avg_len)?;
 
         for term in self.dict.iter() {
-            fst_builder.insert(term.0, dict_offset)?;
-            dict_offset += self.write_dict(&mut dict_writer, term.1)?;
+            let mut builder =
+                PostingListBuilder::new(&mut writer.dict_writer, term.1.get_posting_map());
+            let len = builder.finish()?;
+            writer.add_term(term.0, len)?;
+        }
+
+        let tombstones = Tombstones::new(&self.deleted);
+        writer.finish(doc_store, &mut self.numbers, &self.keys, &tombstones)?;
+
+        self.dict.clear();
+        self.numbers.clear();
+        self.keys.clear();
+        self.allocated.clear();
+        self.key_lookup = None;
+        self.doc_num = 0;
+        self.len_sum = (0, 0);
+        self.deleted.clear();
+        self.added.clear();
+        self.segment = None;
+
+        Ok(segment)
+    }
+
+    /// 把所有已经finish的segment合并成一个，去掉已经删除和被覆盖的文档，查询时就不需要再跨segment合并。
+    /// 还没有finish的文档不包含在内。doc_num和平均长度按剩下的文档重新计算
+    pub fn merge_segments(&mut self) -> Result<()> {
+        let readers = open_segments(&self.config.directory, &self.config.identifier)?;
+        let readers = readers.iter().collect::<Vec<_>>();
+
+        let segment = self.write_merged(&readers, &Tombstones::default())?;
+        self.replace_segments(segment, &readers)
+    }
+
+    /// 整理索引：和merge_segments一样把所有segment合并成一个，去掉已经删除和被覆盖的文档的posting和原文，
+    /// doc id不变。新文件先写到.tmp再rename，identifier.segments换成新列表之前查询端看到的还是原来的索引。
+    /// 没有segment时什么都不做
+    pub fn compact(&mut self) -> Result<CompactStats> {
+        let segments = Segments::read(&self.config.directory, &self.config.identifier)?;
+        let mut stats = CompactStats {
+            segments: segments.ids().len(),
+            bytes_before: self.segments_size(&segments)?,
+            ..CompactStats::default()
+        };
+        if stats.segments == 0 {
+            return Ok(stats);
         }
 
-        fst_builder.finish()?;
+        self.merge_segments()?;
+
+        let segments = Segments::read(&self.config.directory, &self.config.identifier)?;
+        stats.bytes_after = self.segments_size(&segments)?;
+        Ok(stats)
+    }
+
+    fn segments_size(&self, segments: &Segments) -> Result<u64> {
+        let mut size = 0;
+        for id in segments.ids().iter() {
+            for suffix in SEGMENT_FILE_SUFFIXES {
+                let name = segment_file_name(&self.config.identifier, *id, suffix);
+                match self.config.directory.open(&name) {
+                    Ok(data) => size += data.len() as u64,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+
+        Ok(size)
+    }
+
+    /// 按policy反复挑出一些segment合并，直到不再需要合并为止，返回合并了几次。
+    /// 和merge_segments一样去掉这些segment里已经删除和被覆盖的文档，没有挑中的segment不变。
+    ///
+    /// 挑中的segment不一定相邻，合并出来的segment排在最后，所以它的tombstones要重新算：
+    /// 留下的segment里已经失效、而且在任何地方都没有有效版本的doc id，
+    /// 这样留下的segment里每篇文档是否有效都和合并前一样
+    pub fn merge_with_policy(&mut self, policy: &MergePolicy) -> Result<usize> {
+        let mut merges = 0;
+
+        loop {
+            let readers = open_segments(&self.config.directory, &self.config.identifier)?;
+            let sizes = readers
+                .iter()
+                .map(|reader| {
+                    let live = reader.doc_store.ids();
+                    live.filter(|id| !reader.tombstones.contains(*id)).count() as u32
+                })
+                .collect::<Vec<_>>();
+
+            let selected = match policy.select(&sizes) {
+                Some(selected) => selected,
+                None => return Ok(merges),
+            };
+            let (merged, kept): (Vec<_>, Vec<_>) = readers
+                .iter()
+                .enumerate()
+                .partition(|(i, _)| selected.contains(i));
+            let merged = merged.into_iter().map(|(_, r)| r).collect::<Vec<_>>();
+
+            let mut dead = BTreeSet::new();
+            let mut live = BTreeSet::new();
+            for (_, reader) in kept.iter() {
+                for id in reader.doc_store.ids() {
+                    if reader.tombstones.contains(id) {
+                        dead.insert(id);
+                    } else {
+                        live.insert(id);
+                    }
+                }
+            }
+            // 合并出来的segment里的文档本身就会覆盖旧的版本，也不能被自己的tombstones删掉
+            for reader in merged.iter() {
+                live.extend(
+                    reader
+                        .doc_store
+                        .ids()
+                        .filter(|id| !reader.tombstones.contains(*id)),
+                );
+            }
+            dead.retain(|id| !live.contains(id));
+
+            let segment = self.write_merged(&merged, &Tombstones::new(&dead))?;
+            self.replace_segments(segment, &merged)?;
+            merges += 1;
+        }
+    }
+
+    /// 把readers里有效的文档写成一个新的segment，返回segment编号，还没有加到identifier.segments里
+    fn write_merged(&mut self, readers: &[&SegmentReader], tombstones: &Tombstones) -> Result<u32> {
+        let segment = self.segment_id()?;
+
+        let mut doc_store = self.create_doc_store()?;
+        let mut numbers = NumericIndexBuilder::new();
+        let mut keys = BTreeMap::new();
+        let mut doc_num = 0u32;
+        let mut len_sum = (0u64, 0u64);
+
+        for reader in readers.iter() {
+            for id in reader.doc_store.ids() {
+                if reader.tombstones.contains(id) {
+                    continue;
+                }
+
+                let doc = reader.doc_store.get(id)?.ok_or(Error::OutOfRange)?;
+                let document = doc.document();
+                doc_store.add(&document)?;
+                numbers.add(id, &document.numbers);
+                if let Some(key) = document.key.filter(|key| !key.is_empty()) {
+                    keys.insert(key.to_string(), id);
+                }
+
+                doc_num += 1;
+                len_sum.0 += doc.title.chars().count().max(self.config.min_norm_len) as u64;
+                len_sum.1 += doc.content.chars().count().max(self.config.min_norm_len) as u64;
+            }
+        }
+
+        let avg_len = (
+            len_sum.0 as f64 / doc_num.max(1) as f64,
+            len_sum.1 as f64 / doc_num.max(1) as f64,
+        );
+        let mut writer = SegmentWriter::new(&self.config, segment, doc_num, avg_len)?;
+
+        // 按term的字典序遍历所有segment，同一个term在各个segment里的list合并成一个
+        let mut op = fst::map::OpBuilder::new();
+        for reader in readers.iter() {
+            op = op.add(&reader.term_index);
+        }
+        let mut stream = op.union();
+
+        while let Some((term, values)) = stream.next() {
+            let mut lists = Vec::with_capacity(values.len());
+            for v in values.iter() {
+                let reader = readers[v.index];
+                lists.push((reader.posting_list(v.value)?, Some(&reader.tombstones)));
+            }
+
+            // 所有doc都已经删除的term不再写入
+            if let Some(list) = RawPostingList::union_all(&lists)? {
+                let len = list.write(&mut writer.dict_writer)?;
+                writer.add_term(term, len)?;
+            }
+        }
+
+        writer.finish(doc_store, &mut numbers, &keys, tombstones)?;
+
+        self.segment = None;
+        self.key_lookup = None;
+        Ok(segment)
+    }
+
+    /// 用这个Builder的analyzer把doc store里所有有效的文档重新分词，建成一个新的segment替换掉所有旧的segment，
+    /// 比如加了同义词或者换了tokenizer之后，不需要再把文档重新发一遍。
+    /// 要换analyzer就用新的analyzer和原来的store_dir、identifier创建一个Builder再调用这个。
+    ///
+    /// doc id不变，已经删除和被覆盖的文档不会再出现，和merge_segments一样doc_num和平均长度按剩下的文档重新计算。
+    /// 还有没finish的文档时先finish，它们也会被重新分词。
+    /// 新的segment写完后才替换identifier.segments，中途出错时查询端看到的还是原来的索引。
+    /// progress在每篇文档添加后调用，参数是已经重新添加的文档数，返回值是总数
+    pub fn reindex(&mut self, mut progress: impl FnMut(u32)) -> Result<u32> {
+        if !self.added.is_empty() || !self.deleted.is_empty() {
+            self.finish()?;
+        }
+
+        let readers = open_segments(&self.config.directory, &self.config.identifier)?;
+        let mut count = 0u32;
+
+        for reader in readers.iter() {
+            for id in reader.doc_store.ids() {
+                if reader.tombstones.contains(id) {
+                    continue;
+                }
+
+                let doc = reader.doc_store.get(id)?.ok_or(Error::OutOfRange)?;
+                self.add_document(doc.document())?;
+
+                count += 1;
+                progress(count);
+            }
+        }
+
+        let segment = self.write_segment()?;
+        self.replace_segments(segment, &readers.iter().collect::<Vec<_>>())?;
+
+        Ok(count)
+    }
+
+    /// 把old从identifier.segments里去掉，segment加到最后，再删掉old的文件
+    fn replace_segments(&self, segment: u32, old: &[&SegmentReader]) -> Result<()> {
+        let mut segments = Segments::read(&self.config.directory, &self.config.identifier)?;
+        segments.retain(|id| old.iter().all(|reader| reader.id != id));
+        segments.push(segment);
+        segments.write(&self.config.directory, &self.config.identifier)?;
+
+        // 新的列表已经生效，旧文件删除失败也不影响查询
+        for reader in old.iter() {
+            for suffix in SEGMENT_FILE_SUFFIXES {
+                let name = segment_file_name(&self.config.identifier, reader.id, suffix);
+                let _ = self.config.directory.remove(&name);
+            }
+        }
 
         Ok(())
     }
 
-    #[inline]
-    fn write_index_header(&self, writer: &mut std::io::BufWriter<File>) -> Result<u64> {
-        writer.write_u64::<LittleEndian>(TERM_INDEX_MAGIC_NUMBER)?;
-        writer.write_u8(VERSION)?;
+    fn segment_id(&mut self) -> Result<u32> {
+        if let Some(segment) = self.segment {
+            return Ok(segment);
+        }
 
-        Ok((64 + 8) / 8)
+        let segment = Segments::read(&self.config.directory, &self.config.identifier)?.next_id();
+        self.segment = Some(segment);
+        Ok(segment)
     }
 
-    #[inline]
-    fn write_dict_header(&self, writer: &mut std::io::BufWriter<File>) -> Result<u64> {
-        writer.write_u64::<LittleEndian>(TERM_DICT_MAGIC_NUMBER)?;
-        writer.write_u8(VERSION)?;
-        writer.write_u32::<LittleEndian>(self.doc_num)?;
+    fn create_doc_store(&mut self) -> Result<DocStoreWriter> {
+        let segment = self.segment_id()?;
+        DocStoreWriter::new(self.config.create_file(segment, DOC_STORE_FILE_SUFFIX)?)
+    }
+}
+
+/// 按顺序写一个segment的term index和dict，doc store、数值字段和外部id的索引、tombstone在finish时写
+struct SegmentWriter<'c> {
+    config: &'c Config,
+    segment: u32,
+    fst_builder: fst::raw::Builder<DirectoryWriter>,
+    dict_writer: DirectoryWriter,
+    dict_offset: u64,
+}
 
-        Ok((64 + 8 + 32) / 8)
+impl<'c> SegmentWriter<'c> {
+    fn new(config: &'c Config, segment: u32, doc_num: u32, avg_len: (f64, f64)) -> Result<Self> {
+        let mut index_writer = config.create_file(segment, TERM_INDEX_FILE_SUFFIX)?;
+        let mut dict_writer = config.create_file(segment, TERM_DICT_FILE_SUFFIX)?;
+
+        write_index_header(&mut index_writer)?;
+        let dict_offset = write_dict_header(&mut dict_writer, doc_num, avg_len)?;
+
+        Ok(SegmentWriter {
+            config,
+            segment,
+            fst_builder: fst::raw::Builder::new(index_writer)?,
+            dict_writer,
+            dict_offset,
+        })
     }
 
-    #[inline]
-    fn write_dict(
-        &self,
-        writer: &mut std::io::BufWriter<File>,
-        data: &BuildingTermData,
-    ) -> Result<u64> {
-        let mut len = 0u64;
+    /// term必须按字典序添加，len是刚写进dict_writer的posting list的长度
+    fn add_term(&mut self, term: impl AsRef<[u8]>, len: u64) -> Result<()> {
+        self.fst_builder.insert(term, self.dict_offset)?;
+        self.dict_offset += len;
+        Ok(())
+    }
+
+    /// index最后写：它是查询时segment的入口，index存在时其他文件一定已经完整落盘
+    fn finish(
+        self,
+        doc_store: DocStoreWriter,
+        numbers: &mut NumericIndexBuilder,
+        keys: &BTreeMap<String, u32>,
+        tombstones: &Tombstones,
+    ) -> Result<()> {
+        self.dict_writer.finish()?;
+        doc_store.finish()?;
+
+        let mut numbers_writer = self
+            .config
+            .create_file(self.segment, NUMERIC_INDEX_FILE_SUFFIX)?;
+        numbers.write(&mut numbers_writer)?;
+        numbers_writer.finish()?;
+
+        let mut keys_writer = self
+            .config
+            .create_file(self.segment, KEY_INDEX_FILE_SUFFIX)?;
+        keys_writer.write_u64::<LittleEndian>(KEY_INDEX_MAGIC_NUMBER)?;
+        keys_writer.write_u8(VERSION)?;
+        let mut keys_builder = fst::MapBuilder::new(keys_writer)?;
+        for (key, id) in keys.iter() {
+            keys_builder.insert(key, *id as u64)?;
+        }
+        keys_builder.into_inner()?.finish()?;
 
-        let mut builder = PostingListBuilder::new(writer, data.get_posting_map());
-        len += builder.finish()?;
+        let mut tombstone_writer = self
+            .config
+            .create_file(self.segment, TOMBSTONE_FILE_SUFFIX)?;
+        tombstones.write(&mut tombstone_writer)?;
+        tombstone_writer.finish()?;
 
-        Ok(len)
+        self.fst_builder.into_inner()?.finish()?;
+
+        Ok(())
+    }
+}
+
+#[inline]
+fn write_index_header(writer: &mut DirectoryWriter) -> Result<u64> {
+    writer.write_u64::<LittleEndian>(TERM_INDEX_MAGIC_NUMBER)?;
+    writer.write_u8(VERSION)?;
+
+    Ok((64 + 8) / 8)
+}
+
+#[inline]
+fn write_dict_header(
+    writer: &mut DirectoryWriter,
+    doc_num: u32,
+    avg_len: (f64, f64),
+) -> Result<u64> {
+    writer.write_u64::<LittleEndian>(TERM_DICT_MAGIC_NUMBER)?;
+    writer.write_u8(VERSION)?;
+    writer.write_u32::<LittleEndian>(doc_num)?;
+    writer.write_f64::<LittleEndian>(avg_len.0)?;
+    writer.write_f64::<LittleEndian>(avg_len.1)?;
+
+    Ok((64 + 8 + 32 + 64 + 64) / 8)
+}
+
+/// 用VALUE_SEPARATOR分开的每个值单独分析，后一个值的位置从前一个值的最后一个词之后再空出gap开始
+fn analyze_values<C, T, I>(
+    analyzer: &Analyzer<C, T, I>,
+    text: &str,
+    gap: u32,
+) -> Result<Vec<(u32, String)>>
+where
+    C: CharFilter,
+    T: TokenFilter,
+    I: Tokenizer,
+{
+    let mut terms = Vec::new();
+    let mut position = 0u32;
+
+    for (i, value) in text.split(VALUE_SEPARATOR).enumerate() {
+        if i > 0 {
+            position = position.saturating_add(gap);
+        }
+        analyzer.analyze_each(value, |term| {
+            terms.push((position, term.to_string()));
+            position = position.saturating_add(1);
+        })?;
     }
+
+    Ok(terms)
 }
@@ -1,6 +1,33 @@
 pub const TERM_INDEX_MAGIC_NUMBER: u64 = 20395824072897813;
 pub const TERM_DICT_MAGIC_NUMBER: u64 = 349820934813094819;
-pub const VERSION: u8 = 1;
+pub const DOC_STORE_MAGIC_NUMBER: u64 = 7429103857201938465;
+pub const TOMBSTONE_MAGIC_NUMBER: u64 = 5820193748561029374;
+pub const SEGMENTS_MAGIC_NUMBER: u64 = 8301749265038172946;
+pub const NUMERIC_INDEX_MAGIC_NUMBER: u64 = 6150392847261937402;
+pub const KEY_INDEX_MAGIC_NUMBER: u64 = 3917465028374619253;
+pub const VERSION: u8 = 10;
+/// 还能直接打开的最早版本，写出的文件总是VERSION。各版本的区别：
+/// - 5：记录词的位置
+/// - 6：文档存储的每篇文档末尾加了keywords
+/// - 7：文档存储的每篇文档末尾加了numbers
+/// - 8：文档存储的每篇文档末尾加了payload
+/// - 9：每个segment多了一个数值字段的索引文件
+/// - 10：文档存储的每篇文档末尾加了外部id，每个segment多了一个外部id的索引文件
+///
+/// 5之后只有文档存储的格式变了，或者加了新的文件，旧segment按它自己的版本读取，缺少的字段当作空的，
+/// 没有数值字段索引时按文档存储过滤，合并segment时会写成新格式。更早的版本缺少词的位置和字段的原始长度，只能重建索引
+pub const MIN_SUPPORTED_VERSION: u8 = 5;
+
+/// 文件头里的版本能不能读
+#[inline]
+pub fn is_supported_version(version: u8) -> bool {
+    (MIN_SUPPORTED_VERSION..=VERSION).contains(&version)
+}
 
 pub const TERM_INDEX_FILE_SUFFIX: &str = ".musou";
 pub const TERM_DICT_FILE_SUFFIX: &str = ".hitotachi";
+pub const DOC_STORE_FILE_SUFFIX: &str = ".isshin";
+pub const TOMBSTONE_FILE_SUFFIX: &str = ".del";
+pub const SEGMENTS_FILE_SUFFIX: &str = ".segments";
+pub const NUMERIC_INDEX_FILE_SUFFIX: &str = ".kagura";
+pub const KEY_INDEX_FILE_SUFFIX: &str = ".sakura";
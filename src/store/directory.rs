@@ -0,0 +1,316 @@
+use memmap2::Mmap;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, ErrorKind, Write};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+type MemoryFiles = Arc<RwLock<HashMap<String, Arc<Vec<u8>>>>>;
+
+/// 索引文件存放的位置。Memory不碰文件系统，clone出来的共享同一份文件，
+/// 可以用同一个Directory建索引再查询，适合测试和临时的小索引
+#[derive(Debug, Clone)]
+pub enum Directory {
+    Fs(PathBuf),
+    Memory(MemoryFiles),
+}
+
+impl Directory {
+    pub fn memory() -> Self {
+        Directory::Memory(Arc::new(RwLock::new(HashMap::new())))
+    }
+
+    /// 已经存在的文件会被覆盖。写完必须调用DirectoryWriter::finish，文件这时才可见：
+    /// 文件系统上先写到name.tmp，finish时fsync再rename成name，进程在写的过程中退出或者机器掉电
+    /// 都不会留下写了一半的name，最多留下没用的.tmp文件，下次写同一个文件时会被覆盖
+    pub fn create(&self, name: &str) -> std::io::Result<DirectoryWriter> {
+        match self {
+            Directory::Fs(dir) => Ok(DirectoryWriter::Fs {
+                writer: BufWriter::new(File::create(dir.join(tmp_file_name(name)))?),
+                dir: dir.clone(),
+                name: name.to_string(),
+            }),
+            Directory::Memory(files) => Ok(DirectoryWriter::Memory {
+                files: files.clone(),
+                name: name.to_string(),
+                buf: Vec::new(),
+            }),
+        }
+    }
+
+    /// 文件系统上的文件直接mmap，Memory的文件共享同一份数据，都不需要拷贝
+    pub fn open(&self, name: &str) -> std::io::Result<FileBytes> {
+        let data = match self {
+            Directory::Fs(dir) => Data::Mmap(unsafe { Mmap::map(&File::open(dir.join(name))?)? }),
+            Directory::Memory(files) => Data::Memory(
+                read_files(files)
+                    .get(name)
+                    .cloned()
+                    .ok_or_else(|| not_found(name))?,
+            ),
+        };
+
+        Ok(FileBytes { data, offset: 0 })
+    }
+
+    pub fn rename(&self, from: &str, to: &str) -> std::io::Result<()> {
+        match self {
+            Directory::Fs(dir) => {
+                std::fs::rename(dir.join(from), dir.join(to))?;
+                sync_dir(dir)
+            }
+            Directory::Memory(files) => {
+                let mut files = write_files(files);
+                let data = files.remove(from).ok_or_else(|| not_found(from))?;
+                files.insert(to.to_string(), data);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn remove(&self, name: &str) -> std::io::Result<()> {
+        match self {
+            Directory::Fs(dir) => std::fs::remove_file(dir.join(name)),
+            Directory::Memory(files) => write_files(files)
+                .remove(name)
+                .map(|_| ())
+                .ok_or_else(|| not_found(name)),
+        }
+    }
+}
+
+impl From<PathBuf> for Directory {
+    fn from(dir: PathBuf) -> Self {
+        Directory::Fs(dir)
+    }
+}
+
+// 持有锁的时候不会panic，锁被污染时数据也是完整的
+fn read_files(
+    files: &MemoryFiles,
+) -> std::sync::RwLockReadGuard<'_, HashMap<String, Arc<Vec<u8>>>> {
+    files.read().unwrap_or_else(|e| e.into_inner())
+}
+
+fn write_files(
+    files: &MemoryFiles,
+) -> std::sync::RwLockWriteGuard<'_, HashMap<String, Arc<Vec<u8>>>> {
+    files.write().unwrap_or_else(|e| e.into_inner())
+}
+
+fn not_found(name: &str) -> std::io::Error {
+    std::io::Error::new(ErrorKind::NotFound, format!("{} not found", name))
+}
+
+fn tmp_file_name(name: &str) -> String {
+    String::from(name) + ".tmp"
+}
+
+/// rename要等目录本身落盘才算持久化。Windows上不能打开目录，NTFS的rename也不需要这一步
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> std::io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum DirectoryWriter {
+    Fs {
+        /// 写的是name.tmp
+        writer: BufWriter<File>,
+        dir: PathBuf,
+        name: String,
+    },
+    Memory {
+        files: MemoryFiles,
+        name: String,
+        buf: Vec<u8>,
+    },
+}
+
+impl DirectoryWriter {
+    pub fn finish(self) -> std::io::Result<()> {
+        match self {
+            DirectoryWriter::Fs { writer, dir, name } => {
+                let file = writer.into_inner().map_err(|e| e.into_error())?;
+                file.sync_all()?;
+                drop(file);
+
+                std::fs::rename(dir.join(tmp_file_name(&name)), dir.join(&name))?;
+                sync_dir(&dir)
+            }
+            DirectoryWriter::Memory { files, name, buf } => {
+                write_files(&files).insert(name, Arc::new(buf));
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Write for DirectoryWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        match self {
+            DirectoryWriter::Fs { writer, .. } => writer.write(data),
+            DirectoryWriter::Memory { buf, .. } => buf.write(data),
+        }
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> std::io::Result<()> {
+        match self {
+            DirectoryWriter::Fs { writer, .. } => writer.write_all(data),
+            DirectoryWriter::Memory { buf, .. } => buf.write_all(data),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            DirectoryWriter::Fs { writer, .. } => writer.flush(),
+            DirectoryWriter::Memory { .. } => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Data {
+    Mmap(Mmap),
+    Memory(Arc<Vec<u8>>),
+}
+
+/// Directory::open得到的只读文件内容
+#[derive(Debug)]
+pub struct FileBytes {
+    data: Data,
+    offset: usize,
+}
+
+impl FileBytes {
+    /// 跳过开头的n个字节，例如fst前面的header
+    pub fn skip(mut self, n: usize) -> Self {
+        self.offset = (self.offset + n).min(self.len_all());
+        self
+    }
+
+    fn len_all(&self) -> usize {
+        match &self.data {
+            Data::Mmap(mmap) => mmap.len(),
+            Data::Memory(buf) => buf.len(),
+        }
+    }
+}
+
+impl Deref for FileBytes {
+    type Target = [u8];
+
+    #[inline(always)]
+    fn deref(&self) -> &[u8] {
+        match &self.data {
+            Data::Mmap(mmap) => &mmap[self.offset..],
+            Data::Memory(buf) => &buf[self.offset..],
+        }
+    }
+}
+
+impl AsRef<[u8]> for FileBytes {
+    #[inline(always)]
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyzer::char_filter::CJKDocCharFilter;
+    use crate::analyzer::token_filter::BasicTokenFilter;
+    use crate::analyzer::tokenizer::UnicodeWordTokenizer;
+    use crate::query::{self, Query, Similarity};
+    use crate::store::{self, Builder, Document};
+    use crate::Analyzer;
+
+    fn analyzer() -> Analyzer<CJKDocCharFilter, BasicTokenFilter, UnicodeWordTokenizer> {
+        Analyzer::new(
+            CJKDocCharFilter::new(),
+            BasicTokenFilter::new(),
+            UnicodeWordTokenizer::new(),
+        )
+    }
+
+    fn open_query(directory: &Directory) -> query::Result<()> {
+        let config = query::Config::new(directory.clone(), "t", 3, 1, Similarity::default());
+        Query::new(analyzer(), config).map(|_| ())
+    }
+
+    fn write(directory: &Directory, name: &str, data: &[u8]) {
+        let mut writer = directory.create(name).unwrap();
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn partial_segment_files_fail_to_open() {
+        let directory = Directory::memory();
+        let mut builder = Builder::new(
+            analyzer(),
+            analyzer(),
+            store::Config::new(directory.clone(), "t", 1),
+        );
+        for id in 0..50 {
+            let content = format!("raiden shogun {} {}", id, "inazuma ".repeat(id as usize));
+            builder
+                .add_document(Document {
+                    id,
+                    title: "",
+                    content: &content,
+                    keywords: vec![("k", "v")],
+                    numbers: vec![("n", id as f64)],
+                    payload: None,
+                    key: None,
+                })
+                .unwrap();
+        }
+        builder.delete_document(3);
+        builder.finish().unwrap();
+        open_query(&directory).unwrap();
+
+        let files = match &directory {
+            Directory::Memory(files) => read_files(files).clone(),
+            Directory::Fs(_) => unreachable!(),
+        };
+        assert!(files.len() > 1);
+
+        // 没有finish的文件不可见，也不会覆盖原来的
+        let segments = files
+            .keys()
+            .find(|name| name.ends_with(".segments"))
+            .unwrap();
+        let mut writer = directory.create(segments).unwrap();
+        writer.write_all(b"garbage").unwrap();
+        drop(writer);
+        open_query(&directory).unwrap();
+
+        for (name, data) in files.iter() {
+            directory.remove(name).unwrap();
+            assert!(open_query(&directory).is_err(), "{} missing", name);
+
+            let mut lens = vec![0, 1, 8, data.len() / 2, data.len() - 1];
+            lens.retain(|len| *len < data.len());
+            for len in lens {
+                write(&directory, name, &data[..len]);
+                assert!(
+                    open_query(&directory).is_err(),
+                    "{} truncated to {}",
+                    name,
+                    len
+                );
+            }
+
+            write(&directory, name, data);
+            open_query(&directory).unwrap();
+        }
+    }
+}
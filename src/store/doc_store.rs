@@ -0,0 +1,364 @@
+use crate::store::constants::{is_supported_version, DOC_STORE_MAGIC_NUMBER, VERSION};
+use crate::store::directory::{DirectoryWriter, FileBytes};
+use crate::store::document::{Document, StoredDocument};
+use crate::store::{Error, Result};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::BTreeMap;
+use std::io::Write;
+
+/// magic number(64bit) + version(8bit)
+const HEADER_SIZE: u64 = (64 + 8) / 8;
+/// doc_id(32bit) + offset(64bit)
+const OFFSET_ENTRY_SIZE: usize = (32 + 64) / 8;
+/// offset表的位置(64bit) + 文档数量(32bit)
+const FOOTER_SIZE: usize = (64 + 32) / 8;
+
+/// 文件格式：header，然后每篇文档依次是 doc_id(32bit) + title长度(32bit) + title +
+/// content长度(32bit) + content + keyword数量(32bit) + 每个keyword的
+/// 字段名长度(32bit) + 字段名 + 值长度(32bit) + 值 + 数值字段数量(32bit) + 每个数值字段的
+/// 字段名长度(32bit) + 字段名 + 值(f64) + payload长度(32bit) + payload的json，长度为0表示没有payload +
+/// 外部id长度(32bit) + 外部id，长度为0表示没有，最后是按doc_id排序的offset表和footer
+#[derive(Debug)]
+pub struct DocStoreWriter {
+    writer: DirectoryWriter,
+    offset: u64,
+    /// (doc_id, 文档在文件中的位置)
+    offsets: Vec<(u32, u64)>,
+}
+
+impl DocStoreWriter {
+    pub fn new(mut writer: DirectoryWriter) -> Result<Self> {
+        writer.write_u64::<LittleEndian>(DOC_STORE_MAGIC_NUMBER)?;
+        writer.write_u8(VERSION)?;
+
+        Ok(DocStoreWriter {
+            writer,
+            offset: HEADER_SIZE,
+            offsets: Vec::new(),
+        })
+    }
+
+    pub fn add(&mut self, doc: &Document) -> Result<()> {
+        self.offsets.push((doc.id, self.offset));
+
+        self.writer.write_u32::<LittleEndian>(doc.id)?;
+        self.offset += 4;
+        self.write_text(doc.title)?;
+        self.write_text(doc.content)?;
+
+        self.writer
+            .write_u32::<LittleEndian>(doc.keywords.len() as u32)?;
+        self.offset += 4;
+        for (name, value) in doc.keywords.iter() {
+            self.write_text(name)?;
+            self.write_text(value)?;
+        }
+
+        self.writer
+            .write_u32::<LittleEndian>(doc.numbers.len() as u32)?;
+        self.offset += 4;
+        for (name, value) in doc.numbers.iter() {
+            self.write_text(name)?;
+            self.writer.write_f64::<LittleEndian>(*value)?;
+            self.offset += 8;
+        }
+
+        // 合法的json不会是空的，可以用长度0表示没有
+        let payload = match doc.payload {
+            Some(payload) => serde_json::to_string(payload).map_err(std::io::Error::from)?,
+            None => String::new(),
+        };
+        self.write_text(&payload)?;
+        self.write_text(doc.key.unwrap_or(""))?;
+
+        Ok(())
+    }
+
+    fn write_text(&mut self, text: &str) -> Result<()> {
+        self.writer.write_u32::<LittleEndian>(text.len() as u32)?;
+        self.writer.write_all(text.as_bytes())?;
+        self.offset += 4 + text.len() as u64;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        // 同一个id添加了多次时只保留最后一次，stable sort保证了相同id的先后顺序不变
+        self.offsets.sort_by_key(|o| o.0);
+        let mut offsets = Vec::<(u32, u64)>::with_capacity(self.offsets.len());
+        for o in self.offsets.into_iter() {
+            match offsets.last_mut() {
+                Some(last) if last.0 == o.0 => *last = o,
+                _ => offsets.push(o),
+            }
+        }
+
+        for o in offsets.iter() {
+            self.writer.write_u32::<LittleEndian>(o.0)?;
+            self.writer.write_u64::<LittleEndian>(o.1)?;
+        }
+
+        self.writer.write_u64::<LittleEndian>(self.offset)?;
+        self.writer
+            .write_u32::<LittleEndian>(offsets.len() as u32)?;
+        self.writer.finish()?;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct DocStoreReader {
+    data: FileBytes,
+    table_offset: usize,
+    len: usize,
+    layout: RecordLayout,
+}
+
+/// 每篇文档带有哪些字段，由文件的版本决定，旧版本没有的字段读出来是空的
+#[derive(Debug, Clone, Copy)]
+struct RecordLayout {
+    keywords: bool,
+    numbers: bool,
+    payload: bool,
+    key: bool,
+}
+
+impl RecordLayout {
+    fn of(version: u8) -> Self {
+        RecordLayout {
+            keywords: version >= 6,
+            numbers: version >= 7,
+            payload: version >= 8,
+            key: version >= 10,
+        }
+    }
+}
+
+impl DocStoreReader {
+    pub fn new(data: FileBytes) -> Result<Self> {
+        let mut header = &data[..];
+        if data.len() < HEADER_SIZE as usize + FOOTER_SIZE
+            || header.read_u64::<LittleEndian>()? != DOC_STORE_MAGIC_NUMBER
+        {
+            return Err(Error::Incompatible);
+        }
+
+        let layout = match header.read_u8()? {
+            v if is_supported_version(v) => RecordLayout::of(v),
+            v => return Err(Error::UnsupportedVersion(v)),
+        };
+
+        let footer = &data[data.len() - FOOTER_SIZE..];
+        let table_offset = LittleEndian::read_u64(footer) as usize;
+        let len = LittleEndian::read_u32(&footer[8..]) as usize;
+
+        if table_offset + len * OFFSET_ENTRY_SIZE + FOOTER_SIZE != data.len() {
+            return Err(Error::Incompatible);
+        }
+
+        Ok(DocStoreReader {
+            data,
+            table_offset,
+            len,
+            layout,
+        })
+    }
+
+    /// 按升序返回所有doc id
+    pub fn ids(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..self.len).map(move |i| {
+            LittleEndian::read_u32(&self.data[self.table_offset + i * OFFSET_ENTRY_SIZE..])
+        })
+    }
+
+    /// 在offset表里二分查找doc_id，不存在时返回None
+    pub fn get(&self, doc_id: u32) -> Result<Option<StoredDocument>> {
+        match self.find(doc_id) {
+            Some(offset) => self.read_document(offset).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// 最大的doc id，没有文档时返回None
+    pub fn last_id(&self) -> Option<u32> {
+        let last = self.len.checked_sub(1)?;
+        Some(LittleEndian::read_u32(
+            &self.data[self.table_offset + last * OFFSET_ENTRY_SIZE..],
+        ))
+    }
+
+    /// 只读一个数值字段，文档不存在时返回Ok(None)，文档没有这个字段时返回Ok(Some(None))。
+    /// title、content等只读长度跳过，不用像get一样解码整篇文档
+    pub fn get_number(&self, doc_id: u32, name: &str) -> Result<Option<Option<f64>>> {
+        let mut pos = match self.find(doc_id) {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+        if !self.layout.numbers {
+            return Ok(Some(None));
+        }
+        let data = &self.data[..self.table_offset];
+
+        // doc_id、title、content
+        pos += 4;
+        for _ in 0..2 {
+            pos += 4 + read_u32(data, pos)? as usize;
+        }
+
+        let keywords = read_u32(data, pos)?;
+        pos += 4;
+        for _ in 0..keywords * 2 {
+            pos += 4 + read_u32(data, pos)? as usize;
+        }
+
+        // 同一个字段名出现多次时和get一样取最后一个
+        let numbers = read_u32(data, pos)?;
+        let mut found = None;
+        pos += 4;
+        for _ in 0..numbers {
+            let len = read_u32(data, pos)? as usize;
+            let key = data.get(pos + 4..pos + 4 + len).ok_or(Error::OutOfRange)?;
+            pos += 4 + len;
+
+            if key == name.as_bytes() {
+                let value = data.get(pos..pos + 8).ok_or(Error::OutOfRange)?;
+                found = Some(LittleEndian::read_f64(value));
+            }
+            pos += 8;
+        }
+
+        Ok(Some(found))
+    }
+
+    /// 只读一个keyword字段，返回值和get_number一样
+    pub fn get_keyword(&self, doc_id: u32, name: &str) -> Result<Option<Option<String>>> {
+        let mut pos = match self.find(doc_id) {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+        if !self.layout.keywords {
+            return Ok(Some(None));
+        }
+        let data = &self.data[..self.table_offset];
+
+        // doc_id、title、content
+        pos += 4;
+        for _ in 0..2 {
+            pos += 4 + read_u32(data, pos)? as usize;
+        }
+
+        // 同一个字段名出现多次时和get一样取最后一个
+        let keywords = read_u32(data, pos)?;
+        let mut found = None;
+        pos += 4;
+        for _ in 0..keywords {
+            let len = read_u32(data, pos)? as usize;
+            let key = data.get(pos + 4..pos + 4 + len).ok_or(Error::OutOfRange)?;
+            pos += 4 + len;
+
+            let len = read_u32(data, pos)? as usize;
+            if key == name.as_bytes() {
+                let value = data.get(pos + 4..pos + 4 + len).ok_or(Error::OutOfRange)?;
+                found = Some(String::from_utf8(value.to_vec()).map_err(|_| Error::Incompatible)?);
+            }
+            pos += 4 + len;
+        }
+
+        Ok(Some(found))
+    }
+
+    /// doc_id对应的文档在文件中的位置
+    fn find(&self, doc_id: u32) -> Option<usize> {
+        let (mut min, mut max) = (0usize, self.len);
+
+        while min < max {
+            let mid = min + ((max - min) >> 1);
+            let entry = &self.data[self.table_offset + mid * OFFSET_ENTRY_SIZE..];
+            let id = LittleEndian::read_u32(entry);
+
+            if id < doc_id {
+                min = mid + 1;
+            } else if id > doc_id {
+                max = mid;
+            } else {
+                return Some(LittleEndian::read_u64(&entry[4..]) as usize);
+            }
+        }
+
+        None
+    }
+
+    fn read_document(&self, offset: usize) -> Result<StoredDocument> {
+        let data = &self.data[..self.table_offset];
+        let mut pos = offset;
+
+        let read_u32 = |pos: &mut usize| -> Result<u32> {
+            let v = read_u32(data, *pos)?;
+            *pos += 4;
+            Ok(v)
+        };
+
+        let read_text = |pos: &mut usize| -> Result<String> {
+            let len = read_u32(pos)? as usize;
+            let bytes = data.get(*pos..*pos + len).ok_or(Error::OutOfRange)?;
+            *pos += len;
+            String::from_utf8(bytes.to_vec()).map_err(|_| Error::Incompatible)
+        };
+
+        let id = read_u32(&mut pos)?;
+        let title = read_text(&mut pos)?;
+        let content = read_text(&mut pos)?;
+
+        let mut keywords = BTreeMap::new();
+        let keyword_num = match self.layout.keywords {
+            true => read_u32(&mut pos)?,
+            false => 0,
+        };
+        for _ in 0..keyword_num {
+            let name = read_text(&mut pos)?;
+            keywords.insert(name, read_text(&mut pos)?);
+        }
+
+        let mut numbers = BTreeMap::new();
+        let number_num = match self.layout.numbers {
+            true => read_u32(&mut pos)?,
+            false => 0,
+        };
+        for _ in 0..number_num {
+            let name = read_text(&mut pos)?;
+            let value = data.get(pos..pos + 8).ok_or(Error::OutOfRange)?;
+            numbers.insert(name, LittleEndian::read_f64(value));
+            pos += 8;
+        }
+
+        let payload = match self.layout.payload {
+            true => read_text(&mut pos)?,
+            false => String::new(),
+        };
+        let payload = match payload.is_empty() {
+            true => None,
+            false => Some(serde_json::from_str(&payload).map_err(|_| Error::Incompatible)?),
+        };
+
+        let key = match self.layout.key {
+            true => Some(read_text(&mut pos)?).filter(|key| !key.is_empty()),
+            false => None,
+        };
+
+        Ok(StoredDocument {
+            id,
+            title,
+            content,
+            keywords,
+            numbers,
+            payload,
+            key,
+        })
+    }
+}
+
+fn read_u32(data: &[u8], pos: usize) -> Result<u32> {
+    data.get(pos..pos + 4)
+        .ok_or(Error::OutOfRange)
+        .map(LittleEndian::read_u32)
+}
@@ -1,5 +1,77 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// 多值字段（tag、作者等）的分隔符，title和content里用它隔开各个值。每个值单独分析，
+/// 值之间的位置空出Config::with_position_gap，短语不会跨值匹配。存进文档存储的仍然是整个字符串
+pub const VALUE_SEPARATOR: char = '\u{1f}';
+
 pub struct Document<'a> {
     pub id: u32,
+    /// 多个值时用VALUE_SEPARATOR隔开，下同
     pub title: &'a str,
     pub content: &'a str,
+    /// keyword字段：(字段名, 值)。不分词也不进倒排索引，原样存进文档存储，用于Query::facet统计。
+    /// 同一个字段名出现多次时读出来只保留最后一个
+    pub keywords: Vec<(&'a str, &'a str)>,
+    /// 数值字段：(字段名, 值)，和keyword一样只存进文档存储，用于QueryOptions::numeric_range过滤
+    pub numbers: Vec<(&'a str, f64)>,
+    /// 调用方自己的数据（url、作者等），序列化成json原样存进文档存储，不分词也不影响打分
+    pub payload: Option<&'a serde_json::Value>,
+    /// 调用方自己的外部id（uuid、url等），一般用Builder::id_for_key分配doc id，
+    /// 查询结果里可以从文档存储取回来。None或者空字符串表示没有
+    pub key: Option<&'a str>,
+}
+
+/// Builder::analyze_document的结果：文档本身和两个字段分析后的(位置, 词)，位置是递增的。
+/// 只有一个值时位置就是词的下标，多值字段后一个值的位置从前一个值之后再空出position_gap开始。
+/// 分析不需要修改Builder，可以在多个线程里并行做，再交给Builder::add_analyzed按顺序插入
+pub struct AnalyzedDocument<'a> {
+    pub doc: Document<'a>,
+    pub title_terms: Vec<(u32, String)>,
+    pub content_terms: Vec<(u32, String)>,
+}
+
+/// 从文档存储中读出来的文档，和Document一样，只是持有自己的数据
+#[derive(Debug, Clone, Serialize)]
+pub struct StoredDocument {
+    pub id: u32,
+    pub title: String,
+    pub content: String,
+    pub keywords: BTreeMap<String, String>,
+    pub numbers: BTreeMap<String, f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+}
+
+impl StoredDocument {
+    /// 借用自己的数据转成Document，用来把存储里的文档重新写进Builder
+    pub fn document(&self) -> Document<'_> {
+        Document {
+            id: self.id,
+            title: self.title.as_str(),
+            content: self.content.as_str(),
+            keywords: self
+                .keywords
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str()))
+                .collect(),
+            numbers: self
+                .numbers
+                .iter()
+                .map(|(name, value)| (name.as_str(), *value))
+                .collect(),
+            payload: self.payload.as_ref(),
+            key: self.key.as_deref(),
+        }
+    }
+}
+
+/// 文档的字段
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Field {
+    Title,
+    Content,
 }
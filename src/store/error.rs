@@ -1,4 +1,5 @@
 use crate::analyzer;
+use crate::store::constants::{MIN_SUPPORTED_VERSION, VERSION};
 
 use std::fmt::{Formatter, Debug, Display};
 
@@ -10,7 +11,11 @@ pub enum Error {
     Io(std::io::Error),
     Fst(fst::Error),
     Incompatible,
-    OutOfRange
+    UnsupportedVersion(u8),
+    OutOfRange,
+    Deleted(u32),
+    /// (外部id, 它已经对应的doc id)
+    KeyConflict(String, u32)
 }
 
 impl std::fmt::Display for Error {
@@ -20,7 +25,14 @@ impl std::fmt::Display for Error {
             Error::Io(ref e) => Display::fmt(&e, f),
             Error::Fst(ref e) => Display::fmt(&e, f),
             Error::Incompatible => write!(f, "incompatible data file"),
-            Error::OutOfRange => write!(f, "out of range")
+            Error::UnsupportedVersion(v) => write!(
+                f,
+                "unsupported index version {} (supported {} to {}), the index needs to be rebuilt",
+                v, MIN_SUPPORTED_VERSION, VERSION
+            ),
+            Error::OutOfRange => write!(f, "out of range"),
+            Error::Deleted(id) => write!(f, "document {} has been deleted", id),
+            Error::KeyConflict(ref key, id) => write!(f, "key {:?} already belongs to document {}", key, id)
         }
     }
 }
@@ -32,7 +44,10 @@ impl std::error::Error for Error {
             Error::Io(ref e) => Some(e),
             Error::Fst(ref e) => Some(e),
             Error::Incompatible => None,
-            Error::OutOfRange => None
+            Error::UnsupportedVersion(_) => None,
+            Error::OutOfRange => None,
+            Error::Deleted(_) => None,
+            Error::KeyConflict(..) => None
         }
     }
 }
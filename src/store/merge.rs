@@ -0,0 +1,76 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// 分层的segment合并策略。segment按有效文档数分层：不超过min_segment_docs的是第0层，
+/// 之后每层的上限是上一层的segments_per_tier倍。某一层攒够segments_per_tier个segment时，
+/// 把它们合并成一个（大概会落到上一层），segment的数量大致只和总文档数的对数成正比。
+/// 见Builder::merge_with_policy
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MergePolicy {
+    /// 每层最多留多少个segment，也是相邻两层之间文档数的倍数，小于2时按2算
+    pub segments_per_tier: usize,
+    /// 第0层的上限，比它小的segment都算一样大
+    pub min_segment_docs: u32,
+    /// 有效文档数达到这么多的segment不再参与合并，避免反复重写很大的segment
+    pub max_segment_docs: u32,
+}
+
+impl Default for MergePolicy {
+    fn default() -> Self {
+        MergePolicy {
+            segments_per_tier: 10,
+            min_segment_docs: 1000,
+            max_segment_docs: 5_000_000,
+        }
+    }
+}
+
+impl MergePolicy {
+    /// sizes是每个segment的有效文档数，返回这次要合并的segment的下标，升序排列，不需要合并时返回None。
+    /// 从最低的层开始找，在第一个凑够segments_per_tier个segment的层里取文档最少的那么多个
+    ///
+    /// ```
+    /// use raiden_shogun_search::store::MergePolicy;
+    ///
+    /// let policy = MergePolicy {
+    ///     segments_per_tier: 3,
+    ///     min_segment_docs: 10,
+    ///     max_segment_docs: 1000,
+    /// };
+    /// assert_eq!(policy.select(&[500, 5, 8, 40]), None);
+    /// assert_eq!(policy.select(&[500, 5, 8, 40, 2]), Some(vec![1, 2, 4]));
+    /// // 超过max_segment_docs的不参与合并
+    /// assert_eq!(policy.select(&[2000, 3000, 1000]), None);
+    /// ```
+    pub fn select(&self, sizes: &[u32]) -> Option<Vec<usize>> {
+        let count = self.segments_per_tier.max(2);
+
+        let mut tiers = BTreeMap::<u32, Vec<usize>>::new();
+        for (i, docs) in sizes.iter().enumerate() {
+            if *docs < self.max_segment_docs {
+                tiers.entry(self.tier(*docs)).or_default().push(i);
+            }
+        }
+
+        let mut selected = tiers.into_values().find(|t| t.len() >= count)?;
+        selected.sort_by_key(|i| sizes[*i]);
+        selected.truncate(count);
+        selected.sort_unstable();
+
+        Some(selected)
+    }
+
+    fn tier(&self, docs: u32) -> u32 {
+        let factor = self.segments_per_tier.max(2) as u64;
+        let mut limit = self.min_segment_docs.max(1) as u64;
+        let mut tier = 0;
+
+        while docs as u64 > limit {
+            limit = limit.saturating_mul(factor);
+            tier += 1;
+        }
+
+        tier
+    }
+}
@@ -1,13 +1,27 @@
 pub mod document;
 pub mod builder;
 mod error;
+mod merge;
 
 pub use error::Error;
 pub use error::Result;
+pub use document::AnalyzedDocument;
 pub use document::Document;
+pub use document::Field;
+pub use document::StoredDocument;
+pub use document::VALUE_SEPARATOR;
 pub use builder::Builder;
+pub use builder::BuilderStats;
+pub use builder::CompactStats;
 pub use builder::Config;
+pub use merge::MergePolicy;
+pub use directory::Directory;
 
 pub(crate) mod term;
 pub(crate) mod posting;
+pub(crate) mod doc_store;
+pub(crate) mod tombstone;
+pub(crate) mod segment;
+pub(crate) mod numeric;
+pub mod directory;
 pub mod constants;
\ No newline at end of file
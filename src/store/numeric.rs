@@ -0,0 +1,148 @@
+use crate::store::constants::{is_supported_version, NUMERIC_INDEX_MAGIC_NUMBER, VERSION};
+use crate::store::directory::FileBytes;
+use crate::store::{Error, Result};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::BTreeMap;
+use std::io::Write;
+
+/// 值(f64) + doc_id(32bit)
+const ENTRY_SIZE: usize = (64 + 32) / 8;
+
+/// 建索引时收集一个segment里的数值字段，写出时每个字段按(值, doc_id)排序，
+/// 查询时二分就能找到一个范围内的所有doc id，不需要逐篇读文档存储
+#[derive(Debug, Default)]
+pub struct NumericIndexBuilder {
+    fields: BTreeMap<String, Vec<(f64, u32)>>,
+}
+
+impl NumericIndexBuilder {
+    pub fn new() -> Self {
+        NumericIndexBuilder::default()
+    }
+
+    /// 同一个字段名出现多次时只保留最后一个，和从文档存储读出来的一样。NaN不在任何范围内，不写入
+    pub fn add(&mut self, doc_id: u32, numbers: &[(&str, f64)]) {
+        let numbers = numbers.iter().copied().collect::<BTreeMap<_, _>>();
+
+        for (name, value) in numbers.into_iter().filter(|n| !n.1.is_nan()) {
+            match self.fields.get_mut(name) {
+                Some(entries) => entries.push((value, doc_id)),
+                None => {
+                    self.fields.insert(name.to_string(), vec![(value, doc_id)]);
+                }
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.fields.clear();
+    }
+
+    /// magic number(64bit) + version(8bit) + 字段数量(32bit) + 每个字段的
+    /// 字段名长度(32bit) + 字段名 + 数量(32bit) + (值(f64) + doc_id(32bit)) * 数量，按值升序，值相同时按doc_id
+    pub fn write(&mut self, mut writer: impl Write) -> Result<()> {
+        writer.write_u64::<LittleEndian>(NUMERIC_INDEX_MAGIC_NUMBER)?;
+        writer.write_u8(VERSION)?;
+        writer.write_u32::<LittleEndian>(self.fields.len() as u32)?;
+
+        for (name, entries) in self.fields.iter_mut() {
+            entries.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+
+            writer.write_u32::<LittleEndian>(name.len() as u32)?;
+            writer.write_all(name.as_bytes())?;
+            writer.write_u32::<LittleEndian>(entries.len() as u32)?;
+            for (value, doc_id) in entries.iter() {
+                writer.write_f64::<LittleEndian>(*value)?;
+                writer.write_u32::<LittleEndian>(*doc_id)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct NumericIndexReader {
+    data: FileBytes,
+    /// 字段名 -> (第一个值在data中的位置, 数量)
+    fields: BTreeMap<String, (usize, usize)>,
+}
+
+impl NumericIndexReader {
+    pub fn new(data: FileBytes) -> Result<Self> {
+        let mut reader = &data[..];
+        if reader.read_u64::<LittleEndian>()? != NUMERIC_INDEX_MAGIC_NUMBER {
+            return Err(Error::Incompatible);
+        }
+
+        match reader.read_u8()? {
+            v if is_supported_version(v) => (),
+            v => return Err(Error::UnsupportedVersion(v)),
+        }
+
+        let mut fields = BTreeMap::new();
+        for _ in 0..reader.read_u32::<LittleEndian>()? {
+            let name_len = reader.read_u32::<LittleEndian>()? as usize;
+            if reader.len() < name_len {
+                return Err(Error::Incompatible);
+            }
+            let name = std::str::from_utf8(&reader[..name_len]).map_err(|_| Error::Incompatible)?;
+            reader = &reader[name_len..];
+
+            let len = reader.read_u32::<LittleEndian>()? as usize;
+            if reader.len() < len * ENTRY_SIZE {
+                return Err(Error::Incompatible);
+            }
+            fields.insert(name.to_string(), (data.len() - reader.len(), len));
+            reader = &reader[len * ENTRY_SIZE..];
+        }
+
+        Ok(NumericIndexReader { data, fields })
+    }
+
+    /// 这个segment里没有文档有这个字段时返回None
+    pub fn field(&self, name: &str) -> Option<NumericColumn<'_>> {
+        let (offset, len) = *self.fields.get(name)?;
+
+        Some(NumericColumn {
+            entries: &self.data[offset..offset + len * ENTRY_SIZE],
+        })
+    }
+}
+
+/// 一个字段的所有(值, doc_id)，按值升序
+#[derive(Debug, Clone, Copy)]
+pub struct NumericColumn<'a> {
+    entries: &'a [u8],
+}
+
+impl NumericColumn<'_> {
+    pub fn len(&self) -> usize {
+        self.entries.len() / ENTRY_SIZE
+    }
+
+    /// (值, doc_id)
+    #[inline]
+    pub fn get(&self, i: usize) -> (f64, u32) {
+        let entry = &self.entries[i * ENTRY_SIZE..];
+        (
+            LittleEndian::read_f64(entry),
+            LittleEndian::read_u32(&entry[8..]),
+        )
+    }
+
+    /// 和slice::partition_point一样，pred对值要满足前面都是true后面都是false
+    pub fn partition_point(&self, pred: impl Fn(f64) -> bool) -> usize {
+        let (mut left, mut right) = (0, self.len());
+        while left < right {
+            let mid = left + (right - left) / 2;
+            if pred(self.get(mid).0) {
+                left = mid + 1;
+            } else {
+                right = mid;
+            }
+        }
+
+        left
+    }
+}
@@ -1,50 +1,175 @@
-use crate::query::score::{calc_norm, calc_tf};
-use crate::store::{Document, Error, Result};
-use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
-use memmap2::{Mmap, MmapOptions};
-use std::cmp::Ordering;
-use std::collections::{BTreeMap, LinkedList};
-use std::fs::File;
-use std::io::{Seek, SeekFrom};
-use std::ops::Deref;
+use crate::query::score::calc_tf;
+use crate::store::document::Field;
+use crate::store::tombstone::Tombstones;
+use crate::store::{Error, Result};
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 
 pub type BuildingPostingMap = BTreeMap<u32, BuildingPostingData>;
 
-/// 因为并没有存document的信息，无法通过doc id找到norm，所以norm只能每个term下都存一份
+/// 因为并没有存document的信息，无法通过doc id找到字段长度，所以长度只能每个term下都存一份。
+/// 存的是原始长度而不是量化后的norm，查询时再按需要的精度计算
 #[derive(Debug)]
 pub struct BuildingPostingData {
-    freq_content: u16,
-    freq_title: u16,
-    norm_content: u8,
-    norm_title: u8,
+    /// 词在字段里出现的位置（第几个token），个数就是词频
+    positions_content: Vec<u32>,
+    positions_title: Vec<u32>,
+    len_content: u16,
+    len_title: u16,
 }
 
 impl BuildingPostingData {
-    pub fn new(doc: &Document) -> Self {
+    /// len: (len_title, len_content)，同一篇文档的长度只需要在Builder里算一次
+    pub fn new(len: (u16, u16)) -> Self {
         BuildingPostingData {
-            freq_title: 0,
-            freq_content: 0,
-            norm_title: calc_norm(doc.title.chars().count()),
-            norm_content: calc_norm(doc.content.chars().count()),
+            positions_title: Vec::new(),
+            positions_content: Vec::new(),
+            len_title: len.0,
+            len_content: len.1,
         }
     }
 
+    /// position必须是递增的
     #[inline]
-    pub fn add_tf(&mut self, is_title: bool) {
+    pub fn add_position(&mut self, position: u32, is_title: bool) {
         if is_title {
-            if self.freq_title < u16::MAX {
-                self.freq_title += 1;
-            }
-        } else if self.freq_content < u16::MAX {
-            self.freq_content += 1;
+            self.positions_title.push(position);
+        } else {
+            self.positions_content.push(position);
+        }
+    }
+}
+
+#[inline(always)]
+fn freq(positions: &[u32]) -> u16 {
+    positions.len().min(u16::MAX as usize) as u16
+}
+
+/// 每隔多少个posting分一个block，除了第一个block，每个block存一个skip anchor，
+/// 查找某个doc id时可以先在anchor里二分，再在block里顺序解码
+const SKIP_INTERVAL: u32 = 64;
+/// skip anchor: 上一个block最后的doc_id(32bit) + block在data中的位置(32bit)
+const SKIP_ANCHOR_SIZE: u32 = (32 + 32) / 8;
+/// tf_title(8bit) + tf_content(8bit) + len_title(16bit) + len_content(16bit)
+const POSTING_INFO_SIZE: usize = (8 + 8 + 16 + 16) / 8;
+
+/// title位置数量(varint) + title位置 + content位置，位置都是和前一个的差值(varint，第一个和0比)，
+/// content的位置一直到结尾，不需要单独存数量
+fn encode_positions(title: &[u32], content: &[u32]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    write_varint(&mut bytes, title.len() as u32);
+
+    for positions in [title, content] {
+        let mut last = 0;
+        for position in positions.iter() {
+            write_varint(&mut bytes, position - last);
+            last = *position;
         }
     }
+
+    bytes
+}
+
+/// 返回(title的位置, content的位置)，都是升序
+fn decode_positions(bytes: &[u8]) -> Result<(Vec<u32>, Vec<u32>)> {
+    let mut pos = 0;
+    let title_num = read_varint(bytes, &mut pos)?;
+    let (mut title, mut content) = (Vec::new(), Vec::new());
+
+    let mut last = 0u32;
+    for _ in 0..title_num {
+        last = last
+            .checked_add(read_varint(bytes, &mut pos)?)
+            .ok_or(Error::OutOfRange)?;
+        title.push(last);
+    }
+
+    last = 0;
+    while pos < bytes.len() {
+        last = last
+            .checked_add(read_varint(bytes, &mut pos)?)
+            .ok_or(Error::OutOfRange)?;
+        content.push(last);
+    }
+
+    Ok((title, content))
+}
+
+/// 两个升序的位置列表合并成一个，去掉重复的
+fn merge_positions(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut merged = Vec::with_capacity(a.len() + b.len());
+    merged.extend_from_slice(a);
+    merged.extend_from_slice(b);
+    merged.sort_unstable();
+    merged.dedup();
+    merged
+}
+
+/// 写入LEB128 varint
+fn write_varint(writer: &mut Vec<u8>, mut value: u32) {
+    while value >= 0x80 {
+        writer.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    writer.push(value as u8);
 }
 
-/// doc_id(32bit) + norm_title(8bit) + norm_content(8bit) + tf_title(8bit) + tf_content(8bit)
-const POSTING_SIZE: u32 = (32 + 8 + 8 + 8 + 8) / 8;
-const INTERSECTION_PERFORMANCE_TIPPING_SIZE_DIFF: u32 = 50;
+#[inline(always)]
+fn read_varint(data: &[u8], pos: &mut usize) -> Result<u32> {
+    let mut value = 0u32;
+    let mut shift = 0;
+
+    loop {
+        let byte = *data.get(*pos).ok_or(Error::OutOfRange)?;
+        *pos += 1;
+
+        if shift > 28 {
+            return Err(Error::OutOfRange);
+        }
+        value |= ((byte & 0x7f) as u32) << shift;
 
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+/// 按doc_id升序编码posting，得到skip anchor和data两部分
+#[derive(Debug, Default)]
+struct PostingListEncoder {
+    len: u32,
+    last_doc_id: u32,
+    anchors: Vec<u8>,
+    data: Vec<u8>,
+}
+
+impl PostingListEncoder {
+    fn add(&mut self, doc_id: u32, tf: (u8, u8), len: (u16, u16), positions: &[u8]) {
+        if self.len > 0 && self.len % SKIP_INTERVAL == 0 {
+            self.anchors
+                .extend_from_slice(&self.last_doc_id.to_le_bytes());
+            self.anchors
+                .extend_from_slice(&(self.data.len() as u32).to_le_bytes());
+        }
+
+        write_varint(&mut self.data, doc_id - self.last_doc_id);
+        self.data.extend_from_slice(&[tf.0, tf.1]);
+        self.data.extend_from_slice(&len.0.to_le_bytes());
+        self.data.extend_from_slice(&len.1.to_le_bytes());
+        write_varint(&mut self.data, positions.len() as u32);
+        self.data.extend_from_slice(positions);
+
+        self.len += 1;
+        self.last_doc_id = doc_id;
+    }
+}
+
+/// 格式：posting数量(32bit) + data长度(32bit) + skip anchor * (block数量 - 1) + data。
+/// data里每个posting是 doc_id与前一个的差值(varint，第一个和0比) +
+/// tf_title(8bit) + tf_content(8bit) + len_title(16bit) + len_content(16bit) +
+/// 位置的字节数(varint) + 位置（格式见encode_positions），不需要位置时可以按字节数直接跳过
 #[derive(Debug)]
 pub struct PostingListBuilder<'a, W: std::io::Write> {
     writer: W,
@@ -57,21 +182,28 @@ impl<'a, W: std::io::Write> PostingListBuilder<'a, W> {
     }
 
     pub fn finish(&mut self) -> Result<u64> {
-        self.writer
-            .write_u32::<LittleEndian>(self.map.len() as u32)?;
-        let mut len = 4u64;
+        // BTreeMap的key已经是升序，差值不会是负数
+        let mut encoder = PostingListEncoder::default();
 
         for v in self.map.iter() {
-            self.writer.write_u32::<LittleEndian>(*v.0)?;
-            self.writer.write_u8(calc_tf(v.1.freq_title))?;
-            self.writer.write_u8(calc_tf(v.1.freq_content))?;
-            self.writer.write_u8(v.1.norm_title)?;
-            self.writer.write_u8(v.1.norm_content)?;
-
-            len += POSTING_SIZE as u64;
+            encoder.add(
+                *v.0,
+                (
+                    calc_tf(freq(&v.1.positions_title)),
+                    calc_tf(freq(&v.1.positions_content)),
+                ),
+                (v.1.len_title, v.1.len_content),
+                &encode_positions(&v.1.positions_title, &v.1.positions_content),
+            );
         }
 
-        Ok(len)
+        self.writer.write_u32::<LittleEndian>(encoder.len)?;
+        self.writer
+            .write_u32::<LittleEndian>(encoder.data.len() as u32)?;
+        self.writer.write_all(&encoder.anchors)?;
+        self.writer.write_all(&encoder.data)?;
+
+        Ok(4 + 4 + encoder.anchors.len() as u64 + encoder.data.len() as u64)
     }
 }
 
@@ -79,17 +211,17 @@ impl<'a, W: std::io::Write> PostingListBuilder<'a, W> {
 pub struct TermPriorityInfo {
     /// (tf_title, tf_content)
     pub tf: (u8, u8),
-    /// (norm_title, norm_content)
-    pub norm: (u8, u8),
+    /// (len_title, len_content)
+    pub len: (u16, u16),
 }
 
 impl TermPriorityInfo {
-    pub fn new(tf: (u8, u8), norm: (u8, u8)) -> Self {
-        TermPriorityInfo { tf, norm }
+    pub fn new(tf: (u8, u8), len: (u16, u16)) -> Self {
+        TermPriorityInfo { tf, len }
     }
 
     pub fn not_exist() -> Self {
-        TermPriorityInfo::new((0u8, 0u8), (0u8, 0u8))
+        TermPriorityInfo::new((0u8, 0u8), (0u16, 0u16))
     }
 }
 
@@ -121,17 +253,23 @@ impl Posting {
 }
 
 #[derive(Debug)]
-pub struct PostingListMerger {
-    // TODO: benchmark一下是vec更快还是LinkedList？LinkedList会导致cache miss
+pub struct PostingListMerger<'t> {
     postings: Vec<Posting>,
     merged_num: u32,
+    /// 只看这个字段：另一个字段的tf当作0，只在另一个字段出现的doc当作不包含这个词
+    field: Option<Field>,
+    /// 已经删除的doc，union时直接跳过，不会进入postings，len()和打分、分页都不用再考虑删除
+    tombstones: Option<&'t Tombstones>,
 }
 
-impl PostingListMerger {
-    pub fn new() -> Self {
+impl<'t> PostingListMerger<'t> {
+    /// list里已经去掉了删除的doc时（多个segment合并出来的list）tombstones传None
+    pub fn new(field: Option<Field>, tombstones: Option<&'t Tombstones>) -> Self {
         PostingListMerger {
             postings: Vec::new(),
             merged_num: 0,
+            field,
+            tombstones: tombstones.filter(|t| !t.is_empty()),
         }
     }
 
@@ -155,181 +293,335 @@ impl PostingListMerger {
         self.merged_num += 1;
     }
 
-    /// 应确保self比list的len要小，当差距足够大的时候性能可能会更好
+    /// 由self驱动，用skip anchor在list里跳着找，所以self比list小很多的时候很快。
+    /// 只会留下self里已有的doc，删除的doc在union时就已经跳过了
     pub fn intersection(&mut self, list: &RawPostingList) -> Result<()> {
-        if self.len() < list.len() / INTERSECTION_PERFORMANCE_TIPPING_SIZE_DIFF {
-            self.intersection_by_search(list)
-        } else {
-            self.intersection_by_stitch(list)
+        let mut cursor = list.cursor();
+        let field = self.field;
+        let mut postings = Vec::with_capacity(self.postings.len());
+
+        for mut posting in self.postings.drain(..) {
+            match cursor.advance_to(posting.doc_id)? {
+                Some(p) if p.doc_id == posting.doc_id => {
+                    if let Some(info) = p.info_in(field) {
+                        posting.add(info);
+                        postings.push(posting);
+                    }
+                }
+                Some(_) => (),
+                None => break,
+            }
         }
-    }
-
-    // TODO: 有没有什么更高效的办法批量删除元素？
-    fn intersection_by_search(&mut self, list: &RawPostingList) -> Result<()> {
-        let mut min = 0u32;
-
-        let mut need_remove = Vec::<usize>::new();
 
-        for i in 0..self.postings.len() {
-            let mut max = list.len();
+        self.postings = postings;
+        self.end_do_merge();
+        Ok(())
+    }
 
-            if min >= max {
-                self.postings.drain(i..self.postings.len());
-                break;
+    pub fn union(&mut self, list: &RawPostingList) -> Result<()> {
+        let mut cursor = list.cursor();
+        let mut next = cursor.next()?;
+        let (merged_num, field, tombstones) = (self.merged_num, self.field, self.tombstones);
+        let mut postings = Vec::with_capacity(self.postings.len() + list.len() as usize);
+
+        let insert = |p: RawPosting, postings: &mut Vec<Posting>| {
+            if tombstones.is_some_and(|t| t.contains(p.doc_id)) {
+                return;
             }
+            if let Some(info) = p.info_in(field) {
+                let mut new = Posting::new(p.doc_id, merged_num);
+                new.add(info);
+                postings.push(new);
+            }
+        };
 
-            let posting = unsafe { self.postings.get_unchecked_mut(i) };
-            let value = posting.doc_id;
-
-            let mut find = false;
-
-            loop {
-                let mid = min + ((max - min) >> 1);
-                let c_value = list.get_doc_id(mid)?;
-
-                if c_value < value {
-                    min = mid + 1;
-                } else if c_value > value {
-                    max = mid;
-                } else {
-                    find = true;
-
-                    posting.add(TermPriorityInfo::new(
-                        list.get_tf(mid)?,
-                        list.get_norm(mid)?,
-                    ));
-
-                    min = mid + 1;
-                    break;
-                }
+        for mut posting in self.postings.drain(..) {
+            // list中比当前posting小的doc之前都没有出现过，前面的term都补not_exist
+            while let Some(p) = next.filter(|p| p.doc_id < posting.doc_id) {
+                insert(p, &mut postings);
+                next = cursor.next()?;
+            }
 
-                if min >= max {
-                    break;
+            match next {
+                Some(p) if p.doc_id == posting.doc_id => {
+                    posting.add(p.info_in(field).unwrap_or_else(TermPriorityInfo::not_exist));
+                    next = cursor.next()?;
                 }
+                _ => posting.add(TermPriorityInfo::not_exist()),
             }
 
-            if !find {
-                need_remove.push(i);
-            }
+            postings.push(posting);
         }
 
-        for i in need_remove {
-            self.postings.remove(i);
+        while let Some(p) = next {
+            insert(p, &mut postings);
+            next = cursor.next()?;
         }
 
+        self.postings = postings;
         self.end_do_merge();
         Ok(())
     }
 
-    fn intersection_by_stitch(&mut self, list: &RawPostingList) -> Result<()> {
-        let (mut i, mut j) = (0usize, 0u32);
-        let mut need_remove = Vec::<usize>::new();
-
-        while i < self.postings.len() && j < list.len() {
-            let va = unsafe { self.postings.get_unchecked_mut(i) };
-            let vb = list.get_doc_id(j)?;
+    /// 去掉list中出现的doc，剩下的posting不变，也不算一次merge（排除的词不参与打分）
+    pub fn difference(&mut self, list: &RawPostingList) -> Result<()> {
+        let mut cursor = list.cursor();
+        let mut postings = Vec::with_capacity(self.postings.len());
 
-            if va.doc_id < vb {
-                need_remove.push(i);
-                i += 1;
-            } else if va.doc_id > vb {
-                j += 1;
-            } else {
-                va.add(TermPriorityInfo::new(list.get_tf(j)?, list.get_norm(j)?));
-                i += 1;
-                j += 1;
+        for posting in self.postings.drain(..) {
+            match cursor.advance_to(posting.doc_id)? {
+                Some(p) if p.doc_id == posting.doc_id && p.info_in(self.field).is_some() => (),
+                _ => postings.push(posting),
             }
         }
 
-        if i < self.postings.len() {
-            self.postings.drain(i..self.postings.len());
-        }
+        self.postings = postings;
+        Ok(())
+    }
+
+    /// 不改变候选文档：list中有的doc补上这个词的信息，没有的补not_exist。
+    /// 用于已经有必须条件时，把可选的词也算进打分
+    pub fn attach(&mut self, list: &RawPostingList) -> Result<()> {
+        let mut cursor = list.cursor();
+        let field = self.field;
 
-        for i in need_remove {
-            self.postings.remove(i);
+        for posting in self.postings.iter_mut() {
+            match cursor.advance_to(posting.doc_id)? {
+                Some(p) if p.doc_id == posting.doc_id => {
+                    posting.add(p.info_in(field).unwrap_or_else(TermPriorityInfo::not_exist))
+                }
+                _ => posting.add(TermPriorityInfo::not_exist()),
+            }
         }
 
         self.end_do_merge();
         Ok(())
     }
 
-    pub fn union(&mut self, list: &RawPostingList) -> Result<()> {
-        let (mut i, mut j) = (0usize, 0u32);
-
-        let mut need_insert = Vec::<u32>::new();
+    /// 和intersection一样，但是和另一个merger合并（嵌套的查询条件），
+    /// 留下两边都有的doc，other的每个词的信息接在self的后面
+    pub fn intersection_merger(&mut self, other: PostingListMerger) {
+        let mut others = other.postings.into_iter().peekable();
+        let mut postings = Vec::with_capacity(self.postings.len().min(others.len()));
 
-        while i < self.postings.len() && j < list.len() {
-            let va = unsafe { self.postings.get_unchecked_mut(i) };
-            let vb = list.get_doc_id(j)?;
+        for mut posting in self.postings.drain(..) {
+            while others.next_if(|o| o.doc_id < posting.doc_id).is_some() {}
 
-            if va.doc_id < vb {
-                va.add(TermPriorityInfo::not_exist());
-                i += 1;
-            } else if va.doc_id > vb {
-                need_insert.push(j);
-                j += 1;
-            } else {
-                va.add(TermPriorityInfo::new(list.get_tf(j)?, list.get_norm(j)?));
-                i += 1;
-                j += 1;
+            if let Some(o) = others.next_if(|o| o.doc_id == posting.doc_id) {
+                posting.term_priority_info.extend(o.term_priority_info);
+                postings.push(posting);
             }
         }
 
-        let mut insert = |i| -> Result<()> {
-            let mut posting = Posting::new(list.get_doc_id(i)?, self.merged_num);
-            posting.add(TermPriorityInfo::new(list.get_tf(i)?, list.get_norm(i)?));
-            self.postings.push(posting);
-            Ok(())
+        self.postings = postings;
+        self.merged_num += other.merged_num;
+    }
+
+    /// 和union一样，但是和另一个merger合并，只在一边出现的doc在另一边的词都补not_exist
+    pub fn union_merger(&mut self, other: PostingListMerger) {
+        let (merged_num, other_num) = (self.merged_num, other.merged_num);
+        let mut others = other.postings.into_iter().peekable();
+        let mut postings = Vec::with_capacity(self.postings.len() + others.len());
+
+        let insert = |o: Posting, postings: &mut Vec<Posting>| {
+            let mut new = Posting::new(o.doc_id, merged_num);
+            new.term_priority_info.extend(o.term_priority_info);
+            postings.push(new);
         };
 
-        if j < list.len() {
-            for i in j..list.len() {
-                insert(i)?;
+        for mut posting in self.postings.drain(..) {
+            while let Some(o) = others.next_if(|o| o.doc_id < posting.doc_id) {
+                insert(o, &mut postings);
+            }
+
+            match others.next_if(|o| o.doc_id == posting.doc_id) {
+                Some(o) => posting.term_priority_info.extend(o.term_priority_info),
+                None => pad_not_exist(&mut posting, other_num),
             }
+            postings.push(posting);
         }
 
-        for i in need_insert {
-            insert(i)?;
+        for o in others {
+            insert(o, &mut postings);
         }
-        // TODO: 已知前面一部分顺序都是排好的，只需要排新insert的部分就好了，并且新insert的部分也是有序的。merge num为0时union就不需要重新排序
-        // insert时直接找到正确的位置insert会不会更快，LinkedList是否会更好？
-        self.postings
-            .sort_unstable_by(|a, b| a.doc_id.cmp(&b.doc_id));
 
-        self.end_do_merge();
-        Ok(())
+        self.postings = postings;
+        self.merged_num += other_num;
+    }
+
+    /// 和attach一样，但是和另一个merger合并：不改变候选文档，other里没有的doc补not_exist
+    pub fn attach_merger(&mut self, other: PostingListMerger) {
+        let other_num = other.merged_num;
+        let mut others = other.postings.into_iter().peekable();
+
+        for posting in self.postings.iter_mut() {
+            while others.next_if(|o| o.doc_id < posting.doc_id).is_some() {}
+
+            match others.next_if(|o| o.doc_id == posting.doc_id) {
+                Some(o) => posting.term_priority_info.extend(o.term_priority_info),
+                None => pad_not_exist(posting, other_num),
+            }
+        }
+
+        self.merged_num += other_num;
+    }
+
+    /// 和difference一样，去掉other里有的doc，不算merge
+    pub fn difference_merger(&mut self, other: &PostingListMerger) {
+        let mut others = other.postings.iter().peekable();
+
+        self.postings.retain(|posting| {
+            while others.next_if(|o| o.doc_id < posting.doc_id).is_some() {}
+            others.next_if(|o| o.doc_id == posting.doc_id).is_none()
+        });
+    }
+}
+
+fn pad_not_exist(posting: &mut Posting, num: u32) {
+    posting.term_priority_info.resize(
+        posting.term_priority_info.len() + num as usize,
+        TermPriorityInfo::not_exist(),
+    );
+}
+
+/// 从posting list中解码出来的一个posting
+#[derive(Debug, Clone, Copy)]
+pub struct RawPosting {
+    pub doc_id: u32,
+    /// (tf_title, tf_content)
+    pub tf: (u8, u8),
+    /// (len_title, len_content)
+    pub len: (u16, u16),
+    /// 位置在posting list的data中的范围，用RawPostingList::positions解码
+    positions: (usize, usize),
+}
+
+impl RawPosting {
+    /// field不是None时只保留这个字段的tf，这个字段的tf为0（词只出现在另一个字段）时返回None
+    #[inline(always)]
+    fn info_in(&self, field: Option<Field>) -> Option<TermPriorityInfo> {
+        match field {
+            None => Some(TermPriorityInfo::new(self.tf, self.len)),
+            Some(Field::Title) if self.tf.0 > 0 => {
+                Some(TermPriorityInfo::new((self.tf.0, 0), self.len))
+            }
+            Some(Field::Content) if self.tf.1 > 0 => {
+                Some(TermPriorityInfo::new((0, self.tf.1), self.len))
+            }
+            _ => None,
+        }
     }
 }
 
 #[derive(Debug)]
-pub struct RawPostingList {
-    mmap: Mmap,
+pub struct RawPostingList<'a> {
+    /// skip anchor + data，从dict文件读的直接借用mmap，不需要拷贝；合并出来的自己持有
+    bytes: Cow<'a, [u8]>,
     len: u32,
+    /// data在bytes中的起始位置，前面是skip anchor
+    data_offset: usize,
 }
 
-impl RawPostingList {
-    pub fn new(file: &mut File, seek_from: SeekFrom) -> Result<Self> {
-        let offset = file.seek(seek_from)?;
-
-        let len = file.read_u32::<LittleEndian>()?;
+impl<'a> RawPostingList<'a> {
+    /// dict: 整个dict文件，offset: posting list在文件中的位置
+    pub fn new(dict: &'a [u8], offset: usize) -> Result<Self> {
+        let header = dict.get(offset..offset + 8).ok_or(Error::OutOfRange)?;
+        let len = LittleEndian::read_u32(header);
+        let data_len = LittleEndian::read_u32(&header[4..]);
 
         if len == 0 {
             return Err(Error::OutOfRange);
         }
 
-        let bytes = len * POSTING_SIZE;
+        let data_offset = ((len.div_ceil(SKIP_INTERVAL) - 1) * SKIP_ANCHOR_SIZE) as usize;
+        let bytes = dict
+            .get(offset + 8..offset + 8 + data_offset + data_len as usize)
+            .ok_or(Error::OutOfRange)?;
 
-        if file.metadata()?.len() < (offset + 4 + bytes as u64) {
-            return Err(Error::OutOfRange);
+        Ok(RawPostingList {
+            bytes: Cow::Borrowed(bytes),
+            len,
+            data_offset,
+        })
+    }
+
+    /// 在dict文件里占的字节数，包括8字节的头
+    pub fn encoded_len(&self) -> usize {
+        8 + self.bytes.len()
+    }
+
+    /// 把多个posting list合并成一个，用于模糊查询时一个词匹配到了词典里的多个词，
+    /// 以及有多个segment时把同一个词在各个segment里的list合并起来。
+    /// 同一个doc出现在多个list里时只算一次，tf取最大值，位置取并集。
+    /// list带着tombstones时跳过其中的doc，合并后什么都不剩时返回None
+    pub fn union_all(
+        lists: &[(RawPostingList, Option<&Tombstones>)],
+    ) -> Result<Option<RawPostingList<'static>>> {
+        Self::union_all_with(lists, || Ok(()))
+    }
+
+    /// 和union_all一样，每合并一个list之前调用一次before_list，它返回错误时停止合并并返回这个错误。
+    /// 查询时用来检查超时，一个词展开成很多个词时合并可能要很久
+    pub fn union_all_with<E: From<Error>>(
+        lists: &[(RawPostingList, Option<&Tombstones>)],
+        mut before_list: impl FnMut() -> std::result::Result<(), E>,
+    ) -> std::result::Result<Option<RawPostingList<'static>>, E> {
+        let mut postings = BTreeMap::<u32, (RawPosting, Cow<[u8]>)>::new();
+
+        for (list, tombstones) in lists.iter() {
+            before_list()?;
+            let mut cursor = list.cursor();
+            while let Some(p) = cursor.next()? {
+                if tombstones.is_some_and(|t| t.contains(p.doc_id)) {
+                    continue;
+                }
+
+                let positions = list.positions_bytes(&p);
+                match postings.get_mut(&p.doc_id) {
+                    None => {
+                        postings.insert(p.doc_id, (p, Cow::Borrowed(positions)));
+                    }
+                    Some((e, e_positions)) => {
+                        e.tf = (e.tf.0.max(p.tf.0), e.tf.1.max(p.tf.1));
+
+                        let (a, b) = (decode_positions(e_positions)?, decode_positions(positions)?);
+                        *e_positions = Cow::Owned(encode_positions(
+                            &merge_positions(&a.0, &b.0),
+                            &merge_positions(&a.1, &b.1),
+                        ));
+                    }
+                }
+            }
         }
 
-        let mmap = unsafe {
-            MmapOptions::new()
-                .offset(offset + 4)
-                .len(bytes as usize)
-                .map(&*file)?
-        };
-        Ok(RawPostingList { mmap, len })
+        if postings.is_empty() {
+            return Ok(None);
+        }
+
+        let mut encoder = PostingListEncoder::default();
+        for (p, positions) in postings.values() {
+            encoder.add(p.doc_id, p.tf, p.len, positions);
+        }
+
+        let data_offset = encoder.anchors.len();
+        let mut bytes = encoder.anchors;
+        bytes.extend_from_slice(&encoder.data);
+
+        Ok(Some(RawPostingList {
+            bytes: Cow::Owned(bytes),
+            len: encoder.len,
+            data_offset,
+        }))
+    }
+
+    /// 按和PostingListBuilder相同的格式写出，返回写入的字节数。合并segment时用
+    pub fn write(&self, mut writer: impl std::io::Write) -> Result<u64> {
+        let data_len = self.bytes.len() - self.data_offset;
+        writer.write_u32::<LittleEndian>(self.len)?;
+        writer.write_u32::<LittleEndian>(data_len as u32)?;
+        writer.write_all(&self.bytes)?;
+
+        Ok(4 + 4 + self.bytes.len() as u64)
     }
 
     #[inline(always)]
@@ -337,34 +629,269 @@ impl RawPostingList {
         self.len
     }
 
+    /// 返回p在两个字段中出现的位置(title, content)，p必须是从这个list解码出来的
+    pub fn positions(&self, p: &RawPosting) -> Result<(Vec<u32>, Vec<u32>)> {
+        decode_positions(self.positions_bytes(p))
+    }
+
     #[inline(always)]
-    pub fn get_doc_id(&self, index: u32) -> Result<u32> {
-        if index >= self.len() {
-            return Err(Error::OutOfRange);
-        }
+    fn positions_bytes(&self, p: &RawPosting) -> &[u8] {
+        &self.get_data()[p.positions.0..p.positions.1]
+    }
+
+    pub fn cursor(&self) -> PostingCursor<'_> {
+        PostingCursor::new(CursorList::Borrowed(self))
+    }
 
-        Ok(LittleEndian::read_u32(
-            &self.mmap[(index * POSTING_SIZE) as usize..],
-        ))
+    /// 和cursor一样，不过cursor持有list，可以和list一起返回给调用方
+    pub fn into_cursor(self) -> PostingCursor<'a> {
+        PostingCursor::new(CursorList::Owned(self))
     }
 
     #[inline(always)]
-    pub fn get_tf(&self, index: u32) -> Result<(u8, u8)> {
-        if index >= self.len() {
-            return Err(Error::OutOfRange);
-        }
+    fn block_num(&self) -> u32 {
+        self.len.div_ceil(SKIP_INTERVAL)
+    }
 
-        let offset = (index * POSTING_SIZE) as usize + 4;
-        Ok((self.mmap[offset], self.mmap[offset + 1]))
+    /// (上一个block最后的doc_id, block在data里的位置)，block不能是0
+    #[inline(always)]
+    fn get_anchor(&self, block: u32) -> (u32, usize) {
+        let offset = ((block - 1) * SKIP_ANCHOR_SIZE) as usize;
+        (
+            LittleEndian::read_u32(&self.bytes[offset..]),
+            LittleEndian::read_u32(&self.bytes[offset + 4..]) as usize,
+        )
     }
 
     #[inline(always)]
-    pub fn get_norm(&self, index: u32) -> Result<(u8, u8)> {
-        if index >= self.len() {
+    fn get_data(&self) -> &[u8] {
+        &self.bytes[self.data_offset..]
+    }
+}
+
+/// PostingCursor借用或者持有的list
+#[derive(Debug)]
+enum CursorList<'a> {
+    Borrowed(&'a RawPostingList<'a>),
+    Owned(RawPostingList<'a>),
+}
+
+impl<'a> std::ops::Deref for CursorList<'a> {
+    type Target = RawPostingList<'a>;
+
+    #[inline(always)]
+    fn deref(&self) -> &Self::Target {
+        match self {
+            CursorList::Borrowed(list) => list,
+            CursorList::Owned(list) => list,
+        }
+    }
+}
+
+/// 顺序解码posting list，doc id是差值编码的，不能按下标随机访问
+#[derive(Debug)]
+pub struct PostingCursor<'a> {
+    list: CursorList<'a>,
+    /// 下一个要解码的posting的序号
+    index: u32,
+    /// 下一个要解码的posting在data中的位置
+    pos: usize,
+    last_doc_id: u32,
+    /// advance_to找到但还没有被next取走的posting
+    peeked: Option<RawPosting>,
+}
+
+impl<'a> PostingCursor<'a> {
+    fn new(list: CursorList<'a>) -> Self {
+        PostingCursor {
+            list,
+            index: 0,
+            pos: 0,
+            last_doc_id: 0,
+            peeked: None,
+        }
+    }
+
+    pub fn next(&mut self) -> Result<Option<RawPosting>> {
+        if let Some(p) = self.peeked.take() {
+            return Ok(Some(p));
+        }
+
+        if self.index >= self.list.len() {
+            return Ok(None);
+        }
+
+        let data = self.list.get_data();
+
+        let doc_id = self
+            .last_doc_id
+            .checked_add(read_varint(data, &mut self.pos)?)
+            .ok_or(Error::OutOfRange)?;
+
+        let info = data
+            .get(self.pos..self.pos + POSTING_INFO_SIZE)
+            .ok_or(Error::OutOfRange)?;
+        self.pos += POSTING_INFO_SIZE;
+
+        let positions_len = read_varint(data, &mut self.pos)? as usize;
+        let positions = (self.pos, self.pos + positions_len);
+        if positions.1 > data.len() {
             return Err(Error::OutOfRange);
         }
+        self.pos = positions.1;
+        self.index += 1;
+        self.last_doc_id = doc_id;
 
-        let offset = (index * POSTING_SIZE) as usize + 4 + 2;
-        Ok((self.mmap[offset], self.mmap[offset + 1]))
+        Ok(Some(RawPosting {
+            doc_id,
+            tf: (info[0], info[1]),
+            len: (
+                LittleEndian::read_u16(&info[2..]),
+                LittleEndian::read_u16(&info[4..]),
+            ),
+            positions,
+        }))
+    }
+
+    /// 找到第一个doc_id >= target的posting，找到的posting不会被取走，
+    /// 下次advance_to或next还会返回它。target必须是递增的
+    pub fn advance_to(&mut self, target: u32) -> Result<Option<RawPosting>> {
+        match self.peeked {
+            Some(p) if p.doc_id >= target => return Ok(Some(p)),
+            _ => self.peeked = None,
+        }
+
+        // target在后面的block里时，先在anchor里二分找到最后一个可能包含它的block直接跳过去
+        let (mut min, mut max) = (
+            self.index.div_ceil(SKIP_INTERVAL).max(1),
+            self.list.block_num(),
+        );
+        while min < max {
+            let mid = min + ((max - min) >> 1);
+            if self.list.get_anchor(mid).0 < target {
+                min = mid + 1;
+            } else {
+                max = mid;
+            }
+        }
+
+        let block = min - 1;
+        if block * SKIP_INTERVAL > self.index {
+            let (last_doc_id, pos) = self.list.get_anchor(block);
+            self.index = block * SKIP_INTERVAL;
+            self.last_doc_id = last_doc_id;
+            self.pos = pos;
+        }
+
+        while let Some(p) = self.next()? {
+            if p.doc_id >= target {
+                self.peeked = Some(p);
+                return Ok(Some(p));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    /// tag存在len_title里，用来区分posting来自哪个list
+    fn encode(ids: impl Iterator<Item = u32>, tag: u16) -> Vec<u8> {
+        let mut map = BuildingPostingMap::new();
+        for id in ids {
+            let mut data = BuildingPostingData::new((tag, 10));
+            data.add_position(0, false);
+            map.insert(id, data);
+        }
+
+        let mut bytes = Vec::new();
+        PostingListBuilder::new(&mut bytes, &map).finish().unwrap();
+        bytes
+    }
+
+    /// 每个posting的doc id和各个词的tag，没有这个词时是0
+    fn dump(merger: &PostingListMerger) -> Vec<(u32, Vec<u16>)> {
+        merger
+            .get_postings()
+            .iter()
+            .map(|p| {
+                let tags = p.get_term_priority_info().iter().map(|i| i.len.0);
+                (p.get_doc_id(), tags.collect())
+            })
+            .collect()
+    }
+
+    /// 按集合算出来的期望结果：每个词是(这个词的信息会出现在哪些doc上, tag)
+    fn expected(
+        ids: impl Iterator<Item = u32>,
+        terms: &[(&BTreeSet<u32>, u16)],
+    ) -> Vec<(u32, Vec<u16>)> {
+        ids.map(|id| {
+            let tags = terms.iter().map(|(set, tag)| match set.contains(&id) {
+                true => *tag,
+                false => 0,
+            });
+            (id, tags.collect())
+        })
+        .collect()
+    }
+
+    const N: u32 = 1000;
+
+    /// 2、3、5的倍数三个互相交错的list，都超过一个block（SKIP_INTERVAL）
+    #[test]
+    fn merges_three_interleaved_lists() {
+        let sets = [2, 3, 5].map(|step| (0..N).step_by(step).collect::<BTreeSet<u32>>());
+        let bytes = [0, 1, 2].map(|i| encode(sets[i].iter().copied(), i as u16 + 1));
+        let lists = [0, 1, 2].map(|i| RawPostingList::new(&bytes[i], 0).unwrap());
+        let [a, b, c] = &sets;
+        let all = || 0..N;
+
+        let mut merger = PostingListMerger::new(None, None);
+        for list in lists.iter() {
+            merger.union(list).unwrap();
+        }
+        let union = all().filter(|id| a.contains(id) || b.contains(id) || c.contains(id));
+        assert_eq!(dump(&merger), expected(union, &[(a, 1), (b, 2), (c, 3)]));
+
+        let mut merger = PostingListMerger::new(None, None);
+        merger.union(&lists[0]).unwrap();
+        merger.intersection(&lists[1]).unwrap();
+        merger.intersection(&lists[2]).unwrap();
+        let both = all().filter(|id| id % 30 == 0);
+        assert_eq!(dump(&merger), expected(both, &[(a, 1), (b, 2), (c, 3)]));
+
+        // (a且b)或c：c里新出现的doc前面的词都是not_exist，即使它在a里
+        let mut merger = PostingListMerger::new(None, None);
+        merger.union(&lists[0]).unwrap();
+        merger.intersection(&lists[1]).unwrap();
+        merger.union(&lists[2]).unwrap();
+        let ab = all().filter(|id| id % 6 == 0).collect::<BTreeSet<_>>();
+        let ids = all().filter(|id| ab.contains(id) || c.contains(id));
+        assert_eq!(dump(&merger), expected(ids, &[(&ab, 1), (&ab, 2), (c, 3)]));
+
+        // a - b，再attach c：difference不算一个词，attach不改变候选文档
+        let mut merger = PostingListMerger::new(None, None);
+        merger.union(&lists[0]).unwrap();
+        merger.difference(&lists[1]).unwrap();
+        merger.attach(&lists[2]).unwrap();
+        let ids = all().filter(|id| a.contains(id) && !b.contains(id));
+        assert_eq!(dump(&merger), expected(ids, &[(a, 1), (c, 3)]));
+
+        // union时跳过删除的doc
+        let deleted = (0..N).step_by(7).collect::<BTreeSet<u32>>();
+        let tombstones = Tombstones::new(&deleted);
+        let mut merger = PostingListMerger::new(None, Some(&tombstones));
+        for list in lists.iter() {
+            merger.union(list).unwrap();
+        }
+        let ids = all().filter(|id| {
+            !deleted.contains(id) && (a.contains(id) || b.contains(id) || c.contains(id))
+        });
+        assert_eq!(dump(&merger), expected(ids, &[(a, 1), (b, 2), (c, 3)]));
     }
 }
@@ -0,0 +1,263 @@
+use crate::store::constants::{
+    is_supported_version, DOC_STORE_FILE_SUFFIX, KEY_INDEX_FILE_SUFFIX, KEY_INDEX_MAGIC_NUMBER,
+    NUMERIC_INDEX_FILE_SUFFIX, SEGMENTS_FILE_SUFFIX, SEGMENTS_MAGIC_NUMBER, TERM_DICT_FILE_SUFFIX,
+    TERM_DICT_MAGIC_NUMBER, TERM_INDEX_FILE_SUFFIX, TERM_INDEX_MAGIC_NUMBER, TOMBSTONE_FILE_SUFFIX,
+    VERSION,
+};
+use crate::store::directory::{Directory, FileBytes};
+use crate::store::doc_store::DocStoreReader;
+use crate::store::numeric::NumericIndexReader;
+use crate::store::posting::RawPostingList;
+use crate::store::tombstone::Tombstones;
+use crate::store::{Error, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::BTreeSet;
+use std::io::ErrorKind;
+
+/// 每次Builder::finish写一个新的segment，文件名是 identifier.编号.后缀。
+/// identifier.segments里按写入顺序记录当前有效的segment编号，编号越大越新
+#[derive(Debug, Default)]
+pub struct Segments {
+    ids: Vec<u32>,
+}
+
+impl Segments {
+    /// 还没有建过索引时返回空的
+    pub fn read(directory: &Directory, identifier: &str) -> Result<Self> {
+        let data = match directory.open(&segments_file_name(identifier)) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Segments::default()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut reader = &data[..];
+
+        if reader.read_u64::<LittleEndian>()? != SEGMENTS_MAGIC_NUMBER {
+            return Err(Error::Incompatible);
+        }
+
+        match reader.read_u8()? {
+            v if is_supported_version(v) => (),
+            v => return Err(Error::UnsupportedVersion(v)),
+        }
+
+        let len = reader.read_u32::<LittleEndian>()?;
+        let mut ids = Vec::with_capacity(len as usize);
+        for _ in 0..len {
+            ids.push(reader.read_u32::<LittleEndian>()?);
+        }
+
+        if ids.windows(2).any(|w| w[0] >= w[1]) {
+            return Err(Error::Incompatible);
+        }
+
+        Ok(Segments { ids })
+    }
+
+    /// magic number(64bit) + version(8bit) + 数量(32bit) + segment编号(32bit) * 数量。
+    /// 这是一次finish最后写的文件，之前的segment文件都已经落盘，新的列表替换旧的列表时才算提交成功。
+    /// Directory::create先写临时文件再rename，查询端不会读到写了一半的列表
+    pub fn write(&self, directory: &Directory, identifier: &str) -> Result<()> {
+        let mut writer = directory.create(&segments_file_name(identifier))?;
+        writer.write_u64::<LittleEndian>(SEGMENTS_MAGIC_NUMBER)?;
+        writer.write_u8(VERSION)?;
+        writer.write_u32::<LittleEndian>(self.ids.len() as u32)?;
+        for id in self.ids.iter() {
+            writer.write_u32::<LittleEndian>(*id)?;
+        }
+        writer.finish()?;
+
+        Ok(())
+    }
+
+    pub fn push(&mut self, id: u32) {
+        self.ids.push(id);
+    }
+
+    pub fn retain(&mut self, mut f: impl FnMut(u32) -> bool) {
+        self.ids.retain(|id| f(*id));
+    }
+
+    /// 从旧到新
+    pub fn ids(&self) -> &[u32] {
+        &self.ids
+    }
+
+    /// 新segment的编号，删掉的编号不会复用
+    pub fn next_id(&self) -> u32 {
+        self.ids.last().map_or(0, |id| id + 1)
+    }
+}
+
+pub fn segments_file_name(identifier: &str) -> String {
+    String::from(identifier) + SEGMENTS_FILE_SUFFIX
+}
+
+pub fn segment_file_name(identifier: &str, segment: u32, suffix: &str) -> String {
+    format!("{}.{}{}", identifier, segment, suffix)
+}
+
+/// 一个segment的全部文件，只读
+#[derive(Debug)]
+pub struct SegmentReader {
+    pub id: u32,
+    pub term_index: fst::Map<FileBytes>,
+    /// 整个dict文件，posting list直接从这里切出来
+    term_dict: FileBytes,
+    pub doc_num: u32,
+    /// (title平均长度, content平均长度)
+    pub avg_len: (f64, f64),
+    pub doc_store: DocStoreReader,
+    /// 这个segment里已经失效的doc：本segment和更新的segment删除的，以及更新的segment里又添加了的。
+    /// 同一个doc id只有最新的一份有效，多个segment的posting合并时不会有重复的doc
+    pub tombstones: Tombstones,
+    /// 版本9之前的segment没有，这时数值字段只能从文档存储里读
+    pub numeric_index: Option<NumericIndexReader>,
+    /// 外部id -> doc id，只有带外部id的文档。版本10之前的segment没有
+    pub key_index: Option<fst::Map<FileBytes>>,
+}
+
+impl SegmentReader {
+    fn open(directory: &Directory, identifier: &str, id: u32) -> Result<Self> {
+        let open = |suffix| directory.open(&segment_file_name(identifier, id, suffix));
+
+        // 每个文件都要能发现写了一半的情况：打开时报错，而不是查询时读到错误的数据或者越界
+        let index = open(TERM_INDEX_FILE_SUFFIX)?;
+        let index_offset = check_header(&index[..], TERM_INDEX_MAGIC_NUMBER)?;
+        // check_header已经检查过文件头的长度
+        let version = index[index_offset - 1];
+        let term_index = fst::Map::new(index.skip(index_offset))?;
+        term_index.as_fst().verify()?;
+
+        let term_dict = open(TERM_DICT_FILE_SUFFIX)?;
+        let mut header = &term_dict[..];
+        check_header(&mut header, TERM_DICT_MAGIC_NUMBER)?;
+        let doc_num = header.read_u32::<LittleEndian>()?;
+        let avg_len = (
+            header.read_f64::<LittleEndian>()?,
+            header.read_f64::<LittleEndian>()?,
+        );
+        // posting list按词的顺序写，最后一个词的list正好在文件末尾结束
+        if let Some(offset) = last_value(term_index.as_fst()) {
+            let list = RawPostingList::new(&term_dict, offset as usize)?;
+            if offset as usize + list.encoded_len() != term_dict.len() {
+                return Err(Error::OutOfRange);
+            }
+        }
+
+        let doc_store = DocStoreReader::new(open(DOC_STORE_FILE_SUFFIX)?)?;
+        let tombstones = Tombstones::read(&open(TOMBSTONE_FILE_SUFFIX)?[..])?;
+        // 版本9开始有数值字段的索引，10开始有外部id的索引，更早的segment没有这两个文件
+        let numeric_index = match open(NUMERIC_INDEX_FILE_SUFFIX) {
+            Ok(data) => Some(NumericIndexReader::new(data)?),
+            Err(e) if e.kind() == ErrorKind::NotFound && version < 9 => None,
+            Err(e) => return Err(e.into()),
+        };
+        let key_index = open_key_index(directory, identifier, id)?;
+        if key_index.is_none() && version >= 10 {
+            let name = segment_file_name(identifier, id, KEY_INDEX_FILE_SUFFIX);
+            return Err(
+                std::io::Error::new(ErrorKind::NotFound, format!("{} not found", name)).into(),
+            );
+        }
+
+        Ok(SegmentReader {
+            id,
+            term_index,
+            term_dict,
+            doc_num,
+            avg_len,
+            doc_store,
+            tombstones,
+            numeric_index,
+            key_index,
+        })
+    }
+
+    /// dict文件的大小（字节）
+    pub fn dict_size(&self) -> usize {
+        self.term_dict.len()
+    }
+
+    #[inline(always)]
+    pub fn posting_list(&self, offset: u64) -> Result<RawPostingList<'_>> {
+        RawPostingList::new(&self.term_dict, offset as usize)
+    }
+}
+
+/// 按identifier.segments打开所有segment，从旧到新排列，tombstones已经换成了最终失效的doc
+pub fn open_segments(directory: &Directory, identifier: &str) -> Result<Vec<SegmentReader>> {
+    let segments = Segments::read(directory, identifier)?;
+    if segments.ids.is_empty() {
+        return Err(std::io::Error::new(
+            ErrorKind::NotFound,
+            format!("no index named {}, the index needs to be built", identifier),
+        )
+        .into());
+    }
+
+    let mut readers = Vec::with_capacity(segments.ids.len());
+    for id in segments.ids.iter() {
+        readers.push(SegmentReader::open(directory, identifier, *id)?);
+    }
+
+    // 从新到旧，删除对自己和更旧的segment有效，添加会覆盖更旧的segment里同一个id
+    let mut newer = BTreeSet::<u32>::new();
+    for reader in readers.iter_mut().rev() {
+        let own = reader.tombstones.get_ids().iter().copied();
+        newer.extend(own);
+
+        reader.tombstones = Tombstones::new(&newer);
+        newer.extend(reader.doc_store.ids());
+    }
+
+    Ok(readers)
+}
+
+/// segment的外部id索引，版本10之前的segment没有这个文件，返回None
+pub fn open_key_index(
+    directory: &Directory,
+    identifier: &str,
+    id: u32,
+) -> Result<Option<fst::Map<FileBytes>>> {
+    let data = match directory.open(&segment_file_name(identifier, id, KEY_INDEX_FILE_SUFFIX)) {
+        Ok(data) => data,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let offset = check_header(&data[..], KEY_INDEX_MAGIC_NUMBER)?;
+    let index = fst::Map::new(data.skip(offset))?;
+    index.as_fst().verify()?;
+
+    Ok(Some(index))
+}
+
+/// 字典序最大的词对应的值，没有词时返回None。每一层都走最后一条边
+fn last_value<D: AsRef<[u8]>>(fst: &fst::raw::Fst<D>) -> Option<u64> {
+    let mut node = fst.root();
+    let mut output = fst::raw::Output::zero();
+
+    while !node.is_empty() {
+        let transition = node.transition(node.len() - 1);
+        output = output.cat(transition.out);
+        node = fst.node(transition.addr);
+    }
+
+    match node.is_final() {
+        true => Some(output.cat(node.final_output()).value()),
+        false => None,
+    }
+}
+
+fn check_header(mut reader: impl std::io::Read, magic_number: u64) -> Result<usize> {
+    if reader.read_u64::<LittleEndian>()? != magic_number {
+        return Err(Error::Incompatible);
+    }
+
+    match reader.read_u8()? {
+        v if is_supported_version(v) => (),
+        v => return Err(Error::UnsupportedVersion(v)),
+    }
+
+    Ok((64 + 8) / 8)
+}
@@ -1,5 +1,4 @@
 use crate::store::posting::{BuildingPostingData, BuildingPostingMap};
-use crate::store::Document;
 use std::collections::BTreeMap;
 
 pub type BuildingTermDictionary = BTreeMap<String, BuildingTermData>;
@@ -16,14 +15,15 @@ impl BuildingTermData {
         }
     }
 
-    pub fn add_posting(&mut self, doc: &Document, is_title: bool) {
-        match self.posting_map.get_mut(&doc.id) {
+    /// len: (len_title, len_content)，position是这个词在字段里是第几个token
+    pub fn add_posting(&mut self, doc_id: u32, len: (u16, u16), position: u32, is_title: bool) {
+        match self.posting_map.get_mut(&doc_id) {
             None => {
-                let mut d = BuildingPostingData::new(doc);
-                d.add_tf(is_title);
-                self.posting_map.insert(doc.id, d);
+                let mut d = BuildingPostingData::new(len);
+                d.add_position(position, is_title);
+                self.posting_map.insert(doc_id, d);
             }
-            Some(d) => d.add_tf(is_title),
+            Some(d) => d.add_position(position, is_title),
         }
     }
 
@@ -0,0 +1,69 @@
+use crate::store::constants::{is_supported_version, TOMBSTONE_MAGIC_NUMBER, VERSION};
+use crate::store::{Error, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::BTreeSet;
+
+/// 被删除的doc id，升序排列。删除只是做个标记，posting和文档存储里的数据都还在，
+/// 查询时把这些doc过滤掉
+#[derive(Debug, Default)]
+pub struct Tombstones {
+    ids: Vec<u32>,
+}
+
+impl Tombstones {
+    pub fn new(ids: &BTreeSet<u32>) -> Self {
+        Tombstones {
+            ids: ids.iter().copied().collect(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    #[inline(always)]
+    pub fn contains(&self, doc_id: u32) -> bool {
+        self.ids.binary_search(&doc_id).is_ok()
+    }
+
+    pub fn get_ids(&self) -> &Vec<u32> {
+        &self.ids
+    }
+
+    /// magic number(64bit) + version(8bit) + 数量(32bit) + doc_id(32bit) * 数量
+    pub fn write(&self, mut writer: impl std::io::Write) -> Result<()> {
+        writer.write_u64::<LittleEndian>(TOMBSTONE_MAGIC_NUMBER)?;
+        writer.write_u8(VERSION)?;
+        writer.write_u32::<LittleEndian>(self.ids.len() as u32)?;
+
+        for id in self.ids.iter() {
+            writer.write_u32::<LittleEndian>(*id)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn read(mut reader: impl std::io::Read) -> Result<Self> {
+        if reader.read_u64::<LittleEndian>()? != TOMBSTONE_MAGIC_NUMBER {
+            return Err(Error::Incompatible);
+        }
+
+        match reader.read_u8()? {
+            v if is_supported_version(v) => (),
+            v => return Err(Error::UnsupportedVersion(v)),
+        }
+
+        let len = reader.read_u32::<LittleEndian>()?;
+        let mut ids = Vec::with_capacity(len as usize);
+        for _ in 0..len {
+            ids.push(reader.read_u32::<LittleEndian>()?);
+        }
+
+        if ids.windows(2).any(|w| w[0] >= w[1]) {
+            return Err(Error::Incompatible);
+        }
+
+        Ok(Tombstones { ids })
+    }
+}